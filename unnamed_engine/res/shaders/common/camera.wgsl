@@ -0,0 +1,11 @@
+// Camera uniform shared by the world space shaders
+// Define CAMERA_GROUP before including to bind it to another group
+#ifndef CAMERA_GROUP
+#define CAMERA_GROUP 0
+#endif
+
+struct CameraUniform {
+    view_proj: mat4x4<f32>,
+};
+@group(CAMERA_GROUP) @binding(0)
+var<uniform> camera: CameraUniform;
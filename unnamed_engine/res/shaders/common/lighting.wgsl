@@ -0,0 +1,81 @@
+// Point lights and ambient light, see renderer/lighting.rs
+// Define LIGHTS_GROUP before including to bind them to another group
+#ifndef LIGHTS_GROUP
+#define LIGHTS_GROUP 0
+#endif
+
+// Must match lighting::MAX_LIGHTS
+const MAX_LIGHTS: u32 = 256u;
+
+struct PointLight {
+    // xyz position, w radius
+    position_radius: vec4<f32>,
+    // rgb color, a intensity
+    color_intensity: vec4<f32>,
+};
+
+struct Lighting {
+    inverse_view_proj: mat4x4<f32>,
+    eye: vec4<f32>,
+    forward: vec4<f32>,
+    ambient: vec4<f32>,
+    light_count: u32,
+    lights: array<PointLight, MAX_LIGHTS>,
+};
+@group(LIGHTS_GROUP) @binding(0)
+var<uniform> lighting: Lighting;
+// 1 where the ambient light is unoccluded, written by SSAO
+@group(LIGHTS_GROUP) @binding(1)
+var t_ambient_occlusion: texture_2d<f32>;
+
+fn ambient_occlusion(pixel: vec2<i32>) -> f32 {
+    let size = vec2<i32>(textureDimensions(t_ambient_occlusion));
+    return textureLoad(t_ambient_occlusion, clamp(pixel, vec2<i32>(0), size - 1), 0).r;
+}
+
+// How a surface reflects light, the deferred path keeps it in the G-buffer's material target
+struct Material {
+    specular: f32,
+    shininess: f32,
+};
+
+// Shininess is stored divided by this
+const MAX_SHININESS: f32 = 256.0;
+
+// Every surface uses it until meshes carry their own materials
+fn default_material() -> Material {
+    return Material(0.25, 32.0);
+}
+
+// Inverse square falloff windowed to reach zero at the radius
+fn light_attenuation(distance: f32, radius: f32) -> f32 {
+    let x = distance / radius;
+    let window = saturate(1.0 - x * x * x * x);
+    return window * window / (1.0 + distance * distance);
+}
+
+// Light reflected towards the camera, `normal` is normalized or zero
+fn point_light(light: PointLight, position: vec3<f32>, normal: vec3<f32>, albedo: vec3<f32>, material: Material) -> vec3<f32> {
+    let to_light = light.position_radius.xyz - position;
+    let distance = length(to_light);
+    let radius = light.position_radius.w;
+    if distance >= radius {
+        return vec3<f32>(0.0);
+    }
+    let l = to_light / max(distance, 1e-4);
+    let v = normalize(lighting.eye.xyz - position);
+    let h = normalize(l + v);
+    let n_dot_l = max(dot(normal, l), 0.0);
+    let highlight = select(0.0, material.specular * pow(max(dot(normal, h), 0.0), material.shininess), n_dot_l > 0.0);
+    let radiance = light.color_intensity.rgb * light.color_intensity.a * light_attenuation(distance, radius);
+    return (albedo * n_dot_l + highlight) * radiance;
+}
+
+// Ambient plus every light, for the forward path, `pixel` is the fragment's framebuffer position
+fn light_surface(pixel: vec2<i32>, position: vec3<f32>, normal: vec3<f32>, albedo: vec3<f32>, material: Material) -> vec3<f32> {
+    var color = albedo * lighting.ambient.rgb * ambient_occlusion(pixel);
+    for (var i = 0u; i < lighting.light_count; i++) {
+        color += point_light(lighting.lights[i], position, normal, albedo, material);
+    }
+    return color;
+}
@@ -0,0 +1,65 @@
+// Particle layout and emitter parameters shared by the simulation and the billboards
+// Define EMITTER_GROUP before including to bind the emitter to another group
+#ifndef EMITTER_GROUP
+#define EMITTER_GROUP 0
+#endif
+
+struct Particle {
+    position: vec3<f32>,
+    age: f32,
+    velocity: vec3<f32>,
+    // Dead once the age reaches it, unused slots have 0
+    lifetime: f32,
+};
+
+struct Emitter {
+    // w is the drag
+    origin: vec4<f32>,
+    // Half extents of the spawn volume, w is the time step
+    spawn_box: vec4<f32>,
+    // w are the lifetime bounds
+    velocity_min: vec4<f32>,
+    velocity_max: vec4<f32>,
+    gravity: vec4<f32>,
+    // Ring buffer slots spawned this step
+    spawn_start: u32,
+    spawn_count: u32,
+    max_particles: u32,
+    seed: u32,
+    color_keys: u32,
+    size_keys: u32,
+    colors: array<vec4<f32>, 8>,
+    // Key times and sizes are packed four to a vec4
+    color_times: array<vec4<f32>, 2>,
+    sizes: array<vec4<f32>, 2>,
+    size_times: array<vec4<f32>, 2>,
+};
+@group(EMITTER_GROUP) @binding(0)
+var<uniform> emitter: Emitter;
+
+// Curves are linear between keys and flat outside them, `t` is the normalized age
+fn sample_color(t: f32) -> vec4<f32> {
+    var color = emitter.colors[0];
+    for (var i = 1u; i < emitter.color_keys; i++) {
+        let t0 = emitter.color_times[(i - 1u) / 4u][(i - 1u) % 4u];
+        let t1 = emitter.color_times[i / 4u][i % 4u];
+        if t >= t0 {
+            color = mix(emitter.colors[i - 1u], emitter.colors[i], clamp((t - t0) / max(t1 - t0, 1e-5), 0.0, 1.0));
+        }
+    }
+    return color;
+}
+
+fn sample_size(t: f32) -> f32 {
+    var size = emitter.sizes[0][0];
+    for (var i = 1u; i < emitter.size_keys; i++) {
+        let t0 = emitter.size_times[(i - 1u) / 4u][(i - 1u) % 4u];
+        let t1 = emitter.size_times[i / 4u][i % 4u];
+        if t >= t0 {
+            let s0 = emitter.sizes[(i - 1u) / 4u][(i - 1u) % 4u];
+            let s1 = emitter.sizes[i / 4u][i % 4u];
+            size = mix(s0, s1, clamp((t - t0) / max(t1 - t0, 1e-5), 0.0, 1.0));
+        }
+    }
+    return size;
+}
@@ -0,0 +1,28 @@
+// Vertex shader
+#include "common/camera.wgsl"
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) color: vec4<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec4<f32>,
+};
+
+@vertex
+fn vs_main(
+    line: VertexInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+    out.color = line.color;
+    out.clip_position = camera.view_proj * vec4<f32>(line.position, 1.0);
+    return out;
+}
+
+// Fragment shader
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return in.color;
+}
@@ -0,0 +1,139 @@
+// Tiled light accumulation of the deferred path, and with DEFERRED_COMPOSITE defined the
+// pass drawing its result under the forward parts of the scene, see renderer/deferred.rs
+#ifdef DEFERRED_COMPOSITE
+
+@group(0) @binding(0)
+var t_lit: texture_2d<f32>;
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    return vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+}
+
+// Pixels without geometry are transparent and keep the clear color
+@fragment
+fn fs_main(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+    return textureLoad(t_lit, vec2<i32>(position.xy), 0);
+}
+
+#else
+
+#include "common/lighting.wgsl"
+
+@group(0) @binding(2)
+var t_albedo: texture_2d<f32>;
+@group(0) @binding(3)
+var t_normal: texture_2d<f32>;
+@group(0) @binding(4)
+var t_material: texture_2d<f32>;
+@group(0) @binding(5)
+var t_depth: texture_2d<f32>;
+@group(0) @binding(6)
+var t_output: texture_storage_2d<rgba16float, write>;
+
+// Must match deferred::TILE_SIZE
+const TILE_SIZE: u32 = 16u;
+
+// Depth bits, positive floats compare like their bit patterns
+var<workgroup> tile_min_depth: atomic<u32>;
+var<workgroup> tile_max_depth: atomic<u32>;
+var<workgroup> tile_light_count: atomic<u32>;
+// At most every light touches a tile
+var<workgroup> tile_lights: array<u32, MAX_LIGHTS>;
+
+fn unproject(pixel: vec2<f32>, size: vec2<f32>, depth: f32) -> vec3<f32> {
+    let uv = pixel / size;
+    let world = lighting.inverse_view_proj * vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+    return world.xyz / world.w;
+}
+
+@compute @workgroup_size(16, 16)
+fn cs_main(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(local_invocation_index) local: u32,
+    @builtin(workgroup_id) tile: vec3<u32>,
+) {
+    let size = textureDimensions(t_depth);
+    let inside = all(id.xy < size);
+    if local == 0u {
+        atomicStore(&tile_min_depth, 0xffffffffu);
+        atomicStore(&tile_max_depth, 0u);
+        atomicStore(&tile_light_count, 0u);
+    }
+    workgroupBarrier();
+
+    // The depth range of the tile's geometry, the background is left out
+    var depth = 1.0;
+    if inside {
+        depth = textureLoad(t_depth, vec2<i32>(id.xy), 0).r;
+    }
+    if depth < 1.0 {
+        atomicMin(&tile_min_depth, bitcast<u32>(depth));
+        atomicMax(&tile_max_depth, bitcast<u32>(depth));
+    }
+    workgroupBarrier();
+
+    let min_bits = atomicLoad(&tile_min_depth);
+    let max_bits = atomicLoad(&tile_max_depth);
+    let size_f = vec2<f32>(size);
+    if min_bits <= max_bits {
+        // The tile's frustum: four side planes through the eye and the view depth range
+        let eye = lighting.eye.xyz;
+        let forward = lighting.forward.xyz;
+        let low = vec2<f32>(tile.xy * TILE_SIZE);
+        let high = min(low + f32(TILE_SIZE), size_f);
+        let far_depth = bitcast<f32>(max_bits);
+        var corners = array<vec3<f32>, 4>(
+            unproject(low, size_f, far_depth) - eye,
+            unproject(vec2<f32>(high.x, low.y), size_f, far_depth) - eye,
+            unproject(high, size_f, far_depth) - eye,
+            unproject(vec2<f32>(low.x, high.y), size_f, far_depth) - eye,
+        );
+        let center = unproject((low + high) * 0.5, size_f, far_depth) - eye;
+        var planes: array<vec3<f32>, 4>;
+        for (var i = 0u; i < 4u; i++) {
+            let normal = normalize(cross(corners[i], corners[(i + 1u) % 4u]));
+            planes[i] = select(normal, -normal, dot(normal, center) < 0.0);
+        }
+        let near = dot(unproject((low + high) * 0.5, size_f, bitcast<f32>(min_bits)) - eye, forward);
+        let far = dot(center, forward);
+
+        for (var i = local; i < lighting.light_count; i += TILE_SIZE * TILE_SIZE) {
+            let light = lighting.lights[i].position_radius;
+            let offset = light.xyz - eye;
+            let distance = dot(offset, forward);
+            var touches = distance + light.w >= near && distance - light.w <= far;
+            for (var p = 0u; p < 4u; p++) {
+                touches = touches && dot(planes[p], offset) >= -light.w;
+            }
+            if touches {
+                tile_lights[atomicAdd(&tile_light_count, 1u)] = i;
+            }
+        }
+    }
+    workgroupBarrier();
+
+    if !inside {
+        return;
+    }
+    if depth >= 1.0 {
+        textureStore(t_output, vec2<i32>(id.xy), vec4<f32>(0.0));
+        return;
+    }
+    let pixel = vec2<i32>(id.xy);
+    let albedo = textureLoad(t_albedo, pixel, 0).rgb;
+    let normal = textureLoad(t_normal, pixel, 0).xyz;
+    let stored = textureLoad(t_material, pixel, 0);
+    let material = Material(stored.x, stored.y * MAX_SHININESS);
+    let position = unproject(vec2<f32>(id.xy) + 0.5, size_f, depth);
+
+    var color = albedo * lighting.ambient.rgb * ambient_occlusion(pixel);
+    let count = atomicLoad(&tile_light_count);
+    for (var i = 0u; i < count; i++) {
+        color += point_light(lighting.lights[tile_lights[i]], position, normal, albedo, material);
+    }
+    textureStore(t_output, pixel, vec4<f32>(color, 1.0));
+}
+
+#endif
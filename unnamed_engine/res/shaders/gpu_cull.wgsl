@@ -0,0 +1,125 @@
+// Culls instances against the frustum and the depth pyramid of the last frame
+// Visible instances are appended to the region of their draw and counted in its arguments
+
+// Words of one InstanceRaw, copied as is
+#ifndef INSTANCE_WORDS
+#define INSTANCE_WORDS 23u
+#endif
+
+struct CullUniform {
+    planes: array<vec4<f32>, 6>,
+    view_proj: mat4x4<f32>,
+    // Size of the pyramid's first level in x and y, its level count in z
+    pyramid: vec4<f32>,
+    instance_count: u32,
+    // Non zero to test against the pyramid
+    hi_z: u32,
+    _padding: vec2<u32>,
+}
+
+struct CullInstance {
+    // World space bounding sphere, radius in w
+    sphere: vec4<f32>,
+    draw: u32,
+    // First instance of the draw's region in the visible buffer
+    base: u32,
+    _padding: vec2<u32>,
+}
+
+struct DrawArgs {
+    index_count: u32,
+    instance_count: atomic<u32>,
+    first_index: u32,
+    base_vertex: i32,
+    first_instance: u32,
+}
+
+@group(0) @binding(0)
+var<uniform> cull: CullUniform;
+@group(0) @binding(1)
+var<storage, read> instances: array<CullInstance>;
+@group(0) @binding(2)
+var<storage, read> source: array<u32>;
+@group(0) @binding(3)
+var<storage, read_write> draws: array<DrawArgs>;
+@group(0) @binding(4)
+var<storage, read_write> visible: array<u32>;
+@group(0) @binding(5)
+var pyramid: texture_2d<f32>;
+
+fn in_frustum(sphere: vec4<f32>) -> bool {
+    for (var i = 0u; i < 6u; i++) {
+        let plane = cull.planes[i];
+        if dot(plane.xyz, sphere.xyz) + plane.w < -sphere.w {
+            return false;
+        }
+    }
+    return true;
+}
+
+// Whether the box around the sphere is behind everything the last frame drew over it
+fn occluded(sphere: vec4<f32>) -> bool {
+    var uv_min = vec2<f32>(1.0);
+    var uv_max = vec2<f32>(0.0);
+    var nearest = 1.0;
+    for (var i = 0u; i < 8u; i++) {
+        let corner = sphere.xyz + sphere.w * vec3<f32>(
+            select(-1.0, 1.0, (i & 1u) != 0u),
+            select(-1.0, 1.0, (i & 2u) != 0u),
+            select(-1.0, 1.0, (i & 4u) != 0u),
+        );
+        let clip = cull.view_proj * vec4<f32>(corner, 1.0);
+        // Boxes reaching behind the camera cover the whole screen
+        if clip.w <= 0.0 {
+            return false;
+        }
+        let ndc = clip.xyz / clip.w;
+        let uv = vec2<f32>(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+        uv_min = min(uv_min, uv);
+        uv_max = max(uv_max, uv);
+        nearest = min(nearest, ndc.z);
+    }
+    uv_min = clamp(uv_min, vec2<f32>(0.0), vec2<f32>(1.0));
+    uv_max = clamp(uv_max, vec2<f32>(0.0), vec2<f32>(1.0));
+
+    // The first level's texels under the rectangle, and the level where they span at most two
+    // texels per axis
+    let first_last = vec2<i32>(cull.pyramid.xy) - 1;
+    let first_lo = min(vec2<i32>(uv_min * cull.pyramid.xy), first_last);
+    let first_hi = min(vec2<i32>(uv_max * cull.pyramid.xy), first_last);
+    let span = f32(max(first_hi.x - first_lo.x, first_hi.y - first_lo.y));
+    let level = min(u32(ceil(log2(span + 1.0))), u32(cull.pyramid.z) - 1u);
+    // Shifted down each texel covers its whole footprint, the last ones of odd sized levels
+    // were reduced from the leftover row or column as well
+    // Sized from the first level, textureDimensions with a level differing between invocations
+    // returns the size of another invocation's level on some drivers
+    let last = max(vec2<i32>(cull.pyramid.xy) >> vec2<u32>(level), vec2<i32>(1)) - 1;
+    let lo = min(first_lo >> vec2<u32>(level), last);
+    let hi = min(first_hi >> vec2<u32>(level), last);
+    let farthest = max(
+        max(textureLoad(pyramid, lo, i32(level)).r, textureLoad(pyramid, vec2<i32>(hi.x, lo.y), i32(level)).r),
+        max(textureLoad(pyramid, vec2<i32>(lo.x, hi.y), i32(level)).r, textureLoad(pyramid, hi, i32(level)).r),
+    );
+    return nearest > farthest;
+}
+
+@compute @workgroup_size(64)
+fn cs_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let index = id.x;
+    if index >= cull.instance_count {
+        return;
+    }
+
+    let instance = instances[index];
+    if !in_frustum(instance.sphere) {
+        return;
+    }
+    if cull.hi_z != 0u && occluded(instance.sphere) {
+        return;
+    }
+
+    let slot = instance.base + atomicAdd(&draws[instance.draw].instance_count, 1u);
+    for (var word = 0u; word < INSTANCE_WORDS; word++) {
+        visible[slot * INSTANCE_WORDS + word] = source[index * INSTANCE_WORDS + word];
+    }
+}
@@ -0,0 +1,49 @@
+// Builds the depth pyramid for occlusion culling, every texel keeps the farthest depth below it
+// With HI_Z_COPY the first level is copied from the depth buffer, otherwise a level is reduced
+// from the one before
+
+// The depth buffer is bound as an unfilterable float texture, GLSL can't load from depth ones
+@group(0) @binding(0)
+var source: texture_2d<f32>;
+@group(0) @binding(1)
+var destination: texture_storage_2d<r32float, write>;
+
+#ifndef HI_Z_COPY
+struct Reduce {
+    // The source view covers the levels before the destination, starting at the first one
+    // Views starting further in are read from their texture's first level on GL
+    source_level: vec4<u32>,
+}
+
+@group(0) @binding(2)
+var<uniform> reduce: Reduce;
+#endif
+
+@compute @workgroup_size(8, 8)
+fn cs_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = textureDimensions(destination);
+    if id.x >= size.x || id.y >= size.y {
+        return;
+    }
+
+#ifdef HI_Z_COPY
+    let depth = textureLoad(source, vec2<i32>(id.xy), 0).r;
+#else
+    let level = i32(reduce.source_level.x);
+    let source_size = vec2<i32>(textureDimensions(source, level));
+    // The last texel of an odd sized level also covers the row or column left over
+    let extra = vec2<i32>(
+        select(0, 1, id.x == size.x - 1u && (source_size.x & 1) == 1),
+        select(0, 1, id.y == size.y - 1u && (source_size.y & 1) == 1),
+    );
+    let base = vec2<i32>(id.xy) * 2;
+    var depth = 0.0;
+    for (var y = 0; y <= 1 + extra.y; y++) {
+        for (var x = 0; x <= 1 + extra.x; x++) {
+            let texel = min(base + vec2<i32>(x, y), source_size - 1);
+            depth = max(depth, textureLoad(source, texel, level).r);
+        }
+    }
+#endif
+    textureStore(destination, vec2<i32>(id.xy), vec4<f32>(depth, 0.0, 0.0, 0.0));
+}
@@ -0,0 +1,15 @@
+// Bounding box proxies for occlusion queries, only depth tested and never shaded
+#include "common/camera.wgsl"
+
+struct BoxInput {
+    // Unit cube corner, 0 or 1 on each axis
+    @location(0) corner: vec3<f32>,
+    @location(1) min: vec3<f32>,
+    @location(2) max: vec3<f32>,
+}
+
+@vertex
+fn vs_main(input: BoxInput) -> @builtin(position) vec4<f32> {
+    let position = mix(input.min, input.max, input.corner);
+    return camera.view_proj * vec4<f32>(position, 1.0);
+}
@@ -0,0 +1,31 @@
+// Resolves weighted blended OIT over the opaque scene with a fullscreen triangle
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+};
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+    return out;
+}
+
+@group(0) @binding(0)
+var t_accum: texture_2d<f32>;
+@group(0) @binding(1)
+var t_revealage: texture_2d<f32>;
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let pixel = vec2<i32>(in.clip_position.xy);
+    // The fraction of the background still visible through every layer
+    let revealage = textureLoad(t_revealage, pixel, 0).r;
+    if revealage >= 1.0 {
+        discard;
+    }
+    let accum = textureLoad(t_accum, pixel, 0);
+    // Weighted average of the layers, blended in with their combined coverage
+    let color = accum.rgb / max(accum.a, 1e-5);
+    return vec4<f32>(color, 1.0 - revealage);
+}
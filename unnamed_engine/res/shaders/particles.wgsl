@@ -0,0 +1,57 @@
+// Camera facing billboards, one instance per particle slot
+#define EMITTER_GROUP 1
+#include "common/particles.wgsl"
+
+struct Frame {
+    view_proj: mat4x4<f32>,
+    // Camera axes in world space the billboards are spanned by
+    right: vec4<f32>,
+    up: vec4<f32>,
+};
+@group(0) @binding(0)
+var<uniform> frame: Frame;
+
+@group(2) @binding(0)
+var t_particle: texture_2d<f32>;
+@group(2) @binding(1)
+var s_particle: sampler;
+
+struct ParticleInput {
+    @location(0) position_age: vec4<f32>,
+    @location(1) velocity_lifetime: vec4<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) color: vec4<f32>,
+};
+
+@vertex
+fn vs_main(
+    @builtin(vertex_index) vertex_index: u32,
+    particle: ParticleInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+    let age = particle.position_age.w;
+    let lifetime = particle.velocity_lifetime.w;
+    if age >= lifetime {
+        // Behind the far plane, the whole quad gets clipped
+        out.clip_position = vec4<f32>(0.0, 0.0, 2.0, 1.0);
+        return out;
+    }
+
+    // Triangle strip corners from -0.5 to 0.5
+    let corner = vec2<f32>(f32(vertex_index & 1u), f32(vertex_index >> 1u)) - 0.5;
+    let t = age / lifetime;
+    let offset = (frame.right.xyz * corner.x + frame.up.xyz * corner.y) * sample_size(t);
+    out.clip_position = frame.view_proj * vec4<f32>(particle.position_age.xyz + offset, 1.0);
+    out.tex_coords = vec2<f32>(corner.x + 0.5, 0.5 - corner.y);
+    out.color = sample_color(t);
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return textureSample(t_particle, s_particle, in.tex_coords) * in.color;
+}
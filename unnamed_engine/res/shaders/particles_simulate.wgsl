@@ -0,0 +1,52 @@
+// Spawns and integrates the particles of one emitter, one invocation per slot
+#include "common/particles.wgsl"
+
+@group(0) @binding(1)
+var<storage, read_write> particles: array<Particle>;
+
+// PCG hash
+fn hash(value: u32) -> u32 {
+    let state = value * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+fn random(state: ptr<function, u32>) -> f32 {
+    *state = hash(*state);
+    return f32(*state) / 4294967295.0;
+}
+
+fn random3(state: ptr<function, u32>) -> vec3<f32> {
+    let x = random(state);
+    let y = random(state);
+    let z = random(state);
+    return vec3<f32>(x, y, z);
+}
+
+@compute @workgroup_size(64)
+fn cs_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let index = id.x;
+    if index >= emitter.max_particles {
+        return;
+    }
+
+    var particle = particles[index];
+    let dt = emitter.spawn_box.w;
+
+    // The spawn range wraps around the end of the ring buffer
+    let slot = (index + emitter.max_particles - emitter.spawn_start) % emitter.max_particles;
+    if slot < emitter.spawn_count {
+        var rng = hash(index ^ hash(emitter.seed));
+        particle.position = emitter.origin.xyz + (random3(&rng) * 2.0 - 1.0) * emitter.spawn_box.xyz;
+        particle.velocity = mix(emitter.velocity_min.xyz, emitter.velocity_max.xyz, random3(&rng));
+        particle.lifetime = mix(emitter.velocity_min.w, emitter.velocity_max.w, random(&rng));
+        particle.age = 0.0;
+    } else if particle.age < particle.lifetime {
+        let drag = max(1.0 - emitter.origin.w * dt, 0.0);
+        particle.velocity = (particle.velocity + emitter.gravity.xyz * dt) * drag;
+        particle.position += particle.velocity * dt;
+        particle.age += dt;
+    }
+
+    particles[index] = particle;
+}
@@ -0,0 +1,68 @@
+// Writes the entity of every scene instance into an R32Uint target, 0 means nothing
+// Bound like the main shader, so cut out texels are skipped the same way
+#define CAMERA_GROUP 1
+#include "common/camera.wgsl"
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+}
+
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+    @location(9) fade: f32,
+    @location(10) entity: u32,
+    @location(11) color: vec4<f32>,
+    @location(12) alpha_cutoff: f32,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) @interpolate(flat) entity: u32,
+    @location(1) @interpolate(flat) fade: f32,
+    @location(2) tex_coords: vec2<f32>,
+    @location(3) alpha: f32,
+    @location(4) @interpolate(flat) alpha_cutoff: f32,
+};
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    var out: VertexOutput;
+    out.entity = instance.entity;
+    out.fade = instance.fade;
+    out.tex_coords = model.tex_coords;
+    out.alpha = instance.color.a;
+    out.alpha_cutoff = instance.alpha_cutoff;
+    out.clip_position = camera.view_proj * model_matrix * vec4<f32>(model.position, 1.0);
+    return out;
+}
+
+@group(0) @binding(0)
+var t_diffuse: texture_2d<f32>;
+@group(0) @binding(1)
+var s_diffuse: sampler;
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) u32 {
+    // During LOD cross-fades only the incoming level is pickable, and without holes
+    if in.fade < 0.0 {
+        discard;
+    }
+    // Clicks go through the texels the main pass cuts out
+    if textureSample(t_diffuse, s_diffuse, in.tex_coords).a * in.alpha < in.alpha_cutoff {
+        discard;
+    }
+    return in.entity;
+}
@@ -0,0 +1,246 @@
+// Vertex shader
+#define CAMERA_GROUP 1
+#include "common/camera.wgsl"
+#define LIGHTS_GROUP 2
+#include "common/lighting.wgsl"
+
+// One of the VIEW_* constants, see renderer/debug_view.rs
+#ifndef DEBUG_VIEW
+#define DEBUG_VIEW 0u
+#endif
+
+const VIEW_LIT: u32 = 0u;
+const VIEW_WIREFRAME: u32 = 1u;
+const VIEW_WIREFRAME_BARYCENTRIC: u32 = 2u;
+const VIEW_NORMALS: u32 = 3u;
+const VIEW_UVS: u32 = 4u;
+const VIEW_ALBEDO: u32 = 5u;
+const VIEW_LIGHTING: u32 = 6u;
+const VIEW_OVERDRAW: u32 = 7u;
+const VIEW_MIP_LEVEL: u32 = 8u;
+const debug_view: u32 = DEBUG_VIEW;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+    @location(2) normal: vec3<f32>,
+}
+
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+    @location(9) fade: f32,
+    @location(11) color: vec4<f32>,
+    @location(12) alpha_cutoff: f32,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) @interpolate(flat) fade: f32,
+    @location(2) world_normal: vec3<f32>,
+    // Only meaningful for unindexed triangles, which the barycentric wireframe draws
+    @location(3) barycentric: vec3<f32>,
+    @location(4) color: vec4<f32>,
+    @location(5) @interpolate(flat) alpha_cutoff: f32,
+    @location(6) world_position: vec3<f32>,
+};
+
+@vertex
+fn vs_main(
+    @builtin(vertex_index) vertex_index: u32,
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    var out: VertexOutput;
+    out.tex_coords = model.tex_coords;
+    out.fade = instance.fade;
+    out.color = instance.color;
+    out.alpha_cutoff = instance.alpha_cutoff;
+    // Assumes uniform scale, which is enough for the debug views
+    out.world_normal = (model_matrix * vec4<f32>(model.normal, 0.0)).xyz;
+    out.barycentric = vec3<f32>(f32(vertex_index % 3u == 0u), f32(vertex_index % 3u == 1u), f32(vertex_index % 3u == 2u));
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+    out.world_position = world_position.xyz;
+    out.clip_position = camera.view_proj * world_position;
+    return out;
+}
+
+// Fragment shader
+@group(0) @binding(0)
+var t_diffuse: texture_2d<f32>;
+@group(0) @binding(1)
+var s_diffuse: sampler;
+
+// 4x4 ordered dither threshold in [0, 1)
+fn bayer4(pixel: vec2<f32>) -> f32 {
+    let p = vec2<u32>(pixel) % 4u;
+    var m = array<u32, 16>(0u, 8u, 2u, 10u, 12u, 4u, 14u, 6u, 3u, 11u, 1u, 9u, 15u, 7u, 13u, 5u);
+    return f32(m[p.y * 4u + p.x]) / 16.0;
+}
+
+// The texture color times the instance color, with cut out and faded pixels discarded
+fn surface_color(in: VertexOutput) -> vec4<f32> {
+    // LOD cross-fade, the level fading out keeps exactly the pixels the new one skips
+    if in.fade < 1.0 {
+        let threshold = bayer4(in.clip_position.xy);
+        if (in.fade >= 0.0 && threshold >= in.fade) || (in.fade < 0.0 && threshold < 1.0 + in.fade) {
+            discard;
+        }
+    }
+    let color = textureSample(t_diffuse, s_diffuse, in.tex_coords) * in.color;
+    if color.a < in.alpha_cutoff {
+        discard;
+    }
+    return color;
+}
+
+fn surface_material(in: VertexOutput) -> Material {
+    return default_material();
+}
+
+// The surface color under the scene's lights
+fn shade(in: VertexOutput, color: vec4<f32>) -> vec4<f32> {
+    let lit = light_surface(vec2<i32>(in.clip_position.xy), in.world_position, safe_normalize(in.world_normal), color.rgb, surface_material(in));
+    return vec4<f32>(lit, color.a);
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let albedo = surface_color(in);
+
+    switch debug_view {
+        case VIEW_WIREFRAME: {
+            return vec4<f32>(WIRE_COLOR, 1.0);
+        }
+        case VIEW_WIREFRAME_BARYCENTRIC: {
+            // Distance to the closest edge in pixels
+            let edge = in.barycentric / fwidth(in.barycentric);
+            if min(edge.x, min(edge.y, edge.z)) > 1.0 {
+                discard;
+            }
+            return vec4<f32>(WIRE_COLOR, 1.0);
+        }
+        case VIEW_NORMALS: {
+            return vec4<f32>(safe_normalize(in.world_normal) * 0.5 + 0.5, 1.0);
+        }
+        case VIEW_UVS: {
+            return vec4<f32>(fract(in.tex_coords), 0.0, 1.0);
+        }
+        case VIEW_ALBEDO: {
+            return vec4<f32>(albedo.rgb, 1.0);
+        }
+        case VIEW_LIGHTING: {
+            let n_dot_l = max(dot(safe_normalize(in.world_normal), KEY_LIGHT_DIRECTION), 0.0);
+            return vec4<f32>(vec3<f32>(AMBIENT_LIGHT + (1.0 - AMBIENT_LIGHT) * n_dot_l), 1.0);
+        }
+        case VIEW_OVERDRAW: {
+            // Added up by the blend state, red saturates first, then green and blue
+            return vec4<f32>(0.2, 0.07, 0.025, 1.0);
+        }
+        case VIEW_MIP_LEVEL: {
+            return vec4<f32>(mip_color(mip_level(in.tex_coords)), 1.0);
+        }
+        default: {
+            return shade(in, albedo);
+        }
+    }
+}
+
+struct OitOutput {
+    @location(0) accum: vec4<f32>,
+    @location(1) revealage: vec4<f32>,
+};
+
+// Weighted blended OIT, closer and more opaque layers weigh more
+fn oit_output(premultiplied: vec4<f32>, depth: f32) -> OitOutput {
+    let a = premultiplied.a;
+    let weight = clamp(pow(min(1.0, a * 10.0) + 0.01, 3.0) * 1e8 * pow(1.0 - depth * 0.9, 3.0), 1e-2, 3e3);
+    var out: OitOutput;
+    out.accum = premultiplied * weight;
+    out.revealage = vec4<f32>(a);
+    return out;
+}
+
+@fragment
+fn fs_oit(in: VertexOutput) -> OitOutput {
+    let color = shade(in, surface_color(in));
+    return oit_output(vec4<f32>(color.rgb * color.a, color.a), in.clip_position.z);
+}
+
+@fragment
+fn fs_oit_premultiplied(in: VertexOutput) -> OitOutput {
+    return oit_output(shade(in, surface_color(in)), in.clip_position.z);
+}
+
+// Targets of the deferred path, see renderer/deferred.rs
+struct GBufferOutput {
+    @location(0) albedo: vec4<f32>,
+    @location(1) normal: vec4<f32>,
+    // Specular strength and shininess over MAX_SHININESS
+    @location(2) material: vec4<f32>,
+};
+
+@fragment
+fn fs_gbuffer(in: VertexOutput) -> GBufferOutput {
+    let albedo = surface_color(in);
+    let material = surface_material(in);
+    var out: GBufferOutput;
+    out.albedo = vec4<f32>(albedo.rgb, 1.0);
+    out.normal = vec4<f32>(safe_normalize(in.world_normal), 0.0);
+    out.material = vec4<f32>(material.specular, material.shininess / MAX_SHININESS, 0.0, 0.0);
+    return out;
+}
+
+// Depth only, for the prepass SSAO reads in the forward path, see renderer/ssao.rs
+@fragment
+fn fs_depth(in: VertexOutput) {
+    // Only for its discards
+    _ = surface_color(in);
+}
+
+const WIRE_COLOR: vec3<f32> = vec3<f32>(0.6, 1.0, 0.6);
+// Stands in for real lights until the scene has them, towards the light
+const KEY_LIGHT_DIRECTION: vec3<f32> = vec3<f32>(0.32, 0.8, 0.51);
+const AMBIENT_LIGHT: f32 = 0.15;
+
+// Zero for degenerate normals, like the ones LOD simplification can leave behind
+fn safe_normalize(v: vec3<f32>) -> vec3<f32> {
+    let length_squared = dot(v, v);
+    if length_squared > 0.0 {
+        return v * inverseSqrt(length_squared);
+    }
+    return vec3<f32>(0.0);
+}
+
+// Level the hardware picks from the screen space footprint of a texel
+// GL has no textureNumLevels, so this keeps counting past the smallest mip of the texture
+fn mip_level(uv: vec2<f32>) -> f32 {
+    let size = vec2<f32>(textureDimensions(t_diffuse));
+    let dx = dpdx(uv * size);
+    let dy = dpdy(uv * size);
+    return max(0.5 * log2(max(dot(dx, dx), dot(dy, dy))), 0.0);
+}
+
+// Blue, cyan, green, yellow, red and magenta from level 0 upwards
+fn mip_color(level: f32) -> vec3<f32> {
+    var colors = array<vec3<f32>, 6>(
+        vec3<f32>(0.0, 0.0, 1.0),
+        vec3<f32>(0.0, 1.0, 1.0),
+        vec3<f32>(0.0, 1.0, 0.0),
+        vec3<f32>(1.0, 1.0, 0.0),
+        vec3<f32>(1.0, 0.0, 0.0),
+        vec3<f32>(1.0, 0.0, 1.0),
+    );
+    let lower = u32(min(floor(level), 5.0));
+    let upper = min(lower + 1u, 5u);
+    return mix(colors[lower], colors[upper], fract(level));
+}
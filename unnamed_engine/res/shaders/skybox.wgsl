@@ -0,0 +1,73 @@
+// Sky behind the scene, a fullscreen triangle at the far plane
+const MODE_CUBEMAP: u32 = 1u;
+const MODE_PROCEDURAL: u32 = 2u;
+
+struct SkyUniform {
+    // Inverse view projection without the camera translation
+    inv_view_proj: mat4x4<f32>,
+    zenith_color: vec4<f32>,
+    horizon_color: vec4<f32>,
+    ground_color: vec4<f32>,
+    // w is the cosine of the sun's angular radius
+    sun_direction: vec4<f32>,
+    sun_color: vec4<f32>,
+    mode: u32,
+    exposure: f32,
+};
+@group(0) @binding(0)
+var<uniform> sky: SkyUniform;
+
+@group(1) @binding(0)
+var t_sky: texture_cube<f32>;
+@group(1) @binding(1)
+var s_sky: sampler;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) ndc: vec2<f32>,
+};
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.ndc = uv * 2.0 - 1.0;
+    // z = w puts the sky at depth 1, so only pixels nothing was drawn to pass
+    out.clip_position = vec4<f32>(out.ndc, 1.0, 1.0);
+    return out;
+}
+
+fn procedural_sky(direction: vec3<f32>) -> vec3<f32> {
+    let sun = normalize(sky.sun_direction.xyz);
+    let sun_cos = dot(direction, sun);
+
+    var color: vec3<f32>;
+    if direction.y >= 0.0 {
+        color = mix(sky.horizon_color.rgb, sky.zenith_color.rgb, sqrt(direction.y));
+        // Haze around the sun, strongest near the horizon
+        let haze = pow(max(sun_cos, 0.0), 8.0) * (1.0 - direction.y);
+        color = mix(color, sky.sun_color.rgb, clamp(haze * 0.3, 0.0, 1.0));
+    } else {
+        color = mix(sky.horizon_color.rgb, sky.ground_color.rgb, clamp(-direction.y * 8.0, 0.0, 1.0));
+    }
+
+    let glow = pow(max(sun_cos, 0.0), 256.0) * 0.5;
+    let disk = smoothstep(sky.sun_direction.w - 0.00002, sky.sun_direction.w, sun_cos);
+    return color + sky.sun_color.rgb * (glow + disk);
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let world = sky.inv_view_proj * vec4<f32>(in.ndc, 1.0, 1.0);
+    let direction = normalize(world.xyz / world.w);
+
+    // Sampled outside the branch to stay in uniform control flow
+    let cubemap = textureSample(t_sky, s_sky, direction).rgb;
+    var color: vec3<f32>;
+    if sky.mode == MODE_CUBEMAP {
+        color = cubemap;
+    } else {
+        color = procedural_sky(direction);
+    }
+    return vec4<f32>(color * sky.exposure, 1.0);
+}
@@ -1,37 +1,37 @@
 // Vertex shader
-struct CameraUniform {
-    view_proj: mat4x4<f32>,
-};
-@group(1) @binding(0)
-var<uniform> camera: CameraUniform;
+#define CAMERA_GROUP 1
+#include "common/camera.wgsl"
 
 struct VertexInput {
     @location(0) position: vec3<f32>,
     @location(1) tex_coords: vec2<f32>,
+    @location(2) color: vec4<f32>,
 }
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) tex_coords: vec2<f32>,
+    @location(1) color: vec4<f32>,
 };
 
 @vertex
 fn vs_main(
-    model: VertexInput,
+    sprite: VertexInput,
 ) -> VertexOutput {
     var out: VertexOutput;
-    out.tex_coords = model.tex_coords;
-    out.clip_position = camera.view_proj * vec4<f32>(model.position, 1.0);
+    out.tex_coords = sprite.tex_coords;
+    out.color = sprite.color;
+    out.clip_position = camera.view_proj * vec4<f32>(sprite.position, 1.0);
     return out;
 }
 
 // Fragment shader
 @group(0) @binding(0)
-var t_diffuse: texture_2d<f32>;
+var t_atlas: texture_2d<f32>;
 @group(0) @binding(1)
-var s_diffuse: sampler;
+var s_atlas: sampler;
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    return textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    return textureSample(t_atlas, s_atlas, in.tex_coords) * in.color;
 }
@@ -0,0 +1,151 @@
+// Screen-space ambient occlusion from the depth buffer and its bilateral blur, see renderer/ssao.rs
+
+struct Ssao {
+    inverse_view_proj: mat4x4<f32>,
+    eye: vec4<f32>,
+    forward: vec4<f32>,
+    // World space
+    radius: f32,
+    intensity: f32,
+    // Pixels per world unit at a view depth of 1
+    projection_scale: f32,
+    sample_count: u32,
+};
+@group(0) @binding(0)
+var<uniform> ssao: Ssao;
+@group(0) @binding(1)
+var t_depth: texture_2d<f32>;
+// The occlusion being blurred, only bound for the blur passes
+@group(0) @binding(2)
+var t_source: texture_2d<f32>;
+
+const TAU: f32 = 6.2831853;
+// Turns of the sample spiral, coprime with common sample counts so taps don't line up
+const SPIRAL_TURNS: f32 = 7.0;
+// Cosine of the angle above the surface an occluder needs, hides faceted and reconstructed normals
+const BIAS: f32 = 0.1;
+// Keeps the taps of surfaces right in front of the camera on a handful of screen tiles
+const MAX_SCREEN_RADIUS: f32 = 0.25;
+const BLUR_RADIUS: i32 = 4;
+const BLUR_SIGMA: f32 = 2.5;
+// Relative view depth difference at which a blur tap stops counting
+const BLUR_DEPTH_TOLERANCE: f32 = 0.05;
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    return vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+}
+
+fn load_depth(pixel: vec2<i32>) -> f32 {
+    let size = vec2<i32>(textureDimensions(t_depth));
+    return textureLoad(t_depth, clamp(pixel, vec2<i32>(0), size - 1), 0).r;
+}
+
+fn load_source(pixel: vec2<i32>) -> f32 {
+    let size = vec2<i32>(textureDimensions(t_source));
+    return textureLoad(t_source, clamp(pixel, vec2<i32>(0), size - 1), 0).r;
+}
+
+fn unproject(pixel: vec2<f32>, depth: f32) -> vec3<f32> {
+    let uv = pixel / vec2<f32>(textureDimensions(t_depth));
+    let world = ssao.inverse_view_proj * vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+    return world.xyz / world.w;
+}
+
+fn position_at(pixel: vec2<i32>) -> vec3<f32> {
+    return unproject(vec2<f32>(pixel) + 0.5, load_depth(pixel));
+}
+
+fn view_depth(position: vec3<f32>) -> f32 {
+    return dot(position - ssao.eye.xyz, ssao.forward.xyz);
+}
+
+// The shorter of the differences to both neighbours, so edges don't bend the normal towards
+// whatever is behind them. Zero ones come from the clamping at the screen border
+fn shorter(a: vec3<f32>, b: vec3<f32>) -> vec3<f32> {
+    let aa = dot(a, a);
+    let bb = dot(b, b);
+    return select(b, a, (aa < bb && aa > 0.0) || bb == 0.0);
+}
+
+// Facing the eye
+fn reconstruct_normal(pixel: vec2<i32>, position: vec3<f32>) -> vec3<f32> {
+    let dx = shorter(position - position_at(pixel - vec2<i32>(1, 0)), position_at(pixel + vec2<i32>(1, 0)) - position);
+    let dy = shorter(position - position_at(pixel - vec2<i32>(0, 1)), position_at(pixel + vec2<i32>(0, 1)) - position);
+    let normal = cross(dx, dy);
+    if dot(normal, normal) == 0.0 {
+        return -ssao.forward.xyz;
+    }
+    return faceForward(normalize(normal), position - ssao.eye.xyz, normal);
+}
+
+// Per pixel rotation of the spiral, noise the blur evens out
+fn interleaved_gradient_noise(pixel: vec2<f32>) -> f32 {
+    return fract(52.9829189 * fract(dot(pixel, vec2<f32>(0.06711056, 0.00583715))));
+}
+
+// Visibility of the ambient light, 1 for the background
+@fragment
+fn fs_ao(@builtin(position) frag: vec4<f32>) -> @location(0) vec4<f32> {
+    let pixel = vec2<i32>(frag.xy);
+    let depth = load_depth(pixel);
+    if depth >= 1.0 {
+        return vec4<f32>(1.0);
+    }
+    let position = unproject(frag.xy, depth);
+    let normal = reconstruct_normal(pixel, position);
+    let max_radius = MAX_SCREEN_RADIUS * f32(textureDimensions(t_depth).y);
+    let screen_radius = min(ssao.projection_scale * ssao.radius / max(view_depth(position), 1e-4), max_radius);
+    let radius_squared = ssao.radius * ssao.radius;
+    let spin = TAU * interleaved_gradient_noise(frag.xy);
+
+    // Cosine weighted occluders above the surface, fading out towards the radius
+    let count = max(ssao.sample_count, 1u);
+    var occlusion = 0.0;
+    for (var i = 0u; i < count; i++) {
+        let alpha = (f32(i) + 0.5) / f32(count);
+        let angle = alpha * SPIRAL_TURNS * TAU + spin;
+        let tap = vec2<i32>(frag.xy + vec2<f32>(cos(angle), sin(angle)) * alpha * screen_radius);
+        let v = position_at(tap) - position;
+        let vv = dot(v, v);
+        let falloff = max(1.0 - vv / radius_squared, 0.0);
+        let cosine = dot(v, normal) * inverseSqrt(vv + 1e-6 * radius_squared);
+        occlusion += falloff * max(cosine - BIAS, 0.0);
+    }
+    let visibility = saturate(1.0 - 2.0 * ssao.intensity * occlusion / f32(count));
+    return vec4<f32>(visibility);
+}
+
+// Gaussian weights times how close the taps are to the pixel's view depth
+fn blur(frag: vec2<f32>, direction: vec2<i32>) -> f32 {
+    let pixel = vec2<i32>(frag);
+    let depth = load_depth(pixel);
+    if depth >= 1.0 {
+        return 1.0;
+    }
+    let center = view_depth(unproject(frag, depth));
+    var sum = 0.0;
+    var weights = 0.0;
+    for (var i = -BLUR_RADIUS; i <= BLUR_RADIUS; i++) {
+        let tap = pixel + direction * i;
+        let tap_depth = load_depth(tap);
+        let difference = abs(view_depth(unproject(vec2<f32>(tap) + 0.5, tap_depth)) - center) / center;
+        let gaussian = exp(-f32(i * i) / (2.0 * BLUR_SIGMA * BLUR_SIGMA));
+        let weight = gaussian * max(1.0 - difference / BLUR_DEPTH_TOLERANCE, 0.0) * f32(tap_depth < 1.0);
+        sum += load_source(tap) * weight;
+        weights += weight;
+    }
+    // The center tap always counts
+    return sum / weights;
+}
+
+@fragment
+fn fs_blur_x(@builtin(position) frag: vec4<f32>) -> @location(0) vec4<f32> {
+    return vec4<f32>(blur(frag.xy, vec2<i32>(1, 0)));
+}
+
+@fragment
+fn fs_blur_y(@builtin(position) frag: vec4<f32>) -> @location(0) vec4<f32> {
+    return vec4<f32>(blur(frag.xy, vec2<i32>(0, 1)));
+}
@@ -0,0 +1,54 @@
+// Vertex shader
+struct TextUniform {
+    screen_proj: mat4x4<f32>,
+    view_proj: mat4x4<f32>,
+    camera_right: vec4<f32>,
+    camera_up: vec4<f32>,
+};
+@group(1) @binding(0)
+var<uniform> text: TextUniform;
+
+struct VertexInput {
+    @location(0) anchor: vec3<f32>,
+    @location(1) offset: vec2<f32>,
+    @location(2) tex_coords: vec2<f32>,
+    @location(3) color: vec4<f32>,
+    @location(4) world: f32,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) color: vec4<f32>,
+};
+
+@vertex
+fn vs_main(
+    glyph: VertexInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+    out.tex_coords = glyph.tex_coords;
+    out.color = glyph.color;
+    if glyph.world > 0.5 {
+        // Billboard, offsets grow downwards like on screen
+        let position = glyph.anchor
+            + text.camera_right.xyz * glyph.offset.x
+            - text.camera_up.xyz * glyph.offset.y;
+        out.clip_position = text.view_proj * vec4<f32>(position, 1.0);
+    } else {
+        out.clip_position = text.screen_proj * vec4<f32>(glyph.anchor.xy + glyph.offset, 0.0, 1.0);
+    }
+    return out;
+}
+
+// Fragment shader
+@group(0) @binding(0)
+var t_glyphs: texture_2d<f32>;
+@group(0) @binding(1)
+var s_glyphs: sampler;
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let coverage = textureSample(t_glyphs, s_glyphs, in.tex_coords).r;
+    return vec4<f32>(in.color.rgb, in.color.a * coverage);
+}
@@ -17,7 +17,7 @@ impl Engine {
     pub fn new(title: String) -> Self {
         Engine {
             running: false,
-            title: title,
+            title,
         }
     }
 
@@ -29,7 +29,8 @@ impl Engine {
         start_f();
 
         self.running = true;
-        let result = tokio::runtime::Runtime::new().unwrap().block_on(self.run(update_f, render_f));
+        tokio::runtime::Runtime::new().unwrap().block_on(self.run(update_f, render_f));
+        self.running = false;
     }
 
     // // Stops the engine
@@ -40,13 +41,22 @@ impl Engine {
     // }
 
     // Starts running the engine
-    async fn run(&self, mut update_f: impl FnMut(), mut render_f: impl FnMut()) {
+    async fn run(&self, _update_f: impl FnMut(), _render_f: impl FnMut()) {
         let event_loop = EventLoop::new().unwrap();
-        let window = WindowBuilder::new().build(&event_loop).unwrap();
+        let window = WindowBuilder::new()
+            .with_title(&self.title)
+            .build(&event_loop)
+            .unwrap();
 
-        let mut state = State::new(window).await;
+        let mut state = match State::new(window).await {
+            Ok(state) => state,
+            Err(e) => {
+                log::error!("{:?}", e);
+                return;
+            },
+        };
 
-        let my_window_id = state.window().id();
+        let my_window_id = state.window().unwrap().id();
 
         event_loop.run(move |event, elwt| {
             match event {
@@ -60,11 +70,19 @@ impl Engine {
                             state.resize(*physical_size);
                         },
                         WindowEvent::ScaleFactorChanged { .. } => {
-                            state.resize(state.window().inner_size());
+                            if let Some(size) = state.window().map(|window| window.inner_size()) {
+                                state.resize(size);
+                            }
+                        },
+                        WindowEvent::CursorMoved { position, .. } => {
+                            state.cursor_moved(*position);
+                        },
+                        WindowEvent::CursorLeft { .. } => {
+                            state.cursor_left();
                         },
                         WindowEvent::KeyboardInput { event, .. } =>{
                             if state.input(event) {
-                                state.window().request_redraw();
+                                state.request_redraw();
                                 return;
                             }
                             if event.state.is_pressed() {
@@ -72,11 +90,35 @@ impl Engine {
                                     Code(KeyCode::Escape) => {
                                         elwt.exit();
                                     },
+                                    Code(KeyCode::F1) => {
+                                        let view = state.debug_view().next();
+                                        match state.set_debug_view(view) {
+                                            Ok(_) => log::info!("Debug view: {}", view),
+                                            Err(e) => log::error!("{:?}", e),
+                                        }
+                                    },
+                                    Code(KeyCode::F12) => {
+                                        Self::take_screenshot(&mut state);
+                                    },
                                     _ => {}
                                 }
                             }
                         },
-                        WindowEvent::RedrawRequested if window_id == state.window().id() => {
+                        WindowEvent::RedrawRequested if state.window().is_some_and(|window| window.id() == window_id) => {
+                            if state.is_device_lost() {
+                                // The event loop runs inside the engine's runtime, which drives the adapter request
+                                let recovered = tokio::task::block_in_place(|| {
+                                    tokio::runtime::Handle::current().block_on(state.recover_device())
+                                });
+                                match recovered {
+                                    Ok(_) => log::info!("Recreated the device on {}", state.adapter_info().name),
+                                    Err(e) => {
+                                        log::error!("{:?}", e);
+                                        elwt.exit();
+                                        return;
+                                    },
+                                }
+                            }
                             state.update();
                             match state.render() {
                                 Ok(_) => {}
@@ -85,25 +127,44 @@ impl Engine {
                                 // The system is out of memory, we should probably quit
                                 Err(wgpu::SurfaceError::OutOfMemory) => elwt.exit(),
                                 // All other errors (Outdated, Timeout) should be resolved by the next frame
-                                Err(e) => eprintln!("{:?}", e),
+                                Err(e) => log::error!("{:?}", e),
                             }
 
                         },
                         _ => {}
                     }
                 },
-                Event::AboutToWait => state.window().request_redraw(),
+                Event::AboutToWait => state.request_redraw(),
+                // Whatever the game didn't release shows up here
+                Event::LoopExiting => log::debug!("{}", state.resource_report()),
                 _ => {}
             }
         }).unwrap();
     }
 
+    // Saves the current frame under screenshots/ named after the capture time
+    fn take_screenshot(state: &mut State) {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|duration| duration.as_millis())
+            .unwrap_or_default();
+        let path = format!("screenshots/screenshot_{}.png", timestamp);
+
+        match state.save_screenshot(&path) {
+            Ok(_) => log::info!("Saved screenshot to {}", path),
+            Err(e) => log::error!("{:?}", e),
+        }
+    }
+
     // Logical update that runs at each iteration of the engine
+    // Not called by the event loop yet, neither is `render`
+    #[allow(dead_code)]
     fn update(&self, mut update_f: impl FnMut()) {
         update_f();
     }
 
     // Rendering that runs at each iteration of the engine
+    #[allow(dead_code)]
     fn render(&self, mut render_f: impl FnMut()) {
         render_f();
     }
@@ -1,243 +1,341 @@
-use wgpu::{util::DeviceExt, InstanceFlags};
-use winit::{window::Window, event::{WindowEvent, ElementState, KeyEvent}, keyboard::{Key, KeyCode, PhysicalKey::Code}, platform::modifier_supplement::KeyEventExtModifierSupplement};
-
-use crate::renderer::texture;
-
-#[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
-struct Vertex {
-    position: [f32; 3],
-    tex_coords: [f32; 2],
-}
+use std::{collections::HashMap, sync::{Arc, Mutex, Weak}};
 
-impl Vertex {
-    fn desc() -> wgpu::VertexBufferLayout<'static> {
-        wgpu::VertexBufferLayout {
-            array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
-            step_mode: wgpu::VertexStepMode::Vertex,
-            attributes: &[
-                wgpu::VertexAttribute {
-                    offset: 0,
-                    shader_location: 0,
-                    format: wgpu::VertexFormat::Float32x3,
-                },
-                wgpu::VertexAttribute {
-                    offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
-                    shader_location: 1,
-                    format: wgpu::VertexFormat::Float32x2,
-                }
-            ]
-        }
-    }
-}
+use anyhow::{ensure, Context, Result};
+use wgpu::{util::DeviceExt, InstanceFlags};
+use winit::{window::Window, event::KeyEvent, dpi::PhysicalPosition};
 
-#[rustfmt::skip]
-pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
-    1.0, 0.0, 0.0, 0.0,
-    0.0, 1.0, 0.0, 0.0,
-    0.0, 0.0, 0.5, 0.5,
-    0.0, 0.0, 0.0, 1.0,
-);
+use crate::renderer::{
+    adapter::{self, AdapterConfig, DeviceLostFlag},
+    atlas::AtlasBuilder,
+    camera::{Camera, CameraController, CameraUniform},
+    debug_draw::DebugDrawRenderer,
+    deferred::{self, DeferredRenderer, RenderPath},
+    debug_view::{self, DebugView},
+    gpu_culling::{DrawIndexedArgs, GpuScene},
+    lighting::{self, Lights},
+    mesh::{InstanceRaw, Vertex},
+    occlusion::OcclusionQueries,
+    compute,
+    offscreen::OffscreenTarget,
+    particles::{EmitterDesc, EmitterId, ParticleSystem},
+    profiler::{FrameTimings, GpuProfiler},
+    picking::{EntityId, PickingRenderer},
+    pipeline_cache::{PipelineCache, PipelineCacheStats, RenderPipelineKey, ShaderKey},
+    resources::{BufferHandle, FrameFence, MeshHandle, ResourceRegistry, ResourceReport, TextureHandle},
+    ring_buffer::{RingAllocation, RingBuffer, RingKind},
+    scene::{CullingStats, Scene},
+    shader::ShaderLibrary,
+    skybox::{Sky, SkyboxRenderer},
+    ssao::SsaoRenderer,
+    stats::{self, FrameCounters, GpuMemory, RenderStats, StatsHistory, TrackedRenderPass},
+    sprite::{AtlasId, SpriteRenderer},
+    text::{FontId, TextRenderer, TextSection, TextSpace},
+    texture,
+    transparency::{BlendMode, OitRenderer},
+};
 
+const MAIN_SHADER: &str = "shader.wgsl";
 
-const VERTICES: &[Vertex] = &[
-    Vertex { position: [-0.5, -0.5, 0.0], tex_coords: [0.0, 1.0] },
-    Vertex { position: [0.5, -0.5, 0.0],  tex_coords: [1.0, 1.0] },
-    Vertex { position: [-0.5, 0.5, 0.0],  tex_coords: [0.0, 0.0] },
-    Vertex { position: [0.5, 0.5, 0.0],   tex_coords: [1.0, 0.0] },
+// The default quad, also used by the tests
+pub(crate) const VERTICES: &[Vertex] = &[
+    Vertex { position: [-0.5, -0.5, 0.0], tex_coords: [0.0, 1.0], normal: [0.0, 0.0, 1.0] },
+    Vertex { position: [0.5, -0.5, 0.0],  tex_coords: [1.0, 1.0], normal: [0.0, 0.0, 1.0] },
+    Vertex { position: [-0.5, 0.5, 0.0],  tex_coords: [0.0, 0.0], normal: [0.0, 0.0, 1.0] },
+    Vertex { position: [0.5, 0.5, 0.0],   tex_coords: [1.0, 0.0], normal: [0.0, 0.0, 1.0] },
 ];
 
-const INDICES: &[u16] = &[
+pub(crate) const INDICES: &[u32] = &[
     0, 1, 2,
     2, 1, 3,
 ];
 
-struct Camera {
-    eye: cgmath::Point3<f32>,
-    target: cgmath::Point3<f32>,
-    up: cgmath::Vector3<f32>,
-    aspect: f32,
-    fovy: f32,
-    znear: f32,
-    zfar: f32,
+pub struct State {
+    surface: Option<wgpu::Surface>,
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    config: wgpu::SurfaceConfiguration,
+    size: winit::dpi::PhysicalSize<u32>,
+    window: Option<Window>,
+    // Render target used when there is no surface, and for captures
+    offscreen: Option<OffscreenTarget>,
+    // Opaque and cutout objects, and everything in debug views
+    render_pipeline: Arc<wgpu::RenderPipeline>,
+    blend_pipelines: HashMap<BlendMode, Arc<wgpu::RenderPipeline>>,
+    // Every variant of the main pipelines created so far, switching back to a view is free
+    pipeline_cache: PipelineCache,
+    render_pipeline_layout: wgpu::PipelineLayout,
+    debug_view: DebugView,
+    diffuse_bind_group: wgpu::BindGroup,
+    camera: Camera,
+    camera_uniform: CameraUniform,
+    camera_buffer: BufferHandle,
+    camera_bind_group: wgpu::BindGroup,
+    camera_bind_group_layout: wgpu::BindGroupLayout,
+    // Group 0 of the main pipelines, also used by the picking pass
+    texture_bind_group_layout: wgpu::BindGroupLayout,
+    camera_controller: CameraController,
+    _diffuse_texture: TextureHandle,
+    // Textures and buffers behind handles, destroyed once the frames using them are done
+    resources: ResourceRegistry,
+    // Per object uniforms of the frame being recorded, see `alloc_uniform`
+    uniform_ring: RingBuffer,
+    // Arrays of the frame, see `alloc_storage`, None on adapters without dynamic storage buffers
+    storage_ring: Option<RingBuffer>,
+    depth_texture: texture::Texture,
+    sprite_renderer: SpriteRenderer,
+    text_renderer: TextRenderer,
+    scene: Scene,
+    debug_draw_renderer: DebugDrawRenderer,
+    skybox_renderer: SkyboxRenderer,
+    particles: ParticleSystem,
+    // Only created once picking is enabled
+    picking_renderer: Option<PickingRenderer>,
+    // Only created once order independent transparency is enabled
+    oit_renderer: Option<OitRenderer>,
+    // Only created once GPU culling is enabled
+    gpu_scene: Option<GpuScene>,
+    // Only created once occlusion culling is enabled
+    occlusion: Option<OcclusionQueries>,
+    lights: Lights,
+    render_path: RenderPath,
+    // Only created for the deferred path
+    deferred: Option<DeferredRenderer>,
+    // Only created once SSAO is enabled
+    ssao: Option<SsaoRenderer>,
+    cursor_position: Option<PhysicalPosition<f64>>,
+    profiler: GpuProfiler,
+    // Draws the pass timings in the top left corner with the debug font
+    timing_overlay: bool,
+    stats_history: StatsHistory,
+    // Keeps the queue's counters alive between frames, see `stats::FrameCounters`
+    frame_counters: Arc<FrameCounters>,
+    stats_overlay: bool,
+    last_update: std::time::Instant,
+    shaders: ShaderLibrary,
+    // Shared with the state `recover_device` rebuilds
+    instance: Arc<wgpu::Instance>,
+    adapter_info: wgpu::AdapterInfo,
+    // Used again to pick an adapter when the device is lost
+    adapter_config: AdapterConfig,
+    device_lost: DeviceLostFlag,
+    // Counts the devices recreated after losses
+    device_generation: u32,
 }
 
-impl Camera {
-    fn build_view_projection_matrix(&self) -> cgmath::Matrix4<f32> {
-        let view = cgmath::Matrix4::look_at_rh(self.eye, self.target, self.up);
-        let proj = cgmath::perspective(cgmath::Deg(self.fovy), self.aspect, self.znear, self.zfar);
-        return OPENGL_TO_WGPU_MATRIX * proj * view;
-    }
-}
+// The instance is a handle to our GPU
+// Backends::all => Vulkan + Metal + DX12 + Browser WebGPU
+// States alive at the same time share the instance of their backends. Device and queue ids
+// are only unique within an instance and the stats counters are keyed by them, and a second
+// GL instance would terminate the EGL display of the first when dropped
+fn create_instance(backends: wgpu::Backends) -> Arc<wgpu::Instance> {
+    static INSTANCES: Mutex<Vec<(wgpu::Backends, Weak<wgpu::Instance>)>> = Mutex::new(Vec::new());
 
-struct CameraController {
-    speed: f32,
-    is_forward_pressed: bool,
-    is_backward_pressed: bool,
-    is_left_pressed: bool,
-    is_right_pressed: bool,
+    let mut instances = INSTANCES.lock().unwrap_or_else(|e| e.into_inner());
+    instances.retain(|(_, instance)| instance.strong_count() > 0);
+    if let Some(instance) = instances.iter().find(|(shared, _)| *shared == backends).and_then(|(_, instance)| instance.upgrade()) {
+        return instance;
+    }
+    let instance = Arc::new(wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends,
+        dx12_shader_compiler: Default::default(),
+        flags: InstanceFlags::default(),
+        gles_minor_version: wgpu::Gles3MinorVersion::Automatic,
+    }));
+    instances.push((backends, Arc::downgrade(&instance)));
+    instance
 }
 
-impl CameraController {
-    fn new(speed: f32) -> Self {
-        Self {
-            speed,
-            is_forward_pressed: false,
-            is_backward_pressed: false,
-            is_left_pressed: false,
-            is_right_pressed: false,
-        }
+// Prefers an sRGB format and the first present and alpha modes the surface lists
+fn surface_config(surface: &wgpu::Surface, adapter: &wgpu::Adapter, size: winit::dpi::PhysicalSize<u32>) -> wgpu::SurfaceConfiguration {
+    let surface_caps = surface.get_capabilities(adapter);
+    let surface_format = surface_caps.formats.iter()
+        .copied()
+        .find(|f| { f.is_srgb() })
+        .unwrap_or(surface_caps.formats[0]);
+    wgpu::SurfaceConfiguration {
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        format: surface_format,
+        width: size.width,
+        height: size.height,
+        present_mode: surface_caps.present_modes[0],
+        alpha_mode: surface_caps.alpha_modes[0],
+        view_formats: vec![],
     }
+}
 
-    fn process_events(&mut self, event: &KeyEvent) -> bool {
-        let is_pressed = event.state.is_pressed();
-        if let Code(keycode) = event.physical_key {
-            match keycode {
-                KeyCode::KeyW | KeyCode::ArrowUp => {
-                    self.is_forward_pressed = is_pressed;
-                    true
-                }
-                KeyCode::KeyA | KeyCode::ArrowLeft => {
-                    self.is_left_pressed = is_pressed;
-                    true
-                }
-                KeyCode::KeyS | KeyCode::ArrowDown => {
-                    self.is_backward_pressed = is_pressed;
-                    true
-                }
-                KeyCode::KeyD | KeyCode::ArrowRight => {
-                    self.is_right_pressed = is_pressed;
-                    true
-                }
-                _ => false
-            }
-        } else {
-            false
-        }
-    }
+// Defines the main shader is compiled with for a debug view
+fn main_shader_defines(device: &wgpu::Device, view: DebugView) -> [(&'static str, &'static str); 1] {
+    [("DEBUG_VIEW", view.define(debug_view::supports_line_polygons(device)))]
+}
 
-    fn update_camera(&self, camera: &mut Camera) {
-        use cgmath::InnerSpace;
-        let forward = camera.target - camera.eye;
-        let forward_norm = forward.normalize();
-        let forward_mag = forward.magnitude();
+fn main_shader_key(device: &wgpu::Device, view: DebugView) -> ShaderKey {
+    ShaderKey::new(MAIN_SHADER, &main_shader_defines(device, view))
+}
 
-        if self.is_forward_pressed && forward_mag > self.speed {
-            camera.eye += forward_norm * self.speed;
-        }
-        if self.is_backward_pressed {
-            camera.eye -= forward_norm * self.speed;
-        }
+// The main shader writing the G-buffer of the deferred path, for opaque and cutout objects
+fn gbuffer_pipeline_key(device: &wgpu::Device) -> RenderPipelineKey {
+    let mut key = main_pipeline_key(device, deferred::ALBEDO_FORMAT, DebugView::Lit, BlendMode::Opaque)
+        .with_entry_points("vs_main", Some("fs_gbuffer"));
+    key.targets = [deferred::ALBEDO_FORMAT, deferred::NORMAL_FORMAT, deferred::MATERIAL_FORMAT]
+        .map(|format| Some(wgpu::ColorTargetState { format, blend: None, write_mask: wgpu::ColorWrites::ALL }))
+        .to_vec();
+    key
+}
 
-        let right = forward_norm.cross(camera.up);
+// The main shader writing only depth, for the forward path's prepass when SSAO is enabled
+fn depth_prepass_pipeline_key(device: &wgpu::Device) -> RenderPipelineKey {
+    let mut key = main_pipeline_key(device, lighting::OCCLUSION_FORMAT, DebugView::Lit, BlendMode::Opaque)
+        .with_entry_points("vs_main", Some("fs_depth"));
+    key.targets = Vec::new();
+    key
+}
 
-        let forward = camera.target - camera.eye;
-        let forward_mag = forward.magnitude();
+type MainPipelines = (Arc<wgpu::RenderPipeline>, HashMap<BlendMode, Arc<wgpu::RenderPipeline>>);
 
-        if self.is_right_pressed {
-            camera.eye = camera.target - (forward + right * self.speed).normalize() * forward_mag;
-        }
-        if self.is_left_pressed {
-            camera.eye = camera.target - (forward - right * self.speed).normalize() * forward_mag;
+// The opaque pipeline and one per transparent blend mode
+// Debug views draw everything with the opaque one, so they only get that
+fn create_main_pipelines(
+    cache: &mut PipelineCache,
+    device: &wgpu::Device,
+    shaders: &mut ShaderLibrary,
+    layout: &wgpu::PipelineLayout,
+    format: wgpu::TextureFormat,
+    view: DebugView,
+) -> Result<MainPipelines> {
+    let opaque = cache.render_pipeline(device, shaders, layout, &main_pipeline_key(device, format, view, BlendMode::Opaque))?;
+    let mut blended = HashMap::new();
+    if !view.is_debug() {
+        for blend in [BlendMode::Alpha, BlendMode::Additive, BlendMode::Premultiplied] {
+            blended.insert(blend, cache.render_pipeline(device, shaders, layout, &main_pipeline_key(device, format, view, blend))?);
         }
     }
+    Ok((opaque, blended))
 }
 
-#[repr(C)]
-#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
-struct CameraUniform {
-    view_proj: [[f32; 4]; 4],
+fn main_pipeline_key(device: &wgpu::Device, format: wgpu::TextureFormat, view: DebugView, blend: BlendMode) -> RenderPipelineKey {
+    let wireframe = view == DebugView::Wireframe;
+    // Every fragment adds to the pixel, hidden ones included
+    let overdraw = view == DebugView::Overdraw;
+    let additive = wgpu::BlendComponent {
+        src_factor: wgpu::BlendFactor::One,
+        dst_factor: wgpu::BlendFactor::One,
+        operation: wgpu::BlendOperation::Add,
+    };
+
+    RenderPipelineKey::new(
+        main_shader_key(device, view),
+        &[Vertex::desc(), InstanceRaw::desc()],
+        &[Some(wgpu::ColorTargetState {
+            format,
+            blend: Some(if overdraw {
+                wgpu::BlendState { color: additive, alpha: additive }
+            } else {
+                blend.blend_state()
+            }),
+            write_mask: wgpu::ColorWrites::ALL,
+        })],
+    )
+    .with_primitive(wgpu::PrimitiveState {
+        topology: wgpu::PrimitiveTopology::TriangleList,
+        strip_index_format: None,
+        front_face: wgpu::FrontFace::Ccw,
+        // Wireframes show the back faces too
+        cull_mode: if wireframe { None } else { Some(wgpu::Face::Back) },
+        polygon_mode: if wireframe && debug_view::supports_line_polygons(device) {
+            wgpu::PolygonMode::Line
+        } else {
+            wgpu::PolygonMode::Fill
+        },
+        unclipped_depth: false,
+        conservative: false
+    })
+    .with_depth_stencil(wgpu::DepthStencilState {
+        format: texture::Texture::DEPTH_FORMAT,
+        // Transparent objects are tested against the opaque ones but don't hide each other
+        depth_write_enabled: !overdraw && !blend.is_transparent(),
+        depth_compare: if overdraw { wgpu::CompareFunction::Always } else { wgpu::CompareFunction::Less },
+        stencil: wgpu::StencilState::default(),
+        bias: wgpu::DepthBiasState::default(),
+    })
 }
 
-impl CameraUniform {
-    fn new() -> Self {
-        use cgmath::SquareMatrix;
-        Self {
-            view_proj: cgmath::Matrix4::identity().into(),
+impl State {
+    // Picks the adapter from `AdapterConfig::from_env` and the render path from
+    // `UNNAMED_RENDER_PATH`, see `new_with_config`
+    pub async fn new(window: Window) -> Result<Self> {
+        let mut state = Self::new_with_config(window, AdapterConfig::from_env()).await?;
+        if let Err(e) = state.set_render_path(RenderPath::from_env()) {
+            log::warn!("Using the forward path: {:#}", e);
         }
+        Ok(state)
     }
 
-    fn update_view_proj(&mut self, camera: &Camera) {
-        self.view_proj = camera.build_view_projection_matrix().into();
+    // Fails with `NoAdapterError` when no adapter, the software one included, can open a device
+    pub async fn new_with_config(window: Window, adapter_config: AdapterConfig) -> Result<Self> {
+        let size = window.inner_size();
+
+        let instance = create_instance(adapter_config.backends);
+
+        let surface = unsafe { instance.create_surface(&window) }.context("Failed to create the window surface")?;
+
+        let opened = adapter::open_device(&instance, &adapter_config, Some(&surface)).await?;
+
+        let config = surface_config(&surface, &opened.0, size);
+        surface.configure(&opened.1, &config);
+
+        Self::from_device(instance, opened, adapter_config, config, Some(surface), Some(window))
     }
-}
 
-pub struct State {
-    surface: wgpu::Surface,
-    device: wgpu::Device,
-    queue: wgpu::Queue,
-    config: wgpu::SurfaceConfiguration,
-    size: winit::dpi::PhysicalSize<u32>,
-    window: Window,
-    render_pipeline: wgpu::RenderPipeline,
-    vertex_buffer: wgpu::Buffer,
-    index_buffer: wgpu::Buffer,
-    num_indices: u32,
-    diffuse_bind_group: wgpu::BindGroup,
-    camera: Camera,
-    camera_uniform: CameraUniform,
-    camera_buffer: wgpu::Buffer,
-    camera_bind_group: wgpu::BindGroup,
-    camera_controller: CameraController,
-    diffuse_texture: texture::Texture,
-}
+    // Creates a state without a window that renders into an offscreen texture
+    // Any adapter is accepted, including the software fallback, so this also
+    // works on machines without a display
+    pub async fn new_headless(width: u32, height: u32, force_fallback_adapter: bool) -> Result<Self> {
+        let adapter_config = AdapterConfig::default().with_force_fallback(force_fallback_adapter);
+        Self::new_headless_with_config(width, height, adapter_config).await
+    }
 
-impl State {
-    pub async fn new(window: Window) -> Self {
-        let size = window.inner_size();
+    pub async fn new_headless_with_config(width: u32, height: u32, adapter_config: AdapterConfig) -> Result<Self> {
+        ensure!(width > 0 && height > 0, "Headless target must not be empty ({}x{})", width, height);
 
-        // The instance is a handle to our GPU
-        // Backends::all => Vulkan + Metal + DX12 + Browser WebGPU
-        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-            backends: wgpu::Backends::all(),
-            dx12_shader_compiler: Default::default(),
-            flags: InstanceFlags::default(),
-            gles_minor_version: wgpu::Gles3MinorVersion::Automatic,
-        });
+        let instance = create_instance(adapter_config.backends);
 
-        let surface = unsafe { instance.create_surface(&window) }.unwrap();
+        let opened = adapter::open_device(&instance, &adapter_config, None).await?;
 
-        let adapter = instance.request_adapter(
-            &wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::default(),
-                compatible_surface: Some(&surface),
-                force_fallback_adapter: false,
-            },
-        ).await.unwrap();
-
-        let (device, queue) = adapter.request_device(
-            &wgpu::DeviceDescriptor {
-                features: wgpu::Features::empty(),
-                // WebGL doesn't support all the wgpu's features, so if
-                // we're building for the web we'll have to disable some
-                limits: if cfg!(target_arch = "wasm32") {
-                    wgpu::Limits::downlevel_webgl2_defaults()
-                } else {
-                    wgpu::Limits::default()
-                },
-                label: None,
-            },
-            None,
-        ).await.unwrap();
-
-        let surface_caps = surface.get_capabilities(&adapter);
-        let surface_format = surface_caps.formats.iter()
-            .copied()
-            .find(|f| { f.is_srgb() })
-            .unwrap_or(surface_caps.formats[0]);
+        // There is no surface here, the configuration only describes the offscreen target
         let config = wgpu::SurfaceConfiguration {
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-            format: surface_format,
-            width: size.width,
-            height: size.height,
-            present_mode: surface_caps.present_modes[0],
-            alpha_mode: surface_caps.alpha_modes[0],
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            width,
+            height,
+            present_mode: wgpu::PresentMode::Fifo,
+            alpha_mode: wgpu::CompositeAlphaMode::Opaque,
             view_formats: vec![],
         };
-        surface.configure(&device, &config);
+
+        Self::from_device(instance, opened, adapter_config, config, None, None)
+    }
+
+    fn from_device(
+        instance: Arc<wgpu::Instance>,
+        // As `adapter::open_device` returns them
+        (adapter, device, queue): (wgpu::Adapter, wgpu::Device, wgpu::Queue),
+        adapter_config: AdapterConfig,
+        config: wgpu::SurfaceConfiguration,
+        surface: Option<wgpu::Surface>,
+        window: Option<Window>,
+    ) -> Result<Self> {
+        let device_lost = DeviceLostFlag::install(&device);
+        let frame_counters = FrameCounters::of(&queue);
+        let size = winit::dpi::PhysicalSize::new(config.width, config.height);
+
+        let offscreen = if surface.is_none() {
+            Some(OffscreenTarget::new(&device, config.width, config.height, config.format, Some("Offscreen Target")))
+        } else {
+            None
+        };
 
         let diffuse_bytes = include_bytes!("../../res/dirt.png");
-        let diffuse_texture = texture::Texture::from_bytes(&device, &queue, diffuse_bytes, "dirt.png").unwrap();
+        let diffuse_texture = texture::Texture::from_bytes(&device, &queue, diffuse_bytes, "dirt.png", &texture::TextureOptions::default())?;
 
         let texture_bind_group_layout = device.create_bind_group_layout(
             &wgpu::BindGroupLayoutDescriptor {
@@ -295,13 +393,13 @@ impl State {
         let mut camera_uniform = CameraUniform::new();
         camera_uniform.update_view_proj(&camera);
 
-        let camera_buffer = device.create_buffer_init(
+        let camera_buffer = stats::track_buffer(&device, device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
                 label: Some("Camera Buffer"),
                 contents: bytemuck::cast_slice(&[camera_uniform]),
                 usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             }
-        );
+        ));
 
         let camera_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             entries: &[
@@ -330,110 +428,287 @@ impl State {
             label: Some("camera_bind_group"),
         });
 
-        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-            label: Some("Shader"),
-            source: wgpu::ShaderSource::Wgsl(include_str!("../shader.wgsl").into()),
-        });
+        let mut shaders = ShaderLibrary::default();
+        let mut pipeline_cache = PipelineCache::new();
+        let lights = Lights::new(&device, &queue, config.width, config.height);
 
         let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("Render Pipeline Layout"),
             bind_group_layouts: &[
                 &texture_bind_group_layout,
                 &camera_bind_group_layout,
+                lights.layout(),
             ],
             push_constant_ranges: &[],
         });
 
-        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: "vs_main",
-                buffers: &[Vertex::desc()],
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: "fs_main",
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: config.format,
-                    blend: Some(wgpu::BlendState::REPLACE),
-                    write_mask: wgpu::ColorWrites::ALL,
-                })],
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: Some(wgpu::Face::Back),
-                polygon_mode: wgpu::PolygonMode::Fill,
-                unclipped_depth: false,
-                conservative: false
-            },
-            depth_stencil: None,
-            multisample: wgpu::MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
-            multiview: None,
-        });
+        let (render_pipeline, blend_pipelines) = create_main_pipelines(
+            &mut pipeline_cache,
+            &device,
+            &mut shaders,
+            &render_pipeline_layout,
+            config.format,
+            DebugView::Lit,
+        )?;
 
-        let vertex_buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Vertex Buffer"),
-                contents: bytemuck::cast_slice(VERTICES),
-                usage: wgpu::BufferUsages::VERTEX,
-            }
-        );
+        let mut resources = ResourceRegistry::default();
+        let diffuse_texture = resources.textures.insert(diffuse_texture, "dirt.png");
+        let camera_buffer = resources.buffers.insert(camera_buffer, "Camera Buffer");
 
-        let index_buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Index Buffer"),
-                contents: bytemuck::cast_slice(INDICES),
-                usage: wgpu::BufferUsages::INDEX,
+        // Bindings span as much as the device allows, so the largest allocations fit
+        let uniform_ring = RingBuffer::new(
+            &device,
+            resources.fence().clone(),
+            RingKind::Uniform,
+            RingBuffer::max_binding_size(&device, RingKind::Uniform),
+            wgpu::ShaderStages::VERTEX_FRAGMENT,
+            "Uniform Ring",
+        )?;
+        let storage_binding_size = RingBuffer::max_binding_size(&device, RingKind::Storage);
+        let storage_ring = if storage_binding_size > 0 {
+            let mut visibility = wgpu::ShaderStages::FRAGMENT;
+            if adapter.get_downlevel_capabilities().flags.contains(wgpu::DownlevelFlags::VERTEX_STORAGE) {
+                visibility |= wgpu::ShaderStages::VERTEX;
             }
-        );
+            if compute::is_supported(&device) {
+                visibility |= wgpu::ShaderStages::COMPUTE;
+            }
+            Some(RingBuffer::new(&device, resources.fence().clone(), RingKind::Storage, storage_binding_size, visibility, "Storage Ring")?)
+        } else {
+            None
+        };
 
-        let num_indices = INDICES.len() as u32;
+        let mut scene = Scene::new(&device, resources.fence().clone());
+        let quad = scene.add_mesh(&device, VERTICES, INDICES, "Quad");
+        scene.add_object(quad, cgmath::SquareMatrix::identity()).context("The default quad was released")?;
 
         let camera_controller = CameraController::new(0.2);
 
-        Self {
+        let sprite_shader = shaders.load(&device, SpriteRenderer::SHADER, &[])?;
+        let sprite_renderer = SpriteRenderer::new(&device, &sprite_shader, config.format, config.width, config.height);
+        let text_shader = shaders.load(&device, TextRenderer::SHADER, &[])?;
+        let text_renderer = TextRenderer::new(&device, &text_shader, config.format);
+        let debug_draw_shader = shaders.load(&device, DebugDrawRenderer::SHADER, &[])?;
+        let debug_draw_renderer = DebugDrawRenderer::new(&device, &debug_draw_shader, config.format, &camera_bind_group_layout);
+        let skybox_shader = shaders.load(&device, SkyboxRenderer::SHADER, &[])?;
+        let skybox_renderer = SkyboxRenderer::new(&device, &queue, &skybox_shader, config.format)?;
+        let particle_shader = shaders.load(&device, ParticleSystem::SHADER, &[])?;
+        let simulate_shader = if compute::is_supported(&device) {
+            Some(shaders.load(&device, ParticleSystem::SIMULATE_SHADER, &[])?)
+        } else {
+            log::warn!("The adapter has no compute shaders, particles are disabled");
+            None
+        };
+        let particles = ParticleSystem::new(&device, &queue, &particle_shader, simulate_shader.as_ref(), &uniform_ring, config.format)?;
+
+        let depth_texture = texture::Texture::create_depth_texture(&device, config.width, config.height, "Depth Texture");
+        let profiler = GpuProfiler::new(&device, &queue);
+
+        Ok(Self {
             window,
             surface,
+            offscreen,
             device,
             queue,
             config,
             size,
             render_pipeline,
-            vertex_buffer,
-            index_buffer,
-            num_indices,
-            diffuse_texture,
+            blend_pipelines,
+            debug_view: DebugView::Lit,
+            scene,
+            _diffuse_texture: diffuse_texture,
+            resources,
+            uniform_ring,
+            storage_ring,
             diffuse_bind_group,
             camera,
             camera_uniform,
             camera_buffer,
             camera_bind_group,
+            camera_bind_group_layout,
+            texture_bind_group_layout,
             camera_controller,
-        }
+            depth_texture,
+            sprite_renderer,
+            text_renderer,
+            debug_draw_renderer,
+            skybox_renderer,
+            particles,
+            picking_renderer: None,
+            oit_renderer: None,
+            gpu_scene: None,
+            occlusion: None,
+            lights,
+            render_path: RenderPath::Forward,
+            deferred: None,
+            ssao: None,
+            cursor_position: None,
+            profiler,
+            timing_overlay: false,
+            stats_history: StatsHistory::default(),
+            frame_counters,
+            stats_overlay: false,
+            last_update: std::time::Instant::now(),
+            shaders,
+            pipeline_cache,
+            render_pipeline_layout,
+            instance,
+            adapter_info: adapter.get_info(),
+            adapter_config,
+            device_lost,
+            device_generation: 0,
+        })
     }
 
-    pub fn window(&self) -> &Window {
-        &self.window
+    pub fn window(&self) -> Option<&Window> {
+        self.window.as_ref()
+    }
+
+    pub fn request_redraw(&self) {
+        if let Some(window) = &self.window {
+            window.request_redraw();
+        }
     }
 
     pub fn size(&self) -> &winit::dpi::PhysicalSize<u32> {
         &self.size
     }
 
+    // Raw access for the tests, games create and release GPU resources through the handles
+    #[cfg(test)]
+    pub(crate) fn device(&self) -> &wgpu::Device {
+        &self.device
+    }
+
+    #[cfg(test)]
+    pub(crate) fn queue(&self) -> &wgpu::Queue {
+        &self.queue
+    }
+
+    pub fn adapter_info(&self) -> &wgpu::AdapterInfo {
+        &self.adapter_info
+    }
+
+    // Frames are skipped while this is set, until `recover_device` succeeds
+    // Best-effort, only losses wgpu reports through an error are noticed
+    pub fn is_device_lost(&self) -> bool {
+        self.device_lost.is_set()
+    }
+
+    // Bumped by every `recover_device`, games reload what the new device lost when it changes
+    pub fn device_generation(&self) -> u32 {
+        self.device_generation
+    }
+
+    // Opens a new device, on another adapter if the old one is gone, and rebuilds the renderers
+    // The scene meshes and objects, textures, buffers, GPU scene, lights, camera, render path,
+    // debug view, picking, OIT, occlusion culling, SSAO, overlays, fonts, sprite atlases,
+    // particle emitters and color or procedural skies carry over, and their handles and ids stay
+    // valid. Cubemap skies, atlas entries copied from textures, buffer contents written by the
+    // GPU, particles in flight and pending occlusion results are lost
+    // Device loss detection is best-effort, see `is_device_lost`
+    pub async fn recover_device(&mut self) -> Result<()> {
+        // The instance and surface outlive the device, a second GL instance would share and
+        // then terminate the EGL display of the first
+        let opened = adapter::open_device(&self.instance, &self.adapter_config, self.surface.as_ref())
+            .await
+            .context("Failed to recreate the device")?;
+
+        let surface = self.surface.take();
+        let config = match &surface {
+            Some(surface) => {
+                let config = surface_config(surface, &opened.0, self.size);
+                surface.configure(&opened.1, &config);
+                config
+            },
+            None => self.config.clone(),
+        };
+        let instance = self.instance.clone();
+        let mut state = Self::from_device(instance, opened, self.adapter_config.clone(), config, surface, self.window.take())?;
+
+        // Game handles stay valid: the old registry uploads its resources again and the
+        // engine's own take over the objects the new state was built with
+        let fence = state.resources.fence().clone();
+        let mut resources = std::mem::take(&mut self.resources);
+        resources.recreate(&state.device, &state.queue, fence.clone());
+        if let Some(texture) = state.resources.textures.remove(state._diffuse_texture) {
+            resources.textures.replace(self._diffuse_texture, texture);
+        }
+        if let Some(buffer) = state.resources.buffers.remove(state.camera_buffer) {
+            resources.buffers.replace(self.camera_buffer, buffer);
+        }
+        state.resources = resources;
+        state._diffuse_texture = self._diffuse_texture;
+        state.camera_buffer = self.camera_buffer;
+        std::mem::swap(&mut state.scene, &mut self.scene);
+        state.scene.recreate(&state.device, fence);
+        std::mem::swap(&mut state.camera, &mut self.camera);
+        std::mem::swap(&mut state.camera_controller, &mut self.camera_controller);
+        std::mem::swap(&mut state.shaders, &mut self.shaders);
+        std::mem::swap(&mut state.stats_history, &mut self.stats_history);
+        std::mem::swap(&mut state.text_renderer.fonts, &mut self.text_renderer.fonts);
+        state.debug_draw_renderer.font = self.debug_draw_renderer.font;
+        std::mem::swap(&mut state.sprite_renderer.camera, &mut self.sprite_renderer.camera);
+        state.sprite_renderer.restore_from(&state.device, &state.queue, &self.sprite_renderer);
+        state.lights.restore_from(&self.lights);
+        state.cursor_position = self.cursor_position;
+        // The window now belongs to the new state, so these only warn instead of failing
+        let restored = [
+            state.set_debug_view(self.debug_view),
+            state.set_picking_enabled(self.picking_renderer.is_some()),
+            state.set_oit_enabled(self.oit_renderer.is_some()),
+            state.set_gpu_culling_enabled(self.gpu_scene.is_some()),
+            state.set_occlusion_culling_enabled(self.occlusion.is_some()),
+            state.set_render_path(self.render_path),
+            state.set_ssao_enabled(self.ssao.is_some()),
+            state.skybox_renderer.restore_from(&state.device, &self.skybox_renderer),
+            state.particles.restore_from(&state.device, &state.queue, &self.particles),
+        ];
+        for e in restored.into_iter().filter_map(Result::err) {
+            log::warn!("Not restored on the new device: {:#}", e);
+        }
+        if let (Some(gpu_scene), Some(old)) = (&mut state.gpu_scene, &mut self.gpu_scene) {
+            gpu_scene.restore_from(old);
+        }
+        if let (Some(ssao), Some(old)) = (&mut state.ssao, &self.ssao) {
+            ssao.settings = old.settings;
+        }
+        state.profiler.enabled = self.profiler.enabled;
+        state.timing_overlay = self.timing_overlay;
+        state.stats_overlay = self.stats_overlay;
+        state.device_generation = self.device_generation + 1;
+
+        *self = state;
+        Ok(())
+    }
+
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
         if new_size.width > 0 && new_size.height > 0 {
             self.size = new_size;
             self.config.width = new_size.width;
             self.config.height = new_size.height;
-            self.surface.configure(&self.device, &self.config);
+            self.sprite_renderer.camera.resize(new_size.width as f32, new_size.height as f32);
+            self.depth_texture = texture::Texture::create_depth_texture(&self.device, new_size.width, new_size.height, "Depth Texture");
+            self.lights.resize(&self.device, &self.queue, new_size.width, new_size.height);
+            if let Some(ssao) = &mut self.ssao {
+                ssao.resize(&self.device, &self.depth_texture);
+            }
+            if let Some(deferred) = &mut self.deferred {
+                deferred.resize(&self.device, &self.lights, &self.depth_texture);
+            }
+            if let Some(picking) = &mut self.picking_renderer {
+                picking.resize(&self.device, new_size.width, new_size.height);
+            }
+            if let Some(oit) = &mut self.oit_renderer {
+                oit.resize(&self.device, new_size.width, new_size.height);
+            }
+            if let Some(surface) = &self.surface {
+                surface.configure(&self.device, &self.config);
+                // The capture target is recreated lazily at the new size
+                self.offscreen = None;
+            } else {
+                self.offscreen = Some(self.create_offscreen_target());
+            }
         }
     }
 
@@ -441,56 +716,925 @@ impl State {
         self.camera_controller.process_events(event)
     }
 
+    // Points the camera from `eye` at `target`
+    pub fn look_at(&mut self, eye: cgmath::Point3<f32>, target: cgmath::Point3<f32>) {
+        self.camera.eye = eye;
+        self.camera.target = target;
+    }
+
     pub fn update(&mut self) {
+        if self.is_device_lost() {
+            return;
+        }
+        // Finishes readbacks like picks without waiting for the GPU
+        self.device.poll(wgpu::Maintain::Poll);
+        self.profiler.poll();
+        self.resources.collect();
+        self.scene.collect();
+
+        for name in self.shaders.poll_changes() {
+            match self.reload_shader(&name) {
+                Ok(()) => log::info!("Reloaded shader {}", name),
+                Err(e) => log::error!("Failed to reload shader {}, keeping the previous version: {:#}", name, e),
+            }
+        }
+
         self.camera_controller.update_camera(&mut self.camera);
         self.camera_uniform.update_view_proj(&self.camera);
-        self.queue.write_buffer(&self.camera_buffer, 0, bytemuck::cast_slice(&[self.camera_uniform]));
+        if let Some(camera_buffer) = self.resources.buffers.get(self.camera_buffer) {
+            stats::write_buffer(&self.queue, camera_buffer, 0, bytemuck::cast_slice(&[self.camera_uniform]));
+        }
+        let now = std::time::Instant::now();
+        let dt = (now - self.last_update).as_secs_f32();
+        self.last_update = now;
+
+        self.sprite_renderer.prepare(&self.device, &self.queue);
+        if let Some(occlusion) = &mut self.occlusion {
+            self.scene.apply_occlusion(&occlusion.take_results());
+        }
+        self.scene.prepare(&self.device, &self.queue, &self.camera, dt);
+        if let Some(occlusion) = &mut self.occlusion {
+            occlusion.prepare(&self.device, &self.queue, self.scene.occlusion_candidates());
+        }
+        if self.debug_view == DebugView::Wireframe && !debug_view::supports_line_polygons(&self.device) {
+            self.scene.prepare_unrolled(&self.device);
+        }
+        self.lights.prepare(&self.queue, &self.camera);
+        if let Some(ssao) = &self.ssao {
+            ssao.prepare(&self.queue, &self.camera);
+        }
+        self.skybox_renderer.prepare(&self.queue, &self.camera);
+        self.particles.update(&self.device, &self.queue, &mut self.uniform_ring, &self.camera, dt);
+        if let Some(gpu_scene) = &mut self.gpu_scene {
+            gpu_scene.prepare(&self.device, &self.queue, &self.camera);
+        }
+        self.debug_draw_renderer.prepare(&self.device, &self.queue, &mut self.text_renderer, dt);
+        if let Some(font) = self.debug_draw_renderer.font {
+            let mut lines = Vec::new();
+            if self.timing_overlay {
+                lines.extend(self.profiler.summary());
+            }
+            if self.stats_overlay {
+                lines.extend(self.render_stats().summary());
+            }
+            if !lines.is_empty() {
+                let text = lines.join("\n");
+                let section = TextSection::new(&text, font, 14.0, TextSpace::Screen([8.0, 8.0]));
+                if let Err(e) = self.text_renderer.queue(&section) {
+                    log::warn!("Skipping the overlay: {}", e);
+                }
+            }
+        }
+
+        self.text_renderer.prepare(&self.device, &self.queue, &self.camera, self.config.width, self.config.height);
+    }
+
+    // Recompiles a shader from disk and rebuilds the pipelines that use it
+    pub fn reload_shader(&mut self, name: &str) -> Result<()> {
+        if name == MAIN_SHADER {
+            // Other views compile the new source once they are switched to
+            self.pipeline_cache.invalidate_shader(MAIN_SHADER);
+            return self.rebuild_main_pipelines(self.debug_view);
+        }
+
+        let shader = self.shaders.load(&self.device, name, &[])?;
+        match name {
+            SpriteRenderer::SHADER => self.sprite_renderer.reload_shader(&self.device, &shader)?,
+            TextRenderer::SHADER => self.text_renderer.reload_shader(&self.device, &shader)?,
+            DebugDrawRenderer::SHADER => self.debug_draw_renderer.reload_shader(&self.device, &shader)?,
+            SkyboxRenderer::SHADER => self.skybox_renderer.reload_shader(&self.device, &shader)?,
+            ParticleSystem::SHADER => self.particles.reload_shader(&self.device, &shader)?,
+            ParticleSystem::SIMULATE_SHADER => self.particles.reload_simulate_shader(&self.device, &shader)?,
+            OitRenderer::SHADER => {
+                if let Some(oit) = &mut self.oit_renderer {
+                    oit.reload_shader(&self.device, &shader)?;
+                }
+            },
+            PickingRenderer::SHADER => {
+                if let Some(picking) = &mut self.picking_renderer {
+                    picking.reload_shader(&self.device, &shader)?;
+                }
+            },
+            DeferredRenderer::SHADER => {
+                if let Some(deferred) = &mut self.deferred {
+                    let composite_shader = self.shaders.load(&self.device, name, &[("DEFERRED_COMPOSITE", "1")])?;
+                    deferred.reload_shaders(&self.device, &shader, &composite_shader)?;
+                }
+            },
+            SsaoRenderer::SHADER => {
+                if let Some(ssao) = &mut self.ssao {
+                    ssao.reload_shader(&self.device, &shader)?;
+                }
+            },
+            OcclusionQueries::SHADER => {
+                if let Some(occlusion) = &mut self.occlusion {
+                    occlusion.reload_shader(&self.device, &shader)?;
+                }
+            },
+            GpuScene::SHADER => {
+                if let Some(gpu_scene) = &mut self.gpu_scene {
+                    gpu_scene.reload_shader(&self.device, &shader)?;
+                }
+            },
+            GpuScene::HI_Z_SHADER => {
+                if let Some(gpu_scene) = &mut self.gpu_scene {
+                    let copy_shader = self.shaders.load(&self.device, name, &[("HI_Z_COPY", "1")])?;
+                    gpu_scene.reload_hi_z_shaders(&self.device, &copy_shader, &shader)?;
+                }
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    // Compiles the main shader for a debug view and rebuilds every pipeline drawn with it
+    // Nothing changes when any of them fails
+    fn rebuild_main_pipelines(&mut self, view: DebugView) -> Result<()> {
+        let device = &self.device;
+        let layout = &self.render_pipeline_layout;
+        let (render_pipeline, blend_pipelines) = create_main_pipelines(
+            &mut self.pipeline_cache,
+            device,
+            &mut self.shaders,
+            layout,
+            self.config.format,
+            view,
+        )?;
+        if let Some(oit) = &mut self.oit_renderer {
+            let shader = self.pipeline_cache.shader(device, &mut self.shaders, &main_shader_key(device, view))?;
+            oit.reload_scene_shader(device, layout, &shader)?;
+        }
+        if let Some(deferred) = &mut self.deferred {
+            deferred.gbuffer_pipeline = self.pipeline_cache.render_pipeline(device, &mut self.shaders, layout, &gbuffer_pipeline_key(device))?;
+        }
+        if let Some(ssao) = &mut self.ssao {
+            ssao.depth_pipeline = self.pipeline_cache.render_pipeline(device, &mut self.shaders, layout, &depth_prepass_pipeline_key(device))?;
+        }
+        self.render_pipeline = render_pipeline;
+        self.blend_pipelines = blend_pipelines;
+        self.debug_view = view;
+        Ok(())
+    }
+
+    // Draws the scene in one of the debug views from the next frame on
+    // The previous view is kept when its shader variant fails to compile
+    pub fn set_debug_view(&mut self, view: DebugView) -> Result<()> {
+        if view != self.debug_view {
+            self.rebuild_main_pipelines(view)?;
+        }
+        Ok(())
+    }
+
+    pub fn debug_view(&self) -> DebugView {
+        self.debug_view
+    }
+
+    pub fn shaders(&mut self) -> &mut ShaderLibrary {
+        &mut self.shaders
+    }
+
+    pub fn pipeline_cache_stats(&self) -> PipelineCacheStats {
+        self.pipeline_cache.stats()
+    }
+
+    // Uploads a mesh that objects in the scene can be drawn with
+    pub fn create_mesh(&mut self, vertices: &[Vertex], indices: &[u32], label: &str) -> MeshHandle {
+        self.scene.add_mesh(&self.device, vertices, indices, label)
+    }
+
+    // Objects still drawn with the mesh keep it alive until they are removed
+    pub fn release_mesh(&mut self, mesh: MeshHandle) {
+        self.scene.release_mesh(mesh);
+    }
+
+    // Decodes an image into a texture held by the handle
+    pub fn create_texture(&mut self, bytes: &[u8], label: &str, options: &texture::TextureOptions) -> Result<TextureHandle> {
+        self.resources.create_texture(&self.device, &self.queue, bytes, label, options)
+    }
+
+    pub fn retain_texture(&mut self, texture: TextureHandle) -> bool {
+        self.resources.textures.retain(texture)
+    }
+
+    pub fn release_texture(&mut self, texture: TextureHandle) {
+        self.resources.release_texture(texture);
+    }
+
+    // `usage` is added to COPY_DST, so the buffer can be written with `write_buffer`
+    pub fn create_buffer(&mut self, contents: &[u8], usage: wgpu::BufferUsages, label: &str) -> BufferHandle {
+        self.resources.create_buffer(&self.device, contents, usage, label)
+    }
+
+    pub fn write_buffer(&mut self, buffer: BufferHandle, offset: wgpu::BufferAddress, data: &[u8]) -> Result<()> {
+        self.resources.write_buffer(&self.queue, buffer, offset, data)
+    }
+
+    pub fn retain_buffer(&mut self, buffer: BufferHandle) -> bool {
+        self.resources.buffers.retain(buffer)
+    }
+
+    pub fn release_buffer(&mut self, buffer: BufferHandle) {
+        self.resources.release_buffer(buffer);
+    }
+
+    // Copies per object data like transforms and material parameters for the next frame, bind
+    // it with `uniform_ring().bind_group(&allocation)` and `&[allocation.offset]`
+    // Fails for data larger than `uniform_ring().binding_size()`
+    pub fn alloc_uniform<T: bytemuck::Pod>(&mut self, value: &T) -> Result<RingAllocation> {
+        self.uniform_ring.alloc(&self.device, value)
+    }
+
+    pub fn uniform_ring(&self) -> &RingBuffer {
+        &self.uniform_ring
+    }
+
+    // Like `alloc_uniform` for arrays read from storage buffers, bound through `storage_ring()`
+    // Fails on adapters without a storage ring
+    pub fn alloc_storage<T: bytemuck::Pod>(&mut self, values: &[T]) -> Result<RingAllocation> {
+        let ring = self.storage_ring.as_mut().context("The adapter has no dynamic storage buffers")?;
+        ring.alloc_bytes(&self.device, bytemuck::cast_slice(values))
+    }
+
+    pub fn storage_ring(&self) -> Option<&RingBuffer> {
+        self.storage_ring.as_ref()
+    }
+
+    // For rings and other per frame data of the game, counts the frames the GPU finished
+    pub fn frame_fence(&self) -> &FrameFence {
+        self.resources.fence()
+    }
+
+    // Every mesh, texture and buffer still referenced, the engine's own included, for finding leaks
+    pub fn resource_report(&self) -> ResourceReport {
+        let mut report = self.resources.report();
+        report.live.extend(self.scene.meshes().live());
+        report.pending += self.scene.meshes().pending();
+        report
+    }
+
+    // Static objects and per frame draws, culled against the camera on update
+    pub fn scene(&mut self) -> &mut Scene {
+        &mut self.scene
+    }
+
+    pub fn culling_stats(&self) -> CullingStats {
+        self.scene.stats()
+    }
+
+    // Packs the builder's entries into an atlas sprites can be drawn from
+    pub fn create_atlas(&mut self, builder: &AtlasBuilder, label: Option<&str>) -> Result<AtlasId> {
+        let atlas = builder.build(&self.device, &self.queue, label)?;
+        Ok(self.sprite_renderer.add_atlas(&self.device, atlas))
+    }
+
+    // Sprites queued here are drawn on top of the scene from the next update on
+    pub fn sprites(&mut self) -> &mut SpriteRenderer {
+        &mut self.sprite_renderer
+    }
+
+    // Loads a TTF or OTF font for text rendering
+    pub fn load_font(&mut self, bytes: &[u8]) -> Result<FontId> {
+        self.text_renderer.load_font(bytes)
+    }
+
+    // Queues text for the next update, in screen space or as a label in the world
+    pub fn draw_text(&mut self, section: &TextSection) -> Result<()> {
+        self.text_renderer.queue(section)
+    }
+
+    pub fn text(&mut self) -> &mut TextRenderer {
+        &mut self.text_renderer
+    }
+
+    // Font used for `debug_draw::text_3d` labels, they are skipped until one is set
+    pub fn set_debug_font(&mut self, font: FontId) -> Result<()> {
+        self.text_renderer.fonts.font(font)?;
+        self.debug_draw_renderer.font = Some(font);
+        Ok(())
+    }
+
+    // What is drawn behind the scene, a flat color by default
+    pub fn set_sky(&mut self, sky: Sky) -> Result<()> {
+        self.skybox_renderer.set_sky(&self.device, sky)
+    }
+
+    pub fn skybox(&mut self) -> &mut SkyboxRenderer {
+        &mut self.skybox_renderer
+    }
+
+    // Times every pass on the GPU when the adapter supports timestamp queries,
+    // and the CPU time spent recording it otherwise
+    pub fn set_profiling_enabled(&mut self, enabled: bool) {
+        self.profiler.enabled = enabled;
+    }
+
+    // Also enables profiling, the overlay needs a debug font
+    pub fn set_timing_overlay(&mut self, enabled: bool) {
+        self.timing_overlay = enabled;
+        if enabled {
+            self.profiler.enabled = true;
+        }
+    }
+
+    // Pass timings of the newest frame the profiler has results for
+    pub fn frame_timings(&self) -> &FrameTimings {
+        self.profiler.latest()
+    }
+
+    pub fn profiler(&mut self) -> &mut GpuProfiler {
+        &mut self.profiler
+    }
+
+    // Counters of the last rendered frame and the memory in use now
+    pub fn render_stats(&self) -> RenderStats {
+        RenderStats {
+            frame: self.stats_history.latest().map(|stats| stats.frame).unwrap_or_default(),
+            memory: GpuMemory::of_device(&self.device),
+        }
+    }
+
+    // The last few seconds of frames, for graphs
+    pub fn stats_history(&self) -> &StatsHistory {
+        &self.stats_history
+    }
+
+    // Draws the counters in the top left corner with the debug font
+    pub fn set_stats_overlay(&mut self, enabled: bool) {
+        self.stats_overlay = enabled;
+    }
+
+    // Fails when the adapter has no compute shaders
+    pub fn add_emitter(&mut self, desc: &EmitterDesc, position: cgmath::Point3<f32>) -> Result<EmitterId> {
+        self.particles.add_emitter(&self.device, &self.queue, desc, position)
+    }
+
+    pub fn particles(&mut self) -> &mut ParticleSystem {
+        &mut self.particles
+    }
+
+    // Renders entity IDs next to the color pass from the next frame on, see `pick`
+    pub fn set_picking_enabled(&mut self, enabled: bool) -> Result<()> {
+        if !enabled {
+            self.picking_renderer = None;
+        } else if self.picking_renderer.is_none() {
+            let shader = self.shaders.load(&self.device, PickingRenderer::SHADER, &[])?;
+            self.picking_renderer = Some(PickingRenderer::new(
+                &self.device,
+                &shader,
+                &self.texture_bind_group_layout,
+                &self.camera_bind_group_layout,
+                self.config.width,
+                self.config.height,
+            ));
+        }
+        Ok(())
+    }
+
+    // Resolves alpha and premultiplied objects with weighted blended OIT instead of sorting them
+    // Costs two extra passes and targets, but intersecting and cyclic overlaps look right
+    pub fn set_oit_enabled(&mut self, enabled: bool) -> Result<()> {
+        if !enabled {
+            self.oit_renderer = None;
+        } else if self.oit_renderer.is_none() {
+            let scene_shader = self.pipeline_cache.shader(&self.device, &mut self.shaders, &main_shader_key(&self.device, self.debug_view))?;
+            let resolve_shader = self.shaders.load(&self.device, OitRenderer::SHADER, &[])?;
+            self.oit_renderer = Some(OitRenderer::new(
+                &self.device,
+                &scene_shader,
+                &self.render_pipeline_layout,
+                &resolve_shader,
+                self.config.format,
+                self.config.width,
+                self.config.height,
+            )?);
+        }
+        Ok(())
+    }
+
+    // Draws the instances of `gpu_scene` culled by a compute pass from the next frame on
+    // Fails on adapters without compute shaders
+    pub fn set_gpu_culling_enabled(&mut self, enabled: bool) -> Result<()> {
+        if !enabled {
+            self.gpu_scene = None;
+        } else if self.gpu_scene.is_none() {
+            let shader = self.shaders.load(&self.device, GpuScene::SHADER, &[])?;
+            let copy_shader = self.shaders.load(&self.device, GpuScene::HI_Z_SHADER, &[("HI_Z_COPY", "1")])?;
+            let reduce_shader = self.shaders.load(&self.device, GpuScene::HI_Z_SHADER, &[])?;
+            self.gpu_scene = Some(GpuScene::new(&self.device, &shader, &copy_shader, &reduce_shader)?);
+        }
+        Ok(())
+    }
+
+    // Skips static scene objects whose bounding boxes were hidden behind the depth buffer a
+    // frame or two earlier
+    pub fn set_occlusion_culling_enabled(&mut self, enabled: bool) -> Result<()> {
+        if !enabled {
+            self.occlusion = None;
+        } else if self.occlusion.is_none() {
+            let shader = self.shaders.load(&self.device, OcclusionQueries::SHADER, &[])?;
+            self.occlusion = Some(OcclusionQueries::new(&self.device, &shader, &self.camera_bind_group_layout));
+        }
+        self.scene.set_occlusion_culling(enabled);
+        Ok(())
+    }
+
+    // Point lights and ambient light of the lit scene
+    pub fn lights(&mut self) -> &mut Lights {
+        &mut self.lights
+    }
+
+    // Switches between shading lights in the main shader and from a G-buffer, from the next
+    // frame on. The deferred path fails on adapters without compute shaders
+    pub fn set_render_path(&mut self, path: RenderPath) -> Result<()> {
+        match path {
+            RenderPath::Forward => self.deferred = None,
+            RenderPath::Deferred if self.deferred.is_none() => {
+                let gbuffer_pipeline = self.pipeline_cache.render_pipeline(
+                    &self.device,
+                    &mut self.shaders,
+                    &self.render_pipeline_layout,
+                    &gbuffer_pipeline_key(&self.device),
+                )?;
+                let lighting_shader = self.shaders.load(&self.device, DeferredRenderer::SHADER, &[])?;
+                let composite_shader = self.shaders.load(&self.device, DeferredRenderer::SHADER, &[("DEFERRED_COMPOSITE", "1")])?;
+                self.deferred = Some(DeferredRenderer::new(
+                    &self.device,
+                    gbuffer_pipeline,
+                    &lighting_shader,
+                    &composite_shader,
+                    &self.lights,
+                    &self.depth_texture,
+                    self.config.format,
+                )?);
+            },
+            RenderPath::Deferred => {},
+        }
+        self.render_path = path;
+        Ok(())
+    }
+
+    pub fn render_path(&self) -> RenderPath {
+        self.render_path
+    }
+
+    // Darkens the ambient light in creases and contact points from the next frame on, with the
+    // settings of `ssao()`. The forward path draws a depth prepass for it
+    pub fn set_ssao_enabled(&mut self, enabled: bool) -> Result<()> {
+        if !enabled {
+            if self.ssao.take().is_some() {
+                self.lights.clear_occlusion(&self.queue);
+            }
+        } else if self.ssao.is_none() {
+            let depth_pipeline = self.pipeline_cache.render_pipeline(
+                &self.device,
+                &mut self.shaders,
+                &self.render_pipeline_layout,
+                &depth_prepass_pipeline_key(&self.device),
+            )?;
+            let shader = self.shaders.load(&self.device, SsaoRenderer::SHADER, &[])?;
+            self.ssao = Some(SsaoRenderer::new(&self.device, depth_pipeline, &shader, &self.depth_texture)?);
+        }
+        Ok(())
+    }
+
+    pub fn ssao(&mut self) -> Option<&mut SsaoRenderer> {
+        self.ssao.as_mut()
+    }
+
+    pub fn gpu_scene(&mut self) -> Option<&mut GpuScene> {
+        self.gpu_scene.as_mut()
+    }
+
+    // The draw arguments the last GPU culling pass wrote, resolves once the device is polled
+    pub fn read_gpu_draw_args(&self) -> impl std::future::Future<Output = Result<Vec<DrawIndexedArgs>>> + 'static {
+        let request = self.gpu_scene.as_ref().map(|gpu_scene| gpu_scene.read_draw_args(&self.device, &self.queue));
+        async move {
+            match request {
+                Some(request) => request.await,
+                None => Ok(Vec::new()),
+            }
+        }
+    }
+
+    // Fed from window events, in physical pixels
+    pub fn cursor_moved(&mut self, position: PhysicalPosition<f64>) {
+        self.cursor_position = Some(position);
+    }
+
+    pub fn cursor_left(&mut self) {
+        self.cursor_position = None;
+    }
+
+    pub fn cursor_position(&self) -> Option<PhysicalPosition<f64>> {
+        self.cursor_position
+    }
+
+    // World space ray under the cursor, for placing things where nothing can be picked
+    pub fn cursor_ray(&self) -> Option<(cgmath::Point3<f32>, cgmath::Vector3<f32>)> {
+        let position = self.cursor_position?;
+        Some(self.camera.screen_ray(position.x as f32, position.y as f32, self.config.width as f32, self.config.height as f32))
+    }
+
+    // Entity at a pixel of the last rendered frame
+    // The future resolves after the device is polled, which `update` does every frame
+    pub fn pick(&self, position: PhysicalPosition<f64>) -> impl std::future::Future<Output = Result<Option<EntityId>>> + 'static {
+        let request = self.picking_renderer.as_ref()
+            .context("Picking isn't enabled")
+            .map(|picking| picking.pick(&self.device, &self.queue, position.x as u32, position.y as u32));
+        async move { request?.await }
+    }
+
+    pub fn pick_at_cursor(&self) -> impl std::future::Future<Output = Result<Option<EntityId>>> + 'static {
+        let request = self.cursor_position.map(|position| self.pick(position));
+        async move {
+            match request {
+                Some(request) => request.await,
+                None => Ok(None),
+            }
+        }
+    }
+
+    // Entities visible inside the rectangle between two corners, for box selection
+    pub fn pick_rect(
+        &self,
+        a: PhysicalPosition<f64>,
+        b: PhysicalPosition<f64>,
+    ) -> impl std::future::Future<Output = Result<Vec<EntityId>>> + 'static {
+        let request = self.picking_renderer.as_ref()
+            .context("Picking isn't enabled")
+            .map(|picking| picking.pick_rect(&self.device, &self.queue, (a.x as u32, a.y as u32), (b.x as u32, b.y as u32)));
+        async move { request?.await }
     }
 
     pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
-        let output = self.surface.get_current_texture()?;
+        // Nothing can be drawn until the device is recovered
+        if self.is_device_lost() {
+            return Ok(());
+        }
+        match &self.surface {
+            Some(surface) => {
+                let output = surface.get_current_texture()?;
+                let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
+                self.render_to(&view);
+                output.present();
+            },
+            None => {
+                self.ensure_offscreen_target();
+                let target = self.offscreen.take().unwrap();
+                self.render_to(&target.view);
+                self.offscreen = Some(target);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Renders a frame into the offscreen target and reads it back
+    // Works the same for windowed and headless states
+    pub fn capture_frame(&mut self) -> Result<image::RgbaImage> {
+        ensure!(!self.is_device_lost(), "The device is lost, recover it before capturing frames");
+        self.ensure_offscreen_target();
+        let target = self.offscreen.take().unwrap();
+        self.render_to(&target.view);
+        let image = target.read_image(&self.device, &self.queue);
+        self.offscreen = Some(target);
+        image
+    }
 
-        let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
+    // Captures a frame and saves it as PNG
+    pub fn save_screenshot(&mut self, path: impl AsRef<std::path::Path>) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let frame = self.capture_frame()?;
+        frame.save_with_format(path, image::ImageFormat::Png)
+            .with_context(|| format!("Failed to save screenshot to {}", path.display()))
+    }
 
+    fn create_offscreen_target(&self) -> OffscreenTarget {
+        OffscreenTarget::new(&self.device, self.config.width, self.config.height, self.config.format, Some("Offscreen Target"))
+    }
+
+    fn ensure_offscreen_target(&mut self) {
+        if self.offscreen.is_none() {
+            self.offscreen = Some(self.create_offscreen_target());
+        }
+    }
+
+    fn render_to(&mut self, view: &wgpu::TextureView) {
         let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
             label: Some("Render Encoder"),
         });
+        self.profiler.begin_frame();
+
+        if let Some(gpu_scene) = &self.gpu_scene {
+            let scope = self.profiler.begin("GPU Culling");
+            gpu_scene.cull(&mut encoder, self.profiler.compute_pass_writes(&scope));
+            self.profiler.end(scope);
+        }
+
+        // Debug views draw everything forward
+        let deferred = self.deferred.as_ref().filter(|_| !self.debug_view.is_debug());
+        if let Some(deferred) = deferred {
+            let scope = self.profiler.begin("G-Buffer");
+            {
+                let mut render_pass = TrackedRenderPass::new(&self.queue, encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: Some("G-Buffer Pass"),
+                    color_attachments: &deferred.color_attachments(),
+                    depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                        view: &self.depth_texture.view,
+                        depth_ops: Some(wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(1.0),
+                            store: wgpu::StoreOp::Store,
+                        }),
+                        stencil_ops: None,
+                    }),
+                    timestamp_writes: self.profiler.render_pass_writes(&scope),
+                    occlusion_query_set: None,
+                }));
+
+                render_pass.set_bind_group(0, &self.diffuse_bind_group, &[]);
+                render_pass.set_bind_group(1, &self.camera_bind_group, &[]);
+                render_pass.set_bind_group(2, self.lights.bind_group(), &[]);
+                self.scene.render_queues(&mut render_pass, |blend| (!blend.is_transparent()).then_some(deferred.gbuffer_pipeline.as_ref()));
+                if let Some(gpu_scene) = &self.gpu_scene {
+                    render_pass.set_pipeline(&deferred.gbuffer_pipeline, wgpu::PrimitiveTopology::TriangleList);
+                    gpu_scene.render(&mut render_pass);
+                }
+            }
+            self.profiler.end(scope);
+        }
+
+        if let Some(ssao) = self.ssao.as_ref().filter(|_| !self.debug_view.is_debug()) {
+            if deferred.is_none() {
+                let scope = self.profiler.begin("Depth Prepass");
+                {
+                    // The scene pass clears and redraws depth, so the main pipelines keep their
+                    // regular depth test
+                    let mut render_pass = TrackedRenderPass::new(&self.queue, encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                        label: Some("Depth Prepass"),
+                        color_attachments: &[],
+                        depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                            view: &self.depth_texture.view,
+                            depth_ops: Some(wgpu::Operations {
+                                load: wgpu::LoadOp::Clear(1.0),
+                                store: wgpu::StoreOp::Store,
+                            }),
+                            stencil_ops: None,
+                        }),
+                        timestamp_writes: self.profiler.render_pass_writes(&scope),
+                        occlusion_query_set: None,
+                    }));
+
+                    render_pass.set_bind_group(0, &self.diffuse_bind_group, &[]);
+                    render_pass.set_bind_group(1, &self.camera_bind_group, &[]);
+                    render_pass.set_bind_group(2, self.lights.bind_group(), &[]);
+                    self.scene.render_queues(&mut render_pass, |blend| (!blend.is_transparent()).then_some(ssao.depth_pipeline.as_ref()));
+                    if let Some(gpu_scene) = &self.gpu_scene {
+                        render_pass.set_pipeline(&ssao.depth_pipeline, wgpu::PrimitiveTopology::TriangleList);
+                        gpu_scene.render(&mut render_pass);
+                    }
+                }
+                self.profiler.end(scope);
+            }
+
+            let scope = self.profiler.begin("SSAO");
+            ssao.render(&self.queue, &mut encoder, self.lights.occlusion_view(), self.profiler.render_pass_writes(&scope));
+            self.profiler.end(scope);
+        }
 
+        if let Some(deferred) = deferred {
+            let scope = self.profiler.begin("Deferred Lighting");
+            deferred.light(&mut encoder, self.profiler.compute_pass_writes(&scope));
+            self.profiler.end(scope);
+        }
+
+        let scope = self.profiler.begin("Scene");
         {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            let mut render_pass = TrackedRenderPass::new(&self.queue, encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
                 color_attachments: &[
                     // This is what @location(0) in the fragment shader targets
                     Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
+                    view,
                     resolve_target: None,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.1,
-                            g: 0.2,
-                            b: 0.3,
-                            a: 1.0,
+                        load: wgpu::LoadOp::Clear(if self.debug_view.is_debug() {
+                            wgpu::Color::BLACK
+                        } else {
+                            self.skybox_renderer.clear_color()
                         }),
                         store: wgpu::StoreOp::Store,
                     },
                 })],
-                depth_stencil_attachment: None,
-                timestamp_writes: None,
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.depth_texture.view,
+                    depth_ops: Some(wgpu::Operations {
+                        // The G-buffer pass already filled it with the opaque objects
+                        load: if deferred.is_some() { wgpu::LoadOp::Load } else { wgpu::LoadOp::Clear(1.0) },
+                        store: wgpu::StoreOp::Store,
+                    }),
+                    stencil_ops: None,
+                }),
+                timestamp_writes: self.profiler.render_pass_writes(&scope),
                 occlusion_query_set: None,
-            });
+            }));
 
-            render_pass.set_pipeline(&self.render_pipeline);
+            if let Some(deferred) = deferred {
+                deferred.composite(&mut render_pass);
+            }
+            render_pass.set_pipeline(&self.render_pipeline, wgpu::PrimitiveTopology::TriangleList);
             render_pass.set_bind_group(0, &self.diffuse_bind_group, &[]);
             render_pass.set_bind_group(1, &self.camera_bind_group, &[]);
-            render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
-            render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
-            render_pass.draw_indexed(0..self.num_indices, 0, 0..1);
+            render_pass.set_bind_group(2, self.lights.bind_group(), &[]);
+            if self.debug_view == DebugView::Wireframe && !debug_view::supports_line_polygons(&self.device) {
+                self.scene.render_unrolled(&mut render_pass);
+            } else if self.debug_view.is_debug() {
+                // Debug views only show the scene geometry, all with the same pipeline
+                self.scene.render_queues(&mut render_pass, |_| Some(self.render_pipeline.as_ref()));
+                if let Some(gpu_scene) = &self.gpu_scene {
+                    render_pass.set_pipeline(&self.render_pipeline, wgpu::PrimitiveTopology::TriangleList);
+                    gpu_scene.render(&mut render_pass);
+                }
+            } else {
+                let oit = self.oit_renderer.is_some();
+                if deferred.is_none() {
+                    self.scene.render_queues(&mut render_pass, |blend| (!blend.is_transparent()).then_some(self.render_pipeline.as_ref()));
+                    if let Some(gpu_scene) = &self.gpu_scene {
+                        render_pass.set_pipeline(&self.render_pipeline, wgpu::PrimitiveTopology::TriangleList);
+                        gpu_scene.render(&mut render_pass);
+                    }
+                }
+                // After the opaque queues, so it only covers the pixels they left empty
+                self.skybox_renderer.render(&mut render_pass);
+                self.scene.render_queues(&mut render_pass, |blend| {
+                    if oit && blend.is_order_independent() {
+                        return None;
+                    }
+                    self.blend_pipelines.get(&blend).map(Arc::as_ref)
+                });
+                // Blended over everything, depth tested against the scene
+                self.particles.render(&mut render_pass, &self.uniform_ring);
+            }
         }
+        self.profiler.end(scope);
+
+        // Results reach the scene a frame or two later
+        if let Some(occlusion) = &self.occlusion {
+            let scope = self.profiler.begin("Occlusion Queries");
+            occlusion.render(&self.queue, &mut encoder, &self.depth_texture.view, &self.camera_bind_group, self.profiler.render_pass_writes(&scope));
+            self.profiler.end(scope);
+        }
+
+        // The next frame culls against the opaque depth of this one
+        if let Some(gpu_scene) = self.gpu_scene.as_mut().filter(|gpu_scene| gpu_scene.hi_z_enabled) {
+            let scope = self.profiler.begin("Hi-Z");
+            let (width, height) = (self.config.width, self.config.height);
+            gpu_scene.build_hi_z(&self.device, &mut encoder, &self.depth_texture.view, width, height, self.profiler.compute_pass_writes(&scope));
+            self.profiler.end(scope);
+        }
+
+        // Particles are already drawn, OIT layers go on top of them
+        if let Some(oit) = self.oit_renderer.as_ref().filter(|_| !self.debug_view.is_debug()) {
+            if self.scene.has_visible(BlendMode::is_order_independent) {
+                let scope = self.profiler.begin("Transparency");
+                {
+                    let mut render_pass = TrackedRenderPass::new(&self.queue, encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                        label: Some("OIT Accumulate Pass"),
+                        color_attachments: &oit.color_attachments(),
+                        depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                            view: &self.depth_texture.view,
+                            depth_ops: Some(wgpu::Operations {
+                                load: wgpu::LoadOp::Load,
+                                store: wgpu::StoreOp::Store,
+                            }),
+                            stencil_ops: None,
+                        }),
+                        timestamp_writes: self.profiler.render_pass_writes(&scope),
+                        occlusion_query_set: None,
+                    }));
 
+                    render_pass.set_bind_group(0, &self.diffuse_bind_group, &[]);
+                    render_pass.set_bind_group(1, &self.camera_bind_group, &[]);
+                    render_pass.set_bind_group(2, self.lights.bind_group(), &[]);
+                    self.scene.render_queues(&mut render_pass, |blend| oit.pipeline(blend));
+                }
+                self.profiler.end(scope);
+
+                let scope = self.profiler.begin("Transparency Resolve");
+                {
+                    let mut render_pass = TrackedRenderPass::new(&self.queue, encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                        label: Some("OIT Resolve Pass"),
+                        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                            view,
+                            resolve_target: None,
+                            ops: wgpu::Operations {
+                                load: wgpu::LoadOp::Load,
+                                store: wgpu::StoreOp::Store,
+                            },
+                        })],
+                        depth_stencil_attachment: None,
+                        timestamp_writes: self.profiler.render_pass_writes(&scope),
+                        occlusion_query_set: None,
+                    }));
+
+                    oit.resolve(&mut render_pass);
+                }
+                self.profiler.end(scope);
+            }
+        }
+
+        if let Some(picking) = &self.picking_renderer {
+            let scope = self.profiler.begin("Picking");
+            picking.render(
+                &self.queue,
+                &mut encoder,
+                &self.scene,
+                self.gpu_scene.as_ref(),
+                [&self.diffuse_bind_group, &self.camera_bind_group],
+                self.profiler.render_pass_writes(&scope),
+            );
+            self.profiler.end(scope);
+        }
+
+        // Debug lines go after the scene so they can test against its depth
+        let scope = self.profiler.begin("Debug Draw");
+        {
+            let mut render_pass = TrackedRenderPass::new(&self.queue, encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Debug Draw Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.depth_texture.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: wgpu::StoreOp::Store,
+                    }),
+                    stencil_ops: None,
+                }),
+                timestamp_writes: self.profiler.render_pass_writes(&scope),
+                occlusion_query_set: None,
+            }));
+
+            self.debug_draw_renderer.render(&mut render_pass, &self.camera_bind_group);
+        }
+        self.profiler.end(scope);
+
+        // Sprites and text are drawn on top of everything
+        let scope = self.profiler.begin("Overlay");
+        {
+            let mut render_pass = TrackedRenderPass::new(&self.queue, encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Overlay Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: self.profiler.render_pass_writes(&scope),
+                occlusion_query_set: None,
+            }));
+
+            self.sprite_renderer.render(&mut render_pass);
+            self.text_renderer.render(&mut render_pass);
+        }
+        self.profiler.end(scope);
+        self.profiler.resolve(&self.device, &mut encoder);
+        // Taken before the device lost check so a dropped frame doesn't leak into the next one
+        let frame = self.frame_counters.take();
+
+        // Submitting to a lost device panics in wgpu, the frame is dropped instead
+        if self.is_device_lost() {
+            return;
+        }
         // submit will accept anything that implements IntoIter
+        self.uniform_ring.flush(&self.queue);
+        if let Some(ring) = &mut self.storage_ring {
+            ring.flush(&self.queue);
+        }
         self.queue.submit(std::iter::once(encoder.finish()));
-        output.present();
+        self.resources.fence().frame_submitted(&self.queue);
+        if let Some(occlusion) = &mut self.occlusion {
+            occlusion.frame_submitted();
+        }
+        self.profiler.end_frame();
 
-        Ok(())
+        // Uploads from the update before this frame are counted with it
+        let stats = RenderStats {
+            frame,
+            memory: GpuMemory::of_device(&self.device),
+        };
+        self.stats_history.push(stats);
     }
 }
@@ -4,5 +4,1239 @@ pub mod renderer;
 #[cfg(test)]
 mod tests {
     use super::*;
+    // The unit quad the default scene draws
+    use crate::core::state::{INDICES, VERTICES};
 
+    // Renderer tests run on whatever adapter is around, CI machines usually only have the
+    // software one. Without any adapter they fail, unless `UNNAMED_ALLOW_NO_ADAPTER` is set
+    fn headless_state(width: u32, height: u32) -> Option<TestState> {
+        headless_state_with_config(width, height, Default::default())
+    }
+
+    fn headless_state_with_config(width: u32, height: u32, config: renderer::adapter::AdapterConfig) -> Option<TestState> {
+        use renderer::{golden::ALLOW_NO_ADAPTER_ENV, offscreen::NoAdapterError};
+
+        let lock = renderer::golden::render_lock();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        match runtime.block_on(core::state::State::new_headless_with_config(width, height, config)) {
+            Ok(state) => Some(TestState { state, _lock: lock }),
+            Err(e) if e.downcast_ref::<NoAdapterError>().is_some() && std::env::var_os(ALLOW_NO_ADAPTER_ENV).is_some() => {
+                eprintln!("Skipping renderer test: {:?}", e);
+                None
+            }
+            Err(e) => panic!("{:?}, set {}=1 to skip renderer tests without an adapter", e, ALLOW_NO_ADAPTER_ENV),
+        }
+    }
+
+    // Keeps other renderers from drawing this test's debug primitives while the state lives
+    struct TestState {
+        state: core::state::State,
+        _lock: renderer::golden::RenderLock,
+    }
+
+    impl std::ops::Deref for TestState {
+        type Target = core::state::State;
+
+        fn deref(&self) -> &Self::Target {
+            &self.state
+        }
+    }
+
+    impl std::ops::DerefMut for TestState {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            &mut self.state
+        }
+    }
+
+    // Largest difference of any channel between two frames of the same size
+    fn max_channel_difference(a: &image::RgbaImage, b: &image::RgbaImage) -> u8 {
+        assert_eq!(a.dimensions(), b.dimensions());
+        a.as_raw().iter().zip(b.as_raw()).map(|(a, b)| a.abs_diff(*b)).max().unwrap_or(0)
+    }
+
+    #[test]
+    fn headless_capture_matches_target_size() {
+        let Some(mut state) = headless_state(64, 48) else { return };
+
+        state.update();
+        let frame = state.capture_frame().unwrap();
+
+        assert_eq!(frame.dimensions(), (64, 48));
+        // The corners are outside the quad and keep the clear color, (0.1, 0.2, 0.3) stored as sRGB
+        assert_eq!(frame.get_pixel(0, 0).0, [89, 124, 149, 255]);
+    }
+
+    #[test]
+    fn golden_default_scene() {
+        renderer::golden::GoldenHarness::engine()
+            .run("default_scene", |_| {})
+            .unwrap();
+    }
+
+    #[test]
+    fn golden_camera_side_view() {
+        renderer::golden::GoldenHarness::engine()
+            .run("camera_side_view", |state| {
+                state.look_at((1.5, 0.5, 1.0).into(), (0.0, 0.0, 0.0).into());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn golden_compare_flags_changed_pixels() {
+        let expected = image::RgbaImage::from_pixel(8, 8, image::Rgba([40, 80, 120, 255]));
+        let mut actual = expected.clone();
+        actual.put_pixel(3, 3, image::Rgba([255, 255, 255, 255]));
+        // Slight noise like different rasterizers produce stays under the threshold
+        actual.put_pixel(5, 5, image::Rgba([41, 79, 121, 255]));
+
+        let tolerance = renderer::golden::Tolerance::default();
+        let (comparison, diff) = renderer::golden::compare(&actual, &expected, &tolerance).unwrap();
+
+        assert_eq!(comparison.mismatched, 1);
+        assert_eq!(diff.get_pixel(3, 3).0, [255, 0, 0, 255]);
+        assert!(!comparison.passes(&tolerance));
+    }
+
+    #[test]
+    fn golden_sprite_batch() {
+        renderer::golden::GoldenHarness::engine()
+            .run("sprite_batch", |state| {
+                use renderer::sprite::Sprite;
+
+                // Left half red, right half green so flips are visible
+                let arrow = image::RgbaImage::from_fn(16, 8, |x, _| {
+                    if x < 8 { image::Rgba([255, 0, 0, 255]) } else { image::Rgba([0, 255, 0, 255]) }
+                });
+                let block = image::RgbaImage::from_pixel(8, 8, image::Rgba([255, 255, 255, 255]));
+
+                let mut builder = renderer::atlas::AtlasBuilder::new();
+                builder
+                    .add_image("arrow", &arrow.into())
+                    .add_image("block", &block.into());
+                let atlas = state.create_atlas(&builder, Some("Test Atlas")).unwrap();
+
+                let arrow = *state.sprites().atlas(atlas).unwrap().region("arrow").unwrap();
+                let block = *state.sprites().atlas(atlas).unwrap().region("block").unwrap();
+
+                let sprites = state.sprites();
+                sprites.draw(atlas, Sprite::from_region((-32.0, 32.0).into(), &arrow).with_layer(1)).unwrap();
+                sprites.draw(atlas, Sprite::from_region((32.0, 32.0).into(), &arrow).with_flip(true, false)).unwrap();
+                sprites.draw(atlas, Sprite::from_region((0.0, -32.0).into(), &arrow).with_rotation(std::f32::consts::FRAC_PI_4)).unwrap();
+                // Submitted first but on a higher layer, so it covers the arrow above
+                sprites.draw(atlas, Sprite::from_region((-28.0, 32.0).into(), &block)
+                    .with_tint([0.0, 0.0, 1.0, 1.0])
+                    .with_layer(2)).unwrap();
+                sprites.camera.zoom = 2.0;
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn atlas_packing_keeps_entries_apart() {
+        let sizes = [(30, 10), (10, 30), (16, 16), (5, 5)];
+        let layout = renderer::atlas::pack(&sizes, 1, 4096).unwrap();
+
+        assert_eq!(layout.width, 64);
+        for (i, (&(x, y), &(w, h))) in layout.positions.iter().zip(sizes.iter()).enumerate() {
+            assert!(x + w <= layout.width && y + h <= layout.height);
+            for (&(ox, oy), &(ow, oh)) in layout.positions.iter().zip(sizes.iter()).skip(i + 1) {
+                assert!(x + w < ox || ox + ow < x || y + h < oy || oy + oh < y);
+            }
+        }
+    }
+
+    // DejaVu Sans, see res/fonts/LICENSE-DejaVu.txt
+    const TEST_FONT: &[u8] = include_bytes!("../res/fonts/DejaVuSans.ttf");
+
+    #[test]
+    fn text_layout_wraps_and_aligns() {
+        use renderer::text::{FontCollection, TextAlign};
+
+        let mut fonts = FontCollection::new();
+        let font = fonts.load(TEST_FONT).unwrap();
+
+        // Just wide enough for the first two words
+        let wrap = fonts.layout("lorem ipsum", font, 16.0, None, TextAlign::Left).unwrap().width + 2.0;
+        let layout = fonts.layout("lorem ipsum dolor", font, 16.0, Some(wrap), TextAlign::Right).unwrap();
+        assert_eq!(layout.lines, 2);
+        // The second line starts with the word that didn't fit
+        let second = layout.glyphs.iter().find(|glyph| glyph.line == 1).unwrap();
+        assert_eq!(second.character, 'd');
+        // Right aligned lines end at the wrap width
+        let last = layout.glyphs.last().unwrap();
+        let advance = fonts.font(font).unwrap().metrics('r', 16.0).advance_width;
+        assert!((last.x + advance - wrap).abs() < 0.5);
+
+        let unwrapped = fonts.layout("lorem ipsum dolor", font, 16.0, None, TextAlign::Left).unwrap();
+        assert_eq!(unwrapped.lines, 1);
+    }
+
+    #[test]
+    fn golden_text() {
+        renderer::golden::GoldenHarness::engine()
+            .run("text", |state| {
+                use renderer::text::{TextAlign, TextSection, TextSpace};
+
+                let font = state.load_font(TEST_FONT).unwrap();
+                state.draw_text(&TextSection::new("Unnamed Engine", font, 24.0, TextSpace::Screen([8.0, 8.0]))).unwrap();
+                state.draw_text(
+                    &TextSection::new("Wrapped and centered text", font, 16.0, TextSpace::Screen([8.0, 180.0]))
+                        .with_max_width(160.0)
+                        .with_align(TextAlign::Center)
+                        .with_color([1.0, 0.8, 0.2, 1.0])
+                ).unwrap();
+                state.draw_text(&TextSection::new(
+                    "Label",
+                    font,
+                    32.0,
+                    TextSpace::World { position: (0.0, 0.5, 0.0).into(), line_height: 0.2 },
+                )).unwrap();
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn golden_debug_draw() {
+        renderer::golden::GoldenHarness::engine()
+            .run("debug_draw", |state| {
+                use renderer::debug_draw::{self, DebugStyle};
+
+                state.look_at((1.5, 1.5, 2.5).into(), (0.0, 0.0, 0.0).into());
+                debug_draw::grid((0.0, -0.6, 0.0).into(), 0.25, 8, [0.6, 0.6, 0.6, 1.0]);
+                debug_draw::aabb((-0.5, -0.5, -0.1).into(), (0.5, 0.5, 0.1).into(), debug_draw::YELLOW);
+                // Half hidden behind the quad, the overlay copy shows through it
+                debug_draw::sphere((0.0, 0.0, -0.3).into(), 0.3, debug_draw::WHITE);
+                debug_draw::sphere((0.0, 0.0, -0.3).into(), 0.2, DebugStyle::new([1.0, 0.0, 1.0, 1.0]).without_depth_test());
+                debug_draw::axes(cgmath::Matrix4::from_translation((-0.6, 0.0, 0.4).into()) * cgmath::Matrix4::from_scale(0.4), debug_draw::WHITE);
+                debug_draw::arrow((0.6, 0.6, 0.0).into(), (0.6, 0.0, 0.0).into(), debug_draw::GREEN);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn debug_draw_collects_primitives_from_every_thread() {
+        use renderer::debug_draw::{self, DebugStyle};
+
+        let Some(mut state) = headless_state(32, 32) else { return };
+        state.look_at((0.0, 0.0, 2.0).into(), (0.0, 0.0, 0.0).into());
+        state.update();
+        let before = state.capture_frame().unwrap();
+
+        std::thread::spawn(|| {
+            debug_draw::line((-1.0, -1.0, 0.5).into(), (1.0, 1.0, 0.5).into(), DebugStyle::new(debug_draw::GREEN).without_depth_test());
+        }).join().unwrap();
+        state.update();
+        assert_ne!(state.capture_frame().unwrap(), before);
+
+        // Single frame primitives are gone after the frame they were drawn in
+        state.update();
+        assert_eq!(state.capture_frame().unwrap(), before);
+    }
+
+    #[test]
+    fn shader_preprocessor_expands_includes_and_defines() {
+        use renderer::shader::{preprocess, validate, SourceLine};
+
+        let files = std::collections::HashMap::from([
+            ("main.wgsl", "#include \"common.wgsl\"\n#include \"common.wgsl\"\n#ifdef RED\nconst COLOR = vec3<f32>(1.0, 0.0, 0.0);\n#else\nconst COLOR = vec3<f32>(0.0, 0.0, SCALE);\n#endif\n"),
+            ("common.wgsl", "#ifndef SCALE\n#define SCALE 2.0\n#endif\nconst HALF: f32 = SCALE * 0.5;\n"),
+            ("broken.wgsl", "#include \"common.wgsl\"\n\nconst BROKEN: f32 = HALF +;\n"),
+        ]);
+        let read = |name: &str| files.get(name).map(|s| s.to_string()).ok_or_else(|| anyhow::anyhow!("missing {}", name));
+
+        let shader = preprocess("main.wgsl", &[], read).unwrap();
+        assert_eq!(shader.source, "const HALF: f32 = 2.0 * 0.5;\nconst COLOR = vec3<f32>(0.0, 0.0, 2.0);\n");
+        assert_eq!(shader.files, vec!["main.wgsl", "common.wgsl"]);
+        assert_eq!(shader.source_line(2), Some(&SourceLine { file: "main.wgsl".to_string(), line: 6 }));
+        validate(&shader).unwrap();
+
+        let red = preprocess("main.wgsl", &[("RED", ""), ("SCALE", "4.0")], read).unwrap();
+        assert_eq!(red.source, "const HALF: f32 = 4.0 * 0.5;\nconst COLOR = vec3<f32>(1.0, 0.0, 0.0);\n");
+
+        // Errors point at the file and line the code was written in
+        let broken = preprocess("broken.wgsl", &[], read).unwrap();
+        let error = validate(&broken).unwrap_err().to_string();
+        assert!(error.starts_with("broken.wgsl:3:"), "{}", error);
+
+        assert!(preprocess("main.wgsl", &[], |name| if name == "main.wgsl" { Ok("#ifdef A\n".to_string()) } else { read(name) }).is_err());
+    }
+
+    #[test]
+    fn frustum_culling_with_bvh_matches_brute_force() {
+        use renderer::{bounds::{Aabb, Frustum}, bvh::Bvh, camera::Camera};
+
+        let camera = Camera {
+            eye: (0.0, 0.0, 0.0).into(),
+            target: (0.0, 0.0, -1.0).into(),
+            up: cgmath::Vector3::unit_y(),
+            aspect: 1.0,
+            fovy: 90.0,
+            znear: 0.1,
+            zfar: 50.0,
+        };
+        let frustum = camera.frustum();
+
+        assert!(frustum.contains_point((0.0, 0.0, -10.0).into()));
+        assert!(!frustum.contains_point((0.0, 0.0, 10.0).into()));
+        assert!(!frustum.contains_point((0.0, 0.0, -60.0).into()));
+        // 90 degrees wide, so the sides are at x = +-z
+        assert!(frustum.contains_point((9.0, 0.0, -10.0).into()));
+        assert!(!frustum.contains_point((11.0, 0.0, -10.0).into()));
+
+        let boxes: Vec<(usize, Aabb)> = (0..40 * 40)
+            .map(|i| {
+                let center = cgmath::Point3::new((i % 40) as f32 * 3.0 - 60.0, 0.0, (i / 40) as f32 * 3.0 - 60.0);
+                (i, Aabb::new(center - cgmath::Vector3::new(0.5, 0.5, 0.5), center + cgmath::Vector3::new(0.5, 0.5, 0.5)))
+            })
+            .collect();
+        let bvh = Bvh::build(boxes.iter().copied());
+
+        let mut visible = Vec::new();
+        let stats = bvh.query_frustum(&frustum, |i| visible.push(i));
+        visible.sort();
+        let expected: Vec<usize> = boxes.iter().filter(|(_, aabb)| frustum.intersects_aabb(aabb)).map(|(i, _)| *i).collect();
+
+        assert_eq!(visible, expected);
+        assert!(!visible.is_empty() && visible.len() < boxes.len() / 2);
+        // Whole subtrees are skipped or accepted without looking at every box
+        assert!((stats.tested as usize) < boxes.len() / 2, "{:?}", stats);
+
+        let rotated = Aabb::new((-1.0, -1.0, -1.0).into(), (1.0, 1.0, 1.0).into())
+            .transform(&(cgmath::Matrix4::from_translation((5.0, 0.0, 0.0).into()) * cgmath::Matrix4::from_angle_y(cgmath::Deg(45.0))));
+        assert!((rotated.max.x - (5.0 + 2f32.sqrt())).abs() < 1e-5 && (rotated.max.y - 1.0).abs() < 1e-5);
+        assert!(Frustum::from_matrix(&camera.build_view_projection_matrix()).intersects_aabb(&rotated) == frustum.intersects_aabb(&rotated));
+    }
+
+    #[test]
+    fn lod_selection_uses_hysteresis_and_simplify_reduces_triangles() {
+        use renderer::{lod::{simplify, LodChain, LodLevel}, mesh::Vertex, resources::MeshHandle};
+
+        let mesh = |index| MeshHandle::new(index, 0, 0);
+        let chain = LodChain::new(vec![LodLevel::new(mesh(0), 0.5), LodLevel::new(mesh(1), 0.1)])
+            .with_impostor(LodLevel::new(mesh(2), 0.0));
+
+        assert_eq!(chain.select(0.8, None, 0.1), 0);
+        assert_eq!(chain.select(0.3, None, 0.1), 1);
+        assert_eq!(chain.select(0.01, None, 0.1), 2);
+        assert!(chain.is_impostor(2));
+
+        // Just below the threshold the current level is kept, well below it switches
+        assert_eq!(chain.select(0.47, Some(0), 0.1), 0);
+        assert_eq!(chain.select(0.44, Some(0), 0.1), 1);
+        // And the way back needs to clear the threshold by the same margin
+        assert_eq!(chain.select(0.53, Some(1), 0.1), 1);
+        assert_eq!(chain.select(0.56, Some(1), 0.1), 0);
+
+        // A finely tessellated plane collapses to a handful of triangles
+        let n = 32;
+        let vertices: Vec<Vertex> = (0..=n).flat_map(|y| (0..=n).map(move |x| Vertex {
+            position: [x as f32 / n as f32, y as f32 / n as f32, 0.0],
+            tex_coords: [x as f32 / n as f32, y as f32 / n as f32],
+            normal: [0.0, 0.0, 1.0],
+        })).collect();
+        let indices: Vec<u32> = (0..n).flat_map(|y| (0..n).flat_map(move |x| {
+            let i = y * (n + 1) + x;
+            [i, i + 1, i + n + 1, i + n + 1, i + 1, i + n + 2]
+        })).collect();
+
+        let (simple_vertices, simple_indices) = simplify(&vertices, &indices, 4);
+        assert!(simple_indices.len() * 16 < indices.len(), "{} indices left", simple_indices.len());
+        assert!(simple_vertices.len() <= 5 * 5);
+        assert!(simple_indices.iter().all(|&i| (i as usize) < simple_vertices.len()));
+    }
+
+    #[test]
+    fn mipmaps_average_in_the_texture_color_space() {
+        use renderer::texture::{Texture, TextureOptions};
+
+        let Some(state) = headless_state(4, 4) else { return };
+        let (device, queue) = (state.device(), state.queue());
+
+        // Reads the single texel of the last mip level
+        let last_mip = |texture: &Texture, layer: u32| -> [u8; 4] {
+            let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: None,
+                size: 256,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            });
+            let mut encoder = device.create_command_encoder(&Default::default());
+            encoder.copy_texture_to_buffer(
+                wgpu::ImageCopyTexture {
+                    texture: &texture.texture,
+                    mip_level: texture.texture.mip_level_count() - 1,
+                    origin: wgpu::Origin3d { x: 0, y: 0, z: layer },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                wgpu::ImageCopyBuffer {
+                    buffer: &buffer,
+                    layout: wgpu::ImageDataLayout { offset: 0, bytes_per_row: Some(256), rows_per_image: None },
+                },
+                wgpu::Extent3d { width: 1, height: 1, depth_or_array_layers: 1 },
+            );
+            queue.submit(Some(encoder.finish()));
+            buffer.slice(..).map_async(wgpu::MapMode::Read, |_| {});
+            device.poll(wgpu::Maintain::Wait);
+            let texel = buffer.slice(..4).get_mapped_range();
+            [texel[0], texel[1], texel[2], texel[3]]
+        };
+
+        let checker = image::DynamicImage::ImageRgba8(image::RgbaImage::from_fn(64, 64, |x, y| {
+            if (x + y) % 2 == 0 { image::Rgba([255, 255, 255, 255]) } else { image::Rgba([0, 0, 0, 255]) }
+        }));
+        let white = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(64, 64, image::Rgba([255, 255, 255, 255])));
+
+        let srgb = Texture::from_image(device, queue, &checker, None, &TextureOptions::tiled()).unwrap();
+        assert_eq!(srgb.texture.mip_level_count(), 7);
+        // Half the light, which is 188 in sRGB
+        assert!((last_mip(&srgb, 0)[0] as i32 - 188).abs() <= 2, "{:?}", last_mip(&srgb, 0));
+
+        let linear = Texture::from_image(device, queue, &checker, None, &TextureOptions::normal_map()).unwrap();
+        assert!((last_mip(&linear, 0)[0] as i32 - 128).abs() <= 2, "{:?}", last_mip(&linear, 0));
+
+        let array = Texture::array_from_images(device, queue, &[checker.clone(), white.clone()], None, &TextureOptions::tiled().linear()).unwrap();
+        assert_eq!(array.texture.depth_or_array_layers(), 2);
+        assert_eq!(last_mip(&array, 1)[0], 255);
+
+        let faces = [0, 1, 2, 3, 4, 5].map(|_| white.clone());
+        let cubemap = Texture::cubemap_from_images(device, queue, &faces, None, &TextureOptions::default()).unwrap();
+        assert_eq!(cubemap.texture.depth_or_array_layers(), 6);
+
+        assert!(Texture::array_from_images(device, queue, &[checker, image::DynamicImage::new_rgba8(8, 8)], None, &TextureOptions::default()).is_err());
+        assert!(TextureOptions::default().with_anisotropy(8).create_sampler(device).is_err());
+    }
+
+    #[test]
+    fn compressed_containers_keep_their_mips_and_decode_on_the_cpu() {
+        use renderer::compressed::{self, CompressedImage};
+        use renderer::texture::{Texture, TextureOptions};
+
+        // BC1 block with both endpoints set to one RGB565 color
+        let solid_bc1 = |color: u16| [color.to_le_bytes(), color.to_le_bytes(), [0, 0], [0, 0]].concat();
+        let u32s = |values: &[u32]| values.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>();
+        let u64s = |values: &[u64]| values.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>();
+
+        // 8x8 sRGB BC1 with a red first level and a blue second one
+        let mut ktx2 = vec![0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A];
+        ktx2.extend(u32s(&[132, 1, 8, 8, 0, 0, 1, 2, 0, 0, 0, 0, 0]));
+        ktx2.extend(u64s(&[0, 0, 128, 32, 32, 160, 8, 8]));
+        ktx2.extend(solid_bc1(0xF800).repeat(4));
+        ktx2.extend(solid_bc1(0x001F));
+
+        let image = CompressedImage::parse(&ktx2, false).unwrap();
+        assert_eq!(image.format, wgpu::TextureFormat::Bc1RgbaUnormSrgb);
+        assert_eq!(image.levels.len(), 2);
+        let decoded = image.decode().unwrap();
+        assert_eq!(decoded.format, wgpu::TextureFormat::Rgba8UnormSrgb);
+        assert_eq!(&decoded.levels[0][..4], &[255, 0, 0, 255]);
+        assert_eq!(decoded.levels[1], [0, 0, 255, 255].repeat(16));
+
+        // 4x4 DXT5 with green color and alpha interpolated at 1/7 of the way
+        let mut dds = b"DDS ".to_vec();
+        dds.extend(u32s(&[124, 0, 4, 4, 16, 0, 1]));
+        dds.resize(84, 0);
+        dds.extend(b"DXT5");
+        dds.resize(128, 0);
+        let alpha_indices = (0..16).fold(0u64, |bits, i| bits | 2 << (3 * i));
+        dds.extend([255, 0]);
+        dds.extend(&alpha_indices.to_le_bytes()[..6]);
+        dds.extend(solid_bc1(0x07E0));
+
+        let image = CompressedImage::parse(&dds, true).unwrap();
+        assert_eq!(image.format, wgpu::TextureFormat::Bc3RgbaUnormSrgb);
+        assert_eq!(&image.decode().unwrap().levels[0][..4], &[0, 255, 0, 219]);
+
+        // Without both endpoints ordered BC1 has a transparent color
+        let transparent = [0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+        let texels = compressed::decode_blocks(wgpu::TextureFormat::Bc1RgbaUnorm, 4, 4, &transparent).unwrap();
+        assert_eq!(&texels[..4], &[0, 0, 0, 0]);
+
+        assert!(compressed::decoded_format(wgpu::TextureFormat::Bc7RgbaUnorm).is_none());
+        assert!(CompressedImage::parse(&ktx2[..100], false).is_err());
+        assert!(CompressedImage::parse(b"\x89PNG", false).is_err());
+
+        let Some(state) = headless_state(4, 4) else { return };
+        let texture = Texture::from_compressed(state.device(), state.queue(), &ktx2, "test.ktx2", &TextureOptions::default()).unwrap();
+        assert_eq!(texture.texture.mip_level_count(), 2);
+        Texture::from_compressed(state.device(), state.queue(), &dds, "test.dds", &TextureOptions::default()).unwrap();
+    }
+
+    #[test]
+    fn skybox_draws_behind_the_scene() {
+        use renderer::skybox::{ProceduralSky, Sky};
+        use renderer::texture::Texture;
+
+        renderer::golden::GoldenHarness::engine()
+            .run("procedural_sky", |state| {
+                state.look_at((0.0, 0.3, 2.0).into(), (0.6, 0.5, 0.0).into());
+                let sky = ProceduralSky::default().with_sun_direction((0.4, 0.15, -1.0).into());
+                state.set_sky(Sky::Procedural(sky)).unwrap();
+            })
+            .unwrap();
+
+        let Some(mut state) = headless_state(32, 32) else { return };
+        // Red above the horizon and blue below it
+        let panorama = image::DynamicImage::ImageRgba8(image::RgbaImage::from_fn(64, 32, |_, y| {
+            if y < 16 { image::Rgba([255, 0, 0, 255]) } else { image::Rgba([0, 0, 255, 255]) }
+        }));
+        let cubemap = Texture::cubemap_from_equirectangular(state.device(), state.queue(), &panorama, 16, None).unwrap();
+        state.set_sky(Sky::Cubemap(cubemap)).unwrap();
+        state.look_at((0.0, 0.0, 2.0).into(), (0.0, 0.0, 0.0).into());
+
+        state.update();
+        let frame = state.capture_frame().unwrap();
+        assert_eq!(frame.get_pixel(0, 0).0, [255, 0, 0, 255]);
+        assert_eq!(frame.get_pixel(31, 31).0, [0, 0, 255, 255]);
+        // The quad in the middle still covers the sky
+        assert_ne!(frame.get_pixel(16, 16).0, [255, 0, 0, 255]);
+
+        let flat = Texture::from_image(state.device(), state.queue(), &panorama, None, &Default::default()).unwrap();
+        assert!(state.set_sky(Sky::Cubemap(flat)).is_err());
+    }
+
+    #[test]
+    fn picking_reads_entities_back_from_the_id_buffer() {
+        use renderer::{picking::EntityId, transparency::BlendMode};
+        use winit::dpi::PhysicalPosition;
+
+        let Some(mut state) = headless_state(64, 64) else { return };
+        state.set_picking_enabled(true).unwrap();
+        state.look_at((0.0, 0.0, 3.0).into(), (0.0, 0.0, 0.0).into());
+
+        let quad = state.create_mesh(VERTICES, INDICES, "Pick Quad");
+        let scale = cgmath::Matrix4::from_scale(0.6);
+        let left = state.scene().add_object(quad, cgmath::Matrix4::from_translation((-0.8, 0.0, 0.0).into()) * scale).unwrap();
+        state.scene().set_entity(left, Some(EntityId(1)));
+        state.scene().draw_entity(quad, cgmath::Matrix4::from_translation((0.8, 0.0, 0.0).into()) * scale, EntityId(2));
+        // Fully cut out and in front of the left quad, so clicks go through it
+        let cutout = state.scene().add_object(quad, cgmath::Matrix4::from_translation((-0.8, 0.0, 0.5).into()) * scale).unwrap();
+        state.scene().set_entity(cutout, Some(EntityId(3)));
+        state.scene().set_blend(cutout, BlendMode::Cutout);
+        state.scene().set_color(cutout, [1.0, 1.0, 1.0, 0.0]);
+        // Above the default quad
+        state.set_gpu_culling_enabled(true).unwrap();
+        let gpu = state.gpu_scene().unwrap();
+        let gpu_quad = gpu.add_mesh(VERTICES, INDICES);
+        let top = gpu.add_instance(gpu_quad, cgmath::Matrix4::from_translation((0.0, 0.9, 0.0).into()) * cgmath::Matrix4::from_scale(0.3)).unwrap();
+        gpu.set_entity(top, Some(EntityId(4)));
+
+        state.update();
+        state.capture_frame().unwrap();
+
+        let pick = |x: f64, y: f64| {
+            let request = state.pick(PhysicalPosition::new(x, y));
+            state.device().poll(wgpu::Maintain::Wait);
+            pollster::block_on(request).unwrap()
+        };
+        assert_eq!(pick(11.0, 32.0), Some(EntityId(1)));
+        assert_eq!(pick(52.0, 32.0), Some(EntityId(2)));
+        assert_eq!(pick(32.0, 8.0), Some(EntityId(4)));
+        // The default quad in the middle has no entity
+        assert_eq!(pick(32.0, 32.0), None);
+        assert_eq!(pick(1000.0, 0.0), None);
+
+        let request = state.pick_rect(PhysicalPosition::new(63.0, 63.0), PhysicalPosition::new(0.0, 0.0));
+        state.device().poll(wgpu::Maintain::Wait);
+        assert_eq!(pollster::block_on(request).unwrap(), vec![EntityId(1), EntityId(2), EntityId(4)]);
+
+        state.cursor_moved(PhysicalPosition::new(32.0, 32.0));
+        let (_, direction) = state.cursor_ray().unwrap();
+        assert!((direction.z + 1.0).abs() < 1e-3, "{:?}", direction);
+
+        state.set_picking_enabled(false).unwrap();
+        assert!(pollster::block_on(state.pick_at_cursor()).is_err());
+    }
+
+    #[test]
+    fn particle_effects_load_from_yaml_and_simulate_on_the_gpu() {
+        use renderer::particles::{self, EmitterDesc, ParticleBlend};
+
+        let effects = particles::load_effects(concat!(env!("CARGO_MANIFEST_DIR"), "/res/particles/effects.yaml")).unwrap();
+        assert_eq!(effects.keys().collect::<Vec<_>>(), ["dust", "fire", "smoke"]);
+        assert_eq!(effects["fire"].blend, ParticleBlend::Additive);
+        assert_eq!(effects["dust"].burst, 40);
+        assert!(particles::parse_effects("spark: { spawn_rate: 1, colour: [] }").is_err());
+        assert!(particles::parse_effects("spark: { size: [[0.5, 1.0], [0.2, 2.0]] }").is_err());
+
+        // Bursts of particles at rest with flat curves, so the image doesn't depend on the frame time
+        let burst = |count, spawn_box, color, size, blend| EmitterDesc {
+            spawn_rate: 0.0,
+            burst: count,
+            lifetime: [10.0, 10.0],
+            spawn_box,
+            gravity: [0.0; 3],
+            color: vec![(0.0, color)],
+            size: vec![(0.0, size)],
+            blend,
+            ..Default::default()
+        };
+        renderer::golden::GoldenHarness::engine()
+            .run("particles", |state| {
+                if !state.particles().is_supported() {
+                    return;
+                }
+                state.look_at((0.0, 0.0, 2.5).into(), (0.0, 0.0, 0.0).into());
+                let smoke = burst(64, [0.8, 0.5, 0.0], [0.1, 0.1, 0.1, 0.6], 0.3, ParticleBlend::Alpha);
+                state.add_emitter(&smoke, (-0.4, 0.0, 0.5).into()).unwrap();
+                let sparks = burst(32, [0.3, 0.3, 0.3], [1.0, 0.5, 0.1, 1.0], 0.15, ParticleBlend::Additive);
+                state.add_emitter(&sparks, (0.5, 0.0, 0.5).into()).unwrap();
+            })
+            .unwrap();
+
+        let Some(mut state) = headless_state(32, 32) else { return };
+        if !state.particles().is_supported() {
+            assert!(state.add_emitter(&EmitterDesc::default(), (0.0, 0.0, 0.0).into()).is_err());
+            return;
+        }
+        let dust = state.add_emitter(&effects["dust"], (0.0, 0.0, 0.0).into()).unwrap();
+        let fire = state.add_emitter(&effects["fire"], (0.0, 0.0, 0.0).into()).unwrap();
+        assert_eq!(state.particles().emitter_count(), 2);
+        state.particles().remove_emitter(dust);
+        assert_eq!(state.particles().emitter_count(), 1);
+        assert!(state.particles().desc(dust).is_none());
+        // Freed slots are reused
+        assert_eq!(state.add_emitter(&effects["smoke"], (0.0, 0.0, 0.0).into()).unwrap(), dust);
+        state.particles().set_position(fire, (1.0, 0.0, 0.0).into());
+        state.update();
+        // The billboards read their emitters from the uniform ring
+        assert!(state.uniform_ring().stats().used > 0);
+        state.capture_frame().unwrap();
+    }
+
+    #[test]
+    fn profiler_reports_every_pass() {
+        let Some(mut state) = headless_state(32, 32) else { return };
+        state.update();
+        state.capture_frame().unwrap();
+        assert!(state.frame_timings().passes.is_empty());
+
+        state.set_profiling_enabled(true);
+        state.update();
+        state.capture_frame().unwrap();
+        // GPU results come in on a later update once the readback is mapped
+        state.device().poll(wgpu::Maintain::Wait);
+        state.update();
+
+        let timings = state.frame_timings().clone();
+        let names: Vec<&str> = timings.passes.iter().map(|pass| pass.name.as_str()).collect();
+        assert_eq!(names, ["Scene", "Debug Draw", "Overlay"]);
+        let timestamps = state.profiler().has_timestamps();
+        assert!(timings.passes.iter().all(|pass| pass.gpu_ms.is_some() == timestamps && pass.cpu_ms >= 0.0));
+        assert_eq!(timings.gpu_ms().is_some(), timestamps);
+        assert_eq!(state.profiler().summary().len(), 3);
+    }
+
+    #[test]
+    fn render_stats_count_draws_uploads_and_memory() {
+        use renderer::{mesh::Vertex, stats::{self, MemoryKind}};
+
+        let Some(mut state) = headless_state(32, 32) else { return };
+        state.update();
+        state.capture_frame().unwrap();
+
+        // Only the default quad is drawn
+        let frame = state.render_stats().frame;
+        assert_eq!((frame.draw_calls, frame.triangles, frame.instances), (1, 2, 1));
+        // The texture, camera and lights bind groups
+        assert_eq!((frame.pipeline_switches, frame.bind_group_switches), (1, 3));
+        assert!(frame.upload_bytes >= 64, "{:?}", frame);
+        assert_eq!(state.stats_history().len(), 1);
+
+        let memory = state.render_stats().memory;
+        // The offscreen target and the depth buffer
+        assert!(memory.get(MemoryKind::RenderTarget).bytes >= 2 * 32 * 32 * 4);
+        let vertex_bytes = memory.get(MemoryKind::VertexBuffer).bytes;
+        let vertices = [Vertex { position: [0.0; 3], tex_coords: [0.0; 2], normal: [0.0; 3] }; 3];
+        state.create_mesh(&vertices, &[0, 1, 2], "Stats Triangle");
+        assert_eq!(
+            state.render_stats().memory.get(MemoryKind::VertexBuffer).bytes - vertex_bytes,
+            std::mem::size_of_val(&vertices) as u64
+        );
+
+        let staging = |state: &core::state::State| state.render_stats().memory.get(MemoryKind::StagingBuffer);
+        let before = staging(&state);
+        let buffer = stats::track_buffer(state.device(), state.device().create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: 256,
+            usage: wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        }));
+        assert_eq!(staging(&state).count, before.count + 1);
+        drop(buffer);
+        assert_eq!(staging(&state), before);
+
+        state.update();
+        state.capture_frame().unwrap();
+        assert_eq!(state.stats_history().series(|stats| stats.frame.draw_calls as f32), [1.0, 1.0]);
+
+        // Uploads from other threads count, another state's don't
+        let uploads = state.render_stats().frame.upload_bytes;
+        let buffer = state.device().create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: 1024,
+            usage: wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let queue = state.queue();
+        std::thread::scope(|scope| {
+            scope.spawn(|| stats::write_buffer(queue, &buffer, 0, &[0; 1024]));
+        });
+        let mut other = headless_state(32, 32).unwrap();
+        other.update();
+        other.capture_frame().unwrap();
+        state.update();
+        state.capture_frame().unwrap();
+        assert_eq!(state.render_stats().frame.upload_bytes, uploads + 1024);
+    }
+
+    #[test]
+    fn debug_views_switch_the_main_pipeline() {
+        use renderer::debug_view::DebugView;
+
+        assert_eq!("Mip_Level".parse::<DebugView>().unwrap(), DebugView::MipLevel);
+        assert!("shaded".parse::<DebugView>().is_err());
+        assert_eq!(DebugView::MipLevel.next(), DebugView::Lit);
+
+        renderer::golden::GoldenHarness::engine()
+            .run("debug_wireframe", |state| {
+                state.set_debug_view(DebugView::Wireframe).unwrap();
+            })
+            .unwrap();
+
+        let Some(mut state) = headless_state(32, 32) else { return };
+        state.look_at((0.0, 0.0, 2.0).into(), (0.0, 0.0, 0.0).into());
+        let center = |state: &mut core::state::State, view: DebugView| {
+            state.set_debug_view(view).unwrap();
+            state.update();
+            state.capture_frame().unwrap().get_pixel(16, 16).0
+        };
+
+        // Every variant of the main shader compiles and the lit view is unchanged
+        let lit = center(&mut state, DebugView::Lit);
+        for view in DebugView::ALL {
+            center(&mut state, view);
+        }
+        assert_eq!(center(&mut state, DebugView::Albedo), lit);
+        // A normal of +Z is (0.5, 0.5, 1.0), which is 188 in sRGB
+        let normal = center(&mut state, DebugView::Normals);
+        assert!(normal[0].abs_diff(188) <= 1 && normal[1].abs_diff(188) <= 1 && normal[2] == 255, "{:?}", normal);
+        let uv = center(&mut state, DebugView::Uvs);
+        assert!(uv[0].abs_diff(188) <= 8 && uv[1].abs_diff(188) <= 8 && uv[2] == 0, "{:?}", uv);
+        let light = center(&mut state, DebugView::Lighting);
+        assert!(light[0] == light[1] && light[1] == light[2] && light[0] > 128, "{:?}", light);
+        // The wireframe leaves the inside of the triangles empty, the diagonal runs through the center
+        state.set_debug_view(DebugView::Wireframe).unwrap();
+        state.update();
+        let wireframe = state.capture_frame().unwrap();
+        assert_ne!(wireframe.get_pixel(16, 16).0[..3], [0, 0, 0]);
+        assert_eq!(wireframe.get_pixel(20, 12).0[..3], [0, 0, 0]);
+
+        // A second quad behind the first one adds up instead of being hidden
+        let single = center(&mut state, DebugView::Overdraw);
+        let quad = state.create_mesh(VERTICES, INDICES, "Overdraw Quad");
+        state.scene().draw(quad, cgmath::Matrix4::from_translation((0.0, 0.0, -0.5).into()));
+        let double = center(&mut state, DebugView::Overdraw);
+        assert!(single[0] > 0 && double[0] > single[0] && double[1] > single[1], "{:?} {:?}", single, double);
+    }
+
+    #[test]
+    fn transparent_queues_sort_back_to_front_and_oit_resolves_them() {
+        use renderer::transparency::BlendMode;
+
+        // The near red layer is added before the far blue one, sorting has to swap them
+        let describe = |state: &mut core::state::State| {
+            state.look_at((0.0, 0.0, 3.0).into(), (0.0, 0.0, 0.0).into());
+            let quad = state.create_mesh(VERTICES, INDICES, "Layer Quad");
+            let layers = [
+                (BlendMode::Alpha, [1.0, 0.0, 0.0, 0.5], (-0.15, 0.0, 1.0)),
+                (BlendMode::Alpha, [0.0, 0.0, 1.0, 0.5], (0.0, 0.0, 0.5)),
+                (BlendMode::Additive, [0.0, 1.0, 0.0, 0.5], (0.4, 0.4, 0.8)),
+                (BlendMode::Premultiplied, [0.0, 0.25, 0.25, 0.5], (-0.4, -0.4, 0.8)),
+                // Under the cutoff, so nothing of it is drawn
+                (BlendMode::Cutout, [1.0, 1.0, 1.0, 0.3], (0.4, -0.4, 1.2)),
+            ];
+            for (blend, color, position) in layers {
+                let object = state.scene().add_object(quad, cgmath::Matrix4::from_translation(position.into()) * cgmath::Matrix4::from_scale(0.6)).unwrap();
+                state.scene().set_blend(object, blend);
+                state.scene().set_color(object, color);
+            }
+        };
+
+        renderer::golden::GoldenHarness::engine()
+            .run("transparency", describe)
+            .unwrap();
+
+        let Some(mut state) = headless_state(64, 64) else { return };
+        let background = {
+            state.look_at((0.0, 0.0, 3.0).into(), (0.0, 0.0, 0.0).into());
+            state.update();
+            state.capture_frame().unwrap()
+        };
+        describe(&mut state);
+        state.update();
+        let sorted = state.capture_frame().unwrap();
+        // Red ends up on top of blue
+        let center = sorted.get_pixel(30, 32).0;
+        assert!(center[0] > center[2], "{:?}", center);
+        // The cutout quad in the bottom right corner is discarded
+        assert_eq!(sorted.get_pixel(47, 47), background.get_pixel(47, 47));
+
+        state.set_profiling_enabled(true);
+        state.set_oit_enabled(true).unwrap();
+        state.update();
+        let resolved = state.capture_frame().unwrap();
+        let center = resolved.get_pixel(30, 32).0;
+        // Weighted blending favors the closer layer as well, but only approximates the order
+        assert!(center[0] > center[2], "{:?}", center);
+        assert_eq!(resolved.get_pixel(47, 47), background.get_pixel(47, 47));
+        state.device().poll(wgpu::Maintain::Wait);
+        state.update();
+        let names: Vec<String> = state.frame_timings().passes.iter().map(|pass| pass.name.clone()).collect();
+        assert!(names.iter().any(|name| name == "Transparency"), "{:?}", names);
+    }
+
+    #[test]
+    fn adapters_rank_by_type_and_backend() {
+        use renderer::adapter;
+
+        assert_eq!(adapter::parse_backends("Vulkan, gl"), Some(wgpu::Backends::VULKAN | wgpu::Backends::GL));
+        assert_eq!(adapter::parse_backends("glide"), None);
+        assert_eq!(adapter::parse_backends(""), None);
+
+        let info = |device_type, backend| wgpu::AdapterInfo {
+            name: String::new(),
+            vendor: 0,
+            device: 0,
+            device_type,
+            driver: String::new(),
+            driver_info: String::new(),
+            backend,
+        };
+        let discrete = info(wgpu::DeviceType::DiscreteGpu, wgpu::Backend::Vulkan);
+        let integrated = info(wgpu::DeviceType::IntegratedGpu, wgpu::Backend::Vulkan);
+        let software = info(wgpu::DeviceType::Cpu, wgpu::Backend::Vulkan);
+        let high = wgpu::PowerPreference::HighPerformance;
+        let low = wgpu::PowerPreference::LowPower;
+        assert!(adapter::score(&discrete, high) > adapter::score(&integrated, high));
+        assert!(adapter::score(&integrated, low) > adapter::score(&discrete, low));
+        assert!(adapter::score(&integrated, high) > adapter::score(&software, high));
+        // Same device type, the native API wins over GL
+        assert!(adapter::score(&software, high) > adapter::score(&info(wgpu::DeviceType::Cpu, wgpu::Backend::Gl), high));
+    }
+
+    #[test]
+    fn lost_device_errors_set_the_flag() {
+        use renderer::adapter::{self, DeviceLostFlag};
+        use wgpu_core::device::DeviceError;
+
+        assert_eq!(adapter::DEVICE_LOST_MESSAGE, DeviceError::Lost.to_string());
+
+        // Formatted the way wgpu reports a failed call
+        let error = |source: DeviceError| wgpu::Error::Validation {
+            description: format!("Validation Error\n\nCaused by:\n    {}\n", source),
+            source: Box::new(source),
+        };
+        let flag = DeviceLostFlag::default();
+        flag.handle(error(DeviceError::Lost));
+        assert!(flag.is_set());
+        // Later losses are ignored
+        flag.handle(error(DeviceError::Lost));
+        assert!(flag.is_set());
+
+        // Anything else is still fatal
+        let other = DeviceLostFlag::default();
+        assert!(std::panic::catch_unwind(|| other.handle(error(DeviceError::Invalid))).is_err());
+        assert!(!other.is_set());
+    }
+
+    #[test]
+    fn devices_recover_with_their_resources() {
+        // A name matching nothing falls back to ranking every adapter
+        let config = renderer::adapter::AdapterConfig::default().with_name("no such adapter");
+        let Some(mut state) = headless_state_with_config(48, 48, config) else { return };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        state.look_at((0.0, 0.0, 2.0).into(), (0.0, 0.0, 0.0).into());
+        state.update();
+        let before = state.capture_frame().unwrap();
+        assert_eq!(state.device_generation(), 0);
+
+        runtime.block_on(state.recover_device()).unwrap();
+        assert!(!state.is_device_lost());
+        assert_eq!(state.device_generation(), 1);
+        // The meshes and objects were uploaded again, so the frame is the same
+        state.update();
+        let after = state.capture_frame().unwrap();
+        assert_eq!(before, after);
+
+        // Atlases, fonts, skies and emitters come back under the same ids
+        use renderer::{particles::EmitterDesc, skybox::Sky, sprite::Sprite};
+        let mut builder = renderer::atlas::AtlasBuilder::new();
+        builder.add_image("block", &image::RgbaImage::from_pixel(8, 8, image::Rgba([255, 0, 0, 255])).into());
+        let atlas = state.create_atlas(&builder, Some("Test Atlas")).unwrap();
+        state.set_sky(Sky::Color(wgpu::Color::BLUE)).unwrap();
+        state.skybox().exposure = 2.0;
+        let font = state.load_font(TEST_FONT).unwrap();
+        let emitter = state.particles().is_supported()
+            .then(|| state.add_emitter(&EmitterDesc::default(), (0.0, 0.0, -5.0).into()).unwrap());
+        let texture = state.create_texture(include_bytes!("../res/dirt.png"), "Dirt", &Default::default()).unwrap();
+        let buffer = state.create_buffer(&[0; 16], wgpu::BufferUsages::UNIFORM, "Kept Buffer");
+        state.write_buffer(buffer, 0, &[1; 8]).unwrap();
+        let live = state.resource_report().live.len();
+
+        runtime.block_on(state.recover_device()).unwrap();
+        assert!(matches!(state.skybox().sky(), Sky::Color(color) if *color == wgpu::Color::BLUE));
+        assert_eq!(state.skybox().exposure, 2.0);
+        state.text().fonts.font(font).unwrap();
+        if let Some(emitter) = emitter {
+            assert!(state.particles().desc(emitter).is_some());
+        }
+        // So do textures and buffers, the engine's own included
+        assert!(state.retain_texture(texture));
+        state.write_buffer(buffer, 8, &[1; 8]).unwrap();
+        assert_eq!(state.resource_report().live.len(), live);
+        // The atlas pixels were uploaded again
+        let block = *state.sprites().atlas(atlas).unwrap().region("block").unwrap();
+        state.sprites().draw(atlas, Sprite::from_region((0.0, 0.0).into(), &block)).unwrap();
+        state.update();
+        let frame = state.capture_frame().unwrap();
+        assert_eq!(frame.get_pixel(24, 24).0, [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn pipeline_cache_reuses_pipelines_with_the_same_state() {
+        use renderer::{
+            pipeline_cache::{PipelineCache, RenderPipelineKey, ShaderKey},
+            shader::ShaderLibrary,
+        };
+
+        let Some(mut state) = headless_state(32, 32) else { return };
+
+        // The default state caches the lit variants, switching back to them doesn't create any
+        let created = state.pipeline_cache_stats().misses;
+        state.set_debug_view(renderer::debug_view::DebugView::Normals).unwrap();
+        let with_normals = state.pipeline_cache_stats();
+        assert_eq!(with_normals.misses, created + 1);
+        state.set_debug_view(renderer::debug_view::DebugView::Lit).unwrap();
+        assert_eq!(state.pipeline_cache_stats().misses, with_normals.misses);
+        assert!(state.pipeline_cache_stats().hits > with_normals.hits);
+
+        let device = state.device();
+        let camera_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&camera_layout],
+            push_constant_ranges: &[],
+        });
+        let mut cache = PipelineCache::new();
+        let mut shaders = ShaderLibrary::default();
+        let line_vertex = wgpu::VertexBufferLayout {
+            array_stride: 28,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32x4],
+        };
+        let target = |blend| [Some(wgpu::ColorTargetState {
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            blend,
+            write_mask: wgpu::ColorWrites::ALL,
+        })];
+        let key = RenderPipelineKey::new(ShaderKey::new("debug_draw.wgsl", &[]), &[line_vertex], &target(None));
+
+        // Failures aren't cached
+        let missing = key.clone().with_entry_points("vs_missing", Some("fs_main"));
+        assert!(cache.render_pipeline(device, &mut shaders, &layout, &missing).is_err());
+        assert_eq!(cache.stats().pipelines, 0);
+
+        let first = cache.render_pipeline(device, &mut shaders, &layout, &key).unwrap();
+        let second = cache.render_pipeline(device, &mut shaders, &layout, &key).unwrap();
+        assert!(std::sync::Arc::ptr_eq(&first, &second));
+
+        let blended = RenderPipelineKey { targets: target(Some(wgpu::BlendState::ALPHA_BLENDING)).to_vec(), ..key.clone() };
+        let third = cache.render_pipeline(device, &mut shaders, &layout, &blended).unwrap();
+        assert!(!std::sync::Arc::ptr_eq(&first, &third));
+        let stats = cache.stats();
+        assert_eq!((stats.pipelines, stats.shaders, stats.hits, stats.misses), (2, 1, 1, 2));
+
+        assert_eq!(cache.invalidate_shader("debug_draw.wgsl"), 2);
+        assert_eq!(cache.stats().shaders, 0);
+    }
+
+    #[test]
+    fn released_resources_outlive_the_frames_using_them() {
+        use renderer::{mesh::Vertex, resources::{FrameFence, ResourcePool}};
+
+        // Handles of freed slots don't resolve to what takes their place
+        let mut pool = ResourcePool::new("Number", FrameFence::default());
+        let one = pool.insert(1, "one");
+        assert!(pool.retain(one));
+        assert!(!pool.release(one));
+        assert!(pool.release(one));
+        let two = pool.insert(2, "two");
+        assert_eq!((pool.get(one), pool.get(two)), (None, Some(&2)));
+        assert!(!pool.retain(one));
+        // Nothing was submitted, so the GPU can't be using the released one
+        assert_eq!(pool.collect(), 1);
+        let other = ResourcePool::<i32>::new("Number", FrameFence::default());
+        assert_eq!(other.get(two), None);
+
+        let Some(mut state) = headless_state(32, 32) else { return };
+        let triangle = [
+            Vertex { position: [-0.5, -0.5, 0.0], tex_coords: [0.0, 1.0], normal: [0.0, 0.0, 1.0] },
+            Vertex { position: [0.5, -0.5, 0.0], tex_coords: [1.0, 1.0], normal: [0.0, 0.0, 1.0] },
+            Vertex { position: [0.0, 0.5, 0.0], tex_coords: [0.5, 0.0], normal: [0.0, 0.0, 1.0] },
+        ];
+        let baseline = state.resource_report().live.len();
+        let mesh = state.create_mesh(&triangle, &[0, 1, 2], "Leaked Triangle");
+        let object = state.scene().add_object(mesh, cgmath::SquareMatrix::identity()).unwrap();
+        let buffer = state.create_buffer(&[0; 16], wgpu::BufferUsages::UNIFORM, "Leaked Buffer");
+        state.write_buffer(buffer, 8, &[1; 8]).unwrap();
+        assert!(state.write_buffer(buffer, 12, &[1; 8]).is_err());
+        let report = state.resource_report();
+        assert_eq!(report.live.len(), baseline + 2);
+        assert!(report.live.iter().any(|resource| resource.label == "Leaked Triangle" && resource.refs == 2));
+
+        state.update();
+        state.capture_frame().unwrap();
+        // The object still holds the mesh
+        state.release_mesh(mesh);
+        assert!(state.scene().mesh(mesh).is_some());
+        state.scene().remove_object(object);
+        assert!(state.scene().mesh(mesh).is_none());
+        // Stale handles and ids are reported instead of panicking
+        assert!(state.scene().add_object(mesh, cgmath::SquareMatrix::identity()).is_none());
+        assert!(!state.scene().set_transform(object, cgmath::SquareMatrix::identity()));
+        state.release_buffer(buffer);
+        assert!(state.write_buffer(buffer, 0, &[0; 4]).is_err());
+        assert_eq!(state.resource_report().pending, 2);
+
+        // Dropped once the frame that drew the mesh has finished
+        state.device().poll(wgpu::Maintain::Wait);
+        state.update();
+        let report = state.resource_report();
+        assert_eq!((report.live.len(), report.pending), (baseline, 0));
+    }
+
+    #[test]
+    fn uniform_ring_packs_aligned_allocations_and_reuses_finished_blocks() {
+        use renderer::ring_buffer::RingBuffer;
+
+        let Some(mut state) = headless_state(32, 32) else { return };
+        let alignment = state.device().limits().min_uniform_buffer_offset_alignment;
+
+        let first = state.alloc_uniform(&[1.0f32; 16]).unwrap();
+        let second = state.alloc_uniform(&[2.0f32; 4]).unwrap();
+        assert_eq!((first.offset, first.size), (0, 64));
+        assert_eq!(second.offset % alignment, 0);
+        assert!(second.offset >= 64);
+        // Bindings span what the device allows, nothing larger fits
+        let binding_size = state.uniform_ring().binding_size();
+        assert_eq!(binding_size, state.device().limits().max_uniform_buffer_binding_size as u64);
+        assert!(state.alloc_uniform(&[[0u32; 4096]; 5]).is_err());
+
+        // More than one block holds, the ring grows
+        let per_block = (state.uniform_ring().stats().capacity / alignment as u64) as usize;
+        for _ in 0..per_block {
+            state.alloc_uniform(&[3u32; 4]).unwrap();
+        }
+        let grown = state.uniform_ring().stats();
+        assert_eq!(grown.blocks, 2);
+
+        state.update();
+        state.capture_frame().unwrap();
+        state.device().poll(wgpu::Maintain::Wait);
+        assert_eq!(state.uniform_ring().stats().used, 0);
+
+        // The frame is done, so the next one fits in the same blocks
+        for _ in 0..per_block + 2 {
+            state.alloc_uniform(&[4u32; 4]).unwrap();
+        }
+        assert_eq!(state.uniform_ring().stats().blocks, grown.blocks);
+
+        // The storage ring binds whole arrays, up to its own binding size
+        if let Some(storage_binding_size) = state.storage_ring().map(RingBuffer::binding_size) {
+            let values = vec![5u32; storage_binding_size as usize / 4];
+            assert_eq!(state.alloc_storage(&values).unwrap().size as u64, storage_binding_size);
+            assert!(state.alloc_storage(&[values, vec![6]].concat()).is_err());
+            state.update();
+            state.capture_frame().unwrap();
+        }
+    }
+
+    #[test]
+    fn gpu_culling_counts_visible_instances_in_indirect_draws() {
+        use cgmath::Matrix4;
+
+        let Some(mut state) = headless_state(64, 64) else { return };
+        state.look_at((0.0, 0.0, 3.0).into(), (0.0, 0.0, 0.0).into());
+        state.update();
+        let background = state.capture_frame().unwrap();
+
+        state.set_gpu_culling_enabled(true).unwrap();
+        let place = |x: f32, z: f32, scale: f32| Matrix4::from_translation((x, 0.0, z).into()) * Matrix4::from_scale(scale);
+        let gpu = state.gpu_scene().unwrap();
+        let quad = gpu.add_mesh(VERTICES, INDICES);
+        let hidden_quad = gpu.add_mesh(VERTICES, INDICES);
+        let left = gpu.add_instance(quad, place(-0.7, 0.5, 0.5)).unwrap();
+        gpu.set_color(left, [1.0, 0.0, 0.0, 1.0]);
+        gpu.add_instance(quad, place(20.0, 0.0, 1.0)).unwrap();
+        // The large quad covers the small one behind it
+        gpu.add_instance(quad, place(0.7, 0.5, 2.0)).unwrap();
+        gpu.add_instance(hidden_quad, place(0.7, -1.0, 0.3)).unwrap();
+
+        let draw_counts = |state: &mut core::state::State| {
+            state.update();
+            let frame = state.capture_frame().unwrap();
+            let request = state.read_gpu_draw_args();
+            state.device().poll(wgpu::Maintain::Wait);
+            let args = pollster::block_on(request).unwrap();
+            (frame, args.iter().map(|args| args.instance_count).collect::<Vec<_>>())
+        };
+
+        // Only the quad off to the side is culled by the frustum
+        let (frame, counts) = draw_counts(&mut state);
+        assert_eq!(counts, vec![2, 1]);
+        assert_eq!(state.gpu_scene().unwrap().stats().draws, 2);
+        let pixel = frame.get_pixel(8, 32).0;
+        assert_ne!(pixel, background.get_pixel(8, 32).0);
+        assert!(pixel[0] > pixel[1] && pixel[0] > pixel[2], "{:?}", pixel);
+
+        // The first frame with Hi-Z builds the pyramid, the ones after cull against it
+        state.gpu_scene().unwrap().hi_z_enabled = true;
+        assert_eq!(draw_counts(&mut state).1, vec![2, 1]);
+        let (occluded, counts) = draw_counts(&mut state);
+        assert!(state.gpu_scene().unwrap().stats().hi_z);
+        assert_eq!(counts, vec![2, 0]);
+        assert_eq!(occluded, frame);
+
+        // Removed instances aren't drawn and ignore the setters
+        let gpu = state.gpu_scene().unwrap();
+        assert!(gpu.remove_instance(left));
+        assert!(!gpu.remove_instance(left));
+        assert!(!gpu.set_color(left, [0.0; 4]));
+        assert!(!gpu.set_transform(left, place(0.0, 0.0, 1.0)));
+        assert_eq!(draw_counts(&mut state).1, vec![1, 0]);
+    }
+
+    #[test]
+    fn occlusion_queries_skip_objects_behind_an_occluder() {
+        use cgmath::Matrix4;
+
+        let Some(mut state) = headless_state(64, 64) else { return };
+        state.look_at((0.0, 0.0, 3.0).into(), (0.0, 0.0, 0.0).into());
+        state.set_occlusion_culling_enabled(true).unwrap();
+
+        // The default quad and a small one next to it, both covered by a large wall
+        let quad = state.create_mesh(VERTICES, INDICES, "Quad");
+        let scene = state.scene();
+        scene.add_object(quad, Matrix4::from_translation((1.2, 0.1, -1.0).into()) * Matrix4::from_scale(0.3)).unwrap();
+        let wall = scene.add_object(quad, Matrix4::from_translation((0.0, 0.0, 1.0).into()) * Matrix4::from_scale(4.0)).unwrap();
+
+        // Results arrive the frame after the queries ran
+        let frames = |state: &mut core::state::State| {
+            for _ in 0..3 {
+                state.update();
+                state.capture_frame().unwrap();
+                state.device().poll(wgpu::Maintain::Wait);
+            }
+            state.update();
+            state.culling_stats()
+        };
+
+        let stats = frames(&mut state);
+        assert_eq!(stats.occluded, 2);
+        assert_eq!(stats.visible, 1);
+
+        // Moving the wall away clears its own result, the objects behind it come back with
+        // the next queries
+        state.scene().set_transform(wall, Matrix4::from_translation((20.0, 0.0, 1.0).into()));
+        let stats = frames(&mut state);
+        assert_eq!(stats.occluded, 0);
+        assert_eq!(stats.visible, 2);
+    }
+
+    #[test]
+    fn deferred_path_matches_forward_shading() {
+        use renderer::{deferred::RenderPath, lighting::PointLight};
+
+        let Some(mut state) = headless_state(64, 64) else { return };
+        state.look_at((0.0, 0.0, 2.0).into(), (0.0, 0.0, 0.0).into());
+        state.lights().set_ambient([0.05; 3]);
+        state.lights().add(PointLight::new((-0.3, 0.0, 0.3).into(), 0.6).with_color([1.0, 0.4, 0.1]).with_intensity(3.0));
+        state.lights().add(PointLight::new((0.4, 0.3, 0.5).into(), 1.0).with_intensity(2.0));
+        // Out of reach of the quad
+        state.lights().add(PointLight::new((0.0, 0.0, -3.0).into(), 1.0).with_intensity(100.0));
+        state.update();
+        let forward = state.capture_frame().unwrap();
+
+        state.set_render_path(RenderPath::Deferred).unwrap();
+        state.update();
+        let deferred = state.capture_frame().unwrap();
+
+        // Only G-buffer precision tells them apart
+        let difference = max_channel_difference(&forward, &deferred);
+        assert!(difference <= 2, "{}", difference);
+
+        // The orange light only reaches the left half
+        let left = deferred.get_pixel(22, 32).0;
+        let right = deferred.get_pixel(44, 44).0;
+        assert!(left[0] > left[2] + 40, "{:?}", left);
+        assert!(right[0].abs_diff(right[2]) < 40 && right[0] > 20, "{:?}", right);
+        assert_eq!(deferred.get_pixel(0, 0), forward.get_pixel(0, 0));
+    }
+
+    #[test]
+    fn ssao_darkens_creases_in_both_render_paths() {
+        use cgmath::{Deg, Matrix4};
+        use renderer::{deferred::RenderPath, ssao::SsaoSettings};
+
+        let Some(mut state) = headless_state(64, 64) else { return };
+        state.look_at((0.0, 1.0, 1.8).into(), (0.0, -0.3, 0.0).into());
+        // A floor meeting the default quad along its bottom edge
+        let quad = state.create_mesh(VERTICES, INDICES, "Quad");
+        let floor = Matrix4::from_translation((0.0, -0.5, 1.0).into()) * Matrix4::from_angle_x(Deg(-90.0)) * Matrix4::from_scale(2.0);
+        state.scene().add_object(quad, floor).unwrap();
+        state.update();
+        let plain = state.capture_frame().unwrap();
+
+        state.set_ssao_enabled(true).unwrap();
+        state.ssao().unwrap().settings = SsaoSettings::default().with_radius(0.4).with_sample_count(24);
+        state.update();
+        let forward = state.capture_frame().unwrap();
+        state.set_render_path(RenderPath::Deferred).unwrap();
+        state.update();
+        let deferred = state.capture_frame().unwrap();
+
+        // Only G-buffer precision tells the paths apart
+        let difference = max_channel_difference(&forward, &deferred);
+        assert!(difference <= 2, "{}", difference);
+
+        // Linear red of the darkened frame over the plain one
+        let linear = |value: u8| (value as f32 / 255.0).powf(2.2);
+        let ratio = |x: u32, y: u32| linear(forward.get_pixel(x, y).0[0]) / linear(plain.get_pixel(x, y).0[0]).max(1e-3);
+        let crease = (16..48).flat_map(|x| (30..42).map(move |y| (x, y))).map(|(x, y)| ratio(x, y)).fold(f32::MAX, f32::min);
+        assert!(crease < 0.85, "{}", crease);
+        // The top of the wall and the front of the floor are out of reach
+        for (x, y) in [(32, 12), (32, 58), (10, 58)] {
+            assert_eq!(forward.get_pixel(x, y), plain.get_pixel(x, y), "{} {}", x, y);
+        }
+
+        // Turning it off lets the full ambient light through again
+        state.set_render_path(RenderPath::Forward).unwrap();
+        state.set_ssao_enabled(false).unwrap();
+        state.update();
+        assert_eq!(state.capture_frame().unwrap(), plain);
+    }
 }
@@ -0,0 +1,283 @@
+// Picking the adapter to render with
+//
+// Every adapter of the enabled backends is listed with its info, a configured name or backend
+// narrows the choice, and the rest are ranked by a score favouring fast hardware. Devices are
+// requested from the best candidate down, so an adapter that fails to open doesn't stop the
+// engine while another one works. The software adapter is the last resort.
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use anyhow::Result;
+
+use super::offscreen::NoAdapterError;
+
+// Environment variables `AdapterConfig::from_env` reads
+pub const ADAPTER_ENV: &str = "UNNAMED_ADAPTER";
+pub const BACKEND_ENV: &str = "UNNAMED_BACKEND";
+pub const SOFTWARE_ENV: &str = "UNNAMED_SOFTWARE";
+
+#[derive(Debug, Clone)]
+pub struct AdapterConfig {
+    pub backends: wgpu::Backends,
+    // Part of the adapter name, ignoring case, e.g. "nvidia" or "llvmpipe"
+    pub name: Option<String>,
+    pub power_preference: wgpu::PowerPreference,
+    // Only the software adapter is considered
+    pub force_fallback: bool,
+}
+
+impl Default for AdapterConfig {
+    fn default() -> Self {
+        Self {
+            backends: wgpu::Backends::all(),
+            name: None,
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            force_fallback: false,
+        }
+    }
+}
+
+impl AdapterConfig {
+    // The default config with `UNNAMED_ADAPTER`, `UNNAMED_BACKEND` and `UNNAMED_SOFTWARE` applied
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Some(name) = std::env::var(ADAPTER_ENV).ok().filter(|name| !name.is_empty()) {
+            config.name = Some(name);
+        }
+        if let Ok(backend) = std::env::var(BACKEND_ENV) {
+            match parse_backends(&backend) {
+                Some(backends) => config.backends = backends,
+                None => log::warn!("Ignoring unknown backend {:?} in {}", backend, BACKEND_ENV),
+            }
+        }
+        config.force_fallback = std::env::var_os(SOFTWARE_ENV).is_some();
+        config
+    }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    pub fn with_backends(mut self, backends: wgpu::Backends) -> Self {
+        self.backends = backends;
+        self
+    }
+
+    pub fn with_power_preference(mut self, power_preference: wgpu::PowerPreference) -> Self {
+        self.power_preference = power_preference;
+        self
+    }
+
+    pub fn with_force_fallback(mut self, force_fallback: bool) -> Self {
+        self.force_fallback = force_fallback;
+        self
+    }
+}
+
+// Comma separated backend names like "vulkan,gl"
+pub fn parse_backends(names: &str) -> Option<wgpu::Backends> {
+    names.split(',').map(str::trim).filter(|name| !name.is_empty()).try_fold(wgpu::Backends::empty(), |backends, name| {
+        let backend = match name.to_ascii_lowercase().as_str() {
+            "vulkan" | "vk" => wgpu::Backends::VULKAN,
+            "metal" => wgpu::Backends::METAL,
+            "dx12" | "d3d12" => wgpu::Backends::DX12,
+            "dx11" | "d3d11" => wgpu::Backends::DX11,
+            "gl" | "opengl" | "gles" => wgpu::Backends::GL,
+            "webgpu" => wgpu::Backends::BROWSER_WEBGPU,
+            "primary" => wgpu::Backends::PRIMARY,
+            "all" => wgpu::Backends::all(),
+            _ => return None,
+        };
+        Some(backends | backend)
+    }).filter(|backends| !backends.is_empty())
+}
+
+// Higher is better, hardware first in the order the power preference asks for
+pub fn score(info: &wgpu::AdapterInfo, power_preference: wgpu::PowerPreference) -> u32 {
+    let device_type = match (info.device_type, power_preference) {
+        (wgpu::DeviceType::IntegratedGpu, wgpu::PowerPreference::LowPower) => 400,
+        (wgpu::DeviceType::DiscreteGpu, wgpu::PowerPreference::LowPower) => 300,
+        (wgpu::DeviceType::DiscreteGpu, _) => 400,
+        (wgpu::DeviceType::IntegratedGpu, _) => 300,
+        (wgpu::DeviceType::VirtualGpu, _) => 200,
+        (wgpu::DeviceType::Cpu, _) => 100,
+        (wgpu::DeviceType::Other, _) => 50,
+    };
+    // The native APIs over GL, which is often a translation layer
+    let backend = match info.backend {
+        wgpu::Backend::Vulkan | wgpu::Backend::Metal | wgpu::Backend::Dx12 | wgpu::Backend::BrowserWebGpu => 20,
+        wgpu::Backend::Dx11 => 10,
+        wgpu::Backend::Gl | wgpu::Backend::Empty => 0,
+    };
+    device_type + backend
+}
+
+pub fn describe(info: &wgpu::AdapterInfo) -> String {
+    format!("{} ({:?}, {:?}, driver {} {})", info.name, info.backend, info.device_type, info.driver, info.driver_info)
+}
+
+// The candidates in the order devices should be requested from them
+pub fn rank_adapters(
+    instance: &wgpu::Instance,
+    config: &AdapterConfig,
+    compatible_surface: Option<&wgpu::Surface>,
+) -> Vec<wgpu::Adapter> {
+    let mut adapters: Vec<(u32, wgpu::Adapter)> = instance.enumerate_adapters(config.backends)
+        .filter(|adapter| {
+            let info = adapter.get_info();
+            let compatible = compatible_surface.map_or(true, |surface| adapter.is_surface_supported(surface));
+            log::info!("Found adapter {}{}", describe(&info), if compatible { "" } else { ", can't present to the window" });
+            compatible
+        })
+        .filter(|adapter| !config.force_fallback || adapter.get_info().device_type == wgpu::DeviceType::Cpu)
+        .map(|adapter| (score(&adapter.get_info(), config.power_preference), adapter))
+        .collect();
+
+    if let Some(name) = &config.name {
+        let name = name.to_lowercase();
+        let named = adapters.iter().any(|(_, adapter)| adapter.get_info().name.to_lowercase().contains(&name));
+        if named {
+            adapters.retain(|(_, adapter)| adapter.get_info().name.to_lowercase().contains(&name));
+        } else {
+            log::warn!("No adapter matches {:?}, picking one by score", name);
+        }
+    }
+
+    // Stable, so equal scores keep the order the backends listed them in
+    adapters.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+    adapters.into_iter().map(|(_, adapter)| adapter).collect()
+}
+
+// Both optional, the profiler falls back to CPU times and the wireframe view to
+// barycentric coordinates without them
+pub fn optional_features() -> wgpu::Features {
+    wgpu::Features::TIMESTAMP_QUERY
+        | wgpu::Features::POLYGON_MODE_LINE
+        | wgpu::Features::MULTI_DRAW_INDIRECT
+        | wgpu::Features::INDIRECT_FIRST_INSTANCE
+}
+
+pub async fn request_device(adapter: &wgpu::Adapter) -> Result<(wgpu::Device, wgpu::Queue), wgpu::RequestDeviceError> {
+    adapter.request_device(
+        &wgpu::DeviceDescriptor {
+            features: adapter.features() & optional_features(),
+            // WebGL doesn't support all the wgpu's features, so if
+            // we're building for the web we'll have to disable some
+            limits: if cfg!(target_arch = "wasm32") {
+                wgpu::Limits::downlevel_webgl2_defaults()
+            } else if wgpu::Limits::default().check_limits(&adapter.limits()) {
+                wgpu::Limits::default()
+            } else {
+                // Software and GL adapters often can't reach the default limits
+                wgpu::Limits::downlevel_defaults().using_resolution(adapter.limits())
+            },
+            label: None,
+        },
+        None,
+    ).await
+}
+
+// Opens a device on the best adapter that accepts the request, then on the software
+// adapter wgpu falls back to, and fails with `NoAdapterError` listing what was tried
+pub async fn open_device(
+    instance: &wgpu::Instance,
+    config: &AdapterConfig,
+    compatible_surface: Option<&wgpu::Surface>,
+) -> Result<(wgpu::Adapter, wgpu::Device, wgpu::Queue)> {
+    let mut tried = Vec::new();
+    for adapter in rank_adapters(instance, config, compatible_surface) {
+        if let Some(opened) = try_open(adapter, &mut tried).await {
+            return Ok(opened);
+        }
+    }
+
+    // Only asked for once nothing else works, some GL drivers crash with two adapters alive
+    let fallback = instance.request_adapter(&wgpu::RequestAdapterOptions {
+        power_preference: config.power_preference,
+        compatible_surface,
+        force_fallback_adapter: true,
+    }).await;
+    if let Some(adapter) = fallback {
+        if let Some(opened) = try_open(adapter, &mut tried).await {
+            return Ok(opened);
+        }
+    }
+    Err(NoAdapterError { tried }.into())
+}
+
+async fn try_open(adapter: wgpu::Adapter, tried: &mut Vec<String>) -> Option<(wgpu::Adapter, wgpu::Device, wgpu::Queue)> {
+    let info = adapter.get_info();
+    match request_device(&adapter).await {
+        Ok((device, queue)) => {
+            log::info!("Using adapter {}", describe(&info));
+            log_capabilities(&adapter, &device);
+            Some((adapter, device, queue))
+        },
+        Err(e) => {
+            log::warn!("Failed to open a device on {}: {}", describe(&info), e);
+            tried.push(format!("{}: {}", describe(&info), e));
+            None
+        },
+    }
+}
+
+// What the adapter supports next to what the device was opened with
+pub fn log_capabilities(adapter: &wgpu::Adapter, device: &wgpu::Device) {
+    let downlevel = adapter.get_downlevel_capabilities();
+    log::info!("Adapter features: {:?}", adapter.features());
+    log::info!("Device features: {:?}", device.features());
+    log::info!("Shader model {:?}, downlevel flags {:?}", downlevel.shader_model, downlevel.flags);
+    log::debug!("Device limits: {:#?}", device.limits());
+}
+
+// Set when wgpu reports the device as lost, cleared by recreating the device
+// Detection is best-effort, see `is_device_lost`
+#[derive(Debug, Clone, Default)]
+pub struct DeviceLostFlag(Arc<AtomicBool>);
+
+impl DeviceLostFlag {
+    // Replaces the default handler, which panics on every uncaptured error. Other errors still
+    // panic, losses only set the flag so the frame can be skipped and the device recreated
+    // wgpu 0.18 has no device lost callback and treats a lost device in `poll` and `submit`
+    // as fatal, so only losses reported by an earlier call are caught
+    pub fn install(device: &wgpu::Device) -> Self {
+        let flag = Self::default();
+        let handler = flag.clone();
+        device.on_uncaptured_error(Box::new(move |error| handler.handle(error)));
+        flag
+    }
+
+    // What the installed handler does with an uncaptured error
+    pub fn handle(&self, error: wgpu::Error) {
+        if is_device_lost(&error) {
+            if !self.0.swap(true, Ordering::AcqRel) {
+                log::error!("The device was lost: {}", error);
+            }
+        } else {
+            panic!("wgpu error: {}\n", error);
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    pub fn set(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+// Message of wgpu-core 0.18's `DeviceError::Lost`, a test keeps it in sync with the dependency
+pub const DEVICE_LOST_MESSAGE: &str = "Parent device is lost";
+
+// Best-effort: wgpu 0.18 wraps the typed `DeviceError::Lost` transparently, so it can't be
+// downcast from the source chain and is matched by its message instead
+pub fn is_device_lost(error: &wgpu::Error) -> bool {
+    match error {
+        wgpu::Error::OutOfMemory { .. } => false,
+        wgpu::Error::Validation { description, .. } => description.contains(DEVICE_LOST_MESSAGE),
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+
+use anyhow::*;
+
+use super::{stats, texture::Texture};
+
+// Normalized texture coordinates of a region, `min` is the top left corner
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UvRect {
+    pub min: [f32; 2],
+    pub max: [f32; 2],
+}
+
+impl UvRect {
+    pub const FULL: UvRect = UvRect { min: [0.0, 0.0], max: [1.0, 1.0] };
+
+    pub fn new(min: [f32; 2], max: [f32; 2]) -> Self {
+        Self { min, max }
+    }
+}
+
+// A packed entry of an atlas, in pixels and in texture coordinates
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AtlasRegion {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub uv: UvRect,
+}
+
+pub struct TextureAtlas {
+    pub texture: Texture,
+    pub width: u32,
+    pub height: u32,
+    regions: HashMap<String, AtlasRegion>,
+    // CPU copy of the packed image entries, kept to upload the atlas again after a device loss
+    pixels: image::RgbaImage,
+    label: Option<String>,
+    // Entries copied from textures on the GPU, which the CPU copy leaves transparent
+    gpu_only: Vec<String>,
+}
+
+impl TextureAtlas {
+    // Uploads the atlas to another device, entries that came from textures stay empty
+    pub fn recreate(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> TextureAtlas {
+        if !self.gpu_only.is_empty() {
+            log::warn!("Atlas entries {:?} were copied from textures and come back empty", self.gpu_only);
+        }
+
+        TextureAtlas {
+            texture: upload(device, queue, &self.pixels, self.label.as_deref()),
+            width: self.width,
+            height: self.height,
+            regions: self.regions.clone(),
+            pixels: self.pixels.clone(),
+            label: self.label.clone(),
+            gpu_only: self.gpu_only.clone(),
+        }
+    }
+
+    pub fn region(&self, name: &str) -> Option<&AtlasRegion> {
+        self.regions.get(name)
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = (&str, &AtlasRegion)> {
+        self.regions.iter().map(|(name, region)| (name.as_str(), region))
+    }
+}
+
+enum AtlasSource<'a> {
+    Image(image::RgbaImage),
+    Texture(&'a Texture),
+}
+
+impl AtlasSource<'_> {
+    fn size(&self) -> (u32, u32) {
+        match self {
+            AtlasSource::Image(img) => img.dimensions(),
+            AtlasSource::Texture(texture) => (texture.texture.width(), texture.texture.height()),
+        }
+    }
+}
+
+// Collects images and already loaded textures and packs them into a single texture at load time
+pub struct AtlasBuilder<'a> {
+    entries: Vec<(String, AtlasSource<'a>)>,
+    padding: u32,
+}
+
+impl<'a> Default for AtlasBuilder<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> AtlasBuilder<'a> {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            padding: 1,
+        }
+    }
+
+    // Empty pixels left around each entry so filtering doesn't bleed between neighbours
+    pub fn with_padding(mut self, padding: u32) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    pub fn add_image(&mut self, name: &str, img: &image::DynamicImage) -> &mut Self {
+        self.entries.push((name.to_string(), AtlasSource::Image(img.to_rgba8())));
+        self
+    }
+
+    pub fn add_bytes(&mut self, name: &str, bytes: &[u8]) -> Result<&mut Self> {
+        let img = image::load_from_memory(bytes)?;
+        Ok(self.add_image(name, &img))
+    }
+
+    // Textures are copied on the GPU, so they need `COPY_SRC` usage and an RGBA8 format
+    pub fn add_texture(&mut self, name: &str, texture: &'a Texture) -> &mut Self {
+        self.entries.push((name.to_string(), AtlasSource::Texture(texture)));
+        self
+    }
+
+    pub fn build(&self, device: &wgpu::Device, queue: &wgpu::Queue, label: Option<&str>) -> Result<TextureAtlas> {
+        ensure!(!self.entries.is_empty(), "Can't build an empty atlas");
+
+        let sizes: Vec<(u32, u32)> = self.entries.iter().map(|(_, source)| source.size()).collect();
+        let max_size = device.limits().max_texture_dimension_2d;
+        let PackedLayout { width, height, positions } = pack(&sizes, self.padding, max_size)?;
+
+        let format = wgpu::TextureFormat::Rgba8UnormSrgb;
+        let mut pixels = image::RgbaImage::new(width, height);
+        let mut gpu_only = Vec::new();
+        let mut regions = HashMap::new();
+        for ((name, source), &(x, y)) in self.entries.iter().zip(positions.iter()) {
+            let (w, h) = source.size();
+            match source {
+                AtlasSource::Image(img) => image::imageops::replace(&mut pixels, img, x as i64, y as i64),
+                AtlasSource::Texture(source) => {
+                    ensure!(
+                        source.texture.format().remove_srgb_suffix() == format.remove_srgb_suffix(),
+                        "Texture {} has format {:?} and can't be copied into an RGBA8 atlas",
+                        name,
+                        source.texture.format()
+                    );
+                    gpu_only.push(name.clone());
+                },
+            }
+
+            regions.insert(name.clone(), AtlasRegion {
+                x,
+                y,
+                width: w,
+                height: h,
+                uv: UvRect::new(
+                    [x as f32 / width as f32, y as f32 / height as f32],
+                    [(x + w) as f32 / width as f32, (y + h) as f32 / height as f32],
+                ),
+            });
+        }
+
+        // The images go up in one write, the textures are copied over it afterwards
+        let texture = upload(device, queue, &pixels, label);
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Atlas Copy Encoder"),
+        });
+        for ((name, source), &(x, y)) in self.entries.iter().zip(positions.iter()) {
+            if let AtlasSource::Texture(source) = source {
+                encoder.copy_texture_to_texture(
+                    wgpu::ImageCopyTexture {
+                        aspect: wgpu::TextureAspect::All,
+                        texture: &source.texture,
+                        mip_level: 0,
+                        origin: wgpu::Origin3d::ZERO,
+                    },
+                    wgpu::ImageCopyTexture {
+                        aspect: wgpu::TextureAspect::All,
+                        texture: &texture.texture,
+                        mip_level: 0,
+                        origin: wgpu::Origin3d { x, y, z: 0 },
+                    },
+                    wgpu::Extent3d {
+                        width: regions[name].width,
+                        height: regions[name].height,
+                        depth_or_array_layers: 1,
+                    },
+                );
+            }
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+
+        Ok(TextureAtlas {
+            texture,
+            width,
+            height,
+            regions,
+            pixels,
+            label: label.map(str::to_string),
+            gpu_only,
+        })
+    }
+}
+
+fn upload(device: &wgpu::Device, queue: &wgpu::Queue, pixels: &image::RgbaImage, label: Option<&str>) -> Texture {
+    let (width, height) = pixels.dimensions();
+    let size = wgpu::Extent3d {
+        width,
+        height,
+        depth_or_array_layers: 1,
+    };
+    let texture = device.create_texture(
+        &wgpu::TextureDescriptor {
+            label,
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        }
+    );
+
+    stats::write_texture(
+        queue,
+        wgpu::ImageCopyTexture {
+            aspect: wgpu::TextureAspect::All,
+            texture: &texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+        },
+        pixels,
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(4 * width),
+            rows_per_image: Some(height),
+        },
+        size,
+    );
+
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let sampler = device.create_sampler(
+        &wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        }
+    );
+
+    Texture { texture: stats::track_texture(device, texture), view, sampler }
+}
+
+// Result of packing, `positions` holds the top left corner of every entry in input order
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackedLayout {
+    pub width: u32,
+    pub height: u32,
+    pub positions: Vec<(u32, u32)>,
+}
+
+// Shelf packer, tallest entries first, into the smallest power of two square that fits
+pub fn pack(sizes: &[(u32, u32)], padding: u32, max_size: u32) -> Result<PackedLayout> {
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by(|&a, &b| sizes[b].1.cmp(&sizes[a].1).then(sizes[b].0.cmp(&sizes[a].0)));
+
+    let padded = |i: usize| (sizes[i].0 + padding * 2, sizes[i].1 + padding * 2);
+    let area: u64 = (0..sizes.len()).map(|i| padded(i).0 as u64 * padded(i).1 as u64).sum();
+    let widest = (0..sizes.len()).map(|i| padded(i).0).max().unwrap_or(1);
+    let tallest = (0..sizes.len()).map(|i| padded(i).1).max().unwrap_or(1);
+
+    let mut size = widest.max(tallest).max((area as f64).sqrt() as u32).max(1).next_power_of_two();
+    while size <= max_size {
+        if let Some(positions) = pack_shelves(&order, padded, padding, size) {
+            return Ok(PackedLayout { width: size, height: size, positions });
+        }
+        size *= 2;
+    }
+
+    bail!("{} textures don't fit into a {}x{} atlas", sizes.len(), max_size, max_size)
+}
+
+fn pack_shelves(order: &[usize], padded: impl Fn(usize) -> (u32, u32), padding: u32, size: u32) -> Option<Vec<(u32, u32)>> {
+    let mut positions = vec![(0, 0); order.len()];
+    let (mut x, mut y, mut shelf_height) = (0u32, 0u32, 0u32);
+
+    for &i in order {
+        let (w, h) = padded(i);
+        if w > size {
+            return None;
+        }
+        if x + w > size {
+            x = 0;
+            y += shelf_height;
+            shelf_height = 0;
+        }
+        if y + h > size {
+            return None;
+        }
+
+        positions[i] = (x + padding, y + padding);
+        x += w;
+        shelf_height = shelf_height.max(h);
+    }
+
+    Some(positions)
+}
@@ -0,0 +1,183 @@
+use cgmath::{InnerSpace, Matrix, Matrix4, Point3, Vector3, Vector4};
+
+// Axis aligned bounding box
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl Aabb {
+    pub fn new(min: Point3<f32>, max: Point3<f32>) -> Self {
+        Self { min, max }
+    }
+
+    // Returns None for an empty set of points
+    pub fn from_points(points: impl IntoIterator<Item = Point3<f32>>) -> Option<Self> {
+        let mut points = points.into_iter();
+        let first = points.next()?;
+        Some(points.fold(Self::new(first, first), |aabb, point| aabb.including(point)))
+    }
+
+    pub fn including(&self, point: Point3<f32>) -> Self {
+        Self {
+            min: Point3::new(self.min.x.min(point.x), self.min.y.min(point.y), self.min.z.min(point.z)),
+            max: Point3::new(self.max.x.max(point.x), self.max.y.max(point.y), self.max.z.max(point.z)),
+        }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Self {
+        self.including(other.min).including(other.max)
+    }
+
+    pub fn center(&self) -> Point3<f32> {
+        Point3::new(
+            (self.min.x + self.max.x) * 0.5,
+            (self.min.y + self.max.y) * 0.5,
+            (self.min.z + self.max.z) * 0.5,
+        )
+    }
+
+    // Half of the size on each axis
+    pub fn extents(&self) -> Vector3<f32> {
+        (self.max - self.min) * 0.5
+    }
+
+    // Grown by `margin` on every side
+    pub fn inflated(&self, margin: f32) -> Self {
+        let margin = Vector3::new(margin, margin, margin);
+        Self::new(self.min - margin, self.max + margin)
+    }
+
+    pub fn contains(&self, point: Point3<f32>) -> bool {
+        point.x >= self.min.x && point.x <= self.max.x
+            && point.y >= self.min.y && point.y <= self.max.y
+            && point.z >= self.min.z && point.z <= self.max.z
+    }
+
+    // Box around the transformed box, tight for rotations and scales
+    pub fn transform(&self, transform: &Matrix4<f32>) -> Self {
+        let center = transform * self.center().to_homogeneous();
+        let extents = self.extents();
+        let axis = |row: usize| {
+            let row = transform.row(row);
+            row.x.abs() * extents.x + row.y.abs() * extents.y + row.z.abs() * extents.z
+        };
+        let half = Vector3::new(axis(0), axis(1), axis(2));
+        let center = Point3::new(center.x, center.y, center.z);
+        Self::new(center - half, center + half)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingSphere {
+    pub center: Point3<f32>,
+    pub radius: f32,
+}
+
+impl BoundingSphere {
+    pub fn new(center: Point3<f32>, radius: f32) -> Self {
+        Self { center, radius }
+    }
+
+    // Centered on the box of the points, not minimal but cheap and stable
+    pub fn from_points(points: impl IntoIterator<Item = Point3<f32>> + Clone) -> Option<Self> {
+        let center = Aabb::from_points(points.clone())?.center();
+        let radius = points.into_iter()
+            .map(|point| (point - center).magnitude())
+            .fold(0.0, f32::max);
+        Some(Self::new(center, radius))
+    }
+
+    pub fn transform(&self, transform: &Matrix4<f32>) -> Self {
+        let center = transform * self.center.to_homogeneous();
+        // Non uniform scales grow the sphere by the largest axis
+        let scale = (0..3)
+            .map(|i| transform[i].truncate().magnitude())
+            .fold(0.0, f32::max);
+        Self::new(Point3::new(center.x, center.y, center.z), self.radius * scale)
+    }
+}
+
+// Points with `normal.dot(p) + distance >= 0` are in front of the plane
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Plane {
+    pub normal: Vector3<f32>,
+    pub distance: f32,
+}
+
+impl Plane {
+    fn from_vector(v: Vector4<f32>) -> Self {
+        let length = v.truncate().magnitude();
+        Self {
+            normal: v.truncate() / length,
+            distance: v.w / length,
+        }
+    }
+
+    pub fn signed_distance(&self, point: Point3<f32>) -> f32 {
+        self.normal.dot(Vector3::new(point.x, point.y, point.z)) + self.distance
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Containment {
+    Outside,
+    Intersecting,
+    Inside,
+}
+
+// The six planes of a view projection, normals point inwards
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frustum {
+    pub planes: [Plane; 6],
+}
+
+impl Frustum {
+    // Extracts the planes of a matrix projecting to wgpu clip space, where depth goes from 0 to 1
+    pub fn from_matrix(view_proj: &Matrix4<f32>) -> Self {
+        let row = |i: usize| view_proj.row(i);
+        Self {
+            planes: [
+                Plane::from_vector(row(3) + row(0)),
+                Plane::from_vector(row(3) - row(0)),
+                Plane::from_vector(row(3) + row(1)),
+                Plane::from_vector(row(3) - row(1)),
+                Plane::from_vector(row(2)),
+                Plane::from_vector(row(3) - row(2)),
+            ],
+        }
+    }
+
+    pub fn contains_point(&self, point: Point3<f32>) -> bool {
+        self.planes.iter().all(|plane| plane.signed_distance(point) >= 0.0)
+    }
+
+    pub fn intersects_sphere(&self, sphere: &BoundingSphere) -> bool {
+        self.planes.iter().all(|plane| plane.signed_distance(sphere.center) >= -sphere.radius)
+    }
+
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.classify_aabb(aabb) != Containment::Outside
+    }
+
+    // Conservative, boxes near the frustum corners may be reported as intersecting
+    pub fn classify_aabb(&self, aabb: &Aabb) -> Containment {
+        let center = aabb.center();
+        let extents = aabb.extents();
+        let mut result = Containment::Inside;
+        for plane in self.planes.iter() {
+            let distance = plane.signed_distance(center);
+            let radius = plane.normal.x.abs() * extents.x
+                + plane.normal.y.abs() * extents.y
+                + plane.normal.z.abs() * extents.z;
+            if distance < -radius {
+                return Containment::Outside;
+            }
+            if distance < radius {
+                result = Containment::Intersecting;
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,126 @@
+// Bounding volume hierarchy over static objects, built once and queried every frame
+use super::bounds::{Aabb, Containment, Frustum};
+
+const MAX_LEAF_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+enum BvhNodeKind {
+    // Range into `Bvh::items`
+    Leaf { first: usize, count: usize },
+    Internal { left: usize, right: usize },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BvhNode {
+    bounds: Aabb,
+    kind: BvhNodeKind,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    // The user index and bounds of every item, grouped by leaf
+    items: Vec<(usize, Aabb)>,
+}
+
+// What a query touched, `tested` counts items whose own bounds had to be checked
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BvhQueryStats {
+    pub nodes_visited: u32,
+    pub tested: u32,
+}
+
+impl Bvh {
+    // Builds a tree over `(index, bounds)` pairs, the index is handed back by queries
+    pub fn build(items: impl IntoIterator<Item = (usize, Aabb)>) -> Self {
+        let mut bvh = Self {
+            nodes: Vec::new(),
+            items: items.into_iter().collect(),
+        };
+        if !bvh.items.is_empty() {
+            bvh.build_node(0, bvh.items.len());
+        }
+        bvh
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn bounds(&self) -> Option<Aabb> {
+        self.nodes.first().map(|node| node.bounds)
+    }
+
+    // Splits at the median of the centers along the longest axis
+    fn build_node(&mut self, first: usize, count: usize) -> usize {
+        let items = &mut self.items[first..first + count];
+        let bounds = items.iter().skip(1).fold(items[0].1, |bounds, (_, aabb)| bounds.union(aabb));
+
+        let index = self.nodes.len();
+        self.nodes.push(BvhNode { bounds, kind: BvhNodeKind::Leaf { first, count } });
+        if count <= MAX_LEAF_SIZE {
+            return index;
+        }
+
+        let centers = items.iter()
+            .map(|(_, aabb)| aabb.center())
+            .fold(None, |acc: Option<Aabb>, center| Some(acc.map_or(Aabb::new(center, center), |acc| acc.including(center))))
+            .unwrap();
+        let size = centers.max - centers.min;
+        let axis = if size.x >= size.y && size.x >= size.z { 0 } else if size.y >= size.z { 1 } else { 2 };
+
+        let half = count / 2;
+        items.select_nth_unstable_by(half, |(_, a), (_, b)| a.center()[axis].total_cmp(&b.center()[axis]));
+
+        let left = self.build_node(first, half);
+        let right = self.build_node(first + half, count - half);
+        self.nodes[index].kind = BvhNodeKind::Internal { left, right };
+        index
+    }
+
+    // Calls `visit` with the index of every item whose bounds touch the frustum
+    // Subtrees completely inside the frustum are accepted without testing their items
+    pub fn query_frustum(&self, frustum: &Frustum, mut visit: impl FnMut(usize)) -> BvhQueryStats {
+        let mut stats = BvhQueryStats::default();
+        if self.nodes.is_empty() {
+            return stats;
+        }
+
+        let mut stack = vec![(0, false)];
+        while let Some((index, inside)) = stack.pop() {
+            let node = &self.nodes[index];
+            stats.nodes_visited += 1;
+
+            let inside = inside || match frustum.classify_aabb(&node.bounds) {
+                Containment::Outside => continue,
+                Containment::Inside => true,
+                Containment::Intersecting => false,
+            };
+
+            match node.kind {
+                BvhNodeKind::Leaf { first, count } => {
+                    for (item, bounds) in self.items[first..first + count].iter() {
+                        if inside {
+                            visit(*item);
+                            continue;
+                        }
+                        stats.tested += 1;
+                        if frustum.intersects_aabb(bounds) {
+                            visit(*item);
+                        }
+                    }
+                },
+                BvhNodeKind::Internal { left, right } => {
+                    stack.push((right, inside));
+                    stack.push((left, inside));
+                },
+            }
+        }
+
+        stats
+    }
+}
@@ -0,0 +1,195 @@
+use winit::{event::KeyEvent, keyboard::{KeyCode, PhysicalKey::Code}};
+
+use super::bounds::Frustum;
+
+#[rustfmt::skip]
+pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
+    1.0, 0.0, 0.0, 0.0,
+    0.0, 1.0, 0.0, 0.0,
+    0.0, 0.0, 0.5, 0.0,
+    0.0, 0.0, 0.5, 1.0,
+);
+
+pub struct Camera {
+    pub eye: cgmath::Point3<f32>,
+    pub target: cgmath::Point3<f32>,
+    pub up: cgmath::Vector3<f32>,
+    pub aspect: f32,
+    pub fovy: f32,
+    pub znear: f32,
+    pub zfar: f32,
+}
+
+impl Camera {
+    pub fn build_view_projection_matrix(&self) -> cgmath::Matrix4<f32> {
+        let view = cgmath::Matrix4::look_at_rh(self.eye, self.target, self.up);
+        let proj = cgmath::perspective(cgmath::Deg(self.fovy), self.aspect, self.znear, self.zfar);
+        OPENGL_TO_WGPU_MATRIX * proj * view
+    }
+
+    pub fn frustum(&self) -> Frustum {
+        Frustum::from_matrix(&self.build_view_projection_matrix())
+    }
+
+    // World space ray through a pixel of a `width` by `height` target, from the near plane
+    // `x` and `y` start at the top left like window and texture coordinates
+    pub fn screen_ray(&self, x: f32, y: f32, width: f32, height: f32) -> (cgmath::Point3<f32>, cgmath::Vector3<f32>) {
+        use cgmath::{InnerSpace, SquareMatrix};
+
+        let ndc_x = 2.0 * x / width - 1.0;
+        let ndc_y = 1.0 - 2.0 * y / height;
+        let inverse = self.build_view_projection_matrix().invert().unwrap_or(cgmath::Matrix4::identity());
+        let unproject = |z: f32| {
+            let point = inverse * cgmath::Vector4::new(ndc_x, ndc_y, z, 1.0);
+            cgmath::Point3::new(point.x / point.w, point.y / point.w, point.z / point.w)
+        };
+
+        let near = unproject(0.0);
+        (near, (unproject(1.0) - near).normalize())
+    }
+}
+
+pub struct CameraController {
+    speed: f32,
+    is_forward_pressed: bool,
+    is_backward_pressed: bool,
+    is_left_pressed: bool,
+    is_right_pressed: bool,
+}
+
+impl CameraController {
+    pub fn new(speed: f32) -> Self {
+        Self {
+            speed,
+            is_forward_pressed: false,
+            is_backward_pressed: false,
+            is_left_pressed: false,
+            is_right_pressed: false,
+        }
+    }
+
+    pub fn process_events(&mut self, event: &KeyEvent) -> bool {
+        let is_pressed = event.state.is_pressed();
+        if let Code(keycode) = event.physical_key {
+            match keycode {
+                KeyCode::KeyW | KeyCode::ArrowUp => {
+                    self.is_forward_pressed = is_pressed;
+                    true
+                }
+                KeyCode::KeyA | KeyCode::ArrowLeft => {
+                    self.is_left_pressed = is_pressed;
+                    true
+                }
+                KeyCode::KeyS | KeyCode::ArrowDown => {
+                    self.is_backward_pressed = is_pressed;
+                    true
+                }
+                KeyCode::KeyD | KeyCode::ArrowRight => {
+                    self.is_right_pressed = is_pressed;
+                    true
+                }
+                _ => false
+            }
+        } else {
+            false
+        }
+    }
+
+    pub fn update_camera(&self, camera: &mut Camera) {
+        use cgmath::InnerSpace;
+        let forward = camera.target - camera.eye;
+        let forward_norm = forward.normalize();
+        let forward_mag = forward.magnitude();
+
+        if self.is_forward_pressed && forward_mag > self.speed {
+            camera.eye += forward_norm * self.speed;
+        }
+        if self.is_backward_pressed {
+            camera.eye -= forward_norm * self.speed;
+        }
+
+        let right = forward_norm.cross(camera.up);
+
+        let forward = camera.target - camera.eye;
+        let forward_mag = forward.magnitude();
+
+        if self.is_right_pressed {
+            camera.eye = camera.target - (forward + right * self.speed).normalize() * forward_mag;
+        }
+        if self.is_left_pressed {
+            camera.eye = camera.target - (forward - right * self.speed).normalize() * forward_mag;
+        }
+    }
+}
+
+// Camera for 2D rendering, one world unit is one pixel at zoom 1
+// `position` is the world point shown at the center of the viewport
+pub struct OrthographicCamera {
+    pub position: cgmath::Vector2<f32>,
+    pub zoom: f32,
+    pub width: f32,
+    pub height: f32,
+    pub znear: f32,
+    pub zfar: f32,
+}
+
+impl OrthographicCamera {
+    pub fn new(width: f32, height: f32) -> Self {
+        Self {
+            position: cgmath::Vector2::new(0.0, 0.0),
+            zoom: 1.0,
+            width,
+            height,
+            znear: -1000.0,
+            zfar: 1000.0,
+        }
+    }
+
+    pub fn resize(&mut self, width: f32, height: f32) {
+        self.width = width;
+        self.height = height;
+    }
+
+    pub fn build_view_projection_matrix(&self) -> cgmath::Matrix4<f32> {
+        let half_width = self.width * 0.5 / self.zoom;
+        let half_height = self.height * 0.5 / self.zoom;
+        let proj = cgmath::ortho(
+            self.position.x - half_width,
+            self.position.x + half_width,
+            self.position.y - half_height,
+            self.position.y + half_height,
+            self.znear,
+            self.zfar,
+        );
+        OPENGL_TO_WGPU_MATRIX * proj
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct CameraUniform {
+    view_proj: [[f32; 4]; 4],
+}
+
+impl Default for CameraUniform {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CameraUniform {
+    pub fn new() -> Self {
+        use cgmath::SquareMatrix;
+        Self {
+            view_proj: cgmath::Matrix4::identity().into(),
+        }
+    }
+
+    pub fn update_view_proj(&mut self, camera: &Camera) {
+        self.view_proj = camera.build_view_projection_matrix().into();
+    }
+
+    pub fn update_view_proj_ortho(&mut self, camera: &OrthographicCamera) {
+        self.view_proj = camera.build_view_projection_matrix().into();
+    }
+}
@@ -0,0 +1,402 @@
+// KTX2 and DDS containers holding block compressed textures
+//
+// Both are parsed into a `CompressedImage` that keeps the mip chain stored in the file. When
+// the adapter can't sample the format, BC1 to BC5 are decoded to RGBA8 on the CPU instead.
+use anyhow::*;
+use wgpu::{AstcBlock, AstcChannel, TextureFormat};
+
+const KTX2_IDENTIFIER: [u8; 12] = [0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A];
+const DDS_MAGIC: &[u8; 4] = b"DDS ";
+
+const DDS_HEADER_SIZE: usize = 4 + 124;
+const DDS_DX10_HEADER_SIZE: usize = 20;
+const DDSCAPS2_CUBEMAP: u32 = 0x200;
+const DDS_RESOURCE_MISC_TEXTURECUBE: u32 = 0x4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompressedImage {
+    pub format: TextureFormat,
+    pub width: u32,
+    pub height: u32,
+    // Array layers times faces, cubemap faces are in the order +X, -X, +Y, -Y, +Z, -Z
+    pub layers: u32,
+    pub cubemap: bool,
+    // Every layer of a mip level back to back, finest level first
+    pub levels: Vec<Vec<u8>>,
+}
+
+impl CompressedImage {
+    pub fn is_container(bytes: &[u8]) -> bool {
+        bytes.starts_with(&KTX2_IDENTIFIER) || bytes.starts_with(DDS_MAGIC)
+    }
+
+    // `srgb` picks the color space for DDS files without a DX10 header, which don't store one
+    pub fn parse(bytes: &[u8], srgb: bool) -> Result<Self> {
+        let image = if bytes.starts_with(&KTX2_IDENTIFIER) {
+            Self::parse_ktx2(bytes)?
+        } else if bytes.starts_with(DDS_MAGIC) {
+            Self::parse_dds(bytes, srgb)?
+        } else {
+            bail!("Not a KTX2 or DDS file");
+        };
+
+        let (block_width, block_height) = image.format.block_dimensions();
+        ensure!(
+            image.width % block_width == 0 && image.height % block_height == 0,
+            "{}x{} isn't a multiple of the {}x{} blocks of {:?}",
+            image.width, image.height, block_width, block_height, image.format
+        );
+        for (level, data) in image.levels.iter().enumerate() {
+            let expected = image.level_size(level as u32) * image.layers as usize;
+            ensure!(data.len() == expected, "Mip level {} has {} bytes, expected {}", level, data.len(), expected);
+        }
+
+        Ok(image)
+    }
+
+    fn parse_ktx2(bytes: &[u8]) -> Result<Self> {
+        let vk_format = read_u32(bytes, 12)?;
+        let width = read_u32(bytes, 20)?;
+        let height = read_u32(bytes, 24)?.max(1);
+        let depth = read_u32(bytes, 28)?;
+        let layer_count = read_u32(bytes, 32)?.max(1);
+        let face_count = read_u32(bytes, 36)?;
+        // 0 asks the loader to generate mips, which isn't possible for compressed formats
+        let level_count = read_u32(bytes, 40)?.max(1);
+        let supercompression = read_u32(bytes, 44)?;
+
+        ensure!(depth == 0, "3D KTX2 textures aren't supported");
+        ensure!(face_count == 1 || face_count == 6, "KTX2 face count must be 1 or 6, got {}", face_count);
+        ensure!(supercompression == 0, "KTX2 supercompression scheme {} isn't supported", supercompression);
+
+        let format = vk_format_to_wgpu(vk_format)
+            .with_context(|| format!("Unsupported KTX2 format VkFormat({})", vk_format))?;
+
+        // The level index follows the 80 byte header
+        let levels = (0..level_count as usize)
+            .map(|level| {
+                let entry = 80 + level * 24;
+                let offset = read_u64(bytes, entry)? as usize;
+                let length = read_u64(bytes, entry + 8)? as usize;
+                let data = bytes.get(offset..offset + length).context("KTX2 mip level is out of bounds")?;
+                Ok(data.to_vec())
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            format,
+            width,
+            height,
+            layers: layer_count * face_count,
+            cubemap: face_count == 6,
+            levels,
+        })
+    }
+
+    fn parse_dds(bytes: &[u8], srgb: bool) -> Result<Self> {
+        let height = read_u32(bytes, 12)?;
+        let width = read_u32(bytes, 16)?;
+        let level_count = read_u32(bytes, 28)?.max(1);
+        let four_cc = bytes.get(84..88).context("DDS header is truncated")?;
+        let caps2 = read_u32(bytes, 112)?;
+
+        let (format, layers, cubemap, data_offset) = if four_cc == b"DX10" {
+            let dxgi_format = read_u32(bytes, DDS_HEADER_SIZE)?;
+            let misc_flags = read_u32(bytes, DDS_HEADER_SIZE + 8)?;
+            let array_size = read_u32(bytes, DDS_HEADER_SIZE + 12)?.max(1);
+            let format = dxgi_format_to_wgpu(dxgi_format)
+                .with_context(|| format!("Unsupported DDS format DXGI_FORMAT({})", dxgi_format))?;
+            let cubemap = misc_flags & DDS_RESOURCE_MISC_TEXTURECUBE != 0;
+            let layers = if cubemap { array_size * 6 } else { array_size };
+            (format, layers, cubemap, DDS_HEADER_SIZE + DDS_DX10_HEADER_SIZE)
+        } else {
+            let format = match four_cc {
+                b"DXT1" => TextureFormat::Bc1RgbaUnorm,
+                b"DXT2" | b"DXT3" => TextureFormat::Bc2RgbaUnorm,
+                b"DXT4" | b"DXT5" => TextureFormat::Bc3RgbaUnorm,
+                b"ATI1" | b"BC4U" => TextureFormat::Bc4RUnorm,
+                b"BC4S" => TextureFormat::Bc4RSnorm,
+                b"ATI2" | b"BC5U" => TextureFormat::Bc5RgUnorm,
+                b"BC5S" => TextureFormat::Bc5RgSnorm,
+                _ => bail!("Unsupported DDS format {:?}", String::from_utf8_lossy(four_cc)),
+            };
+            let format = if srgb { format.add_srgb_suffix() } else { format };
+            let cubemap = caps2 & DDSCAPS2_CUBEMAP != 0;
+            (format, if cubemap { 6 } else { 1 }, cubemap, DDS_HEADER_SIZE)
+        };
+
+        let mut image = Self {
+            format,
+            width,
+            height,
+            layers,
+            cubemap,
+            levels: vec![Vec::new(); level_count as usize],
+        };
+
+        // DDS stores each layer with its whole mip chain, KTX2 and wgpu want levels first
+        let mut offset = data_offset;
+        for _ in 0..layers {
+            for level in 0..level_count {
+                let size = image.level_size(level);
+                let data = bytes.get(offset..offset + size).context("DDS data is truncated")?;
+                image.levels[level as usize].extend_from_slice(data);
+                offset += size;
+            }
+        }
+
+        Ok(image)
+    }
+
+    pub fn size(&self) -> wgpu::Extent3d {
+        wgpu::Extent3d {
+            width: self.width,
+            height: self.height,
+            depth_or_array_layers: self.layers,
+        }
+    }
+
+    pub fn view_dimension(&self) -> wgpu::TextureViewDimension {
+        match (self.cubemap, self.layers) {
+            (true, 6) => wgpu::TextureViewDimension::Cube,
+            (true, _) => wgpu::TextureViewDimension::CubeArray,
+            (false, 1) => wgpu::TextureViewDimension::D2,
+            (false, _) => wgpu::TextureViewDimension::D2Array,
+        }
+    }
+
+    // Size of one layer of a mip level, rounded up to whole blocks
+    pub fn level_extent(&self, level: u32) -> wgpu::Extent3d {
+        wgpu::Extent3d { depth_or_array_layers: 1, ..self.size() }
+            .mip_level_size(level, wgpu::TextureDimension::D2)
+    }
+
+    // Bytes in one layer of a mip level
+    pub fn level_size(&self, level: u32) -> usize {
+        let physical = self.level_extent(level).physical_size(self.format);
+        let (block_width, block_height) = self.format.block_dimensions();
+        let block_size = self.format.block_size(None).unwrap_or(0);
+        ((physical.width / block_width) * (physical.height / block_height) * block_size) as usize
+    }
+
+    pub fn is_supported(&self, features: wgpu::Features) -> bool {
+        features.contains(self.format.required_features())
+    }
+
+    // Converts every level to RGBA8 in the same color space, for adapters without the feature
+    pub fn decode(&self) -> Result<Self> {
+        let format = decoded_format(self.format)
+            .with_context(|| format!("{:?} isn't supported by the adapter and has no CPU decoder", self.format))?;
+
+        let levels = self.levels.iter().enumerate()
+            .map(|(level, data)| {
+                let extent = self.level_extent(level as u32);
+                data.chunks_exact(self.level_size(level as u32))
+                    .map(|layer| decode_blocks(self.format, extent.width, extent.height, layer))
+                    .collect::<Result<Vec<_>>>()
+                    .map(|layers| layers.concat())
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { format, levels, ..self.clone() })
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32> {
+    let field = bytes.get(offset..offset + 4).context("Texture header is truncated")?;
+    Ok(u32::from_le_bytes(field.try_into().unwrap()))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64> {
+    let field = bytes.get(offset..offset + 8).context("Texture header is truncated")?;
+    Ok(u64::from_le_bytes(field.try_into().unwrap()))
+}
+
+fn vk_format_to_wgpu(vk_format: u32) -> Option<TextureFormat> {
+    use TextureFormat::*;
+    let astc = |block, srgb| Astc {
+        block,
+        channel: if srgb { AstcChannel::UnormSrgb } else { AstcChannel::Unorm },
+    };
+
+    Some(match vk_format {
+        37 => Rgba8Unorm,
+        43 => Rgba8UnormSrgb,
+        131 | 133 => Bc1RgbaUnorm,
+        132 | 134 => Bc1RgbaUnormSrgb,
+        135 => Bc2RgbaUnorm,
+        136 => Bc2RgbaUnormSrgb,
+        137 => Bc3RgbaUnorm,
+        138 => Bc3RgbaUnormSrgb,
+        139 => Bc4RUnorm,
+        140 => Bc4RSnorm,
+        141 => Bc5RgUnorm,
+        142 => Bc5RgSnorm,
+        143 => Bc6hRgbUfloat,
+        144 => Bc6hRgbFloat,
+        145 => Bc7RgbaUnorm,
+        146 => Bc7RgbaUnormSrgb,
+        147 => Etc2Rgb8Unorm,
+        148 => Etc2Rgb8UnormSrgb,
+        149 => Etc2Rgb8A1Unorm,
+        150 => Etc2Rgb8A1UnormSrgb,
+        151 => Etc2Rgba8Unorm,
+        152 => Etc2Rgba8UnormSrgb,
+        153 => EacR11Unorm,
+        154 => EacR11Snorm,
+        155 => EacRg11Unorm,
+        156 => EacRg11Snorm,
+        // The ASTC formats come in UNORM and SRGB pairs
+        157..=184 => {
+            let blocks = [
+                AstcBlock::B4x4, AstcBlock::B5x4, AstcBlock::B5x5, AstcBlock::B6x5,
+                AstcBlock::B6x6, AstcBlock::B8x5, AstcBlock::B8x6, AstcBlock::B8x8,
+                AstcBlock::B10x5, AstcBlock::B10x6, AstcBlock::B10x8, AstcBlock::B10x10,
+                AstcBlock::B12x10, AstcBlock::B12x12,
+            ];
+            let index = vk_format - 157;
+            astc(blocks[index as usize / 2], index % 2 == 1)
+        },
+        _ => return None,
+    })
+}
+
+fn dxgi_format_to_wgpu(dxgi_format: u32) -> Option<TextureFormat> {
+    use TextureFormat::*;
+    Some(match dxgi_format {
+        28 => Rgba8Unorm,
+        29 => Rgba8UnormSrgb,
+        71 => Bc1RgbaUnorm,
+        72 => Bc1RgbaUnormSrgb,
+        74 => Bc2RgbaUnorm,
+        75 => Bc2RgbaUnormSrgb,
+        77 => Bc3RgbaUnorm,
+        78 => Bc3RgbaUnormSrgb,
+        80 => Bc4RUnorm,
+        81 => Bc4RSnorm,
+        83 => Bc5RgUnorm,
+        84 => Bc5RgSnorm,
+        95 => Bc6hRgbUfloat,
+        96 => Bc6hRgbFloat,
+        98 => Bc7RgbaUnorm,
+        99 => Bc7RgbaUnormSrgb,
+        _ => return None,
+    })
+}
+
+// The format `decode_blocks` produces, None when there is no decoder
+pub fn decoded_format(format: TextureFormat) -> Option<TextureFormat> {
+    use TextureFormat::*;
+    match format {
+        Rgba8Unorm | Rgba8UnormSrgb => Some(format),
+        Bc1RgbaUnorm | Bc2RgbaUnorm | Bc3RgbaUnorm | Bc4RUnorm | Bc5RgUnorm => Some(Rgba8Unorm),
+        Bc1RgbaUnormSrgb | Bc2RgbaUnormSrgb | Bc3RgbaUnormSrgb => Some(Rgba8UnormSrgb),
+        Bc4RSnorm | Bc5RgSnorm => Some(Rgba8Snorm),
+        _ => None,
+    }
+}
+
+// Decodes one layer of `width` by `height` texels to tightly packed RGBA8
+// Channels missing from the format are 0, alpha is 1
+pub fn decode_blocks(format: TextureFormat, width: u32, height: u32, data: &[u8]) -> Result<Vec<u8>> {
+    use TextureFormat::*;
+    if matches!(format, Rgba8Unorm | Rgba8UnormSrgb) {
+        return Ok(data.to_vec());
+    }
+
+    let block_size = format.block_size(None).unwrap_or(0) as usize;
+    let blocks_wide = (width as usize).div_ceil(4);
+    let blocks_high = (height as usize).div_ceil(4);
+    ensure!(data.len() >= blocks_wide * blocks_high * block_size, "Not enough block data for {}x{} {:?}", width, height, format);
+
+    let mut rgba = vec![0; width as usize * height as usize * 4];
+    let mut texels = [[0u8; 4]; 16];
+    for (index, block) in data.chunks_exact(block_size).take(blocks_wide * blocks_high).enumerate() {
+        match format {
+            Bc1RgbaUnorm | Bc1RgbaUnormSrgb => decode_color_block(block, true, &mut texels),
+            Bc2RgbaUnorm | Bc2RgbaUnormSrgb => {
+                decode_color_block(&block[8..], false, &mut texels);
+                let alpha = u64::from_le_bytes(block[..8].try_into().unwrap());
+                for (i, texel) in texels.iter_mut().enumerate() {
+                    texel[3] = ((alpha >> (4 * i)) & 0xF) as u8 * 17;
+                }
+            },
+            Bc3RgbaUnorm | Bc3RgbaUnormSrgb => {
+                decode_color_block(&block[8..], false, &mut texels);
+                decode_channel_block(&block[..8], false, 3, &mut texels);
+            },
+            Bc4RUnorm | Bc4RSnorm => {
+                let signed = format == Bc4RSnorm;
+                texels = [[0, 0, 0, if signed { 127 } else { 255 }]; 16];
+                decode_channel_block(block, signed, 0, &mut texels);
+            },
+            Bc5RgUnorm | Bc5RgSnorm => {
+                let signed = format == Bc5RgSnorm;
+                texels = [[0, 0, 0, if signed { 127 } else { 255 }]; 16];
+                decode_channel_block(&block[..8], signed, 0, &mut texels);
+                decode_channel_block(&block[8..], signed, 1, &mut texels);
+            },
+            _ => bail!("No CPU decoder for {:?}", format),
+        }
+
+        // Blocks past the edge of small mip levels are cropped
+        let (block_x, block_y) = (index % blocks_wide * 4, index / blocks_wide * 4);
+        for (i, texel) in texels.iter().enumerate() {
+            let (x, y) = (block_x + i % 4, block_y + i / 4);
+            if x < width as usize && y < height as usize {
+                let offset = (y * width as usize + x) * 4;
+                rgba[offset..offset + 4].copy_from_slice(texel);
+            }
+        }
+    }
+
+    Ok(rgba)
+}
+
+// The RGB565 part shared by BC1 to BC3, only BC1 has the 3 color mode with transparency
+fn decode_color_block(block: &[u8], allow_transparent: bool, texels: &mut [[u8; 4]; 16]) {
+    let c0 = u16::from_le_bytes([block[0], block[1]]);
+    let c1 = u16::from_le_bytes([block[2], block[3]]);
+    let expand = |c: u16| {
+        let (r, g, b) = ((c >> 11) & 0x1F, (c >> 5) & 0x3F, c & 0x1F);
+        [(r << 3 | r >> 2) as u32, (g << 2 | g >> 4) as u32, (b << 3 | b >> 2) as u32]
+    };
+    let (e0, e1) = (expand(c0), expand(c1));
+    let mix = |a: u32, b: u32, den: u32| -> [u8; 4] {
+        let channel = |i: usize| ((e0[i] * a + e1[i] * b) / den) as u8;
+        [channel(0), channel(1), channel(2), 255]
+    };
+
+    let palette = if c0 > c1 || !allow_transparent {
+        [mix(1, 0, 1), mix(0, 1, 1), mix(2, 1, 3), mix(1, 2, 3)]
+    } else {
+        [mix(1, 0, 1), mix(0, 1, 1), mix(1, 1, 2), [0, 0, 0, 0]]
+    };
+
+    let indices = u32::from_le_bytes(block[4..8].try_into().unwrap());
+    for (i, texel) in texels.iter_mut().enumerate() {
+        *texel = palette[((indices >> (2 * i)) & 0x3) as usize];
+    }
+}
+
+// The 8 byte single channel block of BC3 alpha, BC4 and BC5, written into `channel`
+fn decode_channel_block(block: &[u8], signed: bool, channel: usize, texels: &mut [[u8; 4]; 16]) {
+    let endpoint = |byte: u8| if signed { (byte as i8).max(-127) as f32 } else { byte as f32 };
+    let (a0, a1) = (endpoint(block[0]), endpoint(block[1]));
+    let (min, max) = if signed { (-127.0, 127.0) } else { (0.0, 255.0) };
+    let mix = |a: f32, b: f32, den: f32| (a0 * a + a1 * b) / den;
+
+    let palette = if a0 > a1 {
+        [a0, a1, mix(6.0, 1.0, 7.0), mix(5.0, 2.0, 7.0), mix(4.0, 3.0, 7.0), mix(3.0, 4.0, 7.0), mix(2.0, 5.0, 7.0), mix(1.0, 6.0, 7.0)]
+    } else {
+        [a0, a1, mix(4.0, 1.0, 5.0), mix(3.0, 2.0, 5.0), mix(2.0, 3.0, 5.0), mix(1.0, 4.0, 5.0), min, max]
+    };
+
+    let mut bits = [0u8; 8];
+    bits[..6].copy_from_slice(&block[2..8]);
+    let indices = u64::from_le_bytes(bits);
+    for (i, texel) in texels.iter_mut().enumerate() {
+        let value = palette[((indices >> (3 * i)) & 0x7) as usize].round();
+        texel[channel] = if signed { value as i8 as u8 } else { value as u8 };
+    }
+}
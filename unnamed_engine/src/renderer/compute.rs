@@ -0,0 +1,219 @@
+// Compute pipelines over uniform and storage buffers and textures
+//
+//     let pipeline = ComputePipeline::new(device, &shader, "main", &[Binding::Uniform, Binding::Storage], 64)?;
+//     let bind_group = pipeline.bind_group(device, &[params.as_entire_binding(), data.binding()]);
+//     pipeline.dispatch(&mut encoder, &bind_group, data.len() as u32);
+//
+// Every pipeline has a single bind group at index 0 with the bindings in the given order.
+use std::marker::PhantomData;
+
+use anyhow::*;
+use wgpu::util::DeviceExt;
+
+use super::{
+    shader,
+    stats::{self, Tracked},
+};
+
+// WebGL and some downlevel adapters have no compute shaders at all
+pub fn is_supported(device: &wgpu::Device) -> bool {
+    device.limits().max_compute_invocations_per_workgroup > 0
+}
+
+// Workgroups needed to cover `invocations` with groups of `workgroup_size`
+pub fn workgroup_count(invocations: u32, workgroup_size: u32) -> u32 {
+    invocations.div_ceil(workgroup_size.max(1))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binding {
+    Uniform,
+    Storage,
+    StorageReadOnly,
+    // Unfilterable float texture read with textureLoad, depth textures can be bound as one
+    Texture,
+    // Write only texture_storage_2d
+    StorageTexture(wgpu::TextureFormat),
+}
+
+impl Binding {
+    fn layout_entry(&self, binding: u32) -> wgpu::BindGroupLayoutEntry {
+        let buffer = |ty| wgpu::BindingType::Buffer {
+            ty,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        };
+        let texture = |sample_type| wgpu::BindingType::Texture {
+            sample_type,
+            view_dimension: wgpu::TextureViewDimension::D2,
+            multisampled: false,
+        };
+        let ty = match *self {
+            Binding::Uniform => buffer(wgpu::BufferBindingType::Uniform),
+            Binding::Storage => buffer(wgpu::BufferBindingType::Storage { read_only: false }),
+            Binding::StorageReadOnly => buffer(wgpu::BufferBindingType::Storage { read_only: true }),
+            Binding::Texture => texture(wgpu::TextureSampleType::Float { filterable: false }),
+            Binding::StorageTexture(format) => wgpu::BindingType::StorageTexture {
+                access: wgpu::StorageTextureAccess::WriteOnly,
+                format,
+                view_dimension: wgpu::TextureViewDimension::D2,
+            },
+        };
+        wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty,
+            count: None,
+        }
+    }
+}
+
+// A typed GPU array shaders read and write as `array<T>`
+pub struct StorageBuffer<T: bytemuck::Pod> {
+    buffer: Tracked<wgpu::Buffer>,
+    len: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T: bytemuck::Pod> StorageBuffer<T> {
+    // `usage` is added to STORAGE and COPY_DST, e.g. VERTEX to draw straight from the buffer
+    pub fn new(device: &wgpu::Device, data: &[T], usage: wgpu::BufferUsages, label: Option<&str>) -> Self {
+        let buffer = stats::track_buffer(device, device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label,
+            contents: bytemuck::cast_slice(data),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | usage,
+        }));
+        Self { buffer, len: data.len(), _marker: PhantomData }
+    }
+
+    // Zero initialized
+    pub fn zeroed(device: &wgpu::Device, len: usize, usage: wgpu::BufferUsages, label: Option<&str>) -> Self {
+        Self::new(device, &vec![T::zeroed(); len], usage, label)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    pub fn binding(&self) -> wgpu::BindingResource<'_> {
+        self.buffer.as_entire_binding()
+    }
+
+    // Overwrites elements starting at `index`
+    pub fn write(&self, queue: &wgpu::Queue, index: usize, data: &[T]) -> Result<()> {
+        ensure!(index + data.len() <= self.len, "Writing {} elements at {} overflows a buffer of {}", data.len(), index, self.len);
+        stats::write_buffer(queue, &self.buffer, (index * std::mem::size_of::<T>()) as wgpu::BufferAddress, bytemuck::cast_slice(data));
+        Ok(())
+    }
+}
+
+pub struct ComputePipeline {
+    pipeline: wgpu::ComputePipeline,
+    pipeline_layout: wgpu::PipelineLayout,
+    bind_group_layout: wgpu::BindGroupLayout,
+    entry_point: String,
+    // Must match the `@workgroup_size` of the entry point
+    pub workgroup_size: u32,
+}
+
+impl ComputePipeline {
+    pub fn new(
+        device: &wgpu::Device,
+        shader: &wgpu::ShaderModule,
+        entry_point: &str,
+        bindings: &[Binding],
+        workgroup_size: u32,
+    ) -> Result<Self> {
+        ensure!(is_supported(device), "The adapter doesn't support compute shaders");
+
+        let entries: Vec<wgpu::BindGroupLayoutEntry> = bindings.iter().enumerate()
+            .map(|(i, binding)| binding.layout_entry(i as u32))
+            .collect();
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &entries,
+            label: Some("compute_bind_group_layout"),
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Compute Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = shader::validated(device, || Self::create_pipeline(device, &pipeline_layout, shader, entry_point))?;
+
+        Ok(Self {
+            pipeline,
+            pipeline_layout,
+            bind_group_layout,
+            entry_point: entry_point.to_string(),
+            workgroup_size,
+        })
+    }
+
+    pub fn reload_shader(&mut self, device: &wgpu::Device, shader: &wgpu::ShaderModule) -> Result<()> {
+        self.pipeline = shader::validated(device, || Self::create_pipeline(device, &self.pipeline_layout, shader, &self.entry_point))?;
+        Ok(())
+    }
+
+    fn create_pipeline(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        shader: &wgpu::ShaderModule,
+        entry_point: &str,
+    ) -> wgpu::ComputePipeline {
+        device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some(entry_point),
+            layout: Some(layout),
+            module: shader,
+            entry_point,
+        })
+    }
+
+    // Resources in the order of the bindings the pipeline was created with
+    pub fn bind_group(&self, device: &wgpu::Device, resources: &[wgpu::BindingResource]) -> wgpu::BindGroup {
+        let entries: Vec<wgpu::BindGroupEntry> = resources.iter().enumerate()
+            .map(|(i, resource)| wgpu::BindGroupEntry { binding: i as u32, resource: resource.clone() })
+            .collect();
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &entries,
+            label: Some("compute_bind_group"),
+        })
+    }
+
+    // Runs enough workgroups for `invocations` threads along X, shaders check the bound themselves
+    pub fn dispatch(&self, encoder: &mut wgpu::CommandEncoder, bind_group: &wgpu::BindGroup, invocations: u32) {
+        if invocations == 0 {
+            return;
+        }
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some(&self.entry_point),
+            timestamp_writes: None,
+        });
+        self.dispatch_in(&mut pass, bind_group, invocations);
+    }
+
+    // Same as `dispatch` inside a pass shared with other dispatches
+    pub fn dispatch_in<'a>(&'a self, pass: &mut wgpu::ComputePass<'a>, bind_group: &'a wgpu::BindGroup, invocations: u32) {
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, bind_group, &[]);
+        pass.dispatch_workgroups(workgroup_count(invocations, self.workgroup_size), 1, 1);
+    }
+
+    // Covers a `width` by `height` grid, for entry points with `@workgroup_size(n, n)`
+    // where n is the pipeline's `workgroup_size`
+    pub fn dispatch_2d_in<'a>(&'a self, pass: &mut wgpu::ComputePass<'a>, bind_group: &'a wgpu::BindGroup, width: u32, height: u32) {
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, bind_group, &[]);
+        pass.dispatch_workgroups(workgroup_count(width, self.workgroup_size), workgroup_count(height, self.workgroup_size), 1);
+    }
+}
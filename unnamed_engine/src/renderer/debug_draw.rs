@@ -0,0 +1,417 @@
+// Immediate mode debug drawing, callable from anywhere in update code
+//
+//     debug_draw::line(a, b, [1.0, 0.0, 0.0, 1.0]);
+//     debug_draw::sphere(center, 2.0, DebugStyle::new(GREEN).for_seconds(3.0).without_depth_test());
+//
+// Everything submitted is collected into one list for the whole process, so any thread can
+// submit. The renderer draws it after the scene and ages it once per prepared frame, dropping
+// primitives once their duration has passed. With several States, the first one to prepare a
+// frame draws the single frame primitives
+use std::sync::Mutex;
+
+use anyhow::Result;
+use cgmath::{InnerSpace, Matrix4, Point3, Vector3};
+use wgpu::util::DeviceExt;
+
+use super::{
+    shader,
+    stats::{self, Tracked, TrackedRenderPass},
+    text::{FontId, TextRenderer, TextSection, TextSpace},
+    texture::Texture,
+};
+
+pub const RED: [f32; 4] = [1.0, 0.0, 0.0, 1.0];
+pub const GREEN: [f32; 4] = [0.0, 1.0, 0.0, 1.0];
+pub const BLUE: [f32; 4] = [0.0, 0.0, 1.0, 1.0];
+pub const WHITE: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+pub const YELLOW: [f32; 4] = [1.0, 1.0, 0.0, 1.0];
+
+// World height of a line of debug text
+const TEXT_HEIGHT: f32 = 0.15;
+const CIRCLE_SEGMENTS: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DebugStyle {
+    pub color: [f32; 4],
+    // Seconds the primitive stays on screen, 0 draws it for a single frame
+    pub duration: f32,
+    // When disabled the primitive is drawn on top of everything
+    pub depth_test: bool,
+}
+
+impl DebugStyle {
+    pub fn new(color: [f32; 4]) -> Self {
+        Self {
+            color,
+            duration: 0.0,
+            depth_test: true,
+        }
+    }
+
+    pub fn for_seconds(mut self, duration: f32) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    pub fn without_depth_test(mut self) -> Self {
+        self.depth_test = false;
+        self
+    }
+}
+
+impl From<[f32; 4]> for DebugStyle {
+    fn from(color: [f32; 4]) -> Self {
+        Self::new(color)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct DebugLine {
+    a: [f32; 3],
+    b: [f32; 3],
+    style: DebugStyle,
+}
+
+#[derive(Debug, Clone)]
+struct DebugText {
+    position: Point3<f32>,
+    text: String,
+    style: DebugStyle,
+}
+
+struct DebugDrawList {
+    lines: Vec<DebugLine>,
+    texts: Vec<DebugText>,
+}
+
+static DEBUG_DRAW: Mutex<DebugDrawList> = Mutex::new(DebugDrawList {
+    lines: Vec::new(),
+    texts: Vec::new(),
+});
+
+fn with_list(f: impl FnOnce(&mut DebugDrawList)) {
+    f(&mut DEBUG_DRAW.lock().unwrap_or_else(|e| e.into_inner()));
+}
+
+pub fn line(a: Point3<f32>, b: Point3<f32>, style: impl Into<DebugStyle>) {
+    let style = style.into();
+    with_list(|list| list.lines.push(DebugLine { a: a.into(), b: b.into(), style }));
+}
+
+fn lines(segments: impl IntoIterator<Item = (Point3<f32>, Point3<f32>)>, style: DebugStyle) {
+    with_list(|list| {
+        list.lines.extend(segments.into_iter().map(|(a, b)| DebugLine { a: a.into(), b: b.into(), style }));
+    });
+}
+
+// Axis aligned box between two corners
+pub fn aabb(min: Point3<f32>, max: Point3<f32>, style: impl Into<DebugStyle>) {
+    let corner = |i: usize| Point3::new(
+        if i & 1 == 0 { min.x } else { max.x },
+        if i & 2 == 0 { min.y } else { max.y },
+        if i & 4 == 0 { min.z } else { max.z },
+    );
+    // Every pair of corners that differ in exactly one axis is an edge
+    let edges = (0..8usize).flat_map(|i| {
+        [1usize, 2, 4].into_iter()
+            .filter(move |bit| i & bit == 0)
+            .map(move |bit| (corner(i), corner(i | bit)))
+    });
+    lines(edges, style.into());
+}
+
+// Three great circles around the center
+pub fn sphere(center: Point3<f32>, radius: f32, style: impl Into<DebugStyle>) {
+    let circle = |axis_a: Vector3<f32>, axis_b: Vector3<f32>| {
+        (0..CIRCLE_SEGMENTS).map(move |i| {
+            let point = |i: usize| {
+                let angle = i as f32 / CIRCLE_SEGMENTS as f32 * std::f32::consts::TAU;
+                center + (axis_a * angle.cos() + axis_b * angle.sin()) * radius
+            };
+            (point(i), point(i + 1))
+        })
+    };
+    let segments = circle(Vector3::unit_x(), Vector3::unit_y())
+        .chain(circle(Vector3::unit_y(), Vector3::unit_z()))
+        .chain(circle(Vector3::unit_z(), Vector3::unit_x()));
+    lines(segments, style.into());
+}
+
+// Line with a four sided head at `to`
+pub fn arrow(from: Point3<f32>, to: Point3<f32>, style: impl Into<DebugStyle>) {
+    let direction = to - from;
+    let length = direction.magnitude();
+    if length <= f32::EPSILON {
+        return;
+    }
+
+    let forward = direction / length;
+    let helper = if forward.y.abs() < 0.99 { Vector3::unit_y() } else { Vector3::unit_x() };
+    let side = forward.cross(helper).normalize();
+    let up = side.cross(forward);
+
+    let head = length * 0.2;
+    let base = to - forward * head;
+    let segments = [
+        (from, to),
+        (to, base + side * head * 0.5),
+        (to, base - side * head * 0.5),
+        (to, base + up * head * 0.5),
+        (to, base - up * head * 0.5),
+    ];
+    lines(segments, style.into());
+}
+
+// The X, Y and Z axes of a transform in red, green and blue, one unit long before scaling
+pub fn axes(transform: Matrix4<f32>, style: impl Into<DebugStyle>) {
+    let style = style.into();
+    let origin = Point3::from_homogeneous(transform * Point3::new(0.0, 0.0, 0.0).to_homogeneous());
+    for (axis, color) in [(Vector3::unit_x(), RED), (Vector3::unit_y(), GREEN), (Vector3::unit_z(), BLUE)] {
+        let end = Point3::from_homogeneous(transform * Point3::from_homogeneous(axis.extend(1.0)).to_homogeneous());
+        arrow(origin, end, DebugStyle { color, ..style });
+    }
+}
+
+// Square grid on the XZ plane with `cells` cells per side
+pub fn grid(center: Point3<f32>, cell_size: f32, cells: u32, style: impl Into<DebugStyle>) {
+    let half = cell_size * cells as f32 * 0.5;
+    let segments = (0..=cells).flat_map(|i| {
+        let offset = -half + i as f32 * cell_size;
+        [
+            (center + Vector3::new(offset, 0.0, -half), center + Vector3::new(offset, 0.0, half)),
+            (center + Vector3::new(-half, 0.0, offset), center + Vector3::new(half, 0.0, offset)),
+        ]
+    });
+    lines(segments, style.into());
+}
+
+// Label facing the camera, drawn with the debug font once one is set
+pub fn text_3d(position: Point3<f32>, text: impl Into<String>, style: impl Into<DebugStyle>) {
+    let text = DebugText { position, text: text.into(), style: style.into() };
+    with_list(|list| list.texts.push(text));
+}
+
+// Drops everything that was submitted, including primitives with time left
+pub fn clear() {
+    with_list(|list| {
+        list.lines.clear();
+        list.texts.clear();
+    });
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct DebugVertex {
+    position: [f32; 3],
+    color: [f32; 4],
+}
+
+impl DebugVertex {
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<DebugVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32x4,
+                }
+            ]
+        }
+    }
+}
+
+pub struct DebugDrawRenderer {
+    depth_tested_pipeline: wgpu::RenderPipeline,
+    overlay_pipeline: wgpu::RenderPipeline,
+    pipeline_layout: wgpu::PipelineLayout,
+    format: wgpu::TextureFormat,
+    vertex_buffer: Tracked<wgpu::Buffer>,
+    // In vertices
+    capacity: usize,
+    // Vertices of depth tested lines come first, then the overlay ones
+    depth_tested_vertices: u32,
+    overlay_vertices: u32,
+    pub font: Option<FontId>,
+}
+
+impl DebugDrawRenderer {
+    pub const SHADER: &'static str = "debug_draw.wgsl";
+
+    pub fn new(
+        device: &wgpu::Device,
+        shader: &wgpu::ShaderModule,
+        format: wgpu::TextureFormat,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+    ) -> Self {
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Debug Draw Pipeline Layout"),
+            bind_group_layouts: &[camera_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let (depth_tested_pipeline, overlay_pipeline) = Self::create_pipelines(device, &pipeline_layout, shader, format);
+
+        let capacity = 4096;
+
+        Self {
+            depth_tested_pipeline,
+            overlay_pipeline,
+            pipeline_layout,
+            format,
+            vertex_buffer: Self::create_vertex_buffer(device, capacity),
+            capacity,
+            depth_tested_vertices: 0,
+            overlay_vertices: 0,
+            font: None,
+        }
+    }
+
+    // Rebuilds both pipelines from a reloaded shader, the old ones are kept on errors
+    pub fn reload_shader(&mut self, device: &wgpu::Device, shader: &wgpu::ShaderModule) -> Result<()> {
+        let (depth_tested, overlay) = shader::validated(device, || Self::create_pipelines(device, &self.pipeline_layout, shader, self.format))?;
+        self.depth_tested_pipeline = depth_tested;
+        self.overlay_pipeline = overlay;
+        Ok(())
+    }
+
+    fn create_pipelines(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        shader: &wgpu::ShaderModule,
+        format: wgpu::TextureFormat,
+    ) -> (wgpu::RenderPipeline, wgpu::RenderPipeline) {
+        let create_pipeline = |label: &str, depth_compare: wgpu::CompareFunction| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(label),
+                layout: Some(layout),
+                vertex: wgpu::VertexState {
+                    module: shader,
+                    entry_point: "vs_main",
+                    buffers: &[DebugVertex::desc()],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: shader,
+                    entry_point: "fs_main",
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format,
+                        blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::LineList,
+                    strip_index_format: None,
+                    front_face: wgpu::FrontFace::Ccw,
+                    cull_mode: None,
+                    polygon_mode: wgpu::PolygonMode::Fill,
+                    unclipped_depth: false,
+                    conservative: false
+                },
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: Texture::DEPTH_FORMAT,
+                    depth_write_enabled: false,
+                    depth_compare,
+                    stencil: wgpu::StencilState::default(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: wgpu::MultisampleState {
+                    count: 1,
+                    mask: !0,
+                    alpha_to_coverage_enabled: false,
+                },
+                multiview: None,
+            })
+        };
+
+        (
+            create_pipeline("Debug Draw Pipeline", wgpu::CompareFunction::LessEqual),
+            create_pipeline("Debug Draw Overlay Pipeline", wgpu::CompareFunction::Always),
+        )
+    }
+
+    // Collects this frame's primitives, queues the labels on the text renderer
+    // and ages everything by `dt` seconds
+    pub fn prepare(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, text_renderer: &mut TextRenderer, dt: f32) {
+        let mut depth_tested = Vec::new();
+        let mut overlay = Vec::new();
+
+        with_list(|list| {
+            for line in list.lines.iter() {
+                let vertices = if line.style.depth_test { &mut depth_tested } else { &mut overlay };
+                vertices.push(DebugVertex { position: line.a, color: line.style.color });
+                vertices.push(DebugVertex { position: line.b, color: line.style.color });
+            }
+
+            if let Some(font) = self.font {
+                for text in list.texts.iter() {
+                    let section = TextSection::new(
+                        &text.text,
+                        font,
+                        32.0,
+                        TextSpace::World { position: text.position, line_height: TEXT_HEIGHT },
+                    )
+                    .with_color(text.style.color);
+                    if let Err(e) = text_renderer.queue(&section) {
+                        log::warn!("Skipping debug label {:?}: {}", text.text, e);
+                    }
+                }
+            }
+
+            list.lines.retain_mut(|line| {
+                line.style.duration -= dt;
+                line.style.duration > 0.0
+            });
+            list.texts.retain_mut(|text| {
+                text.style.duration -= dt;
+                text.style.duration > 0.0
+            });
+        });
+
+        self.depth_tested_vertices = depth_tested.len() as u32;
+        self.overlay_vertices = overlay.len() as u32;
+        depth_tested.append(&mut overlay);
+        if depth_tested.is_empty() {
+            return;
+        }
+
+        if depth_tested.len() > self.capacity {
+            self.capacity = depth_tested.len().next_power_of_two();
+            self.vertex_buffer = Self::create_vertex_buffer(device, self.capacity);
+        }
+        stats::write_buffer(queue, &self.vertex_buffer, 0, bytemuck::cast_slice(&depth_tested));
+    }
+
+    pub fn render<'a>(&'a self, render_pass: &mut TrackedRenderPass<'a>, camera_bind_group: &'a wgpu::BindGroup) {
+        if self.depth_tested_vertices + self.overlay_vertices == 0 {
+            return;
+        }
+
+        render_pass.set_bind_group(0, camera_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+
+        if self.depth_tested_vertices > 0 {
+            render_pass.set_pipeline(&self.depth_tested_pipeline, wgpu::PrimitiveTopology::LineList);
+            render_pass.draw(0..self.depth_tested_vertices, 0..1);
+        }
+        if self.overlay_vertices > 0 {
+            render_pass.set_pipeline(&self.overlay_pipeline, wgpu::PrimitiveTopology::LineList);
+            let first = self.depth_tested_vertices;
+            render_pass.draw(first..first + self.overlay_vertices, 0..1);
+        }
+    }
+
+    fn create_vertex_buffer(device: &wgpu::Device, vertices: usize) -> Tracked<wgpu::Buffer> {
+        stats::track_buffer(device, device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Debug Draw Vertex Buffer"),
+            contents: bytemuck::cast_slice(&vec![DebugVertex { position: [0.0; 3], color: [0.0; 4] }; vertices]),
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        }))
+    }
+}
@@ -0,0 +1,106 @@
+// Alternative ways of drawing the scene for inspecting meshes, textures and fill rate
+//
+// Every view is a variant of the main shader selected with the DEBUG_VIEW define, the
+// skybox and particles are left out so only the scene geometry shows up on black.
+use std::{fmt, str::FromStr};
+
+use anyhow::*;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum DebugView {
+    // The regular shading
+    #[default]
+    Lit,
+    // Triangle edges, drawn with PolygonMode::Line when the adapter supports it
+    Wireframe,
+    // World space normals mapped to colors
+    Normals,
+    // Texture coordinates as red and green
+    Uvs,
+    // The texture color without any lighting
+    Albedo,
+    // White surfaces under a fixed key light
+    Lighting,
+    // Brighter where more fragments land on the same pixel, from red over yellow to white
+    Overdraw,
+    // The mip level sampled from the diffuse texture, from blue for the full size up to red
+    MipLevel,
+}
+
+impl DebugView {
+    pub const ALL: [DebugView; 8] = [
+        DebugView::Lit,
+        DebugView::Wireframe,
+        DebugView::Normals,
+        DebugView::Uvs,
+        DebugView::Albedo,
+        DebugView::Lighting,
+        DebugView::Overdraw,
+        DebugView::MipLevel,
+    ];
+
+    // The view after this one, wrapping around, for cycling with a hotkey
+    pub fn next(self) -> Self {
+        let index = Self::ALL.iter().position(|view| *view == self).unwrap();
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DebugView::Lit => "lit",
+            DebugView::Wireframe => "wireframe",
+            DebugView::Normals => "normals",
+            DebugView::Uvs => "uvs",
+            DebugView::Albedo => "albedo",
+            DebugView::Lighting => "lighting",
+            DebugView::Overdraw => "overdraw",
+            DebugView::MipLevel => "mip_level",
+        }
+    }
+
+    // Value of the DEBUG_VIEW define, the wireframe without line polygons uses barycentric coordinates
+    pub(crate) fn define(self, line_polygons: bool) -> &'static str {
+        match self {
+            DebugView::Lit => "0u",
+            DebugView::Wireframe if line_polygons => "1u",
+            DebugView::Wireframe => "2u",
+            DebugView::Normals => "3u",
+            DebugView::Uvs => "4u",
+            DebugView::Albedo => "5u",
+            DebugView::Lighting => "6u",
+            DebugView::Overdraw => "7u",
+            DebugView::MipLevel => "8u",
+        }
+    }
+
+    pub fn is_debug(self) -> bool {
+        self != DebugView::Lit
+    }
+}
+
+impl fmt::Display for DebugView {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// Parses the names `name` returns, ignoring case, so consoles can switch views by name
+impl FromStr for DebugView {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        Self::ALL.into_iter()
+            .find(|view| view.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| anyhow!(
+                "Unknown debug view {:?}, expected one of {}",
+                s,
+                Self::ALL.map(|view| view.name()).join(", ")
+            ))
+    }
+}
+
+// Whether wireframes can be drawn as line polygons, the barycentric fallback is used otherwise
+pub fn supports_line_polygons(device: &wgpu::Device) -> bool {
+    device.features().contains(wgpu::Features::POLYGON_MODE_LINE)
+}
@@ -0,0 +1,308 @@
+// Deferred shading, an alternative to shading every light in the main shader
+//
+//     state.set_render_path(RenderPath::Deferred)?;   // or UNNAMED_RENDER_PATH=deferred
+//
+// Opaque and cutout objects write their albedo, normal and material into the G-buffer with the
+// main shader's `fs_gbuffer`, next to the regular depth buffer. A compute pass then splits the
+// screen into tiles, culls the lights against the depth range of each tile's geometry and
+// shades every pixel with only the lights touching its tile. The scene pass starts by drawing
+// that result, the skybox, transparent objects and particles follow forward shaded as usual.
+// Debug views always draw with the forward path.
+use std::{fmt, str::FromStr, sync::Arc};
+
+use anyhow::*;
+
+use super::{
+    compute::{Binding, ComputePipeline},
+    lighting::Lights,
+    shader,
+    stats::{self, Tracked, TrackedRenderPass},
+    texture::Texture,
+};
+
+// Selects the render path at startup, "forward" or "deferred"
+pub const RENDER_PATH_ENV: &str = "UNNAMED_RENDER_PATH";
+
+pub const ALBEDO_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+// World space, unnormalized precision is enough with half floats
+pub const NORMAL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+pub const MATERIAL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
+// Linear light before the target's sRGB encoding
+pub const LIT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+// Pixels per side of the tiles lights are culled for, the lighting workgroup size
+pub const TILE_SIZE: u32 = 16;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum RenderPath {
+    // Every light is shaded in the main shader
+    #[default]
+    Forward,
+    // Lights are accumulated from the G-buffer, cheaper with many small lights
+    Deferred,
+}
+
+impl RenderPath {
+    pub const ALL: [RenderPath; 2] = [RenderPath::Forward, RenderPath::Deferred];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RenderPath::Forward => "forward",
+            RenderPath::Deferred => "deferred",
+        }
+    }
+
+    // The path `UNNAMED_RENDER_PATH` names, forward when it is unset or unknown
+    pub fn from_env() -> Self {
+        let Some(value) = std::env::var(RENDER_PATH_ENV).ok() else { return RenderPath::Forward };
+        value.parse().unwrap_or_else(|e| {
+            log::warn!("Ignoring {}: {:#}", RENDER_PATH_ENV, e);
+            RenderPath::Forward
+        })
+    }
+}
+
+impl fmt::Display for RenderPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for RenderPath {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        Self::ALL.into_iter()
+            .find(|path| path.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| anyhow!(
+                "Unknown render path {:?}, expected one of {}",
+                s,
+                Self::ALL.map(|path| path.name()).join(", ")
+            ))
+    }
+}
+
+struct Target {
+    _texture: Tracked<wgpu::Texture>,
+    view: wgpu::TextureView,
+}
+
+impl Target {
+    fn new(device: &wgpu::Device, format: wgpu::TextureFormat, usage: wgpu::TextureUsages, width: u32, height: u32, label: &str) -> Self {
+        let texture = stats::track_texture(device, device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: usage | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        }));
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        Self { _texture: texture, view }
+    }
+}
+
+// The G-buffer and lit targets, recreated with the depth buffer on resize
+struct Targets {
+    albedo: Target,
+    normal: Target,
+    material: Target,
+    // Only read through the bind groups
+    _lit: Target,
+    lighting_bind_group: wgpu::BindGroup,
+    composite_bind_group: wgpu::BindGroup,
+    width: u32,
+    height: u32,
+}
+
+pub struct DeferredRenderer {
+    // The main shader's `fs_gbuffer`, kept up to date by the state with the other main pipelines
+    pub gbuffer_pipeline: Arc<wgpu::RenderPipeline>,
+    lighting_pipeline: ComputePipeline,
+    composite_pipeline: wgpu::RenderPipeline,
+    composite_pipeline_layout: wgpu::PipelineLayout,
+    composite_bind_group_layout: wgpu::BindGroupLayout,
+    targets: Targets,
+    format: wgpu::TextureFormat,
+}
+
+impl DeferredRenderer {
+    pub const SHADER: &'static str = "deferred.wgsl";
+
+    // `lighting_shader` and `composite_shader` are SHADER without and with DEFERRED_COMPOSITE
+    // The targets match the size of `depth`. Fails on adapters without compute shaders
+    pub fn new(
+        device: &wgpu::Device,
+        gbuffer_pipeline: Arc<wgpu::RenderPipeline>,
+        lighting_shader: &wgpu::ShaderModule,
+        composite_shader: &wgpu::ShaderModule,
+        lights: &Lights,
+        depth: &Texture,
+        format: wgpu::TextureFormat,
+    ) -> Result<Self> {
+        let lighting_pipeline = ComputePipeline::new(device, lighting_shader, "cs_main", &[
+            Binding::Uniform,
+            Binding::Texture,
+            Binding::Texture,
+            Binding::Texture,
+            Binding::Texture,
+            Binding::Texture,
+            Binding::StorageTexture(LIT_FORMAT),
+        ], TILE_SIZE)?;
+
+        let composite_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                },
+                count: None,
+            }],
+            label: Some("deferred_composite_bind_group_layout"),
+        });
+        let composite_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Deferred Composite Pipeline Layout"),
+            bind_group_layouts: &[&composite_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let composite_pipeline = shader::validated(device, || {
+            Self::create_composite_pipeline(device, &composite_pipeline_layout, composite_shader, format)
+        })?;
+
+        let targets = Self::create_targets(device, &lighting_pipeline, &composite_bind_group_layout, lights, depth);
+
+        Ok(Self {
+            gbuffer_pipeline,
+            lighting_pipeline,
+            composite_pipeline,
+            composite_pipeline_layout,
+            composite_bind_group_layout,
+            targets,
+            format,
+        })
+    }
+
+    pub fn reload_shaders(&mut self, device: &wgpu::Device, lighting_shader: &wgpu::ShaderModule, composite_shader: &wgpu::ShaderModule) -> Result<()> {
+        let composite_pipeline = shader::validated(device, || {
+            Self::create_composite_pipeline(device, &self.composite_pipeline_layout, composite_shader, self.format)
+        })?;
+        self.lighting_pipeline.reload_shader(device, lighting_shader)?;
+        self.composite_pipeline = composite_pipeline;
+        Ok(())
+    }
+
+    fn create_composite_pipeline(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        shader: &wgpu::ShaderModule,
+        format: wgpu::TextureFormat,
+    ) -> wgpu::RenderPipeline {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Deferred Composite Pipeline"),
+            layout: Some(layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            // Drawn in the scene pass, which keeps the G-buffer's depth for the forward parts
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::Always,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        })
+    }
+
+    fn create_targets(
+        device: &wgpu::Device,
+        lighting_pipeline: &ComputePipeline,
+        composite_bind_group_layout: &wgpu::BindGroupLayout,
+        lights: &Lights,
+        depth: &Texture,
+    ) -> Targets {
+        let (width, height) = (depth.texture.width(), depth.texture.height());
+        let attachment = wgpu::TextureUsages::RENDER_ATTACHMENT;
+        let albedo = Target::new(device, ALBEDO_FORMAT, attachment, width, height, "G-Buffer Albedo");
+        let normal = Target::new(device, NORMAL_FORMAT, attachment, width, height, "G-Buffer Normal");
+        let material = Target::new(device, MATERIAL_FORMAT, attachment, width, height, "G-Buffer Material");
+        let lit = Target::new(device, LIT_FORMAT, wgpu::TextureUsages::STORAGE_BINDING, width, height, "Deferred Lit Texture");
+
+        let lighting_bind_group = lighting_pipeline.bind_group(device, &[
+            lights.binding(),
+            wgpu::BindingResource::TextureView(lights.occlusion_view()),
+            wgpu::BindingResource::TextureView(&albedo.view),
+            wgpu::BindingResource::TextureView(&normal.view),
+            wgpu::BindingResource::TextureView(&material.view),
+            wgpu::BindingResource::TextureView(&depth.view),
+            wgpu::BindingResource::TextureView(&lit.view),
+        ]);
+        let composite_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: composite_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&lit.view),
+            }],
+            label: Some("deferred_composite_bind_group"),
+        });
+
+        Targets { albedo, normal, material, _lit: lit, lighting_bind_group, composite_bind_group, width, height }
+    }
+
+    // `depth` is the new depth buffer, the G-buffer pass writes it. After `lights` resized too
+    pub fn resize(&mut self, device: &wgpu::Device, lights: &Lights, depth: &Texture) {
+        self.targets = Self::create_targets(device, &self.lighting_pipeline, &self.composite_bind_group_layout, lights, depth);
+    }
+
+    // Color attachments of the G-buffer pass, cleared to nothing
+    pub fn color_attachments(&self) -> [Option<wgpu::RenderPassColorAttachment<'_>>; 3] {
+        [&self.targets.albedo, &self.targets.normal, &self.targets.material].map(|target| Some(wgpu::RenderPassColorAttachment {
+            view: &target.view,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                store: wgpu::StoreOp::Store,
+            },
+        }))
+    }
+
+    // Shades the G-buffer into the lit target, after the G-buffer pass
+    pub fn light(&self, encoder: &mut wgpu::CommandEncoder, timestamp_writes: Option<wgpu::ComputePassTimestampWrites>) {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Deferred Lighting Pass"),
+            timestamp_writes,
+        });
+        self.lighting_pipeline.dispatch_2d_in(&mut pass, &self.targets.lighting_bind_group, self.targets.width, self.targets.height);
+    }
+
+    // Draws the lit pixels over the clear color, first thing in the scene pass
+    pub fn composite<'a>(&'a self, render_pass: &mut TrackedRenderPass<'a>) {
+        render_pass.set_pipeline(&self.composite_pipeline, wgpu::PrimitiveTopology::TriangleList);
+        render_pass.set_bind_group(0, &self.targets.composite_bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
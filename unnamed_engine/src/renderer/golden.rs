@@ -0,0 +1,256 @@
+use std::{
+    cell::Cell,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::core::state::State;
+use crate::renderer::offscreen::NoAdapterError;
+
+// Setting this variable regenerates the reference images instead of comparing against them
+pub const UPDATE_ENV: &str = "UNNAMED_UPDATE_GOLDEN";
+
+// Setting this variable skips renderer tests on machines without any adapter, they fail otherwise
+pub const ALLOW_NO_ADAPTER_ENV: &str = "UNNAMED_ALLOW_NO_ADAPTER";
+
+// Debug draw primitives are shared by the whole process, so renderers in tests take turns to
+// keep them out of each other's frames. Taking the lock again on the same thread doesn't block
+pub struct RenderLock(Option<MutexGuard<'static, ()>>);
+
+static RENDER_LOCK: Mutex<()> = Mutex::new(());
+
+thread_local! {
+    static HOLDS_RENDER_LOCK: Cell<bool> = const { Cell::new(false) };
+}
+
+pub fn render_lock() -> RenderLock {
+    if HOLDS_RENDER_LOCK.get() {
+        return RenderLock(None);
+    }
+    let guard = RENDER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    HOLDS_RENDER_LOCK.set(true);
+    RenderLock(Some(guard))
+}
+
+impl Drop for RenderLock {
+    fn drop(&mut self) {
+        if self.0.is_some() {
+            HOLDS_RENDER_LOCK.set(false);
+        }
+    }
+}
+
+// How far a rendered image may drift from its reference
+#[derive(Debug, Clone, Copy)]
+pub struct Tolerance {
+    // Perceptual distance (0..1) above which a single pixel counts as different
+    pub pixel_threshold: f32,
+    // Fraction of the pixels that may differ before the image is rejected
+    pub max_mismatch_ratio: f32,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Self {
+            pixel_threshold: 0.1,
+            max_mismatch_ratio: 0.001,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Comparison {
+    pub mismatched: usize,
+    pub total: usize,
+    pub max_delta: f32,
+}
+
+impl Comparison {
+    pub fn mismatch_ratio(&self) -> f32 {
+        self.mismatched as f32 / self.total.max(1) as f32
+    }
+
+    pub fn passes(&self, tolerance: &Tolerance) -> bool {
+        self.mismatch_ratio() <= tolerance.max_mismatch_ratio
+    }
+}
+
+// Perceptual color distance between two pixels, 0 for equal and 1 for black against white
+// Pixels are blended over white and compared in YIQ space, which weights
+// brightness changes above hue changes the same way the eye does
+pub fn perceptual_delta(a: image::Rgba<u8>, b: image::Rgba<u8>) -> f32 {
+    // Largest possible weighted YIQ distance for 8 bit channels
+    const MAX_DELTA: f32 = 35215.0;
+
+    fn to_yiq(pixel: image::Rgba<u8>) -> [f32; 3] {
+        let alpha = pixel.0[3] as f32 / 255.0;
+        let blend = |c: u8| 255.0 + (c as f32 - 255.0) * alpha;
+        let (r, g, b) = (blend(pixel.0[0]), blend(pixel.0[1]), blend(pixel.0[2]));
+        [
+            r * 0.298_895_3 + g * 0.586_622_5 + b * 0.114_482_23,
+            r * 0.595_977_99 - g * 0.274_176_1 - b * 0.321_801_9,
+            r * 0.211_470_17 - g * 0.522_617_1 + b * 0.311_146_94,
+        ]
+    }
+
+    if a == b {
+        return 0.0;
+    }
+
+    let a = to_yiq(a);
+    let b = to_yiq(b);
+    let (y, i, q) = (a[0] - b[0], a[1] - b[1], a[2] - b[2]);
+    let delta = 0.5053 * y * y + 0.299 * i * i + 0.1957 * q * q;
+
+    (delta / MAX_DELTA).sqrt().min(1.0)
+}
+
+// Compares two images pixel by pixel and paints the differences in red over a faded
+// copy of the expected image
+pub fn compare(actual: &image::RgbaImage, expected: &image::RgbaImage, tolerance: &Tolerance) -> Result<(Comparison, image::RgbaImage)> {
+    ensure!(
+        actual.dimensions() == expected.dimensions(),
+        "Image size {:?} doesn't match the reference size {:?}",
+        actual.dimensions(),
+        expected.dimensions()
+    );
+
+    let mut diff = image::RgbaImage::new(actual.width(), actual.height());
+    let mut comparison = Comparison {
+        mismatched: 0,
+        total: (actual.width() * actual.height()) as usize,
+        max_delta: 0.0,
+    };
+
+    for ((a, e), d) in actual.pixels().zip(expected.pixels()).zip(diff.pixels_mut()) {
+        let delta = perceptual_delta(*a, *e);
+        comparison.max_delta = comparison.max_delta.max(delta);
+
+        *d = if delta > tolerance.pixel_threshold {
+            comparison.mismatched += 1;
+            image::Rgba([255, 0, 0, 255])
+        } else {
+            let luma = 0.299 * e.0[0] as f32 + 0.587 * e.0[1] as f32 + 0.114 * e.0[2] as f32;
+            let faded = (255.0 - (255.0 - luma) * 0.1) as u8;
+            image::Rgba([faded, faded, faded, 255])
+        };
+    }
+
+    Ok((comparison, diff))
+}
+
+// Renders scenes on the software adapter and checks them against reference images
+// References live in `reference_dir`, mismatch artifacts are written to `output_dir`
+pub struct GoldenHarness {
+    pub reference_dir: PathBuf,
+    pub output_dir: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    pub tolerance: Tolerance,
+}
+
+impl GoldenHarness {
+    pub fn new(reference_dir: impl Into<PathBuf>, output_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            reference_dir: reference_dir.into(),
+            output_dir: output_dir.into(),
+            width: 256,
+            height: 256,
+            tolerance: Tolerance::default(),
+        }
+    }
+
+    // The harness used by the engine's own tests
+    pub fn engine() -> Self {
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        Self::new(manifest_dir.join("tests/golden"), manifest_dir.join("../target/golden"))
+    }
+
+    pub fn with_resolution(mut self, width: u32, height: u32) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    pub fn with_tolerance(mut self, tolerance: Tolerance) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    // Creates a headless state on the software adapter, lets `describe` set up the
+    // scene and captures a single frame
+    pub fn render(&self, describe: impl FnOnce(&mut State)) -> Result<image::RgbaImage> {
+        let _lock = render_lock();
+        // Left over from renders that didn't prepare a frame
+        crate::renderer::debug_draw::clear();
+        let runtime = tokio::runtime::Runtime::new()?;
+        let mut state = runtime.block_on(State::new_headless(self.width, self.height, true))?;
+
+        describe(&mut state);
+        state.update();
+        state.capture_frame()
+    }
+
+    // Compares an image with the reference called `name`, or replaces the reference
+    // when `UNNAMED_UPDATE_GOLDEN` is set
+    pub fn check(&self, name: &str, actual: &image::RgbaImage) -> Result<()> {
+        let reference_path = self.reference_dir.join(format!("{}.png", name));
+
+        if std::env::var_os(UPDATE_ENV).is_some() {
+            std::fs::create_dir_all(&self.reference_dir)?;
+            actual.save_with_format(&reference_path, image::ImageFormat::Png)?;
+            log::info!("Updated golden image {}", reference_path.display());
+            return Ok(());
+        }
+
+        let expected = image::open(&reference_path)
+            .with_context(|| format!(
+                "Missing golden image {}, run the tests with {}=1 to create it",
+                reference_path.display(),
+                UPDATE_ENV
+            ))?
+            .to_rgba8();
+
+        let (comparison, diff) = compare(actual, &expected, &self.tolerance)?;
+        if comparison.passes(&self.tolerance) {
+            return Ok(());
+        }
+
+        std::fs::create_dir_all(&self.output_dir)?;
+        let actual_path = self.output_dir.join(format!("{}.actual.png", name));
+        let diff_path = self.output_dir.join(format!("{}.diff.png", name));
+        actual.save_with_format(&actual_path, image::ImageFormat::Png)?;
+        diff.save_with_format(&diff_path, image::ImageFormat::Png)?;
+
+        bail!(
+            "Golden image {} differs in {} of {} pixels ({:.3}%, max delta {:.3}), see {} and {}",
+            name,
+            comparison.mismatched,
+            comparison.total,
+            comparison.mismatch_ratio() * 100.0,
+            comparison.max_delta,
+            actual_path.display(),
+            diff_path.display()
+        )
+    }
+
+    // Renders the scene and checks it in one go
+    // Machines without any adapter fail the check unless `UNNAMED_ALLOW_NO_ADAPTER` is set
+    pub fn run(&self, name: &str, describe: impl FnOnce(&mut State)) -> Result<()> {
+        let actual = match self.render(describe) {
+            Ok(actual) => actual,
+            Err(e) if e.downcast_ref::<NoAdapterError>().is_some() => {
+                if std::env::var_os(ALLOW_NO_ADAPTER_ENV).is_none() {
+                    return Err(e.context(format!("Golden image {} needs an adapter, set {}=1 to skip it", name, ALLOW_NO_ADAPTER_ENV)));
+                }
+                log::warn!("Skipping golden image {}: {}", name, e);
+                return Ok(());
+            },
+            Err(e) => return Err(e),
+        };
+
+        self.check(name, &actual)
+    }
+}
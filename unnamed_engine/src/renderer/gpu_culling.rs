@@ -0,0 +1,616 @@
+// GPU driven drawing for scenes with more instances than the CPU can cull and submit
+//
+//     state.set_gpu_culling_enabled(true)?;
+//     let gpu = state.gpu_scene().unwrap();
+//     let rock = gpu.add_mesh(&vertices, &indices);
+//     for transform in transforms {
+//         gpu.add_instance(rock, transform).unwrap();
+//     }
+//
+// Meshes share one vertex and one index buffer, instances live in storage buffers grouped by
+// mesh. Every frame a compute pass tests each instance against the frustum and, with Hi-Z on,
+// against the depth pyramid of the last frame, then appends the visible ones to the region of
+// their mesh and counts them in that mesh's draw_indexed_indirect arguments. The CPU records the
+// same draws every frame: one multi_draw_indexed_indirect where the adapter has it, one indirect
+// draw per mesh otherwise.
+//
+// The pyramid lags one frame behind the camera, so objects coming out from behind an occluder
+// can show up one frame late. Everything is drawn opaque and nothing is sorted.
+use anyhow::*;
+use cgmath::{Matrix4, Point3};
+use wgpu::util::DeviceExt;
+
+use super::{
+    bounds::BoundingSphere,
+    camera::Camera,
+    compute::{self, Binding, ComputePipeline, StorageBuffer},
+    mesh::{InstanceRaw, Vertex},
+    picking::EntityId,
+    stats::{self, Tracked, TrackedRenderPass},
+};
+
+const WORKGROUP_SIZE: u32 = 64;
+const HI_Z_WORKGROUP_SIZE: u32 = 8;
+const HI_Z_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R32Float;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GpuMeshId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GpuInstanceId(usize);
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct CullUniform {
+    planes: [[f32; 4]; 6],
+    view_proj: [[f32; 4]; 4],
+    // Size of the pyramid's first level and its level count
+    pyramid: [f32; 4],
+    instance_count: u32,
+    hi_z: u32,
+    _padding: [u32; 2],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct CullInstance {
+    sphere: [f32; 4],
+    draw: u32,
+    // First slot of the draw's region in the visible instances
+    base: u32,
+    _padding: [u32; 2],
+}
+
+// What draw_indexed_indirect reads, `instance_count` is filled in by the culling pass
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct DrawIndexedArgs {
+    pub index_count: u32,
+    pub instance_count: u32,
+    pub first_index: u32,
+    pub base_vertex: i32,
+    pub first_instance: u32,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GpuCullingStats {
+    pub meshes: u32,
+    pub instances: u32,
+    // Indirect draws recorded per frame, one per mesh with instances
+    pub draws: u32,
+    pub multi_draw: bool,
+    pub hi_z: bool,
+}
+
+struct GpuMesh {
+    first_index: u32,
+    index_count: u32,
+    base_vertex: i32,
+    sphere: BoundingSphere,
+}
+
+struct GpuInstance {
+    mesh: GpuMeshId,
+    transform: Matrix4<f32>,
+    entity: Option<EntityId>,
+    color: [f32; 4],
+}
+
+struct Geometry {
+    vertex_buffer: Tracked<wgpu::Buffer>,
+    index_buffer: Tracked<wgpu::Buffer>,
+}
+
+// Rebuilt whenever instances are added or removed
+struct InstanceBuffers {
+    // Instance slots in upload order, grouped by mesh
+    order: Vec<usize>,
+    cull_instances: StorageBuffer<CullInstance>,
+    source: StorageBuffer<InstanceRaw>,
+    visible: StorageBuffer<InstanceRaw>,
+    draws: StorageBuffer<DrawIndexedArgs>,
+    // `draws` with no instances, written before every culling pass
+    args: Vec<DrawIndexedArgs>,
+    // First slot of each draw's region
+    bases: Vec<u32>,
+}
+
+struct DepthPyramid {
+    _texture: Tracked<wgpu::Texture>,
+    view: wgpu::TextureView,
+    // One view per level, for writing them
+    levels: Vec<wgpu::TextureView>,
+    // Level i from level i - 1, the first entry is unused
+    reduce_bind_groups: Vec<Option<wgpu::BindGroup>>,
+    // The source level of each reduction
+    _reduce_buffers: Vec<Tracked<wgpu::Buffer>>,
+    // Level 0 from the depth buffer it was made for
+    copy_bind_group: Option<(wgpu::Id<wgpu::TextureView>, wgpu::BindGroup)>,
+    width: u32,
+    height: u32,
+    // Whether a frame has been reduced into it, until then it occludes nothing
+    built: bool,
+}
+
+pub struct GpuScene {
+    cull: ComputePipeline,
+    hi_z_copy: ComputePipeline,
+    hi_z_reduce: ComputePipeline,
+    uniform_buffer: Tracked<wgpu::Buffer>,
+    // Bound in place of the pyramid before the first one is built
+    placeholder: (Tracked<wgpu::Texture>, wgpu::TextureView),
+    meshes: Vec<GpuMesh>,
+    vertices: Vec<Vertex>,
+    indices: Vec<u32>,
+    instances: Vec<Option<GpuInstance>>,
+    geometry: Option<Geometry>,
+    buffers: Option<InstanceBuffers>,
+    bind_group: Option<wgpu::BindGroup>,
+    pyramid: Option<DepthPyramid>,
+    geometry_dirty: bool,
+    layout_dirty: bool,
+    instances_dirty: bool,
+    multi_draw: bool,
+    // Occlusion culling against the last frame's depth, frustum culling is always on
+    pub hi_z_enabled: bool,
+}
+
+impl GpuScene {
+    pub const SHADER: &'static str = "gpu_cull.wgsl";
+    pub const HI_Z_SHADER: &'static str = "hi_z.wgsl";
+
+    // `copy_shader` is HI_Z_SHADER with HI_Z_COPY defined
+    pub fn new(
+        device: &wgpu::Device,
+        shader: &wgpu::ShaderModule,
+        copy_shader: &wgpu::ShaderModule,
+        reduce_shader: &wgpu::ShaderModule,
+    ) -> Result<Self> {
+        ensure!(compute::is_supported(device), "GPU culling needs compute shaders");
+        let cull = ComputePipeline::new(device, shader, "cs_main", &[
+            Binding::Uniform,
+            Binding::StorageReadOnly,
+            Binding::StorageReadOnly,
+            Binding::Storage,
+            Binding::Storage,
+            Binding::Texture,
+        ], WORKGROUP_SIZE)?;
+        let hi_z_copy = ComputePipeline::new(device, copy_shader, "cs_main", &[
+            Binding::Texture,
+            Binding::StorageTexture(HI_Z_FORMAT),
+        ], HI_Z_WORKGROUP_SIZE)?;
+        let hi_z_reduce = ComputePipeline::new(device, reduce_shader, "cs_main", &[
+            Binding::Texture,
+            Binding::StorageTexture(HI_Z_FORMAT),
+            Binding::Uniform,
+        ], HI_Z_WORKGROUP_SIZE)?;
+
+        let uniform: CullUniform = bytemuck::Zeroable::zeroed();
+        let uniform_buffer = stats::track_buffer(device, device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("GPU Culling Uniform Buffer"),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        }));
+
+        let placeholder = Self::create_pyramid_texture(device, 1, 1, 1);
+        let placeholder_view = placeholder.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let multi_draw = device.features().contains(wgpu::Features::MULTI_DRAW_INDIRECT | wgpu::Features::INDIRECT_FIRST_INSTANCE);
+        if !multi_draw {
+            log::info!("The device has no multi_draw_indexed_indirect, GPU culled meshes are drawn one by one");
+        }
+
+        Ok(Self {
+            cull,
+            hi_z_copy,
+            hi_z_reduce,
+            uniform_buffer,
+            placeholder: (placeholder, placeholder_view),
+            meshes: Vec::new(),
+            vertices: Vec::new(),
+            indices: Vec::new(),
+            instances: Vec::new(),
+            geometry: None,
+            buffers: None,
+            bind_group: None,
+            pyramid: None,
+            geometry_dirty: false,
+            layout_dirty: false,
+            instances_dirty: false,
+            multi_draw,
+            hi_z_enabled: false,
+        })
+    }
+
+    pub fn reload_shader(&mut self, device: &wgpu::Device, shader: &wgpu::ShaderModule) -> Result<()> {
+        self.cull.reload_shader(device, shader)
+    }
+
+    pub fn reload_hi_z_shaders(&mut self, device: &wgpu::Device, copy_shader: &wgpu::ShaderModule, reduce_shader: &wgpu::ShaderModule) -> Result<()> {
+        self.hi_z_copy.reload_shader(device, copy_shader)?;
+        self.hi_z_reduce.reload_shader(device, reduce_shader)
+    }
+
+    // Takes over the meshes and instances of a scene made for another device
+    pub fn restore_from(&mut self, old: &mut GpuScene) {
+        std::mem::swap(&mut self.meshes, &mut old.meshes);
+        std::mem::swap(&mut self.vertices, &mut old.vertices);
+        std::mem::swap(&mut self.indices, &mut old.indices);
+        std::mem::swap(&mut self.instances, &mut old.instances);
+        self.hi_z_enabled = old.hi_z_enabled;
+        self.geometry_dirty = true;
+        self.layout_dirty = true;
+    }
+
+    // Appended to the shared geometry, uploaded with the next prepare
+    pub fn add_mesh(&mut self, vertices: &[Vertex], indices: &[u32]) -> GpuMeshId {
+        let sphere = BoundingSphere::from_points(vertices.iter().map(|v| Point3::from(v.position)))
+            .unwrap_or(BoundingSphere::new(Point3::new(0.0, 0.0, 0.0), 0.0));
+        self.meshes.push(GpuMesh {
+            first_index: self.indices.len() as u32,
+            index_count: indices.len() as u32,
+            base_vertex: self.vertices.len() as i32,
+            sphere,
+        });
+        self.vertices.extend_from_slice(vertices);
+        self.indices.extend_from_slice(indices);
+        self.geometry_dirty = true;
+        GpuMeshId(self.meshes.len() - 1)
+    }
+
+    // None for a mesh of another scene
+    pub fn add_instance(&mut self, mesh: GpuMeshId, transform: Matrix4<f32>) -> Option<GpuInstanceId> {
+        if mesh.0 >= self.meshes.len() {
+            return None;
+        }
+        self.instances.push(Some(GpuInstance { mesh, transform, entity: None, color: [1.0; 4] }));
+        self.layout_dirty = true;
+        Some(GpuInstanceId(self.instances.len() - 1))
+    }
+
+    // Returns false when the instance was already removed, like the setters
+    pub fn remove_instance(&mut self, id: GpuInstanceId) -> bool {
+        let removed = self.instances.get_mut(id.0).and_then(Option::take).is_some();
+        self.layout_dirty |= removed;
+        removed
+    }
+
+    // The setters return false for removed instances
+    pub fn set_transform(&mut self, id: GpuInstanceId, transform: Matrix4<f32>) -> bool {
+        self.instance_mut(id).map(|instance| instance.transform = transform).is_some()
+    }
+
+    // What picking returns for the instance, instances without an entity can't be picked
+    pub fn set_entity(&mut self, id: GpuInstanceId, entity: Option<EntityId>) -> bool {
+        self.instance_mut(id).map(|instance| instance.entity = entity).is_some()
+    }
+
+    pub fn set_color(&mut self, id: GpuInstanceId, color: [f32; 4]) -> bool {
+        self.instance_mut(id).map(|instance| instance.color = color).is_some()
+    }
+
+    // Marks the instances for upload, so only use it to change them
+    fn instance_mut(&mut self, id: GpuInstanceId) -> Option<&mut GpuInstance> {
+        let instance = self.instances.get_mut(id.0).and_then(Option::as_mut)?;
+        self.instances_dirty = true;
+        Some(instance)
+    }
+
+    pub fn stats(&self) -> GpuCullingStats {
+        GpuCullingStats {
+            meshes: self.meshes.len() as u32,
+            instances: self.instances.iter().flatten().count() as u32,
+            draws: self.buffers.as_ref().map_or(0, |buffers| buffers.args.len() as u32),
+            multi_draw: self.multi_draw,
+            hi_z: self.hi_z_active(),
+        }
+    }
+
+    fn hi_z_active(&self) -> bool {
+        self.hi_z_enabled && self.pyramid.as_ref().is_some_and(|pyramid| pyramid.built)
+    }
+
+    fn instance_raw(instance: &GpuInstance) -> InstanceRaw {
+        InstanceRaw::new(instance.transform)
+            .with_entity(instance.entity.map_or(0, |entity| entity.0))
+            .with_color(instance.color)
+    }
+
+    fn cull_instance(&self, instance: &GpuInstance, draw: u32, base: u32) -> CullInstance {
+        let sphere = self.meshes[instance.mesh.0].sphere.transform(&instance.transform);
+        CullInstance {
+            sphere: [sphere.center.x, sphere.center.y, sphere.center.z, sphere.radius],
+            draw,
+            base,
+            _padding: [0; 2],
+        }
+    }
+
+    // Uploads what changed and resets the draw arguments for this frame's culling pass
+    pub fn prepare(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, camera: &Camera) {
+        if std::mem::take(&mut self.geometry_dirty) {
+            self.geometry = (!self.indices.is_empty()).then(|| Geometry {
+                vertex_buffer: stats::track_buffer(device, device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("GPU Scene Vertex Buffer"),
+                    contents: bytemuck::cast_slice(&self.vertices),
+                    usage: wgpu::BufferUsages::VERTEX,
+                })),
+                index_buffer: stats::track_buffer(device, device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("GPU Scene Index Buffer"),
+                    contents: bytemuck::cast_slice(&self.indices),
+                    usage: wgpu::BufferUsages::INDEX,
+                })),
+            });
+        }
+        if std::mem::take(&mut self.layout_dirty) {
+            self.buffers = self.create_instance_buffers(device);
+            self.bind_group = None;
+            self.instances_dirty = false;
+        } else if std::mem::take(&mut self.instances_dirty) {
+            self.write_instances(queue);
+        }
+
+        let Some(buffers) = &self.buffers else { return };
+        let _ = buffers.draws.write(queue, 0, &buffers.args);
+
+        let frustum = camera.frustum();
+        let (pyramid, hi_z) = match self.pyramid.as_ref().filter(|_| self.hi_z_active()) {
+            Some(pyramid) => ([pyramid.width as f32, pyramid.height as f32, pyramid.levels.len() as f32, 0.0], 1),
+            None => ([1.0, 1.0, 1.0, 0.0], 0),
+        };
+        let uniform = CullUniform {
+            planes: frustum.planes.map(|plane| plane.normal.extend(plane.distance).into()),
+            view_proj: camera.build_view_projection_matrix().into(),
+            pyramid,
+            instance_count: buffers.order.len() as u32,
+            hi_z,
+            _padding: [0; 2],
+        };
+        stats::write_buffer(queue, &self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+
+        if self.bind_group.is_none() {
+            let pyramid_view = self.pyramid.as_ref().map_or(&self.placeholder.1, |pyramid| &pyramid.view);
+            self.bind_group = Some(self.cull.bind_group(device, &[
+                self.uniform_buffer.as_entire_binding(),
+                buffers.cull_instances.binding(),
+                buffers.source.binding(),
+                buffers.draws.binding(),
+                buffers.visible.binding(),
+                wgpu::BindingResource::TextureView(pyramid_view),
+            ]));
+        }
+    }
+
+    fn create_instance_buffers(&self, device: &wgpu::Device) -> Option<InstanceBuffers> {
+        let mut order: Vec<usize> = self.instances.iter().enumerate()
+            .filter_map(|(slot, instance)| instance.as_ref().map(|_| slot))
+            .collect();
+        if order.is_empty() {
+            return None;
+        }
+        order.sort_by_key(|&slot| self.instances[slot].as_ref().unwrap().mesh.0);
+
+        let mut args = Vec::new();
+        let mut bases = Vec::new();
+        let mut draw_of_mesh = vec![u32::MAX; self.meshes.len()];
+        for (i, &slot) in order.iter().enumerate() {
+            let mesh_id = self.instances[slot].as_ref().unwrap().mesh.0;
+            if draw_of_mesh[mesh_id] != u32::MAX {
+                continue;
+            }
+            let mesh = &self.meshes[mesh_id];
+            draw_of_mesh[mesh_id] = args.len() as u32;
+            bases.push(i as u32);
+            args.push(DrawIndexedArgs {
+                index_count: mesh.index_count,
+                instance_count: 0,
+                first_index: mesh.first_index,
+                base_vertex: mesh.base_vertex,
+                // Without multi draw the instance buffer is bound at the region instead
+                first_instance: if self.multi_draw { i as u32 } else { 0 },
+            });
+        }
+
+        let (raw, cull) = self.upload_data(&order, &draw_of_mesh, &bases);
+        Some(InstanceBuffers {
+            cull_instances: StorageBuffer::new(device, &cull, wgpu::BufferUsages::empty(), Some("GPU Culling Instances")),
+            source: StorageBuffer::new(device, &raw, wgpu::BufferUsages::empty(), Some("GPU Scene Instances")),
+            visible: StorageBuffer::zeroed(device, raw.len(), wgpu::BufferUsages::VERTEX, Some("GPU Visible Instances")),
+            draws: StorageBuffer::new(device, &args, wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::COPY_SRC, Some("GPU Draw Arguments")),
+            order,
+            args,
+            bases,
+        })
+    }
+
+    fn upload_data(&self, order: &[usize], draw_of_mesh: &[u32], bases: &[u32]) -> (Vec<InstanceRaw>, Vec<CullInstance>) {
+        order.iter()
+            .map(|&slot| {
+                let instance = self.instances[slot].as_ref().unwrap();
+                let draw = draw_of_mesh[instance.mesh.0];
+                (Self::instance_raw(instance), self.cull_instance(instance, draw, bases[draw as usize]))
+            })
+            .unzip()
+    }
+
+    // Same order and regions as before, only transforms and colors changed
+    fn write_instances(&self, queue: &wgpu::Queue) {
+        let Some(buffers) = &self.buffers else { return };
+        let mut draw_of_mesh = vec![u32::MAX; self.meshes.len()];
+        for (draw, &base) in buffers.bases.iter().enumerate() {
+            let mesh = self.instances[buffers.order[base as usize]].as_ref().unwrap().mesh;
+            draw_of_mesh[mesh.0] = draw as u32;
+        }
+        let (raw, cull) = self.upload_data(&buffers.order, &draw_of_mesh, &buffers.bases);
+        let _ = buffers.source.write(queue, 0, &raw);
+        let _ = buffers.cull_instances.write(queue, 0, &cull);
+    }
+
+    // Records the culling pass, it has to run before the pass drawing with `render`
+    pub fn cull(&self, encoder: &mut wgpu::CommandEncoder, timestamp_writes: Option<wgpu::ComputePassTimestampWrites>) {
+        let (Some(buffers), Some(bind_group)) = (&self.buffers, &self.bind_group) else { return };
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("GPU Culling Pass"),
+            timestamp_writes,
+        });
+        self.cull.dispatch_in(&mut pass, bind_group, buffers.order.len() as u32);
+    }
+
+    // Draws the visible instances with the pipeline and bind groups that are set already
+    pub fn render<'a>(&'a self, render_pass: &mut TrackedRenderPass<'a>) {
+        let (Some(geometry), Some(buffers)) = (&self.geometry, &self.buffers) else { return };
+        render_pass.set_vertex_buffer(0, geometry.vertex_buffer.slice(..));
+        render_pass.set_index_buffer(geometry.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        if self.multi_draw {
+            render_pass.set_vertex_buffer(1, buffers.visible.buffer().slice(..));
+            render_pass.multi_draw_indexed_indirect(buffers.draws.buffer(), 0, buffers.args.len() as u32);
+            return;
+        }
+        let stride = std::mem::size_of::<InstanceRaw>() as wgpu::BufferAddress;
+        let args_size = std::mem::size_of::<DrawIndexedArgs>() as wgpu::BufferAddress;
+        for (draw, &base) in buffers.bases.iter().enumerate() {
+            render_pass.set_vertex_buffer(1, buffers.visible.buffer().slice(base as wgpu::BufferAddress * stride..));
+            render_pass.draw_indexed_indirect(buffers.draws.buffer(), draw as wgpu::BufferAddress * args_size);
+        }
+    }
+
+    // Reduces the depth buffer of the frame just drawn into the pyramid the next frame culls with
+    pub fn build_hi_z(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        depth: &wgpu::TextureView,
+        width: u32,
+        height: u32,
+        timestamp_writes: Option<wgpu::ComputePassTimestampWrites>,
+    ) {
+        if !self.hi_z_enabled || self.buffers.is_none() {
+            return;
+        }
+        if self.pyramid.as_ref().map_or(true, |pyramid| (pyramid.width, pyramid.height) != (width, height)) {
+            self.pyramid = Some(self.create_pyramid(device, width, height));
+            self.bind_group = None;
+        }
+        let pyramid = self.pyramid.as_mut().unwrap();
+        if pyramid.copy_bind_group.as_ref().map_or(true, |(id, _)| *id != depth.global_id()) {
+            let bind_group = self.hi_z_copy.bind_group(device, &[
+                wgpu::BindingResource::TextureView(depth),
+                wgpu::BindingResource::TextureView(&pyramid.levels[0]),
+            ]);
+            pyramid.copy_bind_group = Some((depth.global_id(), bind_group));
+        }
+
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Hi-Z Pass"),
+            timestamp_writes,
+        });
+        let (_, copy_bind_group) = pyramid.copy_bind_group.as_ref().unwrap();
+        self.hi_z_copy.dispatch_2d_in(&mut pass, copy_bind_group, width, height);
+        for (level, bind_group) in pyramid.reduce_bind_groups.iter().enumerate().skip(1) {
+            let (width, height) = mip_size(width, height, level as u32);
+            self.hi_z_reduce.dispatch_2d_in(&mut pass, bind_group.as_ref().unwrap(), width, height);
+        }
+        pyramid.built = true;
+    }
+
+    fn create_pyramid_texture(device: &wgpu::Device, width: u32, height: u32, levels: u32) -> Tracked<wgpu::Texture> {
+        stats::track_texture(device, device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Hi-Z Pyramid"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: levels,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: HI_Z_FORMAT,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::STORAGE_BINDING,
+            view_formats: &[],
+        }))
+    }
+
+    fn create_pyramid(&self, device: &wgpu::Device, width: u32, height: u32) -> DepthPyramid {
+        let level_count = width.max(height).ilog2() + 1;
+        let texture = Self::create_pyramid_texture(device, width, height, level_count);
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let levels: Vec<wgpu::TextureView> = (0..level_count)
+            .map(|level| texture.create_view(&wgpu::TextureViewDescriptor {
+                base_mip_level: level,
+                mip_level_count: Some(1),
+                ..Default::default()
+            }))
+            .collect();
+        // Sources are views of every level before the destination, so they start at the first
+        // level on every backend and never overlap what the dispatch writes
+        let sources: Vec<wgpu::TextureView> = (1..level_count)
+            .map(|level| texture.create_view(&wgpu::TextureViewDescriptor {
+                mip_level_count: Some(level),
+                ..Default::default()
+            }))
+            .collect();
+        let reduce_buffers: Vec<Tracked<wgpu::Buffer>> = (1..level_count)
+            .map(|level| stats::track_buffer(device, device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Hi-Z Reduce Buffer"),
+                contents: bytemuck::cast_slice(&[level - 1, 0, 0, 0]),
+                usage: wgpu::BufferUsages::UNIFORM,
+            })))
+            .collect();
+        let reduce_bind_groups = (0..levels.len())
+            .map(|level| (level > 0).then(|| self.hi_z_reduce.bind_group(device, &[
+                wgpu::BindingResource::TextureView(&sources[level - 1]),
+                wgpu::BindingResource::TextureView(&levels[level]),
+                reduce_buffers[level - 1].as_entire_binding(),
+            ])))
+            .collect();
+        DepthPyramid {
+            _texture: texture,
+            view,
+            levels,
+            reduce_bind_groups,
+            _reduce_buffers: reduce_buffers,
+            copy_bind_group: None,
+            width,
+            height,
+            built: false,
+        }
+    }
+
+    // Copies the draw arguments the last culling pass wrote, for tests and debugging
+    pub fn read_draw_args(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> impl std::future::Future<Output = Result<Vec<DrawIndexedArgs>>> + 'static {
+        let readback = self.buffers.as_ref().map(|buffers| {
+            let size = (buffers.args.len() * std::mem::size_of::<DrawIndexedArgs>()) as wgpu::BufferAddress;
+            let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Draw Arguments Readback"),
+                size,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            });
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Draw Arguments Readback Encoder"),
+            });
+            encoder.copy_buffer_to_buffer(buffers.draws.buffer(), 0, &buffer, 0, size);
+            queue.submit(std::iter::once(encoder.finish()));
+
+            let (sender, receiver) = tokio::sync::oneshot::channel();
+            buffer.slice(..).map_async(wgpu::MapMode::Read, move |result| {
+                let _ = sender.send(result);
+            });
+            (buffer, receiver)
+        });
+
+        async move {
+            let Some((buffer, receiver)) = readback else {
+                return Ok(Vec::new());
+            };
+            receiver.await.context("Draw argument readback was dropped")??;
+            let args = bytemuck::cast_slice(&buffer.slice(..).get_mapped_range()).to_vec();
+            buffer.unmap();
+            Ok(args)
+        }
+    }
+}
+
+fn mip_size(width: u32, height: u32, level: u32) -> (u32, u32) {
+    ((width >> level).max(1), (height >> level).max(1))
+}
@@ -0,0 +1,318 @@
+// Point lights and the ambient light the lit scene is shaded with
+//
+//     let lamp = state.lights().add(PointLight::new((0.0, 3.0, 0.0).into(), 8.0).with_color([1.0, 0.8, 0.5]));
+//     state.lights().set_ambient([0.05, 0.05, 0.1]);
+//
+// Lights are Lambert plus a Blinn-Phong highlight with a smooth falloff that reaches zero at
+// their radius. They live in one uniform buffer with the camera data the shading needs: the
+// forward path loops over all of them and the deferred one culls them per screen tile first.
+// A uniform array keeps the forward path working on adapters without storage buffers in
+// fragment shaders. The ambient light starts white and there are no lights, which leaves
+// surfaces at their texture color as before the scene had lights.
+//
+// Ambient light is multiplied with a screen sized occlusion map, which stays white unless
+// SSAO renders into it.
+use cgmath::{InnerSpace, SquareMatrix};
+
+use super::{
+    camera::Camera,
+    stats::{self, Tracked},
+};
+
+// The size of the uniform array, lights past it are left out with a warning
+pub const MAX_LIGHTS: usize = 256;
+
+pub const OCCLUSION_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PointLight {
+    pub position: cgmath::Point3<f32>,
+    // Nothing is lit past it
+    pub radius: f32,
+    pub color: [f32; 3],
+    pub intensity: f32,
+}
+
+impl PointLight {
+    // A white light of intensity 1
+    pub fn new(position: cgmath::Point3<f32>, radius: f32) -> Self {
+        Self { position, radius, color: [1.0; 3], intensity: 1.0 }
+    }
+
+    pub fn with_color(mut self, color: [f32; 3]) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn with_intensity(mut self, intensity: f32) -> Self {
+        self.intensity = intensity;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LightId(usize);
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightRaw {
+    position_radius: [f32; 4],
+    color_intensity: [f32; 4],
+}
+
+// The part written every frame, the lights follow it in the same buffer
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightingHeader {
+    // For reconstructing world positions from depth
+    inverse_view_proj: [[f32; 4]; 4],
+    eye: [f32; 4],
+    // The camera's view direction
+    forward: [f32; 4],
+    ambient: [f32; 4],
+    light_count: u32,
+    _padding: [u32; 3],
+}
+
+const HEADER_SIZE: usize = std::mem::size_of::<LightingHeader>();
+const BUFFER_SIZE: usize = HEADER_SIZE + MAX_LIGHTS * std::mem::size_of::<LightRaw>();
+
+pub struct Lights {
+    lights: Vec<Option<PointLight>>,
+    ambient: [f32; 3],
+    // Lights changed since the last upload
+    dirty: bool,
+    buffer: Tracked<wgpu::Buffer>,
+    // Ambient visibility per pixel, 1 is unoccluded
+    occlusion: Tracked<wgpu::Texture>,
+    occlusion_view: wgpu::TextureView,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+}
+
+impl Lights {
+    // The occlusion map is `width` by `height`, the size of the render target
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, width: u32, height: u32) -> Self {
+        let buffer = stats::track_buffer(device, device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Lighting Buffer"),
+            size: BUFFER_SIZE as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        }));
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(BUFFER_SIZE as u64),
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("lighting_bind_group_layout"),
+        });
+        let (occlusion, occlusion_view) = Self::create_occlusion(device, queue, width, height);
+        let bind_group = Self::create_bind_group(device, &bind_group_layout, &buffer, &occlusion_view);
+
+        Self {
+            lights: Vec::new(),
+            ambient: [1.0; 3],
+            dirty: true,
+            buffer,
+            occlusion,
+            occlusion_view,
+            bind_group_layout,
+            bind_group,
+        }
+    }
+
+    fn create_occlusion(device: &wgpu::Device, queue: &wgpu::Queue, width: u32, height: u32) -> (Tracked<wgpu::Texture>, wgpu::TextureView) {
+        let texture = stats::track_texture(device, device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Ambient Occlusion Texture"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: OCCLUSION_FORMAT,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        }));
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let occlusion = (texture, view);
+        Self::fill_unoccluded(queue, &occlusion.0);
+        occlusion
+    }
+
+    fn fill_unoccluded(queue: &wgpu::Queue, texture: &wgpu::Texture) {
+        let (width, height) = (texture.width(), texture.height());
+        queue.write_texture(
+            texture.as_image_copy(),
+            &vec![u8::MAX; (width * height) as usize],
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(width),
+                rows_per_image: Some(height),
+            },
+            texture.size(),
+        );
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        buffer: &wgpu::Buffer,
+        occlusion_view: &wgpu::TextureView,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(occlusion_view),
+                },
+            ],
+            label: Some("lighting_bind_group"),
+        })
+    }
+
+    // Recreates the occlusion map unoccluded, bind groups holding it have to be recreated too
+    pub fn resize(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, width: u32, height: u32) {
+        (self.occlusion, self.occlusion_view) = Self::create_occlusion(device, queue, width, height);
+        self.bind_group = Self::create_bind_group(device, &self.bind_group_layout, &self.buffer, &self.occlusion_view);
+    }
+
+    // What SSAO renders into
+    pub fn occlusion_view(&self) -> &wgpu::TextureView {
+        &self.occlusion_view
+    }
+
+    // Lets the full ambient light through everywhere again, after SSAO is turned off
+    pub fn clear_occlusion(&self, queue: &wgpu::Queue) {
+        Self::fill_unoccluded(queue, &self.occlusion);
+    }
+
+    // For the pipeline layouts of shaders including common/lighting.wgsl
+    pub fn layout(&self) -> &wgpu::BindGroupLayout {
+        &self.bind_group_layout
+    }
+
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+
+    pub fn binding(&self) -> wgpu::BindingResource<'_> {
+        self.buffer.as_entire_binding()
+    }
+
+    pub fn add(&mut self, light: PointLight) -> LightId {
+        self.dirty = true;
+        match self.lights.iter().position(Option::is_none) {
+            Some(index) => {
+                self.lights[index] = Some(light);
+                LightId(index)
+            },
+            None => {
+                self.lights.push(Some(light));
+                LightId(self.lights.len() - 1)
+            },
+        }
+    }
+
+    pub fn remove(&mut self, id: LightId) {
+        if let Some(slot) = self.lights.get_mut(id.0) {
+            *slot = None;
+            self.dirty = true;
+        }
+    }
+
+    pub fn get(&self, id: LightId) -> Option<&PointLight> {
+        self.lights.get(id.0)?.as_ref()
+    }
+
+    // Replaces a light, ignored for removed ones
+    pub fn set(&mut self, id: LightId, light: PointLight) {
+        if let Some(slot) = self.lights.get_mut(id.0).filter(|slot| slot.is_some()) {
+            *slot = Some(light);
+            self.dirty = true;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lights.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Linear color added on every lit surface regardless of lights
+    pub fn set_ambient(&mut self, ambient: [f32; 3]) {
+        self.ambient = ambient;
+    }
+
+    pub fn ambient(&self) -> [f32; 3] {
+        self.ambient
+    }
+
+    // Takes the lights and ambient light of another set, e.g. one of a lost device
+    pub fn restore_from(&mut self, other: &Lights) {
+        self.lights = other.lights.clone();
+        self.ambient = other.ambient;
+        self.dirty = true;
+    }
+
+    // Uploads the camera data every frame and the lights when they changed
+    pub fn prepare(&mut self, queue: &wgpu::Queue, camera: &Camera) {
+        let count = self.len().min(MAX_LIGHTS);
+        let view_proj = camera.build_view_projection_matrix();
+        let forward = (camera.target - camera.eye).normalize();
+        let header = LightingHeader {
+            inverse_view_proj: view_proj.invert().unwrap_or(cgmath::Matrix4::identity()).into(),
+            eye: camera.eye.to_homogeneous().into(),
+            forward: forward.extend(0.0).into(),
+            ambient: [self.ambient[0], self.ambient[1], self.ambient[2], 1.0],
+            light_count: count as u32,
+            _padding: [0; 3],
+        };
+        stats::write_buffer(queue, &self.buffer, 0, bytemuck::bytes_of(&header));
+
+        if !self.dirty {
+            return;
+        }
+        self.dirty = false;
+        if self.len() > MAX_LIGHTS {
+            log::warn!("{} lights are more than the {} that can be drawn, the rest stay dark", self.len(), MAX_LIGHTS);
+        }
+        let lights: Vec<LightRaw> = self.lights.iter().flatten()
+            .take(MAX_LIGHTS)
+            .map(|light| LightRaw {
+                position_radius: [light.position.x, light.position.y, light.position.z, light.radius],
+                color_intensity: [light.color[0], light.color[1], light.color[2], light.intensity],
+            })
+            .collect();
+        if !lights.is_empty() {
+            stats::write_buffer(queue, &self.buffer, HEADER_SIZE as wgpu::BufferAddress, bytemuck::cast_slice(&lights));
+        }
+    }
+}
@@ -0,0 +1,203 @@
+// Level of detail selection and mesh simplification
+//
+// A chain lists the levels of a mesh from finest to coarsest. Each level is used while the
+// object covers at least `min_screen_size` of the screen height, below the last one the
+// impostor takes over if there is one.
+use std::collections::HashMap;
+
+use bytemuck::Zeroable;
+
+use super::{bounds::Aabb, mesh::Vertex, resources::MeshHandle};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LodLevel {
+    pub mesh: MeshHandle,
+    // Fraction of the screen height the bounding sphere has to cover
+    pub min_screen_size: f32,
+}
+
+impl LodLevel {
+    pub fn new(mesh: MeshHandle, min_screen_size: f32) -> Self {
+        Self { mesh, min_screen_size }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LodChain {
+    pub levels: Vec<LodLevel>,
+    // Camera facing quad drawn instead of the coarsest level, sized to the bounding sphere
+    // The mesh is expected to span -0.5 to 0.5 on X and Y
+    pub impostor: Option<LodLevel>,
+}
+
+impl LodChain {
+    pub fn new(levels: Vec<LodLevel>) -> Self {
+        Self { levels, impostor: None }
+    }
+
+    pub fn with_impostor(mut self, impostor: LodLevel) -> Self {
+        self.impostor = Some(impostor);
+        self
+    }
+
+    // All levels including the impostor, which always comes last
+    pub fn len(&self) -> usize {
+        self.levels.len() + self.impostor.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn level(&self, index: usize) -> LodLevel {
+        self.levels.get(index).copied().or(self.impostor).expect("LOD level out of range")
+    }
+
+    // Meshes of every level, the impostor included
+    pub fn meshes(&self) -> impl Iterator<Item = MeshHandle> + '_ {
+        self.levels.iter().chain(self.impostor.iter()).map(|level| level.mesh)
+    }
+
+    pub fn is_impostor(&self, index: usize) -> bool {
+        index >= self.levels.len()
+    }
+
+    fn thresholds(&self) -> impl Iterator<Item = f32> + '_ {
+        self.levels.iter().chain(self.impostor.iter()).map(|level| level.min_screen_size)
+    }
+
+    // Picks a level for `screen_size`, switching away from `current` only once the size
+    // is `hysteresis` (a fraction) past the threshold, so objects near it don't flicker
+    pub fn select(&self, screen_size: f32, current: Option<usize>, hysteresis: f32) -> usize {
+        let raw = |size: f32| {
+            self.thresholds().position(|threshold| size >= threshold).unwrap_or(self.len() - 1)
+        };
+
+        let Some(current) = current.filter(|&current| current < self.len()) else {
+            return raw(screen_size);
+        };
+
+        let finer = raw(screen_size / (1.0 + hysteresis));
+        if finer < current {
+            return finer;
+        }
+        let coarser = raw(screen_size / (1.0 - hysteresis).max(f32::EPSILON));
+        if coarser > current {
+            return coarser;
+        }
+        current
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LodSettings {
+    // Fraction past a threshold before the level changes
+    pub hysteresis: f32,
+    // Seconds two levels are dithered into each other, 0 switches instantly
+    pub fade_duration: f32,
+    // Multiplies every screen size, below 1 switches to coarser levels earlier
+    pub bias: f32,
+}
+
+impl Default for LodSettings {
+    fn default() -> Self {
+        Self {
+            hysteresis: 0.1,
+            fade_duration: 0.25,
+            bias: 1.0,
+        }
+    }
+}
+
+// Level an object is drawn with, and the one it is fading out from
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct LodState {
+    pub level: Option<usize>,
+    pub previous: Option<usize>,
+    // 0 when a change started, 1 once the new level is fully visible
+    pub fade: f32,
+}
+
+impl LodState {
+    pub fn advance(&mut self, level: usize, dt: f32, settings: &LodSettings) {
+        match self.level {
+            Some(current) if current != level => {
+                self.previous = (settings.fade_duration > 0.0).then_some(current);
+                self.fade = 0.0;
+            },
+            None => self.fade = 1.0,
+            _ => {},
+        }
+        self.level = Some(level);
+
+        if self.previous.is_some() {
+            self.fade += dt / settings.fade_duration;
+            if self.fade >= 1.0 {
+                self.fade = 1.0;
+                self.previous = None;
+            }
+        }
+    }
+}
+
+// Merges vertices that fall into the same cell of a grid laid over the mesh bounds
+// `resolution` is the number of cells along the longest side, triangles that collapse are dropped
+pub fn simplify(vertices: &[Vertex], indices: &[u32], resolution: u32) -> (Vec<Vertex>, Vec<u32>) {
+    let Some(bounds) = Aabb::from_points(vertices.iter().map(|v| v.position.into())) else {
+        return (Vec::new(), Vec::new());
+    };
+    let size = bounds.max - bounds.min;
+    let cell = size.x.max(size.y).max(size.z) / resolution.max(1) as f32;
+    if cell <= 0.0 {
+        return (vertices.to_vec(), indices.to_vec());
+    }
+
+    let key = |v: &Vertex| {
+        let p = v.position;
+        (
+            ((p[0] - bounds.min.x) / cell) as u32,
+            ((p[1] - bounds.min.y) / cell) as u32,
+            ((p[2] - bounds.min.z) / cell) as u32,
+        )
+    };
+
+    // Every cell becomes the average of the vertices inside it
+    let mut clusters: HashMap<(u32, u32, u32), u32> = HashMap::new();
+    let mut sums: Vec<(Vertex, f32)> = Vec::new();
+    let remap: Vec<u32> = vertices.iter().map(|v| {
+        let index = *clusters.entry(key(v)).or_insert_with(|| {
+            sums.push((Vertex::zeroed(), 0.0));
+            sums.len() as u32 - 1
+        });
+        let sum = &mut sums[index as usize];
+        for i in 0..3 {
+            sum.0.position[i] += v.position[i];
+            sum.0.normal[i] += v.normal[i];
+        }
+        for i in 0..2 {
+            sum.0.tex_coords[i] += v.tex_coords[i];
+        }
+        sum.1 += 1.0;
+        index
+    }).collect();
+
+    let simplified_vertices = sums.iter()
+        .map(|(sum, count)| {
+            // Opposing normals cancel out, those vertices keep a zero normal
+            let length = sum.normal.iter().map(|c| c * c).sum::<f32>().sqrt();
+            Vertex {
+                position: sum.position.map(|c| c / count),
+                tex_coords: sum.tex_coords.map(|c| c / count),
+                normal: sum.normal.map(|c| if length > 0.0 { c / length } else { 0.0 }),
+            }
+        })
+        .collect();
+
+    let simplified_indices = indices.chunks_exact(3)
+        .map(|triangle| [remap[triangle[0] as usize], remap[triangle[1] as usize], remap[triangle[2] as usize]])
+        .filter(|[a, b, c]| a != b && b != c && a != c)
+        .flatten()
+        .collect();
+
+    (simplified_vertices, simplified_indices)
+}
@@ -0,0 +1,198 @@
+use cgmath::Point3;
+use wgpu::util::DeviceExt;
+
+use super::{
+    bounds::{Aabb, BoundingSphere},
+    stats::{self, Tracked},
+};
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Vertex {
+    pub position: [f32; 3],
+    pub tex_coords: [f32; 2],
+    pub normal: [f32; 3],
+}
+
+impl Vertex {
+    pub fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                    format: wgpu::VertexFormat::Float32x3,
+                }
+            ]
+        }
+    }
+}
+
+// Per instance data, the model matrix takes one attribute per column
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct InstanceRaw {
+    model: [[f32; 4]; 4],
+    // Dithered visibility for LOD cross-fades, 1 is fully visible
+    // From 0 to 1 the instance fades in, from -1 to 0 it fades out on the complementary pixels
+    fade: f32,
+    // Written to the picking buffer, 0 can't be picked
+    entity: u32,
+    // Multiplies the texture color, alpha included
+    color: [f32; 4],
+    // Fragments with less alpha are discarded, 0 keeps everything
+    alpha_cutoff: f32,
+}
+
+impl InstanceRaw {
+    pub fn new(model: cgmath::Matrix4<f32>) -> Self {
+        Self { model: model.into(), fade: 1.0, entity: 0, color: [1.0; 4], alpha_cutoff: 0.0 }
+    }
+
+    pub fn with_fade(mut self, fade: f32) -> Self {
+        self.fade = fade;
+        self
+    }
+
+    pub fn with_entity(mut self, entity: u32) -> Self {
+        self.entity = entity;
+        self
+    }
+
+    pub fn with_color(mut self, color: [f32; 4]) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn with_alpha_cutoff(mut self, alpha_cutoff: f32) -> Self {
+        self.alpha_cutoff = alpha_cutoff;
+        self
+    }
+
+    pub fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<InstanceRaw>() as wgpu::BufferAddress,
+            // Shaders only move on to the next instance when they start drawing a new one
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &[
+                // A mat4 takes up 4 vertex slots as it is technically 4 vec4s
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 5,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
+                    shader_location: 6,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+                    shader_location: 7,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 12]>() as wgpu::BufferAddress,
+                    shader_location: 8,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
+                    shader_location: 9,
+                    format: wgpu::VertexFormat::Float32,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 17]>() as wgpu::BufferAddress,
+                    shader_location: 10,
+                    format: wgpu::VertexFormat::Uint32,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 18]>() as wgpu::BufferAddress,
+                    shader_location: 11,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 22]>() as wgpu::BufferAddress,
+                    shader_location: 12,
+                    format: wgpu::VertexFormat::Float32,
+                },
+            ],
+        }
+    }
+}
+
+pub struct Mesh {
+    pub vertex_buffer: Tracked<wgpu::Buffer>,
+    pub index_buffer: Tracked<wgpu::Buffer>,
+    pub num_indices: u32,
+    // In model space
+    pub aabb: Aabb,
+    pub sphere: BoundingSphere,
+    // Every triangle with its own three vertices, for the barycentric wireframe on adapters
+    // without PolygonMode::Line, only uploaded once the wireframe is shown
+    unrolled_buffer: Option<Tracked<wgpu::Buffer>>,
+}
+
+impl Mesh {
+    pub fn new(device: &wgpu::Device, vertices: &[Vertex], indices: &[u32], label: &str) -> Self {
+        let vertex_buffer = stats::track_buffer(device, device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{} Vertex Buffer", label)),
+                contents: bytemuck::cast_slice(vertices),
+                usage: wgpu::BufferUsages::VERTEX,
+            }
+        ));
+
+        let index_buffer = stats::track_buffer(device, device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{} Index Buffer", label)),
+                contents: bytemuck::cast_slice(indices),
+                usage: wgpu::BufferUsages::INDEX,
+            }
+        ));
+
+        let points = vertices.iter().map(|v| Point3::from(v.position));
+        let origin = Point3::new(0.0, 0.0, 0.0);
+
+        Self {
+            vertex_buffer,
+            index_buffer,
+            num_indices: indices.len() as u32,
+            aabb: Aabb::from_points(points.clone()).unwrap_or(Aabb::new(origin, origin)),
+            sphere: BoundingSphere::from_points(points).unwrap_or(BoundingSphere::new(origin, 0.0)),
+            unrolled_buffer: None,
+        }
+    }
+
+    // Uploads the unrolled triangles unless they are already, from the data the mesh was created with
+    pub fn prepare_unrolled(&mut self, device: &wgpu::Device, vertices: &[Vertex], indices: &[u32]) {
+        if self.unrolled_buffer.is_some() {
+            return;
+        }
+        let unrolled: Vec<Vertex> = indices.iter().map(|&i| vertices[i as usize]).collect();
+        self.unrolled_buffer = Some(stats::track_buffer(device, device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Unrolled Vertex Buffer"),
+                contents: bytemuck::cast_slice(&unrolled),
+                usage: wgpu::BufferUsages::VERTEX,
+            }
+        )));
+    }
+
+    // Drawn without an index buffer, vertex `i` is corner `i % 3` of its triangle
+    pub fn unrolled_buffer(&self) -> Option<&wgpu::Buffer> {
+        self.unrolled_buffer.as_deref()
+    }
+}
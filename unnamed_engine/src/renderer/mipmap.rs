@@ -0,0 +1,147 @@
+// Fills the mip chain of a texture by repeatedly blitting each level into the next
+// one with linear filtering. Every level is rendered into a scratch texture and copied
+// over, since sampling one level of a texture while rendering into another isn't
+// portable (the GL backend ignores the mip range of views). The texture needs `COPY_SRC` and `COPY_DST`
+// usage and a renderable format.
+use super::shader;
+
+pub fn mip_level_count(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+pub fn generate_mipmaps(device: &wgpu::Device, queue: &wgpu::Queue, texture: &wgpu::Texture) {
+    let mip_count = texture.mip_level_count();
+    if mip_count <= 1 {
+        return;
+    }
+
+    let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("Blit Shader"),
+        source: wgpu::ShaderSource::Wgsl(shader::embedded("blit.wgsl").unwrap().into()),
+    });
+
+    let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Mipmap Pipeline"),
+        // The layout is derived from the shader
+        layout: None,
+        vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vs_main",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point: "fs_main",
+            targets: &[Some(texture.format().into())],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            ..Default::default()
+        },
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    });
+
+    let bind_group_layout = pipeline.get_bind_group_layout(0);
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+        label: Some("Mipmap Sampler"),
+        address_mode_u: wgpu::AddressMode::ClampToEdge,
+        address_mode_v: wgpu::AddressMode::ClampToEdge,
+        address_mode_w: wgpu::AddressMode::ClampToEdge,
+        mag_filter: wgpu::FilterMode::Linear,
+        min_filter: wgpu::FilterMode::Linear,
+        mipmap_filter: wgpu::FilterMode::Nearest,
+        ..Default::default()
+    });
+
+    let size = texture.size();
+    let scratch: Vec<(wgpu::Texture, wgpu::TextureView)> = (0..mip_count)
+        .map(|mip| {
+            let scratch = device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("Mipmap Scratch Texture"),
+                size: wgpu::Extent3d {
+                    width: (size.width >> mip).max(1),
+                    height: (size.height >> mip).max(1),
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: texture.format(),
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                    | wgpu::TextureUsages::TEXTURE_BINDING
+                    | wgpu::TextureUsages::COPY_SRC
+                    | wgpu::TextureUsages::COPY_DST,
+                view_formats: &[],
+            });
+            let view = scratch.create_view(&wgpu::TextureViewDescriptor::default());
+            (scratch, view)
+        })
+        .collect();
+
+    let bind_groups: Vec<wgpu::BindGroup> = scratch[..scratch.len() - 1].iter()
+        .map(|(_, view)| device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Mipmap Bind Group"),
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+            ],
+        }))
+        .collect();
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Mipmap Encoder"),
+    });
+
+    let level = |mip: u32, layer: u32| wgpu::ImageCopyTexture {
+        texture,
+        mip_level: mip,
+        origin: wgpu::Origin3d { x: 0, y: 0, z: layer },
+        aspect: wgpu::TextureAspect::All,
+    };
+    let scratch_level = |mip: u32| wgpu::ImageCopyTexture {
+        texture: &scratch[mip as usize].0,
+        mip_level: 0,
+        origin: wgpu::Origin3d::ZERO,
+        aspect: wgpu::TextureAspect::All,
+    };
+
+    // Array layers and cubemap faces each get their own chain
+    for layer in 0..texture.depth_or_array_layers() {
+        encoder.copy_texture_to_texture(level(0, layer), scratch_level(0), scratch[0].0.size());
+
+        for mip in 1..mip_count {
+            {
+                let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: Some("Mipmap Pass"),
+                    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                        view: &scratch[mip as usize].1,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                            store: wgpu::StoreOp::Store,
+                        },
+                    })],
+                    depth_stencil_attachment: None,
+                    timestamp_writes: None,
+                    occlusion_query_set: None,
+                });
+                render_pass.set_pipeline(&pipeline);
+                render_pass.set_bind_group(0, &bind_groups[mip as usize - 1], &[]);
+                render_pass.draw(0..3, 0..1);
+            }
+
+            encoder.copy_texture_to_texture(scratch_level(mip), level(mip, layer), scratch[mip as usize].0.size());
+        }
+    }
+
+    queue.submit(std::iter::once(encoder.finish()));
+}
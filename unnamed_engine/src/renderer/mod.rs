@@ -1 +1,35 @@
+pub mod adapter;
 pub mod texture;
+pub mod mipmap;
+pub mod compressed;
+pub mod shader;
+pub mod pipeline_cache;
+pub mod offscreen;
+// Only built for the crate's tests and with the `golden` feature
+#[cfg(any(test, feature = "golden"))]
+pub mod golden;
+pub mod camera;
+pub mod skybox;
+pub mod atlas;
+pub mod sprite;
+pub mod text;
+pub mod debug_draw;
+pub mod debug_view;
+pub mod bounds;
+pub mod bvh;
+pub mod mesh;
+pub mod resources;
+pub mod ring_buffer;
+pub mod scene;
+pub mod occlusion;
+pub mod lighting;
+pub mod deferred;
+pub mod ssao;
+pub mod picking;
+pub mod compute;
+pub mod particles;
+pub mod gpu_culling;
+pub mod profiler;
+pub mod stats;
+pub mod transparency;
+pub mod lod;
@@ -0,0 +1,343 @@
+// Occlusion culling with hardware occlusion queries
+//
+//     state.set_occlusion_culling_enabled(true)?;
+//
+// After the scene pass the bounding box of every static object in the frustum is drawn against
+// the depth buffer inside its own occlusion query, writing nothing. The results are read back
+// without blocking and reach the scene a frame or two later, objects whose box passed no
+// samples are skipped from then on. Hidden objects keep being queried, so one that comes into
+// view is drawn again as soon as its result is back, at worst a couple of frames late.
+use std::ops::Range;
+
+use tokio::sync::oneshot;
+use wgpu::util::DeviceExt;
+
+use super::{
+    bounds::Aabb,
+    scene::ObjectId,
+    stats::{self, Tracked, TrackedRenderPass},
+    texture::Texture,
+};
+
+// wgpu's limit for a single query set, objects past it are treated as visible
+pub const MAX_QUERIES: u32 = 8192;
+
+const CUBE_CORNERS: [[f32; 3]; 8] = [
+    [0.0, 0.0, 0.0],
+    [1.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0],
+    [1.0, 1.0, 0.0],
+    [0.0, 0.0, 1.0],
+    [1.0, 0.0, 1.0],
+    [0.0, 1.0, 1.0],
+    [1.0, 1.0, 1.0],
+];
+
+#[rustfmt::skip]
+const CUBE_INDICES: [u16; 36] = [
+    0, 2, 1, 1, 2, 3, // -z
+    4, 5, 6, 5, 7, 6, // +z
+    0, 4, 2, 2, 4, 6, // -x
+    1, 3, 5, 3, 7, 5, // +x
+    0, 1, 4, 1, 5, 4, // -y
+    2, 6, 3, 3, 6, 7, // +y
+];
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct BoxInstance {
+    min: [f32; 3],
+    max: [f32; 3],
+}
+
+impl BoxInstance {
+    const ATTRIBUTES: [wgpu::VertexAttribute; 2] = wgpu::vertex_attr_array![1 => Float32x3, 2 => Float32x3];
+
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<BoxInstance>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &Self::ATTRIBUTES,
+        }
+    }
+}
+
+struct Readback {
+    buffer: wgpu::Buffer,
+    // In queries
+    capacity: u32,
+    // The queried objects with the boxes they were tested with
+    objects: Vec<(ObjectId, Aabb)>,
+    frame: u64,
+    // Set while the buffer is being mapped
+    mapping: Option<oneshot::Receiver<Result<(), wgpu::BufferAsyncError>>>,
+}
+
+pub struct OcclusionQueries {
+    pipeline: wgpu::RenderPipeline,
+    pipeline_layout: wgpu::PipelineLayout,
+    corner_buffer: Tracked<wgpu::Buffer>,
+    index_buffer: Tracked<wgpu::Buffer>,
+    box_buffer: Tracked<wgpu::Buffer>,
+    query_set: wgpu::QuerySet,
+    resolve_buffer: Tracked<wgpu::Buffer>,
+    // In queries, for the boxes, the query set and the resolve buffer
+    capacity: u32,
+    // Objects queried this frame, in query order
+    queried: Vec<(ObjectId, Aabb)>,
+    // Objects past MAX_QUERIES, reported visible with the next results
+    unqueried: Vec<(ObjectId, Aabb)>,
+    readbacks: Vec<Readback>,
+    // The readback this frame's results are copied into
+    current: Option<usize>,
+    frame: u64,
+}
+
+impl OcclusionQueries {
+    pub const SHADER: &'static str = "occlusion.wgsl";
+
+    pub fn new(device: &wgpu::Device, shader: &wgpu::ShaderModule, camera_bind_group_layout: &wgpu::BindGroupLayout) -> Self {
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Occlusion Pipeline Layout"),
+            bind_group_layouts: &[camera_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = Self::create_pipeline(device, &pipeline_layout, shader);
+
+        let corner_buffer = stats::track_buffer(device, device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Occlusion Cube Vertex Buffer"),
+            contents: bytemuck::cast_slice(&CUBE_CORNERS),
+            usage: wgpu::BufferUsages::VERTEX,
+        }));
+        let index_buffer = stats::track_buffer(device, device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Occlusion Cube Index Buffer"),
+            contents: bytemuck::cast_slice(&CUBE_INDICES),
+            usage: wgpu::BufferUsages::INDEX,
+        }));
+
+        let capacity = 256;
+        let (box_buffer, query_set, resolve_buffer) = Self::create_query_resources(device, capacity);
+
+        Self {
+            pipeline,
+            pipeline_layout,
+            corner_buffer,
+            index_buffer,
+            box_buffer,
+            query_set,
+            resolve_buffer,
+            capacity,
+            queried: Vec::new(),
+            unqueried: Vec::new(),
+            readbacks: Vec::new(),
+            current: None,
+            frame: 0,
+        }
+    }
+
+    pub fn reload_shader(&mut self, device: &wgpu::Device, shader: &wgpu::ShaderModule) -> anyhow::Result<()> {
+        self.pipeline = super::shader::validated(device, || Self::create_pipeline(device, &self.pipeline_layout, shader))?;
+        Ok(())
+    }
+
+    fn create_pipeline(device: &wgpu::Device, layout: &wgpu::PipelineLayout, shader: &wgpu::ShaderModule) -> wgpu::RenderPipeline {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Occlusion Pipeline"),
+            layout: Some(layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: "vs_main",
+                buffers: &[
+                    wgpu::VertexBufferLayout {
+                        array_stride: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                        step_mode: wgpu::VertexStepMode::Vertex,
+                        attributes: &wgpu::vertex_attr_array![0 => Float32x3],
+                    },
+                    BoxInstance::desc(),
+                ],
+            },
+            // Depth only, the queries count the samples passing the depth test
+            fragment: None,
+            // Back faces count as well, so boxes the camera is close to still pass
+            primitive: wgpu::PrimitiveState {
+                cull_mode: None,
+                ..Default::default()
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                // Objects exactly filling their box pass against their own depth
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        })
+    }
+
+    fn create_query_resources(device: &wgpu::Device, capacity: u32) -> (Tracked<wgpu::Buffer>, wgpu::QuerySet, Tracked<wgpu::Buffer>) {
+        let box_buffer = stats::track_buffer(device, device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Occlusion Box Buffer"),
+            size: (capacity as usize * std::mem::size_of::<BoxInstance>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        }));
+        let query_set = device.create_query_set(&wgpu::QuerySetDescriptor {
+            label: Some("Occlusion Query Set"),
+            ty: wgpu::QueryType::Occlusion,
+            count: capacity,
+        });
+        let resolve_buffer = stats::track_buffer(device, device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Occlusion Resolve Buffer"),
+            size: capacity as wgpu::BufferAddress * wgpu::QUERY_SIZE as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        }));
+        (box_buffer, query_set, resolve_buffer)
+    }
+
+    // Uploads the boxes of the objects to query this frame
+    pub fn prepare(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, candidates: &[(ObjectId, Aabb)]) {
+        let count = candidates.len().min(MAX_QUERIES as usize);
+        self.queried = candidates[..count].to_vec();
+        self.unqueried.extend_from_slice(&candidates[count..]);
+        self.current = None;
+        if count == 0 {
+            return;
+        }
+
+        if count as u32 > self.capacity {
+            self.capacity = (count as u32).next_power_of_two().min(MAX_QUERIES);
+            (self.box_buffer, self.query_set, self.resolve_buffer) = Self::create_query_resources(device, self.capacity);
+        }
+        let boxes: Vec<BoxInstance> = self.queried.iter()
+            .map(|(_, aabb)| {
+                // A little larger than the object, so depth precision doesn't hide it behind itself
+                let aabb = aabb.inflated(aabb.extents().x.max(aabb.extents().y).max(aabb.extents().z) * 0.01 + 1e-3);
+                BoxInstance { min: aabb.min.into(), max: aabb.max.into() }
+            })
+            .collect();
+        stats::write_buffer(queue, &self.box_buffer, 0, bytemuck::cast_slice(&boxes));
+
+        // Buffers still being mapped hold results of earlier frames
+        let free = self.readbacks.iter().position(|readback| readback.mapping.is_none());
+        let index = free.unwrap_or_else(|| {
+            self.readbacks.push(Self::create_readback(device, self.capacity));
+            self.readbacks.len() - 1
+        });
+        let readback = &mut self.readbacks[index];
+        if readback.capacity < count as u32 {
+            *readback = Self::create_readback(device, self.capacity);
+        }
+        readback.objects = self.queried.clone();
+        readback.frame = self.frame;
+        self.frame += 1;
+        self.current = Some(index);
+    }
+
+    fn create_readback(device: &wgpu::Device, capacity: u32) -> Readback {
+        Readback {
+            buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Occlusion Readback Buffer"),
+                size: capacity as wgpu::BufferAddress * wgpu::QUERY_SIZE as wgpu::BufferAddress,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            }),
+            capacity,
+            objects: Vec::new(),
+            frame: 0,
+            mapping: None,
+        }
+    }
+
+    // Records the queries against `depth`, which must hold the frame's opaque geometry
+    pub fn render(
+        &self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        depth: &wgpu::TextureView,
+        camera_bind_group: &wgpu::BindGroup,
+        timestamp_writes: Option<wgpu::RenderPassTimestampWrites>,
+    ) {
+        let Some(readback) = self.current.map(|index| &self.readbacks[index]) else { return };
+        let count = self.queried.len() as u32;
+        {
+            let mut render_pass = TrackedRenderPass::new(queue, encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Occlusion Pass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: depth,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: wgpu::StoreOp::Store,
+                    }),
+                    stencil_ops: None,
+                }),
+                timestamp_writes,
+                occlusion_query_set: Some(&self.query_set),
+            }));
+
+            render_pass.set_pipeline(&self.pipeline, wgpu::PrimitiveTopology::TriangleList);
+            render_pass.set_bind_group(0, camera_bind_group, &[]);
+            render_pass.set_vertex_buffer(0, self.corner_buffer.slice(..));
+            render_pass.set_vertex_buffer(1, self.box_buffer.slice(..));
+            render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
+            for query in 0..count {
+                render_pass.begin_occlusion_query(query);
+                render_pass.draw_indexed(0..CUBE_INDICES.len() as u32, 0, query..query + 1);
+                render_pass.end_occlusion_query();
+            }
+        }
+
+        let size = Self::results_size(0..count);
+        encoder.resolve_query_set(&self.query_set, 0..count, &self.resolve_buffer, 0);
+        encoder.copy_buffer_to_buffer(&self.resolve_buffer, 0, &readback.buffer, 0, size);
+    }
+
+    fn results_size(queries: Range<u32>) -> wgpu::BufferAddress {
+        queries.len() as wgpu::BufferAddress * wgpu::QUERY_SIZE as wgpu::BufferAddress
+    }
+
+    // Starts reading back the frame's results, call it once the frame is submitted
+    pub fn frame_submitted(&mut self) {
+        let Some(index) = self.current.take() else { return };
+        let readback = &mut self.readbacks[index];
+        let (sender, receiver) = oneshot::channel();
+        readback.buffer.slice(..Self::results_size(0..readback.objects.len() as u32)).map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        readback.mapping = Some(receiver);
+    }
+
+    // Results that arrived since the last call, oldest first: each queried object with the box
+    // it was tested with and whether any of its samples passed
+    pub fn take_results(&mut self) -> Vec<(ObjectId, Aabb, bool)> {
+        let mut results: Vec<(ObjectId, Aabb, bool)> = self.unqueried.drain(..).map(|(id, aabb)| (id, aabb, true)).collect();
+
+        let mut arrived: Vec<usize> = Vec::new();
+        for (index, readback) in self.readbacks.iter_mut().enumerate() {
+            let Some(receiver) = &mut readback.mapping else { continue };
+            match receiver.try_recv() {
+                Ok(Ok(())) => arrived.push(index),
+                Err(oneshot::error::TryRecvError::Empty) => {},
+                // Failed maps lose their results, the objects keep their last state
+                Ok(Err(_)) | Err(oneshot::error::TryRecvError::Closed) => readback.mapping = None,
+            }
+        }
+        arrived.sort_by_key(|&index| self.readbacks[index].frame);
+
+        for index in arrived {
+            let readback = &mut self.readbacks[index];
+            {
+                let size = Self::results_size(0..readback.objects.len() as u32);
+                let data = readback.buffer.slice(..size).get_mapped_range();
+                let samples: &[u64] = bytemuck::cast_slice(&data);
+                results.extend(readback.objects.iter().zip(samples).map(|(&(id, aabb), &samples)| (id, aabb, samples > 0)));
+            }
+            readback.buffer.unmap();
+            readback.mapping = None;
+        }
+        results
+    }
+}
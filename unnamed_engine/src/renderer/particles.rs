@@ -0,0 +1,672 @@
+// GPU particles, simulated in a compute shader and drawn as camera facing billboards
+//
+// Emitters are data, see res/particles/effects.yaml for the format:
+//
+//     let effects = particles::load_effects("res/particles/effects.yaml")?;
+//     let smoke = state.add_emitter(&effects["smoke"], Point3::new(0.0, 0.0, 0.0))?;
+//
+// Every emitter owns a ring buffer of particles the simulation reads and writes in place,
+// new particles replace the oldest slots. The same buffer is drawn as per instance vertex
+// data, so particles never go back to the CPU. Particles aren't sorted, smoke looks fine
+// that way but overlapping alpha blended emitters can pop.
+// The billboards read their emitter's parameters from the state's uniform ring, copied there
+// every update, so emitters need no bind group of their own for drawing.
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use anyhow::*;
+use cgmath::{InnerSpace, Point3};
+use serde::Deserialize;
+use wgpu::util::DeviceExt;
+
+use super::{
+    camera::Camera,
+    compute::{Binding, ComputePipeline, StorageBuffer},
+    ring_buffer::{RingAllocation, RingBuffer},
+    shader,
+    stats::{self, Tracked, TrackedRenderPass},
+    texture::{Texture, TextureOptions},
+};
+
+// Keys per curve, the uniform has room for this many
+pub const MAX_CURVE_KEYS: usize = 8;
+
+const WORKGROUP_SIZE: u32 = 64;
+// Long frames are simulated as this step so a hitch doesn't scatter every particle
+const MAX_STEP: f32 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParticleBlend {
+    #[default]
+    Alpha,
+    Additive,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmitterDesc {
+    pub max_particles: u32,
+    // Particles per second
+    pub spawn_rate: f32,
+    // Particles spawned at once when the emitter is added
+    pub burst: u32,
+    // Seconds, picked between the two
+    pub lifetime: [f32; 2],
+    // Half extents of the box around the emitter that particles start in
+    pub spawn_box: [f32; 3],
+    // Initial velocity, picked per axis between the two
+    pub velocity_min: [f32; 3],
+    pub velocity_max: [f32; 3],
+    pub gravity: [f32; 3],
+    // Fraction of the velocity lost per second
+    pub drag: f32,
+    // Keys of normalized age and linear RGBA color
+    pub color: Vec<(f32, [f32; 4])>,
+    // Keys of normalized age and size in world units
+    pub size: Vec<(f32, f32)>,
+    // A soft dot when None, `load_effects` makes paths relative to the YAML file
+    pub texture: Option<PathBuf>,
+    pub blend: ParticleBlend,
+}
+
+impl Default for EmitterDesc {
+    fn default() -> Self {
+        Self {
+            max_particles: 256,
+            spawn_rate: 10.0,
+            burst: 0,
+            lifetime: [1.0, 1.0],
+            spawn_box: [0.0; 3],
+            velocity_min: [0.0; 3],
+            velocity_max: [0.0; 3],
+            gravity: [0.0, -9.81, 0.0],
+            drag: 0.0,
+            color: vec![(0.0, [1.0; 4])],
+            size: vec![(0.0, 0.1)],
+            texture: None,
+            blend: ParticleBlend::Alpha,
+        }
+    }
+}
+
+impl EmitterDesc {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(self.max_particles > 0, "An emitter needs room for at least one particle");
+        ensure!(
+            self.lifetime[0] > 0.0 && self.lifetime[0] <= self.lifetime[1],
+            "Lifetime {:?} must be positive and in order", self.lifetime
+        );
+        ensure!(self.spawn_rate >= 0.0, "Negative spawn rate {}", self.spawn_rate);
+        check_curve("color", self.color.iter().map(|(t, _)| *t))?;
+        check_curve("size", self.size.iter().map(|(t, _)| *t))?;
+        Ok(())
+    }
+}
+
+fn check_curve(name: &str, times: impl ExactSizeIterator<Item = f32> + Clone) -> Result<()> {
+    ensure!(
+        (1..=MAX_CURVE_KEYS).contains(&times.len()),
+        "The {} curve needs 1 to {} keys, got {}", name, MAX_CURVE_KEYS, times.len()
+    );
+    ensure!(
+        times.clone().zip(times.skip(1)).all(|(a, b)| a <= b),
+        "The keys of the {} curve must be sorted by age", name
+    );
+    Ok(())
+}
+
+// Named emitters from a YAML map, textures stay as written
+pub fn parse_effects(yaml: &str) -> Result<BTreeMap<String, EmitterDesc>> {
+    let effects: BTreeMap<String, EmitterDesc> = serde_yaml::from_str(yaml)?;
+    for (name, desc) in &effects {
+        desc.validate().with_context(|| format!("Invalid particle effect {}", name))?;
+    }
+    Ok(effects)
+}
+
+pub fn load_effects(path: impl AsRef<Path>) -> Result<BTreeMap<String, EmitterDesc>> {
+    let path = path.as_ref();
+    let yaml = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut effects = parse_effects(&yaml).with_context(|| format!("Failed to load {}", path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    for desc in effects.values_mut() {
+        if let Some(texture) = &mut desc.texture {
+            *texture = dir.join(&*texture);
+        }
+    }
+    Ok(effects)
+}
+
+// Matches `Particle` in common/particles.wgsl
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Particle {
+    pub position: [f32; 3],
+    pub age: f32,
+    pub velocity: [f32; 3],
+    pub lifetime: f32,
+}
+
+impl Particle {
+    const ATTRIBUTES: [wgpu::VertexAttribute; 2] = wgpu::vertex_attr_array![0 => Float32x4, 1 => Float32x4];
+
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<Particle>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &Self::ATTRIBUTES,
+        }
+    }
+}
+
+// Matches `Emitter` in common/particles.wgsl
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct EmitterUniform {
+    origin: [f32; 4],
+    spawn_box: [f32; 4],
+    velocity_min: [f32; 4],
+    velocity_max: [f32; 4],
+    gravity: [f32; 4],
+    spawn_start: u32,
+    spawn_count: u32,
+    max_particles: u32,
+    seed: u32,
+    color_keys: u32,
+    size_keys: u32,
+    _padding: [u32; 2],
+    colors: [[f32; 4]; MAX_CURVE_KEYS],
+    color_times: [f32; MAX_CURVE_KEYS],
+    sizes: [f32; MAX_CURVE_KEYS],
+    size_times: [f32; MAX_CURVE_KEYS],
+}
+
+impl EmitterUniform {
+    fn new(desc: &EmitterDesc) -> Self {
+        let mut uniform: Self = bytemuck::Zeroable::zeroed();
+        uniform.origin[3] = desc.drag;
+        uniform.spawn_box[..3].copy_from_slice(&desc.spawn_box);
+        uniform.velocity_min = [desc.velocity_min[0], desc.velocity_min[1], desc.velocity_min[2], desc.lifetime[0]];
+        uniform.velocity_max = [desc.velocity_max[0], desc.velocity_max[1], desc.velocity_max[2], desc.lifetime[1]];
+        uniform.gravity[..3].copy_from_slice(&desc.gravity);
+        uniform.max_particles = desc.max_particles;
+        uniform.color_keys = desc.color.len() as u32;
+        uniform.size_keys = desc.size.len() as u32;
+        for (i, (t, color)) in desc.color.iter().enumerate() {
+            uniform.color_times[i] = *t;
+            uniform.colors[i] = *color;
+        }
+        for (i, (t, size)) in desc.size.iter().enumerate() {
+            uniform.size_times[i] = *t;
+            uniform.sizes[i] = *size;
+        }
+        uniform
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct FrameUniform {
+    view_proj: [[f32; 4]; 4],
+    right: [f32; 4],
+    up: [f32; 4],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EmitterId(usize);
+
+struct Emitter {
+    desc: EmitterDesc,
+    uniform: EmitterUniform,
+    uniform_buffer: Tracked<wgpu::Buffer>,
+    particles: StorageBuffer<Particle>,
+    compute_bind_group: wgpu::BindGroup,
+    // The uniform copied into the ring by the last update, None before the first one
+    allocation: Option<RingAllocation>,
+    texture: usize,
+    // Fraction of a particle carried over to the next step
+    spawn_accumulator: f32,
+    pending_burst: u32,
+    next_slot: u32,
+    spawning: bool,
+}
+
+pub struct ParticleSystem {
+    // None when the adapter has no compute shaders, emitters can't be added then
+    simulate: Option<ComputePipeline>,
+    alpha_pipeline: wgpu::RenderPipeline,
+    additive_pipeline: wgpu::RenderPipeline,
+    pipeline_layout: wgpu::PipelineLayout,
+    format: wgpu::TextureFormat,
+    frame_buffer: Tracked<wgpu::Buffer>,
+    frame_bind_group: wgpu::BindGroup,
+    texture_bind_group_layout: wgpu::BindGroupLayout,
+    // The first one is the default dot, the rest are loaded on demand and shared by path
+    textures: Vec<(Texture, wgpu::BindGroup)>,
+    texture_paths: HashMap<PathBuf, usize>,
+    emitters: Vec<Option<Emitter>>,
+    steps: u32,
+}
+
+impl ParticleSystem {
+    pub const SHADER: &'static str = "particles.wgsl";
+    pub const SIMULATE_SHADER: &'static str = "particles_simulate.wgsl";
+
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        shader: &wgpu::ShaderModule,
+        simulate_shader: Option<&wgpu::ShaderModule>,
+        emitter_ring: &RingBuffer,
+        format: wgpu::TextureFormat,
+    ) -> Result<Self> {
+        let emitter_size = std::mem::size_of::<EmitterUniform>() as u64;
+        ensure!(
+            emitter_size <= emitter_ring.binding_size(),
+            "Emitters need {} bytes, the ring binds {}",
+            emitter_size,
+            emitter_ring.binding_size()
+        );
+        let simulate = simulate_shader
+            .map(|shader| ComputePipeline::new(device, shader, "cs_main", &[Binding::Uniform, Binding::Storage], WORKGROUP_SIZE))
+            .transpose()?;
+
+        let uniform_layout = |visibility, label| device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some(label),
+        });
+        let frame_bind_group_layout = uniform_layout(wgpu::ShaderStages::VERTEX, "particle_frame_bind_group_layout");
+
+        let texture_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("particle_texture_bind_group_layout"),
+        });
+
+        let frame: FrameUniform = bytemuck::Zeroable::zeroed();
+        let frame_buffer = stats::track_buffer(device, device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Particle Frame Buffer"),
+            contents: bytemuck::cast_slice(&[frame]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        }));
+        let frame_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &frame_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: frame_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("particle_frame_bind_group"),
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Particle Pipeline Layout"),
+            bind_group_layouts: &[&frame_bind_group_layout, emitter_ring.layout(), &texture_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let alpha_pipeline = Self::create_pipeline(device, &pipeline_layout, shader, format, ParticleBlend::Alpha);
+        let additive_pipeline = Self::create_pipeline(device, &pipeline_layout, shader, format, ParticleBlend::Additive);
+
+        let dot = Texture::from_image(device, queue, &soft_dot(32), Some("Particle Dot"), &Self::texture_options())?;
+        let dot_bind_group = Self::create_texture_bind_group(device, &texture_bind_group_layout, &dot);
+
+        Ok(Self {
+            simulate,
+            alpha_pipeline,
+            additive_pipeline,
+            pipeline_layout,
+            format,
+            frame_buffer,
+            frame_bind_group,
+            texture_bind_group_layout,
+            textures: vec![(dot, dot_bind_group)],
+            texture_paths: HashMap::new(),
+            emitters: Vec::new(),
+            steps: 0,
+        })
+    }
+
+    pub fn reload_shader(&mut self, device: &wgpu::Device, shader: &wgpu::ShaderModule) -> Result<()> {
+        self.alpha_pipeline = shader::validated(device, || {
+            Self::create_pipeline(device, &self.pipeline_layout, shader, self.format, ParticleBlend::Alpha)
+        })?;
+        self.additive_pipeline = shader::validated(device, || {
+            Self::create_pipeline(device, &self.pipeline_layout, shader, self.format, ParticleBlend::Additive)
+        })?;
+        Ok(())
+    }
+
+    pub fn reload_simulate_shader(&mut self, device: &wgpu::Device, shader: &wgpu::ShaderModule) -> Result<()> {
+        if let Some(simulate) = &mut self.simulate {
+            simulate.reload_shader(device, shader)?;
+        }
+        Ok(())
+    }
+
+    fn create_pipeline(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        shader: &wgpu::ShaderModule,
+        format: wgpu::TextureFormat,
+        blend: ParticleBlend,
+    ) -> wgpu::RenderPipeline {
+        let blend = match blend {
+            ParticleBlend::Alpha => wgpu::BlendState::ALPHA_BLENDING,
+            ParticleBlend::Additive => wgpu::BlendState {
+                color: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::SrcAlpha,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::Zero,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                },
+            },
+        };
+
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Particle Pipeline"),
+            layout: Some(layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: "vs_main",
+                buffers: &[Particle::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(blend),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleStrip,
+                ..Default::default()
+            },
+            // Hidden behind the scene but don't hide each other
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        })
+    }
+
+    fn texture_options() -> TextureOptions {
+        TextureOptions::default().with_mipmaps(true).with_filter(wgpu::FilterMode::Linear)
+    }
+
+    fn create_texture_bind_group(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, texture: &Texture) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                },
+            ],
+            label: Some("particle_texture_bind_group"),
+        })
+    }
+
+    fn load_texture(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, path: &Path) -> Result<usize> {
+        if let Some(index) = self.texture_paths.get(path) {
+            return Ok(*index);
+        }
+        let bytes = std::fs::read(path).with_context(|| format!("Failed to read particle texture {}", path.display()))?;
+        let texture = Texture::from_bytes(device, queue, &bytes, &path.display().to_string(), &Self::texture_options())?;
+        let bind_group = Self::create_texture_bind_group(device, &self.texture_bind_group_layout, &texture);
+        self.textures.push((texture, bind_group));
+        self.texture_paths.insert(path.to_path_buf(), self.textures.len() - 1);
+        Ok(self.textures.len() - 1)
+    }
+
+    pub fn is_supported(&self) -> bool {
+        self.simulate.is_some()
+    }
+
+    pub fn add_emitter(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        desc: &EmitterDesc,
+        position: Point3<f32>,
+    ) -> Result<EmitterId> {
+        let emitter = self.create_emitter(device, queue, desc, position)?;
+        let index = match self.emitters.iter().position(Option::is_none) {
+            Some(index) => index,
+            None => {
+                self.emitters.push(None);
+                self.emitters.len() - 1
+            },
+        };
+        self.emitters[index] = Some(emitter);
+        Ok(EmitterId(index))
+    }
+
+    // Adds the emitters of a system from a lost device again, in the same slots so their
+    // ids stay valid. The particles in flight are gone, the emitters start over
+    pub fn restore_from(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, old: &ParticleSystem) -> Result<()> {
+        for (index, slot) in old.emitters.iter().enumerate() {
+            let Some(old) = slot else { continue };
+            let [x, y, z, _] = old.uniform.origin;
+            let mut emitter = self.create_emitter(device, queue, &old.desc, Point3::new(x, y, z))?;
+            emitter.spawning = old.spawning;
+            if self.emitters.len() <= index {
+                self.emitters.resize_with(index + 1, || None);
+            }
+            self.emitters[index] = Some(emitter);
+        }
+        Ok(())
+    }
+
+    fn create_emitter(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        desc: &EmitterDesc,
+        position: Point3<f32>,
+    ) -> Result<Emitter> {
+        let Some(simulate) = &self.simulate else {
+            bail!("Particles need compute shaders, which the adapter doesn't support");
+        };
+        desc.validate()?;
+
+        let mut uniform = EmitterUniform::new(desc);
+        uniform.origin[..3].copy_from_slice(&[position.x, position.y, position.z]);
+        let uniform_buffer = stats::track_buffer(device, device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Emitter Uniform Buffer"),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        }));
+        let particles = StorageBuffer::zeroed(device, desc.max_particles as usize, wgpu::BufferUsages::VERTEX, Some("Particle Buffer"));
+
+        let compute_bind_group = simulate.bind_group(device, &[uniform_buffer.as_entire_binding(), particles.binding()]);
+
+        let texture = match &desc.texture {
+            Some(path) => self.load_texture(device, queue, path)?,
+            None => 0,
+        };
+
+        Ok(Emitter {
+            desc: desc.clone(),
+            uniform,
+            uniform_buffer,
+            particles,
+            compute_bind_group,
+            allocation: None,
+            texture,
+            spawn_accumulator: 0.0,
+            pending_burst: desc.burst,
+            next_slot: 0,
+            spawning: true,
+        })
+    }
+
+    // Particles already in flight stay where they are
+    pub fn set_position(&mut self, id: EmitterId, position: Point3<f32>) {
+        if let Some(emitter) = self.emitter_mut(id) {
+            emitter.uniform.origin[..3].copy_from_slice(&[position.x, position.y, position.z]);
+        }
+    }
+
+    // Stops or resumes continuous spawning, live particles finish their lifetime either way
+    pub fn set_spawning(&mut self, id: EmitterId, spawning: bool) {
+        if let Some(emitter) = self.emitter_mut(id) {
+            emitter.spawning = spawning;
+        }
+    }
+
+    // Spawns `count` particles on the next update
+    pub fn burst(&mut self, id: EmitterId, count: u32) {
+        if let Some(emitter) = self.emitter_mut(id) {
+            emitter.pending_burst += count;
+        }
+    }
+
+    pub fn remove_emitter(&mut self, id: EmitterId) {
+        if let Some(slot) = self.emitters.get_mut(id.0) {
+            *slot = None;
+        }
+    }
+
+    pub fn desc(&self, id: EmitterId) -> Option<&EmitterDesc> {
+        self.emitters.get(id.0)?.as_ref().map(|emitter| &emitter.desc)
+    }
+
+    pub fn emitter_count(&self) -> usize {
+        self.emitters.iter().flatten().count()
+    }
+
+    fn emitter_mut(&mut self, id: EmitterId) -> Option<&mut Emitter> {
+        self.emitters.get_mut(id.0)?.as_mut()
+    }
+
+    // Spawns and moves every particle by `dt` seconds
+    // Emitters are drawn with the uniforms copied into `ring` here, flush it before the frame
+    pub fn update(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, ring: &mut RingBuffer, camera: &Camera, dt: f32) {
+        let forward = (camera.target - camera.eye).normalize();
+        let right = forward.cross(camera.up).normalize();
+        let up = right.cross(forward);
+        let frame = FrameUniform {
+            view_proj: camera.build_view_projection_matrix().into(),
+            right: right.extend(0.0).into(),
+            up: up.extend(0.0).into(),
+        };
+        stats::write_buffer(queue, &self.frame_buffer, 0, bytemuck::cast_slice(&[frame]));
+
+        let Some(simulate) = &self.simulate else {
+            return;
+        };
+        if self.emitters.iter().all(Option::is_none) {
+            return;
+        }
+
+        let dt = dt.min(MAX_STEP);
+        for emitter in self.emitters.iter_mut().flatten() {
+            if emitter.spawning {
+                emitter.spawn_accumulator += emitter.desc.spawn_rate * dt;
+            }
+            let spawned = emitter.spawn_accumulator.floor();
+            emitter.spawn_accumulator -= spawned;
+            let max_particles = emitter.desc.max_particles;
+            let count = (spawned as u32).saturating_add(std::mem::take(&mut emitter.pending_burst)).min(max_particles);
+
+            emitter.uniform.spawn_box[3] = dt;
+            emitter.uniform.spawn_start = emitter.next_slot;
+            emitter.uniform.spawn_count = count;
+            emitter.uniform.seed = self.steps;
+            emitter.next_slot = (emitter.next_slot + count) % max_particles;
+            stats::write_buffer(queue, &emitter.uniform_buffer, 0, bytemuck::cast_slice(&[emitter.uniform]));
+            // Fits, `new` checked the size against the ring's bindings
+            emitter.allocation = ring.alloc(device, &emitter.uniform).ok();
+        }
+        self.steps = self.steps.wrapping_add(1);
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Particle Encoder"),
+        });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Particle Simulation"),
+                timestamp_writes: None,
+            });
+            for emitter in self.emitters.iter().flatten() {
+                simulate.dispatch_in(&mut pass, &emitter.compute_bind_group, emitter.particles.len() as u32);
+            }
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    // Draws into a pass with the scene's depth buffer, after the opaque geometry
+    pub fn render<'a>(&'a self, render_pass: &mut TrackedRenderPass<'a>, ring: &'a RingBuffer) {
+        for emitter in self.emitters.iter().flatten() {
+            let Some(allocation) = &emitter.allocation else { continue };
+            let pipeline = match emitter.desc.blend {
+                ParticleBlend::Alpha => &self.alpha_pipeline,
+                ParticleBlend::Additive => &self.additive_pipeline,
+            };
+            render_pass.set_pipeline(pipeline, wgpu::PrimitiveTopology::TriangleStrip);
+            render_pass.set_bind_group(0, &self.frame_bind_group, &[]);
+            render_pass.set_bind_group(1, ring.bind_group(allocation), &[allocation.offset]);
+            render_pass.set_bind_group(2, &self.textures[emitter.texture].1, &[]);
+            render_pass.set_vertex_buffer(0, emitter.particles.buffer().slice(..));
+            render_pass.draw(0..4, 0..emitter.particles.len() as u32);
+        }
+    }
+}
+
+// White dot fading out towards the edge
+fn soft_dot(size: u32) -> image::DynamicImage {
+    let image = image::RgbaImage::from_fn(size, size, |x, y| {
+        let center = (size as f32 - 1.0) / 2.0;
+        let distance = ((x as f32 - center).powi(2) + (y as f32 - center).powi(2)).sqrt() / (size as f32 / 2.0);
+        let alpha = (1.0 - distance).clamp(0.0, 1.0);
+        image::Rgba([255, 255, 255, (alpha * alpha * 255.0) as u8])
+    });
+    image::DynamicImage::ImageRgba8(image)
+}
@@ -0,0 +1,301 @@
+// Object picking through an ID buffer
+//
+// When enabled, the scene and the GPU scene are drawn a second time into an R32Uint target
+// holding the entity of every pixel, with the diffuse texture bound so cutout objects
+// discard the same texels as in the color pass. Picks copy a small region of it into a buffer that is mapped without
+// blocking, the returned futures resolve once the device is polled again.
+use std::{collections::BTreeSet, future::Future};
+
+use anyhow::*;
+
+use super::{
+    gpu_culling::GpuScene,
+    mesh::{InstanceRaw, Vertex},
+    scene::Scene,
+    shader,
+    stats::{self, Tracked, TrackedRenderPass},
+    texture::Texture,
+};
+
+pub const ID_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R32Uint;
+
+// Pixels around the cursor searched when it isn't exactly over an entity
+const PICK_RADIUS: u32 = 2;
+
+// Game side identifier handed back by picks, 0 is reserved for nothing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct EntityId(pub u32);
+
+// IDs read back row by row, starting at `origin`
+struct IdRegion {
+    origin: (u32, u32),
+    width: u32,
+    ids: Vec<u32>,
+}
+
+pub struct PickingRenderer {
+    pipeline: wgpu::RenderPipeline,
+    pipeline_layout: wgpu::PipelineLayout,
+    id_texture: Tracked<wgpu::Texture>,
+    id_view: wgpu::TextureView,
+    depth_texture: Texture,
+    width: u32,
+    height: u32,
+}
+
+impl PickingRenderer {
+    pub const SHADER: &'static str = "picking.wgsl";
+
+    pub fn new(
+        device: &wgpu::Device,
+        shader: &wgpu::ShaderModule,
+        texture_bind_group_layout: &wgpu::BindGroupLayout,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Picking Pipeline Layout"),
+            bind_group_layouts: &[texture_bind_group_layout, camera_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = Self::create_pipeline(device, &pipeline_layout, shader);
+        let (id_texture, id_view) = Self::create_id_texture(device, width, height);
+
+        Self {
+            pipeline,
+            pipeline_layout,
+            id_texture,
+            id_view,
+            depth_texture: Texture::create_depth_texture(device, width, height, "Picking Depth Texture"),
+            width,
+            height,
+        }
+    }
+
+    pub fn reload_shader(&mut self, device: &wgpu::Device, shader: &wgpu::ShaderModule) -> Result<()> {
+        self.pipeline = shader::validated(device, || Self::create_pipeline(device, &self.pipeline_layout, shader))?;
+        Ok(())
+    }
+
+    fn create_pipeline(device: &wgpu::Device, layout: &wgpu::PipelineLayout, shader: &wgpu::ShaderModule) -> wgpu::RenderPipeline {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Picking Pipeline"),
+            layout: Some(layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: "vs_main",
+                buffers: &[Vertex::desc(), InstanceRaw::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: shader,
+                entry_point: "fs_main",
+                targets: &[Some(ID_FORMAT.into())],
+            }),
+            // Same culling as the main pipeline so picks match what is visible
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: Some(wgpu::Face::Back),
+                ..Default::default()
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        })
+    }
+
+    fn create_id_texture(device: &wgpu::Device, width: u32, height: u32) -> (Tracked<wgpu::Texture>, wgpu::TextureView) {
+        let texture = stats::track_texture(device, device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Picking ID Texture"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: ID_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        }));
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        (texture, view)
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        (self.id_texture, self.id_view) = Self::create_id_texture(device, width, height);
+        self.depth_texture = Texture::create_depth_texture(device, width, height, "Picking Depth Texture");
+        self.width = width;
+        self.height = height;
+    }
+
+    // Records the ID pass, it has its own depth buffer so it doesn't depend on the color pass
+    // `gpu_scene` must have been culled for this frame already, `bind_groups` are the
+    // diffuse texture and camera groups of the main pass
+    pub fn render(
+        &self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        scene: &Scene,
+        gpu_scene: Option<&GpuScene>,
+        bind_groups: [&wgpu::BindGroup; 2],
+        timestamp_writes: Option<wgpu::RenderPassTimestampWrites>,
+    ) {
+        let mut render_pass = TrackedRenderPass::new(queue, encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Picking Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &self.id_view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &self.depth_texture.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: wgpu::StoreOp::Discard,
+                }),
+                stencil_ops: None,
+            }),
+            timestamp_writes,
+            occlusion_query_set: None,
+        }));
+
+        render_pass.set_pipeline(&self.pipeline, wgpu::PrimitiveTopology::TriangleList);
+        for (index, bind_group) in bind_groups.into_iter().enumerate() {
+            render_pass.set_bind_group(index as u32, bind_group, &[]);
+        }
+        scene.render(&mut render_pass);
+        if let Some(gpu_scene) = gpu_scene {
+            gpu_scene.render(&mut render_pass);
+        }
+    }
+
+    // Entity under a pixel of the last rendered frame, or the closest one within a few pixels
+    pub fn pick(&self, device: &wgpu::Device, queue: &wgpu::Queue, x: u32, y: u32) -> impl Future<Output = Result<Option<EntityId>>> + 'static {
+        let min = (x.saturating_sub(PICK_RADIUS), y.saturating_sub(PICK_RADIUS));
+        let max = (x.saturating_add(PICK_RADIUS), y.saturating_add(PICK_RADIUS));
+        let region = self.read_region(device, queue, min, max);
+
+        async move {
+            let Some(IdRegion { origin, width, ids }) = region.await? else {
+                return Ok(None);
+            };
+            // Closest pixel to the requested one that hit something
+            let closest = ids.iter().enumerate()
+                .filter(|(_, id)| **id != 0)
+                .min_by_key(|(i, _)| {
+                    let dx = origin.0 as i64 + (*i as u32 % width) as i64 - x as i64;
+                    let dy = origin.1 as i64 + (*i as u32 / width) as i64 - y as i64;
+                    dx * dx + dy * dy
+                });
+            Ok(closest.map(|(_, id)| EntityId(*id)))
+        }
+    }
+
+    // Every entity with at least one pixel between two corners, for box selection
+    pub fn pick_rect(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        a: (u32, u32),
+        b: (u32, u32),
+    ) -> impl Future<Output = Result<Vec<EntityId>>> + 'static {
+        let min = (a.0.min(b.0), a.1.min(b.1));
+        let max = (a.0.max(b.0), a.1.max(b.1));
+        let region = self.read_region(device, queue, min, max);
+
+        async move {
+            let Some(IdRegion { ids, .. }) = region.await? else {
+                return Ok(Vec::new());
+            };
+            let unique: BTreeSet<u32> = ids.into_iter().filter(|id| *id != 0).collect();
+            Ok(unique.into_iter().map(EntityId).collect())
+        }
+    }
+
+    // Starts copying the inclusive pixel range, clamped to the target, into a mappable buffer
+    // Resolves to None when the range is off screen
+    fn read_region(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        min: (u32, u32),
+        max: (u32, u32),
+    ) -> impl Future<Output = Result<Option<IdRegion>>> + 'static {
+        let region = (min.0 < self.width && min.1 < self.height).then(|| {
+            let max = (max.0.min(self.width - 1), max.1.min(self.height - 1));
+            (min, max.0 - min.0 + 1, max.1 - min.1 + 1)
+        });
+
+        let readback = region.map(|(origin, width, height)| {
+            let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+            let padded_bytes_per_row = (4 * width).div_ceil(align) * align;
+            let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Picking Readback Buffer"),
+                size: (padded_bytes_per_row * height) as wgpu::BufferAddress,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            });
+
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Picking Readback Encoder"),
+            });
+            encoder.copy_texture_to_buffer(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &self.id_texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d { x: origin.0, y: origin.1, z: 0 },
+                },
+                wgpu::ImageCopyBuffer {
+                    buffer: &buffer,
+                    layout: wgpu::ImageDataLayout {
+                        offset: 0,
+                        bytes_per_row: Some(padded_bytes_per_row),
+                        rows_per_image: Some(height),
+                    },
+                },
+                wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+            );
+            queue.submit(std::iter::once(encoder.finish()));
+
+            let (sender, receiver) = tokio::sync::oneshot::channel();
+            buffer.slice(..).map_async(wgpu::MapMode::Read, move |result| {
+                let _ = sender.send(result);
+            });
+            (buffer, receiver, origin, width, padded_bytes_per_row)
+        });
+
+        async move {
+            let Some((buffer, receiver, origin, width, padded_bytes_per_row)) = readback else {
+                return Ok(None);
+            };
+            receiver.await.context("Picking readback was dropped")??;
+
+            let ids = {
+                let data = buffer.slice(..).get_mapped_range();
+                data.chunks(padded_bytes_per_row as usize)
+                    .flat_map(|row| bytemuck::cast_slice::<u8, u32>(&row[..4 * width as usize]).to_vec())
+                    .collect()
+            };
+            buffer.unmap();
+            Ok(Some(IdRegion { origin, width, ids }))
+        }
+    }
+}
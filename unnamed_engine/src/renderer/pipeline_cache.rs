@@ -0,0 +1,214 @@
+// Render pipelines created on first use and shared by every draw with the same state
+//
+//     let key = RenderPipelineKey::new(ShaderKey::new("shader.wgsl", &[]), &[Vertex::desc()], targets);
+//     let pipeline = cache.render_pipeline(device, shaders, layout, &key)?;
+//
+// The key holds everything that goes into the descriptor: the shader variant, entry points,
+// vertex layouts, color targets, primitive, depth and multisample state. The layout is
+// matched by its id, so pipelines are only shared between users of the same layout.
+//
+// Only the pipelines drawn with the main pipeline layout go through the cache: the lit and
+// debug view variants of the main shader for each blend mode, the G-buffer pass and the depth
+// prepass, which are the ones rebuilt when the debug view or a define changes. The sprite,
+// text, debug draw, picking, OIT, particle, sky, SSAO and deferred lighting renderers own
+// their few fixed pipelines and build them directly, the cache doesn't see those.
+//
+// Nothing is written to disk: wgpu 0.18 has no way to read back the driver's compiled
+// pipelines. Vulkan and GL drivers usually keep their own shader caches across runs.
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::*;
+
+use super::shader::{self, ShaderLibrary};
+
+// A shader file with the defines it was preprocessed with
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ShaderKey {
+    pub name: String,
+    pub defines: Vec<(String, String)>,
+}
+
+impl ShaderKey {
+    pub fn new(name: &str, defines: &[(&str, &str)]) -> Self {
+        Self {
+            name: name.to_string(),
+            defines: defines.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+        }
+    }
+}
+
+// An owned `wgpu::VertexBufferLayout`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct VertexLayoutKey {
+    pub array_stride: wgpu::BufferAddress,
+    pub step_mode: wgpu::VertexStepMode,
+    pub attributes: Vec<wgpu::VertexAttribute>,
+}
+
+impl From<&wgpu::VertexBufferLayout<'_>> for VertexLayoutKey {
+    fn from(layout: &wgpu::VertexBufferLayout) -> Self {
+        Self {
+            array_stride: layout.array_stride,
+            step_mode: layout.step_mode,
+            attributes: layout.attributes.to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RenderPipelineKey {
+    pub shader: ShaderKey,
+    pub vertex_entry: String,
+    // None for depth only pipelines
+    pub fragment_entry: Option<String>,
+    pub vertex_layouts: Vec<VertexLayoutKey>,
+    pub targets: Vec<Option<wgpu::ColorTargetState>>,
+    pub primitive: wgpu::PrimitiveState,
+    pub depth_stencil: Option<wgpu::DepthStencilState>,
+    pub multisample: wgpu::MultisampleState,
+}
+
+impl RenderPipelineKey {
+    // `vs_main` and `fs_main`, triangle lists culling back faces, no depth and no MSAA
+    pub fn new(shader: ShaderKey, vertex_layouts: &[wgpu::VertexBufferLayout], targets: &[Option<wgpu::ColorTargetState>]) -> Self {
+        Self {
+            shader,
+            vertex_entry: "vs_main".to_string(),
+            fragment_entry: Some("fs_main".to_string()),
+            vertex_layouts: vertex_layouts.iter().map(VertexLayoutKey::from).collect(),
+            targets: targets.to_vec(),
+            primitive: wgpu::PrimitiveState {
+                cull_mode: Some(wgpu::Face::Back),
+                ..Default::default()
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+        }
+    }
+
+    pub fn with_entry_points(mut self, vertex: &str, fragment: Option<&str>) -> Self {
+        self.vertex_entry = vertex.to_string();
+        self.fragment_entry = fragment.map(str::to_string);
+        self
+    }
+
+    pub fn with_primitive(mut self, primitive: wgpu::PrimitiveState) -> Self {
+        self.primitive = primitive;
+        self
+    }
+
+    pub fn with_depth_stencil(mut self, depth_stencil: wgpu::DepthStencilState) -> Self {
+        self.depth_stencil = Some(depth_stencil);
+        self
+    }
+
+    pub fn with_sample_count(mut self, count: u32) -> Self {
+        self.multisample.count = count;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PipelineCacheStats {
+    pub pipelines: usize,
+    pub shaders: usize,
+    // Lookups answered from the cache and ones that created a pipeline
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Default)]
+pub struct PipelineCache {
+    shaders: HashMap<ShaderKey, Arc<wgpu::ShaderModule>>,
+    pipelines: HashMap<(wgpu::Id<wgpu::PipelineLayout>, RenderPipelineKey), Arc<wgpu::RenderPipeline>>,
+    hits: u64,
+    misses: u64,
+}
+
+impl PipelineCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Preprocesses and compiles a shader variant the first time it is asked for
+    pub fn shader(&mut self, device: &wgpu::Device, library: &mut ShaderLibrary, key: &ShaderKey) -> Result<Arc<wgpu::ShaderModule>> {
+        if let Some(module) = self.shaders.get(key) {
+            return Ok(module.clone());
+        }
+        let defines: Vec<(&str, &str)> = key.defines.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect();
+        let module = Arc::new(library.load(device, &key.name, &defines)?);
+        self.shaders.insert(key.clone(), module.clone());
+        Ok(module)
+    }
+
+    // The pipeline for `key`, created with the layout on a miss
+    // Fails without caching anything when the shader or the pipeline doesn't validate
+    pub fn render_pipeline(
+        &mut self,
+        device: &wgpu::Device,
+        library: &mut ShaderLibrary,
+        layout: &wgpu::PipelineLayout,
+        key: &RenderPipelineKey,
+    ) -> Result<Arc<wgpu::RenderPipeline>> {
+        let cache_key = (layout.global_id(), key.clone());
+        if let Some(pipeline) = self.pipelines.get(&cache_key) {
+            self.hits += 1;
+            return Ok(pipeline.clone());
+        }
+
+        let module = self.shader(device, library, &key.shader)?;
+        let vertex_layouts: Vec<wgpu::VertexBufferLayout> = key.vertex_layouts.iter()
+            .map(|layout| wgpu::VertexBufferLayout {
+                array_stride: layout.array_stride,
+                step_mode: layout.step_mode,
+                attributes: &layout.attributes,
+            })
+            .collect();
+        let pipeline = shader::validated(device, || device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(&key.shader.name),
+            layout: Some(layout),
+            vertex: wgpu::VertexState {
+                module: &module,
+                entry_point: &key.vertex_entry,
+                buffers: &vertex_layouts,
+            },
+            fragment: key.fragment_entry.as_ref().map(|entry_point| wgpu::FragmentState {
+                module: &module,
+                entry_point,
+                targets: &key.targets,
+            }),
+            primitive: key.primitive,
+            depth_stencil: key.depth_stencil.clone(),
+            multisample: key.multisample,
+            multiview: None,
+        }))?;
+
+        let pipeline = Arc::new(pipeline);
+        self.misses += 1;
+        self.pipelines.insert(cache_key, pipeline.clone());
+        Ok(pipeline)
+    }
+
+    // Drops every variant of a shader and the pipelines made from them, so the next lookups
+    // compile the file again. Pipelines still held elsewhere stay alive until dropped
+    pub fn invalidate_shader(&mut self, name: &str) -> usize {
+        self.shaders.retain(|key, _| key.name != name);
+        let before = self.pipelines.len();
+        self.pipelines.retain(|(_, key), _| key.shader.name != name);
+        before - self.pipelines.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.shaders.clear();
+        self.pipelines.clear();
+    }
+
+    pub fn stats(&self) -> PipelineCacheStats {
+        PipelineCacheStats {
+            pipelines: self.pipelines.len(),
+            shaders: self.shaders.len(),
+            hits: self.hits,
+            misses: self.misses,
+        }
+    }
+}
@@ -0,0 +1,300 @@
+// Per pass GPU timings from timestamp queries
+//
+//     profiler.begin_frame();
+//     let scope = profiler.begin("Scene");
+//     let pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+//         timestamp_writes: profiler.render_pass_writes(&scope),
+//         ..
+//     });
+//     drop(pass);
+//     profiler.end(scope);
+//     profiler.resolve(device, &mut encoder);
+//     queue.submit(..);
+//     profiler.end_frame();
+//
+// Every pass writes a timestamp when it begins and ends, they are resolved into a buffer
+// that is mapped without blocking, so GPU times arrive a few frames late. Adapters without
+// TIMESTAMP_QUERY, like WebGL and GL, only report the CPU time spent encoding each pass.
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+// Passes timed per frame, later ones only get CPU times
+pub const MAX_SCOPES: u32 = 32;
+// Frames whose timestamps can be waiting for readback at once
+const MAX_PENDING_FRAMES: usize = 3;
+const LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+const MAP_PENDING: u8 = 0;
+const MAP_DONE: u8 = 1;
+const MAP_FAILED: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PassTiming {
+    pub name: String,
+    // None without timestamp queries
+    pub gpu_ms: Option<f32>,
+    // Time spent recording the pass
+    pub cpu_ms: f32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FrameTimings {
+    // Counts the frames rendered while profiling
+    pub frame: u64,
+    pub passes: Vec<PassTiming>,
+}
+
+impl FrameTimings {
+    pub fn gpu_ms(&self) -> Option<f32> {
+        self.passes.iter().map(|pass| pass.gpu_ms).sum()
+    }
+
+    pub fn cpu_ms(&self) -> f32 {
+        self.passes.iter().map(|pass| pass.cpu_ms).sum()
+    }
+
+    pub fn pass(&self, name: &str) -> Option<&PassTiming> {
+        self.passes.iter().find(|pass| pass.name == name)
+    }
+}
+
+// Returned by `begin` and handed back to `end` once the pass is recorded
+#[must_use]
+pub struct ProfileScope {
+    name: String,
+    // First of the begin and end query indices
+    query: Option<u32>,
+    start: Instant,
+}
+
+struct ScopeRecord {
+    name: String,
+    query: Option<u32>,
+    cpu_ms: f32,
+}
+
+struct PendingFrame {
+    frame: u64,
+    scopes: Vec<ScopeRecord>,
+    buffer: wgpu::Buffer,
+    state: Arc<AtomicU8>,
+    mapping: bool,
+}
+
+pub struct GpuProfiler {
+    // None without TIMESTAMP_QUERY
+    query_set: Option<wgpu::QuerySet>,
+    resolve_buffer: Option<wgpu::Buffer>,
+    // Nanoseconds per timestamp tick
+    period: f32,
+    free_buffers: Vec<wgpu::Buffer>,
+    pending: VecDeque<PendingFrame>,
+    scopes: Vec<ScopeRecord>,
+    next_query: u32,
+    // Whether this frame writes timestamps, off while too many frames wait for readback
+    timestamps: bool,
+    frame: u64,
+    latest: FrameTimings,
+    last_log: Instant,
+    pub enabled: bool,
+}
+
+impl GpuProfiler {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        let supported = device.features().contains(wgpu::Features::TIMESTAMP_QUERY);
+        let query_set = supported.then(|| device.create_query_set(&wgpu::QuerySetDescriptor {
+            label: Some("Profiler Query Set"),
+            ty: wgpu::QueryType::Timestamp,
+            count: MAX_SCOPES * 2,
+        }));
+        let resolve_buffer = supported.then(|| device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Profiler Resolve Buffer"),
+            size: Self::buffer_size(),
+            usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        }));
+
+        Self {
+            query_set,
+            resolve_buffer,
+            period: queue.get_timestamp_period(),
+            free_buffers: Vec::new(),
+            pending: VecDeque::new(),
+            scopes: Vec::new(),
+            next_query: 0,
+            timestamps: false,
+            frame: 0,
+            latest: FrameTimings::default(),
+            last_log: Instant::now(),
+            enabled: false,
+        }
+    }
+
+    fn buffer_size() -> wgpu::BufferAddress {
+        (MAX_SCOPES * 2) as wgpu::BufferAddress * std::mem::size_of::<u64>() as wgpu::BufferAddress
+    }
+
+    // False when only CPU times are reported
+    pub fn has_timestamps(&self) -> bool {
+        self.query_set.is_some()
+    }
+
+    // The newest complete frame, GPU times lag a few frames behind
+    pub fn latest(&self) -> &FrameTimings {
+        &self.latest
+    }
+
+    pub fn begin_frame(&mut self) {
+        self.scopes.clear();
+        self.next_query = 0;
+        self.timestamps = self.enabled && self.query_set.is_some() && self.pending.len() < MAX_PENDING_FRAMES;
+    }
+
+    pub fn begin(&mut self, name: &str) -> ProfileScope {
+        let query = (self.timestamps && self.next_query < MAX_SCOPES * 2).then(|| {
+            self.next_query += 2;
+            self.next_query - 2
+        });
+        ProfileScope {
+            name: name.to_string(),
+            query,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn end(&mut self, scope: ProfileScope) {
+        if !self.enabled {
+            return;
+        }
+        self.scopes.push(ScopeRecord {
+            name: scope.name,
+            query: scope.query,
+            cpu_ms: scope.start.elapsed().as_secs_f32() * 1000.0,
+        });
+    }
+
+    pub fn render_pass_writes(&self, scope: &ProfileScope) -> Option<wgpu::RenderPassTimestampWrites<'_>> {
+        Some(wgpu::RenderPassTimestampWrites {
+            query_set: self.query_set.as_ref()?,
+            beginning_of_pass_write_index: Some(scope.query?),
+            end_of_pass_write_index: Some(scope.query? + 1),
+        })
+    }
+
+    pub fn compute_pass_writes(&self, scope: &ProfileScope) -> Option<wgpu::ComputePassTimestampWrites<'_>> {
+        Some(wgpu::ComputePassTimestampWrites {
+            query_set: self.query_set.as_ref()?,
+            beginning_of_pass_write_index: Some(scope.query?),
+            end_of_pass_write_index: Some(scope.query? + 1),
+        })
+    }
+
+    // Copies this frame's timestamps out of the query set, call before submitting the encoder
+    pub fn resolve(&mut self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder) {
+        if !self.enabled {
+            return;
+        }
+        self.frame += 1;
+        let scopes = std::mem::take(&mut self.scopes);
+
+        let (Some(query_set), Some(resolve_buffer)) = (&self.query_set, &self.resolve_buffer) else {
+            self.publish(FrameTimings { frame: self.frame, passes: Self::cpu_timings(scopes) });
+            return;
+        };
+        if self.next_query == 0 {
+            self.publish(FrameTimings { frame: self.frame, passes: Self::cpu_timings(scopes) });
+            return;
+        }
+
+        let buffer = self.free_buffers.pop().unwrap_or_else(|| device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Profiler Readback Buffer"),
+            size: Self::buffer_size(),
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        }));
+        encoder.resolve_query_set(query_set, 0..self.next_query, resolve_buffer, 0);
+        let size = self.next_query as wgpu::BufferAddress * std::mem::size_of::<u64>() as wgpu::BufferAddress;
+        encoder.copy_buffer_to_buffer(resolve_buffer, 0, &buffer, 0, size);
+
+        self.pending.push_back(PendingFrame {
+            frame: self.frame,
+            scopes,
+            buffer,
+            state: Arc::new(AtomicU8::new(MAP_PENDING)),
+            mapping: false,
+        });
+    }
+
+    // Starts reading back the resolved timestamps, call after the encoder was submitted
+    pub fn end_frame(&mut self) {
+        for pending in self.pending.iter_mut().filter(|pending| !pending.mapping) {
+            let state = pending.state.clone();
+            pending.buffer.slice(..).map_async(wgpu::MapMode::Read, move |result| {
+                state.store(if result.is_ok() { MAP_DONE } else { MAP_FAILED }, Ordering::Release);
+            });
+            pending.mapping = true;
+        }
+    }
+
+    // Picks up readbacks that finished, call after polling the device
+    pub fn poll(&mut self) {
+        while let Some(pending) = self.pending.front() {
+            let state = pending.state.load(Ordering::Acquire);
+            if state == MAP_PENDING {
+                break;
+            }
+            let pending = self.pending.pop_front().unwrap();
+
+            if state == MAP_DONE {
+                let timestamps: Vec<u64> = {
+                    let data = pending.buffer.slice(..).get_mapped_range();
+                    bytemuck::cast_slice(&data).to_vec()
+                };
+                pending.buffer.unmap();
+                let passes = pending.scopes.into_iter()
+                    .map(|scope| {
+                        let gpu_ms = scope.query.map(|query| {
+                            let ticks = timestamps[query as usize + 1].saturating_sub(timestamps[query as usize]);
+                            ticks as f32 * self.period / 1_000_000.0
+                        });
+                        PassTiming { name: scope.name, gpu_ms, cpu_ms: scope.cpu_ms }
+                    })
+                    .collect();
+                self.publish(FrameTimings { frame: pending.frame, passes });
+            } else {
+                log::warn!("Failed to read back the GPU timestamps of frame {}", pending.frame);
+            }
+            self.free_buffers.push(pending.buffer);
+        }
+    }
+
+    fn cpu_timings(scopes: Vec<ScopeRecord>) -> Vec<PassTiming> {
+        scopes.into_iter()
+            .map(|scope| PassTiming { name: scope.name, gpu_ms: None, cpu_ms: scope.cpu_ms })
+            .collect()
+    }
+
+    fn publish(&mut self, timings: FrameTimings) {
+        self.latest = timings;
+        if self.last_log.elapsed() >= LOG_INTERVAL {
+            self.last_log = Instant::now();
+            log::debug!("Frame {}: {}", self.latest.frame, self.summary().join(", "));
+        }
+    }
+
+    // One line per pass of the latest frame
+    pub fn summary(&self) -> Vec<String> {
+        self.latest.passes.iter()
+            .map(|pass| match pass.gpu_ms {
+                Some(gpu_ms) => format!("{} {:.2} ms GPU, {:.2} ms CPU", pass.name, gpu_ms, pass.cpu_ms),
+                None => format!("{} {:.2} ms CPU", pass.name, pass.cpu_ms),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,438 @@
+// GPU resources behind typed handles with reference counts
+//
+//     let texture = state.create_texture(bytes, "Crate", &TextureOptions::default())?;
+//     state.retain_texture(texture);   // a second owner
+//     state.release_texture(texture);  // still alive
+//     state.release_texture(texture);  // destroyed once the GPU is done with it
+//
+// Game code holds `MeshHandle`, `TextureHandle` and `BufferHandle` instead of wgpu objects.
+// A handle carries the generation of its slot and the pool it came from, so handles of
+// destroyed resources, or of another pool, resolve to nothing instead of whatever took their
+// place. Resources start with one reference and once it drops to zero they wait until every
+// frame submitted so far has finished on the GPU before they are dropped.
+//
+// Textures and buffers created through the registry keep what they were made from, so
+// `recreate` can upload them to a new device under the same handles after a device loss.
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{ensure, Context, Result};
+use wgpu::util::DeviceExt;
+
+use super::{
+    mesh::Mesh,
+    stats::{self, Tracked},
+    texture::{Texture, TextureOptions},
+};
+
+pub type MeshHandle = Handle<Mesh>;
+pub type TextureHandle = Handle<Texture>;
+pub type BufferHandle = Handle<Tracked<wgpu::Buffer>>;
+
+// Every pool gets its own ID for the handles it gives out
+static NEXT_POOL: AtomicU32 = AtomicU32::new(0);
+
+pub struct Handle<T> {
+    index: u32,
+    generation: u32,
+    pool: u32,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Handle<T> {
+    pub(crate) fn new(index: u32, generation: u32, pool: u32) -> Self {
+        Self { index, generation, pool, _marker: PhantomData }
+    }
+}
+
+// Derives would require T to implement the traits as well
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Handle<T> {}
+
+impl<T> PartialEq for Handle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.index, self.generation, self.pool) == (other.index, other.generation, other.pool)
+    }
+}
+
+impl<T> Eq for Handle<T> {}
+
+impl<T> Hash for Handle<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.index, self.generation, self.pool).hash(state);
+    }
+}
+
+impl<T> fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Handle({}v{}@{})", self.index, self.generation, self.pool)
+    }
+}
+
+// Counts the frames submitted to the queue and the ones the GPU has finished
+// Clones share the counters, all pools of one device use the same fence
+#[derive(Debug, Clone, Default)]
+pub struct FrameFence {
+    submitted: Arc<AtomicU64>,
+    completed: Arc<AtomicU64>,
+}
+
+impl FrameFence {
+    // Call right after submitting a frame, it completes during a later `Device::poll`
+    pub fn frame_submitted(&self, queue: &wgpu::Queue) {
+        let frame = self.submitted.fetch_add(1, Ordering::AcqRel) + 1;
+        let completed = self.completed.clone();
+        queue.on_submitted_work_done(move || {
+            completed.fetch_max(frame, Ordering::AcqRel);
+        });
+    }
+
+    pub fn last_submitted(&self) -> u64 {
+        self.submitted.load(Ordering::Acquire)
+    }
+
+    pub fn last_completed(&self) -> u64 {
+        self.completed.load(Ordering::Acquire)
+    }
+}
+
+// A resource still referenced, for leak reports
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiveResource {
+    pub kind: &'static str,
+    pub label: String,
+    pub refs: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResourceReport {
+    pub live: Vec<LiveResource>,
+    // Released, waiting for the GPU to finish the frames that use them
+    pub pending: usize,
+}
+
+impl fmt::Display for ResourceReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} live GPU resources, {} pending destruction", self.live.len(), self.pending)?;
+        for resource in self.live.iter() {
+            write!(f, "\n  {} {:?} ({} refs)", resource.kind, resource.label, resource.refs)?;
+        }
+        Ok(())
+    }
+}
+
+struct Entry<T> {
+    resource: T,
+    refs: u32,
+    label: String,
+}
+
+struct Slot<T> {
+    // Bumped whenever the slot is emptied, so old handles stop matching
+    generation: u32,
+    entry: Option<Entry<T>>,
+}
+
+pub struct ResourcePool<T> {
+    id: u32,
+    kind: &'static str,
+    slots: Vec<Slot<T>>,
+    free: Vec<u32>,
+    // Released resources with the last frame submitted before their release
+    pending: Vec<(u64, T)>,
+    fence: FrameFence,
+}
+
+impl<T> ResourcePool<T> {
+    // `kind` names the resources in reports, e.g. "Mesh"
+    pub fn new(kind: &'static str, fence: FrameFence) -> Self {
+        Self {
+            id: NEXT_POOL.fetch_add(1, Ordering::Relaxed),
+            kind,
+            slots: Vec::new(),
+            free: Vec::new(),
+            pending: Vec::new(),
+            fence,
+        }
+    }
+
+    // Adds a resource with one reference
+    pub fn insert(&mut self, resource: T, label: &str) -> Handle<T> {
+        let entry = Some(Entry { resource, refs: 1, label: label.to_string() });
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.slots[index as usize].entry = entry;
+                index
+            },
+            None => {
+                self.slots.push(Slot { generation: 0, entry });
+                self.slots.len() as u32 - 1
+            },
+        };
+        Handle::new(index, self.slots[index as usize].generation, self.id)
+    }
+
+    fn entry(&self, handle: Handle<T>) -> Option<&Entry<T>> {
+        let slot = self.slots.get(handle.index as usize).filter(|_| handle.pool == self.id)?;
+        slot.entry.as_ref().filter(|_| slot.generation == handle.generation)
+    }
+
+    fn entry_mut(&mut self, handle: Handle<T>) -> Option<&mut Entry<T>> {
+        let slot = self.slots.get_mut(handle.index as usize).filter(|_| handle.pool == self.id)?;
+        slot.entry.as_mut().filter(|_| slot.generation == handle.generation)
+    }
+
+    // None once the resource was released for good
+    pub fn get(&self, handle: Handle<T>) -> Option<&T> {
+        self.entry(handle).map(|entry| &entry.resource)
+    }
+
+    pub fn get_mut(&mut self, handle: Handle<T>) -> Option<&mut T> {
+        self.entry_mut(handle).map(|entry| &mut entry.resource)
+    }
+
+    pub fn contains(&self, handle: Handle<T>) -> bool {
+        self.entry(handle).is_some()
+    }
+
+    pub fn refs(&self, handle: Handle<T>) -> Option<u32> {
+        self.entry(handle).map(|entry| entry.refs)
+    }
+
+    // Adds a reference, false for stale handles
+    pub fn retain(&mut self, handle: Handle<T>) -> bool {
+        match self.entry_mut(handle) {
+            Some(entry) => {
+                entry.refs += 1;
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Drops a reference, the last one queues the resource for destruction and frees the slot
+    // Returns whether that happened, stale handles are ignored
+    pub fn release(&mut self, handle: Handle<T>) -> bool {
+        let Some(entry) = self.entry_mut(handle) else { return false };
+        entry.refs -= 1;
+        if entry.refs > 0 {
+            return false;
+        }
+
+        let slot = &mut self.slots[handle.index as usize];
+        let entry = slot.entry.take().unwrap();
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(handle.index);
+        self.pending.push((self.fence.last_submitted(), entry.resource));
+        true
+    }
+
+    // Swaps the resource behind a live handle, e.g. for one uploaded to another device
+    pub fn replace(&mut self, handle: Handle<T>, resource: T) -> Option<T> {
+        self.entry_mut(handle).map(|entry| std::mem::replace(&mut entry.resource, resource))
+    }
+
+    // Takes a resource out right away, whatever its references, without waiting for the GPU
+    pub fn remove(&mut self, handle: Handle<T>) -> Option<T> {
+        self.entry(handle)?;
+        let slot = &mut self.slots[handle.index as usize];
+        let entry = slot.entry.take().unwrap();
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(handle.index);
+        Some(entry.resource)
+    }
+
+    // Drops the released resources the GPU is done with, returns how many
+    pub fn collect(&mut self) -> usize {
+        let completed = self.fence.last_completed();
+        let before = self.pending.len();
+        self.pending.retain(|(frame, _)| *frame > completed);
+        before - self.pending.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Handle<T>, &T)> {
+        let id = self.id;
+        self.slots.iter().enumerate().filter_map(move |(i, slot)| {
+            slot.entry.as_ref().map(|entry| (Handle::new(i as u32, slot.generation, id), &entry.resource))
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Handle<T>, &mut T)> {
+        let id = self.id;
+        self.slots.iter_mut().enumerate().filter_map(move |(i, slot)| {
+            let generation = slot.generation;
+            slot.entry.as_mut().map(|entry| (Handle::new(i as u32, generation, id), &mut entry.resource))
+        })
+    }
+
+    pub fn live(&self) -> impl Iterator<Item = LiveResource> + '_ {
+        self.slots.iter().filter_map(|slot| slot.entry.as_ref()).map(|entry| LiveResource {
+            kind: self.kind,
+            label: entry.label.clone(),
+            refs: entry.refs,
+        })
+    }
+
+    // Moves the pool to another device's fence, resources pending on the old one are dropped
+    pub fn set_fence(&mut self, fence: FrameFence) {
+        self.pending.clear();
+        self.fence = fence;
+    }
+}
+
+// What a texture or buffer was created from, to upload it again on another device
+struct TextureSource {
+    bytes: Vec<u8>,
+    label: String,
+    options: TextureOptions,
+}
+
+struct BufferSource {
+    contents: Vec<u8>,
+    label: String,
+    usage: wgpu::BufferUsages,
+}
+
+// Textures and buffers of one device, meshes live in the scene's pool on the same fence
+pub struct ResourceRegistry {
+    pub textures: ResourcePool<Texture>,
+    pub buffers: ResourcePool<Tracked<wgpu::Buffer>>,
+    fence: FrameFence,
+    texture_sources: HashMap<TextureHandle, TextureSource>,
+    buffer_sources: HashMap<BufferHandle, BufferSource>,
+}
+
+impl Default for ResourceRegistry {
+    fn default() -> Self {
+        let fence = FrameFence::default();
+        Self {
+            textures: ResourcePool::new("Texture", fence.clone()),
+            buffers: ResourcePool::new("Buffer", fence.clone()),
+            fence,
+            texture_sources: HashMap::new(),
+            buffer_sources: HashMap::new(),
+        }
+    }
+}
+
+impl ResourceRegistry {
+    pub fn fence(&self) -> &FrameFence {
+        &self.fence
+    }
+
+    // Decodes an image into a texture, the encoded bytes are kept for `recreate`
+    pub fn create_texture(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        bytes: &[u8],
+        label: &str,
+        options: &TextureOptions,
+    ) -> Result<TextureHandle> {
+        let texture = Texture::from_bytes(device, queue, bytes, label, options)?;
+        let handle = self.textures.insert(texture, label);
+        self.texture_sources.insert(handle, TextureSource { bytes: bytes.to_vec(), label: label.to_string(), options: *options });
+        Ok(handle)
+    }
+
+    pub fn release_texture(&mut self, texture: TextureHandle) {
+        if self.textures.release(texture) {
+            self.texture_sources.remove(&texture);
+        }
+    }
+
+    // `usage` is added to COPY_DST, so the buffer can be written with `write_buffer`
+    pub fn create_buffer(&mut self, device: &wgpu::Device, contents: &[u8], usage: wgpu::BufferUsages, label: &str) -> BufferHandle {
+        let usage = usage | wgpu::BufferUsages::COPY_DST;
+        let handle = self.buffers.insert(Self::upload_buffer(device, contents, usage, label), label);
+        self.buffer_sources.insert(handle, BufferSource { contents: contents.to_vec(), label: label.to_string(), usage });
+        handle
+    }
+
+    // Also updates the copy `recreate` uploads
+    pub fn write_buffer(&mut self, queue: &wgpu::Queue, buffer: BufferHandle, offset: wgpu::BufferAddress, data: &[u8]) -> Result<()> {
+        let target = self.buffers.get(buffer).context("The buffer was released")?;
+        ensure!(offset + data.len() as u64 <= target.size(), "Writing {} bytes at {} overflows a buffer of {}", data.len(), offset, target.size());
+        stats::write_buffer(queue, target, offset, data);
+        if let Some(source) = self.buffer_sources.get_mut(&buffer) {
+            source.contents[offset as usize..][..data.len()].copy_from_slice(data);
+        }
+        Ok(())
+    }
+
+    pub fn release_buffer(&mut self, buffer: BufferHandle) {
+        if self.buffers.release(buffer) {
+            self.buffer_sources.remove(&buffer);
+        }
+    }
+
+    fn upload_buffer(device: &wgpu::Device, contents: &[u8], usage: wgpu::BufferUsages, label: &str) -> Tracked<wgpu::Buffer> {
+        stats::track_buffer(device, device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(label),
+            contents,
+            usage,
+        }))
+    }
+
+    // Uploads every texture and buffer made by `create_texture` and `create_buffer` to another
+    // device after the old one was lost, under the same handles. Buffers come back with what
+    // was written through `write_buffer`, GPU writes are gone. Resources inserted into the
+    // pools directly keep their dead objects until the caller replaces them
+    pub fn recreate(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, fence: FrameFence) {
+        self.textures.set_fence(fence.clone());
+        self.buffers.set_fence(fence.clone());
+        self.fence = fence;
+
+        // Released straight through the pools
+        self.texture_sources.retain(|&handle, _| self.textures.contains(handle));
+        self.buffer_sources.retain(|&handle, _| self.buffers.contains(handle));
+
+        for (&handle, source) in self.texture_sources.iter() {
+            match Texture::from_bytes(device, queue, &source.bytes, &source.label, &source.options) {
+                Ok(texture) => {
+                    self.textures.replace(handle, texture);
+                },
+                Err(e) => log::warn!("Texture {:?} not restored on the new device: {:#}", source.label, e),
+            }
+        }
+        for (&handle, source) in self.buffer_sources.iter() {
+            self.buffers.replace(handle, Self::upload_buffer(device, &source.contents, source.usage, &source.label));
+        }
+    }
+
+    pub fn collect(&mut self) -> usize {
+        self.textures.collect() + self.buffers.collect()
+    }
+
+    pub fn report(&self) -> ResourceReport {
+        ResourceReport {
+            live: self.textures.live().chain(self.buffers.live()).collect(),
+            pending: self.textures.pending() + self.buffers.pending(),
+        }
+    }
+}
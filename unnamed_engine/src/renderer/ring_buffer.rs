@@ -0,0 +1,234 @@
+// Per frame data sub-allocated from large buffers and bound with dynamic offsets
+//
+//     let object = ring.alloc(&ObjectUniform { model, tint })?;
+//     render_pass.set_bind_group(2, ring.bind_group(&object), &[object.offset]);
+//     ...
+//     ring.flush(queue); // before the frame is submitted
+//
+// Allocations are aligned to the adapter's dynamic offset alignment and packed into blocks,
+// each with one buffer and one bind group. A frame takes blocks until it is submitted, they
+// come back once the fence says the GPU finished that frame. When every block is still in
+// flight a new one is created, so the ring grows to what the busiest frames need.
+use anyhow::*;
+
+use super::{
+    resources::FrameFence,
+    stats::{self, Tracked},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RingKind {
+    Uniform,
+    // Read only in shaders, for arrays that don't fit a uniform binding
+    Storage,
+}
+
+// Where an allocation landed, `offset` is the dynamic offset to bind it with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RingAllocation {
+    block: usize,
+    pub offset: u32,
+    pub size: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RingStats {
+    pub blocks: usize,
+    // Bytes in all blocks, and the ones allocated since the last flush
+    pub capacity: u64,
+    pub used: u64,
+}
+
+struct Block {
+    buffer: Tracked<wgpu::Buffer>,
+    bind_group: wgpu::BindGroup,
+    // The last frame that used the block, it is free again once the GPU completed it
+    frame: u64,
+    // Written to the buffer on flush
+    staging: Vec<u8>,
+}
+
+pub struct RingBuffer {
+    kind: RingKind,
+    // Every binding spans this many bytes from its offset
+    binding_size: u64,
+    alignment: u64,
+    block_size: u64,
+    layout: wgpu::BindGroupLayout,
+    blocks: Vec<Block>,
+    // Blocks taken by the frame being recorded, the last one is filled next
+    current: Vec<usize>,
+    fence: FrameFence,
+    label: String,
+}
+
+impl RingBuffer {
+    // Smallest size of a block, more are added when a frame needs them
+    pub const BLOCK_SIZE: u64 = 64 * 1024;
+    // Largest storage binding `max_binding_size` picks, the adapter limit can be far more
+    pub const MAX_STORAGE_BINDING_SIZE: u64 = 256 * 1024;
+
+    // The largest binding the device allows for a kind of ring, 0 when it has none
+    pub fn max_binding_size(device: &wgpu::Device, kind: RingKind) -> u64 {
+        let limits = device.limits();
+        match kind {
+            RingKind::Uniform => limits.max_uniform_buffer_binding_size as u64,
+            RingKind::Storage if limits.max_storage_buffers_per_shader_stage == 0
+                || limits.max_dynamic_storage_buffers_per_pipeline_layout == 0 => 0,
+            RingKind::Storage => (limits.max_storage_buffer_binding_size as u64).min(Self::MAX_STORAGE_BINDING_SIZE),
+        }
+    }
+
+    // `binding_size` is the largest allocation, shaders see that much from every offset
+    pub fn new(
+        device: &wgpu::Device,
+        fence: FrameFence,
+        kind: RingKind,
+        binding_size: u64,
+        visibility: wgpu::ShaderStages,
+        label: &str,
+    ) -> Result<Self> {
+        let limits = device.limits();
+        let (alignment, max_binding_size) = match kind {
+            RingKind::Uniform => (limits.min_uniform_buffer_offset_alignment, limits.max_uniform_buffer_binding_size as u64),
+            RingKind::Storage => (limits.min_storage_buffer_offset_alignment, limits.max_storage_buffer_binding_size as u64),
+        };
+        ensure!(binding_size > 0 && binding_size <= max_binding_size, "{} bindings of {} bytes are out of the adapter's range 1..={}", label, binding_size, max_binding_size);
+
+        let ty = match kind {
+            RingKind::Uniform => wgpu::BufferBindingType::Uniform,
+            RingKind::Storage => wgpu::BufferBindingType::Storage { read_only: true },
+        };
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility,
+                ty: wgpu::BindingType::Buffer {
+                    ty,
+                    has_dynamic_offset: true,
+                    min_binding_size: wgpu::BufferSize::new(binding_size),
+                },
+                count: None,
+            }],
+            label: Some("ring_bind_group_layout"),
+        });
+
+        Ok(Self {
+            kind,
+            binding_size,
+            alignment: alignment as u64,
+            // Room for a few full bindings, so large ones don't take a block each
+            block_size: Self::BLOCK_SIZE.max(4 * binding_size.next_power_of_two()),
+            layout,
+            blocks: Vec::new(),
+            current: Vec::new(),
+            fence,
+            label: label.to_string(),
+        })
+    }
+
+    // For the pipeline layouts of shaders reading the allocations
+    pub fn layout(&self) -> &wgpu::BindGroupLayout {
+        &self.layout
+    }
+
+    pub fn binding_size(&self) -> u64 {
+        self.binding_size
+    }
+
+    pub fn alloc<T: bytemuck::Pod>(&mut self, device: &wgpu::Device, value: &T) -> Result<RingAllocation> {
+        self.alloc_bytes(device, bytemuck::bytes_of(value))
+    }
+
+    // Copies `bytes` into the frame's blocks, they reach the GPU with the next flush
+    pub fn alloc_bytes(&mut self, device: &wgpu::Device, bytes: &[u8]) -> Result<RingAllocation> {
+        ensure!(bytes.len() as u64 <= self.binding_size, "{} bytes don't fit the {} bindings of {}", bytes.len(), self.label, self.binding_size);
+
+        let fits = |block: &Block| {
+            let offset = (block.staging.len() as u64).next_multiple_of(self.alignment);
+            // The whole binding has to stay inside the buffer
+            (offset + self.binding_size <= self.block_size).then_some(offset)
+        };
+        let (block, offset) = match self.current.last().and_then(|&block| Some((block, fits(&self.blocks[block])?))) {
+            Some(found) => found,
+            None => (self.take_block(device), 0),
+        };
+
+        let staging = &mut self.blocks[block].staging;
+        staging.resize(offset as usize, 0);
+        staging.extend_from_slice(bytes);
+        Ok(RingAllocation { block, offset: offset as u32, size: bytes.len() as u32 })
+    }
+
+    pub fn bind_group(&self, allocation: &RingAllocation) -> &wgpu::BindGroup {
+        &self.blocks[allocation.block].bind_group
+    }
+
+    pub fn buffer(&self, allocation: &RingAllocation) -> &wgpu::Buffer {
+        &self.blocks[allocation.block].buffer
+    }
+
+    // Uploads the frame's allocations, call it once before submitting the frame using them
+    // The blocks are held until the fence reports that frame as done
+    pub fn flush(&mut self, queue: &wgpu::Queue) {
+        let frame = self.fence.last_submitted() + 1;
+        for index in self.current.drain(..) {
+            let block = &mut self.blocks[index];
+            if !block.staging.is_empty() {
+                stats::write_buffer(queue, &block.buffer, 0, &block.staging);
+                block.staging.clear();
+            }
+            block.frame = frame;
+        }
+    }
+
+    pub fn stats(&self) -> RingStats {
+        RingStats {
+            blocks: self.blocks.len(),
+            capacity: self.blocks.len() as u64 * self.block_size,
+            used: self.current.iter().map(|&block| self.blocks[block].staging.len() as u64).sum(),
+        }
+    }
+
+    // A block no frame in flight uses, or a new one when there is none
+    fn take_block(&mut self, device: &wgpu::Device) -> usize {
+        let completed = self.fence.last_completed();
+        let free = (0..self.blocks.len())
+            .find(|block| self.blocks[*block].frame <= completed && !self.current.contains(block));
+        let block = match free {
+            Some(block) => block,
+            None => {
+                self.blocks.push(self.create_block(device));
+                self.blocks.len() - 1
+            },
+        };
+        self.current.push(block);
+        block
+    }
+
+    fn create_block(&self, device: &wgpu::Device) -> Block {
+        let usage = match self.kind {
+            RingKind::Uniform => wgpu::BufferUsages::UNIFORM,
+            RingKind::Storage => wgpu::BufferUsages::STORAGE,
+        };
+        let buffer = stats::track_buffer(device, device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(&self.label),
+            size: self.block_size,
+            usage: usage | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        }));
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &buffer,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(self.binding_size),
+                }),
+            }],
+            label: Some("ring_bind_group"),
+        });
+        Block { buffer, bind_group, frame: 0, staging: Vec::new() }
+    }
+}
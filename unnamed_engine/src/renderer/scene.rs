@@ -0,0 +1,617 @@
+// Meshes and the objects drawn with them, culled against the camera every frame
+//
+// Static objects are added once and live in a BVH that is rebuilt when they change.
+// Dynamic ones are queued with `draw` every frame and tested one by one.
+// Meshes with a LOD chain are swapped for the level matching their size on screen,
+// static objects remember their level for hysteresis and cross-fades.
+// With occlusion culling on, static objects whose bounding box proxies drew no samples in an
+// earlier frame are skipped until a later query sees them again.
+// Visible objects are queued by blend mode, opaque ones front to back and batched per mesh,
+// transparent ones back to front and only batched while the same mesh follows itself.
+use std::{collections::HashMap, ops::Range};
+
+use cgmath::{InnerSpace, Matrix4, Vector4};
+use wgpu::util::DeviceExt;
+
+use super::{
+    bounds::{Aabb, BoundingSphere},
+    bvh::Bvh,
+    camera::Camera,
+    debug_draw,
+    lod::{self, LodChain, LodLevel, LodSettings, LodState},
+    mesh::{InstanceRaw, Mesh, Vertex},
+    picking::EntityId,
+    resources::{FrameFence, MeshHandle, ResourcePool},
+    stats::{self, Tracked, TrackedRenderPass},
+    transparency::BlendMode,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjectId(usize);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CullingStats {
+    // Static and dynamic objects submitted this frame
+    pub objects: u32,
+    pub visible: u32,
+    pub culled: u32,
+    pub bvh_nodes_visited: u32,
+    // Objects whose own bounds were tested, the rest was decided by the BVH
+    pub objects_tested: u32,
+    pub draw_calls: u32,
+    // Inside the frustum but hidden according to earlier occlusion queries, counted as culled
+    pub occluded: u32,
+    // Visible objects drawn as impostors, and ones dithering between two levels
+    pub impostors: u32,
+    pub lod_transitions: u32,
+}
+
+struct SceneObject {
+    mesh: MeshHandle,
+    transform: Matrix4<f32>,
+    aabb: Aabb,
+    sphere: BoundingSphere,
+    lod: LodState,
+    entity: Option<EntityId>,
+    blend: BlendMode,
+    color: [f32; 4],
+    // No samples of its bounding box passed the depth test in the last query result
+    occluded: bool,
+}
+
+struct DynamicDraw {
+    mesh: MeshHandle,
+    transform: Matrix4<f32>,
+    entity: Option<EntityId>,
+    blend: BlendMode,
+    color: [f32; 4],
+}
+
+struct VisibleObject {
+    mesh: MeshHandle,
+    transform: Matrix4<f32>,
+    aabb: Aabb,
+    sphere: BoundingSphere,
+    // Index of the static object, dynamic draws have no LOD history
+    object: Option<usize>,
+    entity: Option<EntityId>,
+    blend: BlendMode,
+    color: [f32; 4],
+}
+
+struct DrawBatch {
+    mesh: MeshHandle,
+    blend: BlendMode,
+    instances: Range<u32>,
+}
+
+// What a mesh was created from, to upload it again
+struct MeshSource {
+    vertices: Vec<Vertex>,
+    indices: Vec<u32>,
+    label: String,
+}
+
+pub struct Scene {
+    meshes: ResourcePool<Mesh>,
+    // Kept on the CPU for the barycentric wireframe and for recreating the device
+    sources: HashMap<MeshHandle, MeshSource>,
+    objects: Vec<Option<SceneObject>>,
+    bvh: Bvh,
+    bvh_dirty: bool,
+    dynamic: Vec<DynamicDraw>,
+    lods: HashMap<MeshHandle, LodChain>,
+    pub lod_settings: LodSettings,
+    // Turned off everything submitted is drawn, handy to check culling artifacts
+    pub culling_enabled: bool,
+    // Outlines the bounds of every visible object with debug lines
+    pub show_bounds: bool,
+    // Cutout objects discard pixels with less alpha
+    pub alpha_cutoff: f32,
+    occlusion_culling: bool,
+    // Static objects in the frustum this frame, whose bounding boxes are queried
+    occlusion_candidates: Vec<(ObjectId, Aabb)>,
+    instance_buffer: Tracked<wgpu::Buffer>,
+    // In instances
+    instance_capacity: usize,
+    batches: Vec<DrawBatch>,
+    stats: CullingStats,
+}
+
+impl Scene {
+    // Meshes are destroyed once `fence` says the frames drawing them are done
+    pub fn new(device: &wgpu::Device, fence: FrameFence) -> Self {
+        let instance_capacity = 256;
+
+        Self {
+            meshes: ResourcePool::new("Mesh", fence),
+            sources: HashMap::new(),
+            objects: Vec::new(),
+            bvh: Bvh::default(),
+            bvh_dirty: false,
+            dynamic: Vec::new(),
+            lods: HashMap::new(),
+            lod_settings: LodSettings::default(),
+            culling_enabled: true,
+            show_bounds: false,
+            alpha_cutoff: 0.5,
+            occlusion_culling: false,
+            occlusion_candidates: Vec::new(),
+            instance_buffer: Self::create_instance_buffer(device, instance_capacity),
+            instance_capacity,
+            batches: Vec::new(),
+            stats: CullingStats::default(),
+        }
+    }
+
+    // The mesh starts with one reference, held by the caller until `release_mesh`
+    pub fn add_mesh(&mut self, device: &wgpu::Device, vertices: &[Vertex], indices: &[u32], label: &str) -> MeshHandle {
+        let mesh = self.meshes.insert(Mesh::new(device, vertices, indices, label), label);
+        self.sources.insert(mesh, MeshSource { vertices: vertices.to_vec(), indices: indices.to_vec(), label: label.to_string() });
+        mesh
+    }
+
+    // None once the mesh was released for good
+    pub fn mesh(&self, mesh: MeshHandle) -> Option<&Mesh> {
+        self.meshes.get(mesh)
+    }
+
+    pub fn meshes(&self) -> &ResourcePool<Mesh> {
+        &self.meshes
+    }
+
+    pub fn retain_mesh(&mut self, mesh: MeshHandle) -> bool {
+        self.meshes.retain(mesh)
+    }
+
+    // Objects hold references of their own, so a mesh stays until the last of them is removed
+    // Its LOD chain goes with it and releases the levels
+    pub fn release_mesh(&mut self, mesh: MeshHandle) {
+        let mut released = vec![mesh];
+        while let Some(mesh) = released.pop() {
+            if self.meshes.release(mesh) {
+                self.sources.remove(&mesh);
+                if let Some(chain) = self.lods.remove(&mesh) {
+                    released.extend(chain.meshes().filter(|&level| level != mesh));
+                }
+            }
+        }
+    }
+
+    // Objects drawn with `base` switch between these levels, the base mesh is usually the first
+    // The chain holds a reference to every level but the base, and releases them with the base
+    pub fn set_lods(&mut self, base: MeshHandle, chain: LodChain) {
+        for mesh in chain.meshes().filter(|&mesh| mesh != base) {
+            self.meshes.retain(mesh);
+        }
+        let previous = if chain.is_empty() {
+            self.lods.remove(&base)
+        } else {
+            self.lods.insert(base, chain)
+        };
+        for mesh in previous.iter().flat_map(LodChain::meshes).filter(|&mesh| mesh != base) {
+            self.release_mesh(mesh);
+        }
+    }
+
+    pub fn lods(&self, base: MeshHandle) -> Option<&LodChain> {
+        self.lods.get(&base)
+    }
+
+    // Uploads a mesh together with `levels` coarser versions made by `lod::simplify`
+    // Each level halves the grid resolution and the screen size it is used from
+    pub fn add_mesh_with_lods(
+        &mut self,
+        device: &wgpu::Device,
+        vertices: &[Vertex],
+        indices: &[u32],
+        label: &str,
+        levels: u32,
+    ) -> MeshHandle {
+        let base = self.add_mesh(device, vertices, indices, label);
+        let mut chain = vec![LodLevel::new(base, 0.5)];
+        for level in 1..=levels {
+            let (vertices, indices) = lod::simplify(vertices, indices, 64 >> level.min(5));
+            if indices.is_empty() {
+                break;
+            }
+            let mesh = self.add_mesh(device, &vertices, &indices, &format!("{} LOD {}", label, level));
+            chain.push(LodLevel::new(mesh, 0.5 / (1 << level) as f32));
+        }
+        let levels: Vec<MeshHandle> = chain[1..].iter().map(|level| level.mesh).collect();
+        self.set_lods(base, LodChain::new(chain));
+        // The chain is the only owner of the coarser levels
+        for mesh in levels {
+            self.meshes.release(mesh);
+        }
+        base
+    }
+
+    // Adds an object that stays in the world until it is removed, keeping its mesh alive
+    // None when the mesh was already released
+    pub fn add_object(&mut self, mesh: MeshHandle, transform: Matrix4<f32>) -> Option<ObjectId> {
+        let object = self.create_object(mesh, transform)?;
+        self.meshes.retain(mesh);
+        self.objects.push(Some(object));
+        self.bvh_dirty = true;
+        Some(ObjectId(self.objects.len() - 1))
+    }
+
+    // The setters return false for removed objects
+    pub fn set_transform(&mut self, id: ObjectId, transform: Matrix4<f32>) -> bool {
+        let Some(object) = self.objects.get(id.0).and_then(Option::as_ref) else { return false };
+        // Objects hold a reference to their mesh, so it is always there
+        let Some(moved) = self.create_object(object.mesh, transform) else { return false };
+        let (lod, entity, blend, color) = (object.lod, object.entity, object.blend, object.color);
+        self.objects[id.0] = Some(SceneObject { lod, entity, blend, color, ..moved });
+        self.bvh_dirty = true;
+        true
+    }
+
+    // What picking returns for the object, objects without an entity can't be picked
+    pub fn set_entity(&mut self, id: ObjectId, entity: Option<EntityId>) -> bool {
+        self.object_mut(id).map(|object| object.entity = entity).is_some()
+    }
+
+    pub fn set_blend(&mut self, id: ObjectId, blend: BlendMode) -> bool {
+        self.object_mut(id).map(|object| object.blend = blend).is_some()
+    }
+
+    // Multiplies the texture color, the alpha matters for every blend mode but opaque
+    pub fn set_color(&mut self, id: ObjectId, color: [f32; 4]) -> bool {
+        self.object_mut(id).map(|object| object.color = color).is_some()
+    }
+
+    pub fn remove_object(&mut self, id: ObjectId) {
+        if let Some(object) = self.objects.get_mut(id.0).and_then(Option::take) {
+            self.release_mesh(object.mesh);
+            self.bvh_dirty = true;
+        }
+    }
+
+    fn object_mut(&mut self, id: ObjectId) -> Option<&mut SceneObject> {
+        self.objects.get_mut(id.0).and_then(Option::as_mut)
+    }
+
+    // Queues a mesh for the next prepared frame only
+    pub fn draw(&mut self, mesh: MeshHandle, transform: Matrix4<f32>) {
+        self.draw_blended(mesh, transform, BlendMode::Opaque, [1.0; 4]);
+    }
+
+    // Like `draw`, but picking returns `entity` for it
+    pub fn draw_entity(&mut self, mesh: MeshHandle, transform: Matrix4<f32>, entity: EntityId) {
+        self.dynamic.push(DynamicDraw { mesh, transform, entity: Some(entity), blend: BlendMode::Opaque, color: [1.0; 4] });
+    }
+
+    // Like `draw` with a blend mode and color, e.g. for ghost previews of placements
+    pub fn draw_blended(&mut self, mesh: MeshHandle, transform: Matrix4<f32>, blend: BlendMode, color: [f32; 4]) {
+        self.dynamic.push(DynamicDraw { mesh, transform, entity: None, blend, color });
+    }
+
+    // Numbers of the last prepared frame
+    pub fn stats(&self) -> CullingStats {
+        self.stats
+    }
+
+    fn create_object(&self, mesh: MeshHandle, transform: Matrix4<f32>) -> Option<SceneObject> {
+        let model = self.meshes.get(mesh)?;
+        Some(SceneObject {
+            mesh,
+            transform,
+            aabb: model.aabb.transform(&transform),
+            sphere: model.sphere.transform(&transform),
+            lod: LodState::default(),
+            entity: None,
+            blend: BlendMode::Opaque,
+            color: [1.0; 4],
+            occluded: false,
+        })
+    }
+
+    // Skips static objects that earlier occlusion queries found hidden, see `apply_occlusion`
+    // Turning it off shows every object again
+    pub fn set_occlusion_culling(&mut self, enabled: bool) {
+        self.occlusion_culling = enabled;
+        if !enabled {
+            self.occlusion_candidates.clear();
+            self.objects.iter_mut().flatten().for_each(|object| object.occluded = false);
+        }
+    }
+
+    pub fn occlusion_culling(&self) -> bool {
+        self.occlusion_culling
+    }
+
+    // Objects whose bounding boxes should be tested against this frame's depth
+    pub fn occlusion_candidates(&self) -> &[(ObjectId, Aabb)] {
+        &self.occlusion_candidates
+    }
+
+    // Takes query results of earlier frames, with the box that was tested and whether any of
+    // its samples passed. Results for objects that moved since are ignored
+    pub fn apply_occlusion(&mut self, results: &[(ObjectId, Aabb, bool)]) {
+        if !self.occlusion_culling {
+            return;
+        }
+        for &(id, aabb, visible) in results {
+            if let Some(object) = self.objects.get_mut(id.0).and_then(Option::as_mut).filter(|object| object.aabb == aabb) {
+                object.occluded = !visible;
+            }
+        }
+    }
+
+    // Culls against the camera, picks LOD levels and uploads the instances
+    // `dt` in seconds advances the LOD cross-fades
+    pub fn prepare(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, camera: &Camera, dt: f32) {
+        if self.bvh_dirty {
+            self.bvh = Bvh::build(self.objects.iter().enumerate()
+                .filter_map(|(i, object)| Some((i, object.as_ref()?.aabb))));
+            self.bvh_dirty = false;
+        }
+
+        let frustum = camera.frustum();
+        let mut stats = CullingStats {
+            objects: (self.bvh.len() + self.dynamic.len()) as u32,
+            ..Default::default()
+        };
+        let mut visible: Vec<VisibleObject> = Vec::new();
+
+        let objects = &self.objects;
+        let candidates = &mut self.occlusion_candidates;
+        candidates.clear();
+        let occlusion_culling = self.occlusion_culling && self.culling_enabled;
+        // Boxes this close to the camera are clipped by the near plane, they are always drawn
+        let near_margin = camera.znear * 2.0;
+        let mut visit = |i: usize| {
+            let object = objects[i].as_ref().unwrap();
+            if occlusion_culling && !object.aabb.inflated(near_margin).contains(camera.eye) {
+                candidates.push((ObjectId(i), object.aabb));
+                if object.occluded {
+                    stats.occluded += 1;
+                    return;
+                }
+            }
+            visible.push(VisibleObject {
+                mesh: object.mesh,
+                transform: object.transform,
+                aabb: object.aabb,
+                sphere: object.sphere,
+                object: Some(i),
+                entity: object.entity,
+                blend: object.blend,
+                color: object.color,
+            });
+        };
+        if self.culling_enabled {
+            let query = self.bvh.query_frustum(&frustum, &mut visit);
+            stats.bvh_nodes_visited = query.nodes_visited;
+            stats.objects_tested = query.tested;
+        } else {
+            objects.iter().enumerate().filter(|(_, object)| object.is_some()).for_each(|(i, _)| visit(i));
+        }
+
+        for DynamicDraw { mesh, transform, entity, blend, color } in self.dynamic.drain(..) {
+            // Released since it was queued
+            let Some(model) = self.meshes.get(mesh) else { continue };
+            let aabb = model.aabb.transform(&transform);
+            let sphere = model.sphere.transform(&transform);
+            if self.culling_enabled {
+                stats.objects_tested += 1;
+                // The sphere rejects most objects before the tighter box test
+                if !frustum.intersects_sphere(&sphere) || !frustum.intersects_aabb(&aabb) {
+                    continue;
+                }
+            }
+            visible.push(VisibleObject { mesh, transform, aabb, sphere, object: None, entity, blend, color });
+        }
+
+        stats.visible = visible.len() as u32;
+        stats.culled = stats.objects - stats.visible;
+
+        if self.show_bounds {
+            for object in visible.iter() {
+                debug_draw::aabb(object.aabb.min, object.aabb.max, debug_draw::YELLOW);
+            }
+        }
+
+        let mut draws: Vec<Draw> = Vec::with_capacity(visible.len());
+        for object in visible {
+            let entity = object.entity.map_or(0, |entity| entity.0);
+            let alpha_cutoff = if object.blend == BlendMode::Cutout { self.alpha_cutoff } else { 0.0 };
+            let draw = |mesh: MeshHandle, instance: InstanceRaw| Draw {
+                mesh,
+                blend: object.blend,
+                distance: (object.sphere.center - camera.eye).magnitude(),
+                instance: instance.with_entity(entity).with_color(object.color).with_alpha_cutoff(alpha_cutoff),
+            };
+            let Some(chain) = self.lods.get(&object.mesh) else {
+                draws.push(draw(object.mesh, InstanceRaw::new(object.transform)));
+                continue;
+            };
+
+            let screen_size = screen_size(camera, &object.sphere) * self.lod_settings.bias;
+            let state = match object.object {
+                Some(i) => {
+                    let state = &mut self.objects[i].as_mut().unwrap().lod;
+                    let level = chain.select(screen_size, state.level, self.lod_settings.hysteresis);
+                    state.advance(level, dt, &self.lod_settings);
+                    *state
+                },
+                None => LodState {
+                    level: Some(chain.select(screen_size, None, 0.0)),
+                    previous: None,
+                    fade: 1.0,
+                },
+            };
+
+            let level = state.level.unwrap();
+            if chain.is_impostor(level) {
+                stats.impostors += 1;
+            }
+            let instance = |level: usize| {
+                let transform = if chain.is_impostor(level) {
+                    impostor_transform(camera, &object.sphere)
+                } else {
+                    object.transform
+                };
+                (chain.level(level).mesh, InstanceRaw::new(transform))
+            };
+
+            // The outgoing level dithers out exactly where the incoming one dithers in
+            let (mesh, incoming) = instance(level);
+            draws.push(draw(mesh, incoming.with_fade(state.fade)));
+            if let Some(previous) = state.previous {
+                stats.lod_transitions += 1;
+                let (mesh, outgoing) = instance(previous);
+                draws.push(draw(mesh, outgoing.with_fade(state.fade - 1.0)));
+            }
+        }
+
+        let (mut transparent, mut opaque): (Vec<Draw>, Vec<Draw>) = draws.into_iter().partition(|draw| draw.blend.is_transparent());
+        // Opaque meshes front to back by their closest instance, cutouts after all opaque ones
+        // as discarding pixels defeats early depth testing
+        opaque.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        let mut order: HashMap<(BlendMode, MeshHandle), usize> = HashMap::new();
+        for draw in opaque.iter() {
+            let next = order.len();
+            order.entry((draw.blend, draw.mesh)).or_insert(next);
+        }
+        opaque.sort_by_key(|draw| (draw.blend == BlendMode::Cutout, order[&(draw.blend, draw.mesh)]));
+        // Transparent ones strictly back to front
+        transparent.sort_by(|a, b| b.distance.total_cmp(&a.distance));
+
+        self.batches.clear();
+        let mut instances = Vec::with_capacity(opaque.len() + transparent.len());
+        for Draw { mesh, blend, instance, .. } in opaque.into_iter().chain(transparent) {
+            let index = instances.len() as u32;
+            match self.batches.last_mut() {
+                Some(batch) if batch.mesh == mesh && batch.blend == blend => batch.instances.end += 1,
+                _ => self.batches.push(DrawBatch { mesh, blend, instances: index..index + 1 }),
+            }
+            instances.push(instance);
+        }
+        stats.draw_calls = self.batches.len() as u32;
+        self.stats = stats;
+
+        if instances.len() > self.instance_capacity {
+            self.instance_capacity = instances.len().next_power_of_two();
+            self.instance_buffer = Self::create_instance_buffer(device, self.instance_capacity);
+        }
+        if !instances.is_empty() {
+            stats::write_buffer(queue, &self.instance_buffer, 0, bytemuck::cast_slice(&instances));
+        }
+    }
+
+    // Draws the batches in queue order, `pipeline` picks the pipeline for each blend mode
+    // Batches it returns None for are skipped, the bind groups must be set already
+    pub fn render_queues<'a>(
+        &'a self,
+        render_pass: &mut TrackedRenderPass<'a>,
+        mut pipeline: impl FnMut(BlendMode) -> Option<&'a wgpu::RenderPipeline>,
+    ) {
+        render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+        for batch in self.batches.iter() {
+            let Some(pipeline) = pipeline(batch.blend) else { continue };
+            let Some(mesh) = self.meshes.get(batch.mesh) else { continue };
+            render_pass.set_pipeline(pipeline, wgpu::PrimitiveTopology::TriangleList);
+            render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+            render_pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+            render_pass.draw_indexed(0..mesh.num_indices, 0, batch.instances.clone());
+        }
+    }
+
+    // Whether any visible object has a blend mode, e.g. to skip passes without them
+    pub fn has_visible(&self, mut blend: impl FnMut(BlendMode) -> bool) -> bool {
+        self.batches.iter().any(|batch| blend(batch.blend))
+    }
+
+    // Draws every batch with the pipeline and bind groups that are set already
+    pub fn render<'a>(&'a self, render_pass: &mut TrackedRenderPass<'a>) {
+        render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+        for batch in self.batches.iter() {
+            // Meshes released after prepare are skipped
+            let Some(mesh) = self.meshes.get(batch.mesh) else { continue };
+            render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+            render_pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+            render_pass.draw_indexed(0..mesh.num_indices, 0, batch.instances.clone());
+        }
+    }
+
+    // Uploads the unrolled triangles `render_unrolled` draws
+    pub fn prepare_unrolled(&mut self, device: &wgpu::Device) {
+        for (handle, mesh) in self.meshes.iter_mut() {
+            let source = &self.sources[&handle];
+            mesh.prepare_unrolled(device, &source.vertices, &source.indices);
+        }
+    }
+
+    // Uploads every mesh and the instances to another device, after the old one was lost
+    // Objects, LOD chains and mesh handles stay as they are
+    pub fn recreate(&mut self, device: &wgpu::Device, fence: FrameFence) {
+        self.meshes.set_fence(fence);
+        for (handle, mesh) in self.meshes.iter_mut() {
+            let source = &self.sources[&handle];
+            *mesh = Mesh::new(device, &source.vertices, &source.indices, &source.label);
+        }
+        self.instance_buffer = Self::create_instance_buffer(device, self.instance_capacity);
+        // Nothing is drawn until the next prepare fills the new instance buffer
+        self.batches.clear();
+    }
+
+    // Same as `render` with every triangle drawn from its own three vertices, for shaders
+    // that need barycentric coordinates. Meshes without unrolled triangles are skipped
+    pub fn render_unrolled<'a>(&'a self, render_pass: &mut TrackedRenderPass<'a>) {
+        render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+        for batch in self.batches.iter() {
+            let Some(mesh) = self.meshes.get(batch.mesh) else { continue };
+            if let Some(buffer) = mesh.unrolled_buffer() {
+                render_pass.set_vertex_buffer(0, buffer.slice(..));
+                render_pass.draw(0..mesh.num_indices, batch.instances.clone());
+            }
+        }
+    }
+
+    // Drops released meshes the GPU is done with
+    pub fn collect(&mut self) -> usize {
+        self.meshes.collect()
+    }
+
+    fn create_instance_buffer(device: &wgpu::Device, instances: usize) -> Tracked<wgpu::Buffer> {
+        stats::track_buffer(device, device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Instance Buffer"),
+            contents: bytemuck::cast_slice(&vec![InstanceRaw::new(cgmath::SquareMatrix::identity()); instances]),
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        }))
+    }
+}
+
+struct Draw {
+    mesh: MeshHandle,
+    blend: BlendMode,
+    // From the camera to the center of the bounds
+    distance: f32,
+    instance: InstanceRaw,
+}
+
+// Fraction of the screen height covered by the sphere
+fn screen_size(camera: &Camera, sphere: &BoundingSphere) -> f32 {
+    let distance = (sphere.center - camera.eye).magnitude();
+    if distance <= sphere.radius {
+        return f32::INFINITY;
+    }
+    sphere.radius / (distance * (camera.fovy.to_radians() * 0.5).tan())
+}
+
+// Places a unit quad over the sphere, facing the camera
+fn impostor_transform(camera: &Camera, sphere: &BoundingSphere) -> Matrix4<f32> {
+    let forward = (sphere.center - camera.eye).normalize();
+    let right = forward.cross(camera.up).normalize();
+    let up = right.cross(forward);
+    let size = sphere.radius * 2.0;
+    Matrix4::from_cols(
+        (right * size).extend(0.0),
+        (up * size).extend(0.0),
+        (-forward).extend(0.0),
+        Vector4::new(sphere.center.x, sphere.center.y, sphere.center.z, 1.0),
+    )
+}
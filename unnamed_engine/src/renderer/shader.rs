@@ -0,0 +1,379 @@
+// Runtime shader loading with a small WGSL preprocessor
+//
+// Shaders are read from the shader directory so they can be edited while the engine
+// runs, the copies embedded at build time are only used when a file is missing.
+// The directory is found at runtime, see `shader_dir`; debug builds hot reload the engine's
+// shaders from the checkout they were built from, `UNNAMED_SHADER_DIR` overrides it.
+// Supported directives, each on its own line:
+//
+//     #include "common/camera.wgsl"   pasted once, paths are relative to the shader directory
+//     #define NAME [value]             later occurrences of NAME are replaced by value
+//     #undef NAME
+//     #ifdef NAME / #ifndef NAME / #else / #endif
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+pub const SHADER_DIR_ENV: &str = "UNNAMED_SHADER_DIR";
+// Relative to the executable or the working directory
+pub const SHADER_DIR: &str = "res/shaders";
+
+// How often the shader directory is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+const MAX_INCLUDE_DEPTH: usize = 32;
+
+const EMBEDDED: &[(&str, &str)] = &[
+    ("shader.wgsl", include_str!("../../res/shaders/shader.wgsl")),
+    ("sprite.wgsl", include_str!("../../res/shaders/sprite.wgsl")),
+    ("text.wgsl", include_str!("../../res/shaders/text.wgsl")),
+    ("debug_draw.wgsl", include_str!("../../res/shaders/debug_draw.wgsl")),
+    ("skybox.wgsl", include_str!("../../res/shaders/skybox.wgsl")),
+    ("picking.wgsl", include_str!("../../res/shaders/picking.wgsl")),
+    ("particles.wgsl", include_str!("../../res/shaders/particles.wgsl")),
+    ("particles_simulate.wgsl", include_str!("../../res/shaders/particles_simulate.wgsl")),
+    ("common/camera.wgsl", include_str!("../../res/shaders/common/camera.wgsl")),
+    ("common/particles.wgsl", include_str!("../../res/shaders/common/particles.wgsl")),
+    ("common/lighting.wgsl", include_str!("../../res/shaders/common/lighting.wgsl")),
+    ("blit.wgsl", include_str!("../../res/shaders/blit.wgsl")),
+    ("oit_resolve.wgsl", include_str!("../../res/shaders/oit_resolve.wgsl")),
+    ("gpu_cull.wgsl", include_str!("../../res/shaders/gpu_cull.wgsl")),
+    ("hi_z.wgsl", include_str!("../../res/shaders/hi_z.wgsl")),
+    ("occlusion.wgsl", include_str!("../../res/shaders/occlusion.wgsl")),
+    ("deferred.wgsl", include_str!("../../res/shaders/deferred.wgsl")),
+    ("ssao.wgsl", include_str!("../../res/shaders/ssao.wgsl")),
+];
+
+// The copy of a shader built into the binary, for internal passes that are never reloaded
+pub fn embedded(name: &str) -> Option<&'static str> {
+    EMBEDDED.iter().find(|(embedded, _)| *embedded == name).map(|(_, source)| *source)
+}
+
+// Where a line of preprocessed source came from, `line` is 1-based
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+    pub file: String,
+    pub line: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct PreprocessedShader {
+    pub source: String,
+    // One entry per line of `source`
+    pub lines: Vec<SourceLine>,
+    // Every file the source was built from, starting with the shader itself
+    pub files: Vec<String>,
+}
+
+impl PreprocessedShader {
+    // Maps a line of the preprocessed source back to the file it was written in
+    pub fn source_line(&self, line: u32) -> Option<&SourceLine> {
+        self.lines.get(line.checked_sub(1)? as usize)
+    }
+
+    fn describe(&self, location: Option<naga::SourceLocation>) -> String {
+        match location.and_then(|location| Some((self.source_line(location.line_number)?, location))) {
+            Some((origin, location)) => format!("{}:{}:{}", origin.file, origin.line, location.line_position),
+            None => self.files[0].clone(),
+        }
+    }
+}
+
+struct Condition {
+    // Whether lines under this condition are emitted
+    active: bool,
+    // Whether the enclosing block is emitted, `#else` can't turn lines on inside a dead block
+    parent_active: bool,
+    seen_else: bool,
+    opened_at: u32,
+}
+
+struct Preprocessor<'a> {
+    read: &'a mut dyn FnMut(&str) -> Result<String>,
+    defines: HashMap<String, String>,
+    included: HashSet<String>,
+    out: PreprocessedShader,
+}
+
+impl Preprocessor<'_> {
+    fn process(&mut self, file: &str, depth: usize) -> Result<()> {
+        if depth > MAX_INCLUDE_DEPTH {
+            bail!("Includes nested deeper than {} levels at {}", MAX_INCLUDE_DEPTH, file);
+        }
+        if !self.included.insert(file.to_string()) {
+            return Ok(());
+        }
+        self.out.files.push(file.to_string());
+
+        let text = (self.read)(file)?;
+        let mut conditions: Vec<Condition> = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let number = i as u32 + 1;
+            let active = conditions.last().map_or(true, |c| c.active);
+            let trimmed = line.trim();
+            let Some(directive) = trimmed.strip_prefix('#') else {
+                if active {
+                    let line = self.substitute(line);
+                    self.out.source.push_str(&line);
+                    self.out.source.push('\n');
+                    self.out.lines.push(SourceLine { file: file.to_string(), line: number });
+                }
+                continue;
+            };
+
+            let (keyword, argument) = directive.split_once(char::is_whitespace).unwrap_or((directive, ""));
+            let argument = argument.trim();
+            let at = || format!("{}:{}", file, number);
+
+            match keyword {
+                "include" if active => {
+                    let path = argument.strip_prefix('"').and_then(|a| a.strip_suffix('"'))
+                        .ok_or_else(|| anyhow!("{}: expected #include \"path\"", at()))?;
+                    self.process(path, depth + 1).with_context(|| format!("included from {}", at()))?;
+                },
+                "define" if active => {
+                    let (name, value) = argument.split_once(char::is_whitespace).unwrap_or((argument, ""));
+                    if !is_identifier(name) {
+                        bail!("{}: invalid name in #define", at());
+                    }
+                    self.defines.insert(name.to_string(), value.trim().to_string());
+                },
+                "undef" if active => {
+                    self.defines.remove(argument);
+                },
+                "include" | "define" | "undef" => {},
+                "ifdef" | "ifndef" => {
+                    if !is_identifier(argument) {
+                        bail!("{}: invalid name in #{}", at(), keyword);
+                    }
+                    let defined = self.defines.contains_key(argument);
+                    conditions.push(Condition {
+                        active: active && (defined == (keyword == "ifdef")),
+                        parent_active: active,
+                        seen_else: false,
+                        opened_at: number,
+                    });
+                },
+                "else" => {
+                    let condition = conditions.last_mut()
+                        .ok_or_else(|| anyhow!("{}: #else without #ifdef", at()))?;
+                    if condition.seen_else {
+                        bail!("{}: second #else for the #ifdef on line {}", at(), condition.opened_at);
+                    }
+                    condition.seen_else = true;
+                    condition.active = condition.parent_active && !condition.active;
+                },
+                "endif" => {
+                    conditions.pop().ok_or_else(|| anyhow!("{}: #endif without #ifdef", at()))?;
+                },
+                _ => bail!("{}: unknown directive #{}", at(), keyword),
+            }
+        }
+
+        if let Some(condition) = conditions.last() {
+            bail!("{}:{}: #ifdef is never closed", file, condition.opened_at);
+        }
+
+        Ok(())
+    }
+
+    fn substitute(&self, line: &str) -> String {
+        if self.defines.values().all(|value| value.is_empty()) {
+            return line.to_string();
+        }
+
+        let mut out = String::with_capacity(line.len());
+        let mut rest = line;
+        while let Some(start) = rest.find(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            let (before, word_start) = rest.split_at(start);
+            let end = word_start.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(word_start.len());
+            let (word, after) = word_start.split_at(end);
+            out.push_str(before);
+            // Letters right after digits are a literal suffix like `1u` or `0x1f`
+            let inside_word = before.ends_with(|c: char| c.is_ascii_digit());
+            match self.defines.get(word) {
+                Some(value) if !value.is_empty() && !inside_word => out.push_str(value),
+                _ => out.push_str(word),
+            }
+            rest = after;
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Expands the directives of `name`, `read` returns the contents of a shader file by name
+pub fn preprocess(
+    name: &str,
+    defines: &[(&str, &str)],
+    mut read: impl FnMut(&str) -> Result<String>,
+) -> Result<PreprocessedShader> {
+    let mut preprocessor = Preprocessor {
+        read: &mut read,
+        defines: defines.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        included: HashSet::new(),
+        out: PreprocessedShader { source: String::new(), lines: Vec::new(), files: Vec::new() },
+    };
+    preprocessor.process(name, 0)?;
+    Ok(preprocessor.out)
+}
+
+// Parses and validates WGSL with naga, errors point at the original file and line
+pub fn validate(shader: &PreprocessedShader) -> Result<()> {
+    let module = naga::front::wgsl::parse_str(&shader.source).map_err(|e| {
+        anyhow!(
+            "{}: {}\n{}",
+            shader.describe(e.location(&shader.source)),
+            e,
+            e.emit_to_string_with_path(&shader.source, &shader.files[0])
+        )
+    })?;
+
+    naga::valid::Validator::new(naga::valid::ValidationFlags::all(), naga::valid::Capabilities::all())
+        .validate(&module)
+        .map_err(|e| {
+            anyhow!(
+                "{}: {}\n{}",
+                shader.describe(e.location(&shader.source)),
+                e.as_inner(),
+                e.emit_to_string_with_path(&shader.source, &shader.files[0])
+            )
+        })?;
+
+    Ok(())
+}
+
+// Runs `create` and turns any wgpu validation error it raised into an Err instead of a panic
+pub fn validated<T>(device: &wgpu::Device, create: impl FnOnce() -> T) -> Result<T> {
+    device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let value = create();
+    match pollster::block_on(device.pop_error_scope()) {
+        Some(error) => Err(anyhow!("{}", error)),
+        None => Ok(value),
+    }
+}
+
+// `UNNAMED_SHADER_DIR` when it is set, otherwise the first `res/shaders` that exists next
+// to the executable or in the working directory. Debug builds fall back to the engine's
+// source checkout. None leaves only the embedded shaders
+pub fn shader_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os(SHADER_DIR_ENV) {
+        return Some(dir.into());
+    }
+
+    let next_to_executable = std::env::current_exe().ok()
+        .and_then(|executable| executable.parent().map(|dir| dir.join(SHADER_DIR)));
+    let working_dir = std::env::current_dir().ok().map(|dir| dir.join(SHADER_DIR));
+    let checkout = cfg!(debug_assertions).then(|| PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/res/shaders")));
+    next_to_executable.into_iter().chain(working_dir).chain(checkout).find(|dir| dir.is_dir())
+}
+
+// Loads shaders from a directory and keeps track of the files they were built from
+pub struct ShaderLibrary {
+    root: Option<PathBuf>,
+    // Last seen modification time of every file read from disk
+    modified: HashMap<String, Option<SystemTime>>,
+    // Files each loaded shader depends on
+    dependencies: HashMap<String, Vec<String>>,
+    last_poll: Instant,
+}
+
+impl Default for ShaderLibrary {
+    // Reads from `shader_dir`, or only the embedded shaders when there is none
+    fn default() -> Self {
+        match shader_dir() {
+            Some(root) => Self::new(root),
+            None => {
+                log::warn!("No {} directory found, using the embedded shaders without hot reloading", SHADER_DIR);
+                Self::embedded()
+            },
+        }
+    }
+}
+
+impl ShaderLibrary {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: Some(root.as_ref().to_path_buf()),
+            modified: HashMap::new(),
+            dependencies: HashMap::new(),
+            last_poll: Instant::now(),
+        }
+    }
+
+    // Only uses the shaders built into the binary, nothing is watched
+    pub fn embedded() -> Self {
+        Self {
+            root: None,
+            ..Self::new("")
+        }
+    }
+
+    pub fn root(&self) -> Option<&Path> {
+        self.root.as_deref()
+    }
+
+    fn read(&mut self, name: &str) -> Result<String> {
+        if let Some(path) = self.root.as_ref().map(|root| root.join(name)).filter(|path| path.is_file()) {
+            let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+            self.modified.insert(name.to_string(), modified);
+            return std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read shader {}", path.display()));
+        }
+
+        embedded(name)
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("Shader {} not found", name))
+    }
+
+    // Preprocesses and validates a shader without creating a module
+    pub fn preprocess(&mut self, name: &str, defines: &[(&str, &str)]) -> Result<PreprocessedShader> {
+        let shader = preprocess(name, defines, |file| self.read(file))?;
+        self.dependencies.insert(name.to_string(), shader.files.clone());
+        validate(&shader)?;
+        Ok(shader)
+    }
+
+    pub fn load(&mut self, device: &wgpu::Device, name: &str, defines: &[(&str, &str)]) -> Result<wgpu::ShaderModule> {
+        let shader = self.preprocess(name, defines)?;
+        validated(device, || device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(name),
+            source: wgpu::ShaderSource::Wgsl(shader.source.into()),
+        }))
+    }
+
+    // Names of the loaded shaders whose files changed on disk since the last call
+    pub fn poll_changes(&mut self) -> Vec<String> {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return Vec::new();
+        }
+        self.last_poll = Instant::now();
+
+        let Some(root) = &self.root else { return Vec::new() };
+        let mut changed_files = HashSet::new();
+        for (name, modified) in self.modified.iter_mut() {
+            let current = std::fs::metadata(root.join(name)).and_then(|m| m.modified()).ok();
+            if current != *modified {
+                *modified = current;
+                changed_files.insert(name.as_str());
+            }
+        }
+
+        let mut changed: Vec<String> = self.dependencies.iter()
+            .filter(|(_, files)| files.iter().any(|file| changed_files.contains(file.as_str())))
+            .map(|(name, _)| name.clone())
+            .collect();
+        changed.sort();
+        changed
+    }
+}
@@ -0,0 +1,336 @@
+// Background behind the scene, a flat clear color, a cubemap or a procedural sky
+//
+// Cubemaps and the procedural sky are drawn as a fullscreen triangle at the far plane after
+// the opaque geometry, the depth test only lets it through where nothing else was drawn.
+use anyhow::*;
+use cgmath::{EuclideanSpace, Matrix4, Point3, SquareMatrix, Vector3};
+use wgpu::util::DeviceExt;
+
+use super::{
+    camera::{Camera, OPENGL_TO_WGPU_MATRIX},
+    shader,
+    stats::{self, Tracked, TrackedRenderPass},
+    texture::Texture,
+};
+
+const MODE_COLOR: u32 = 0;
+const MODE_CUBEMAP: u32 = 1;
+const MODE_PROCEDURAL: u32 = 2;
+
+pub enum Sky {
+    Color(wgpu::Color),
+    // Any cubemap texture, see `Texture::hdr_cubemap_from_images` and
+    // `Texture::cubemap_from_equirectangular` for HDR sources
+    Cubemap(Texture),
+    Procedural(ProceduralSky),
+}
+
+impl Default for Sky {
+    fn default() -> Self {
+        Self::Color(wgpu::Color { r: 0.1, g: 0.2, b: 0.3, a: 1.0 })
+    }
+}
+
+// Gradient from the ground over the horizon to the zenith with a sun disk and haze
+// Colors are linear
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProceduralSky {
+    pub zenith_color: [f32; 3],
+    pub horizon_color: [f32; 3],
+    pub ground_color: [f32; 3],
+    // Towards the sun, doesn't need to be normalized
+    pub sun_direction: Vector3<f32>,
+    pub sun_color: [f32; 3],
+    // Angular radius of the sun disk in degrees
+    pub sun_size: f32,
+}
+
+impl Default for ProceduralSky {
+    fn default() -> Self {
+        Self {
+            zenith_color: [0.1, 0.25, 0.6],
+            horizon_color: [0.6, 0.7, 0.85],
+            ground_color: [0.2, 0.18, 0.16],
+            sun_direction: Vector3::new(0.3, 0.4, -1.0),
+            sun_color: [1.0, 0.9, 0.7],
+            sun_size: 1.5,
+        }
+    }
+}
+
+impl ProceduralSky {
+    pub fn with_sun_direction(mut self, sun_direction: Vector3<f32>) -> Self {
+        self.sun_direction = sun_direction;
+        self
+    }
+
+    pub fn with_sun(mut self, color: [f32; 3], size: f32) -> Self {
+        self.sun_color = color;
+        self.sun_size = size;
+        self
+    }
+
+    pub fn with_colors(mut self, zenith: [f32; 3], horizon: [f32; 3], ground: [f32; 3]) -> Self {
+        self.zenith_color = zenith;
+        self.horizon_color = horizon;
+        self.ground_color = ground;
+        self
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct SkyUniform {
+    inv_view_proj: [[f32; 4]; 4],
+    zenith_color: [f32; 4],
+    horizon_color: [f32; 4],
+    ground_color: [f32; 4],
+    sun_direction: [f32; 4],
+    sun_color: [f32; 4],
+    mode: u32,
+    exposure: f32,
+    _padding: [u32; 2],
+}
+
+pub struct SkyboxRenderer {
+    pipeline: wgpu::RenderPipeline,
+    pipeline_layout: wgpu::PipelineLayout,
+    format: wgpu::TextureFormat,
+    uniform_buffer: Tracked<wgpu::Buffer>,
+    uniform_bind_group: wgpu::BindGroup,
+    texture_bind_group_layout: wgpu::BindGroupLayout,
+    texture_bind_group: wgpu::BindGroup,
+    // Bound while there is no cubemap, the layout always needs one
+    placeholder: Texture,
+    sky: Sky,
+    // Multiplies the sky color, for HDR cubemaps that are too bright
+    pub exposure: f32,
+}
+
+impl SkyboxRenderer {
+    pub const SHADER: &'static str = "skybox.wgsl";
+
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, shader: &wgpu::ShaderModule, format: wgpu::TextureFormat) -> Result<Self> {
+        let uniform_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("sky_uniform_bind_group_layout"),
+        });
+
+        let texture_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::Cube,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("sky_texture_bind_group_layout"),
+        });
+
+        let uniform: SkyUniform = bytemuck::Zeroable::zeroed();
+        let uniform_buffer = stats::track_buffer(device, device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Sky Uniform Buffer"),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        }));
+
+        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &uniform_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("sky_uniform_bind_group"),
+        });
+
+        let black = image::DynamicImage::ImageRgba32F(image::Rgba32FImage::new(1, 1));
+        let placeholder = Texture::hdr_cubemap_from_images(device, queue, &[0; 6].map(|_| black.clone()), Some("Sky Placeholder"))?;
+        let texture_bind_group = Self::create_texture_bind_group(device, &texture_bind_group_layout, &placeholder);
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Sky Pipeline Layout"),
+            bind_group_layouts: &[&uniform_bind_group_layout, &texture_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = Self::create_pipeline(device, &pipeline_layout, shader, format);
+
+        Ok(Self {
+            pipeline,
+            pipeline_layout,
+            format,
+            uniform_buffer,
+            uniform_bind_group,
+            texture_bind_group_layout,
+            texture_bind_group,
+            placeholder,
+            sky: Sky::default(),
+            exposure: 1.0,
+        })
+    }
+
+    pub fn reload_shader(&mut self, device: &wgpu::Device, shader: &wgpu::ShaderModule) -> Result<()> {
+        self.pipeline = shader::validated(device, || Self::create_pipeline(device, &self.pipeline_layout, shader, self.format))?;
+        Ok(())
+    }
+
+    fn create_pipeline(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        shader: &wgpu::ShaderModule,
+        format: wgpu::TextureFormat,
+    ) -> wgpu::RenderPipeline {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Sky Pipeline"),
+            layout: Some(layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                ..Default::default()
+            },
+            // Drawn where the depth buffer still holds the cleared far plane
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        })
+    }
+
+    fn create_texture_bind_group(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, texture: &Texture) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                },
+            ],
+            label: Some("sky_texture_bind_group"),
+        })
+    }
+
+    pub fn sky(&self) -> &Sky {
+        &self.sky
+    }
+
+    // Cubemaps must have six layers and a cube view
+    pub fn set_sky(&mut self, device: &wgpu::Device, sky: Sky) -> Result<()> {
+        let texture = match &sky {
+            Sky::Cubemap(texture) => {
+                let layers = texture.texture.depth_or_array_layers();
+                ensure!(layers == 6, "A sky cubemap needs 6 layers, got {}", layers);
+                texture
+            },
+            _ => &self.placeholder,
+        };
+        self.texture_bind_group = Self::create_texture_bind_group(device, &self.texture_bind_group_layout, texture);
+        self.sky = sky;
+        Ok(())
+    }
+
+    // Takes over the sky and exposure of a renderer from a lost device
+    // Cubemaps only live on the old device, the sky stays the default color then
+    pub fn restore_from(&mut self, device: &wgpu::Device, old: &SkyboxRenderer) -> Result<()> {
+        self.exposure = old.exposure;
+        match &old.sky {
+            Sky::Color(color) => self.set_sky(device, Sky::Color(*color)),
+            Sky::Procedural(procedural) => self.set_sky(device, Sky::Procedural(*procedural)),
+            Sky::Cubemap(_) => bail!("The sky cubemap was lost with the old device, set it again"),
+        }
+    }
+
+    // What the scene pass clears to, drawn skies cover it anyway
+    pub fn clear_color(&self) -> wgpu::Color {
+        match self.sky {
+            Sky::Color(color) => color,
+            _ => wgpu::Color::BLACK,
+        }
+    }
+
+    pub fn prepare(&mut self, queue: &wgpu::Queue, camera: &Camera) {
+        // Only the rotation matters, the sky is infinitely far away
+        let view = Matrix4::look_to_rh(Point3::origin(), camera.target - camera.eye, camera.up);
+        let proj = cgmath::perspective(cgmath::Deg(camera.fovy), camera.aspect, camera.znear, camera.zfar);
+        let inv_view_proj = (OPENGL_TO_WGPU_MATRIX * proj * view).invert().unwrap_or(Matrix4::identity());
+
+        let mut uniform = SkyUniform {
+            inv_view_proj: inv_view_proj.into(),
+            exposure: self.exposure,
+            ..bytemuck::Zeroable::zeroed()
+        };
+        match &self.sky {
+            Sky::Color(_) => uniform.mode = MODE_COLOR,
+            Sky::Cubemap(_) => uniform.mode = MODE_CUBEMAP,
+            Sky::Procedural(procedural) => {
+                let rgb = |c: [f32; 3]| [c[0], c[1], c[2], 1.0];
+                let sun = procedural.sun_direction;
+                uniform.mode = MODE_PROCEDURAL;
+                uniform.zenith_color = rgb(procedural.zenith_color);
+                uniform.horizon_color = rgb(procedural.horizon_color);
+                uniform.ground_color = rgb(procedural.ground_color);
+                uniform.sun_direction = [sun.x, sun.y, sun.z, procedural.sun_size.to_radians().cos()];
+                uniform.sun_color = rgb(procedural.sun_color);
+            },
+        }
+
+        stats::write_buffer(queue, &self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+
+    // Goes after the opaque geometry in the pass that cleared the depth buffer
+    pub fn render<'a>(&'a self, render_pass: &mut TrackedRenderPass<'a>) {
+        if let Sky::Color(_) = self.sky {
+            return;
+        }
+
+        render_pass.set_pipeline(&self.pipeline, wgpu::PrimitiveTopology::TriangleList);
+        render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
+        render_pass.set_bind_group(1, &self.texture_bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
@@ -0,0 +1,472 @@
+use anyhow::{anyhow, Result};
+
+use super::{
+    atlas::{AtlasRegion, TextureAtlas, UvRect},
+    camera::{CameraUniform, OrthographicCamera},
+    shader,
+    stats::{self, Tracked, TrackedRenderPass},
+};
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct SpriteVertex {
+    position: [f32; 3],
+    tex_coords: [f32; 2],
+    color: [f32; 4],
+}
+
+impl SpriteVertex {
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<SpriteVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                    format: wgpu::VertexFormat::Float32x4,
+                }
+            ]
+        }
+    }
+}
+
+// Unit quad every sprite is built from, same winding as the main quad
+// (corner offset, texture coordinates)
+const QUAD: [([f32; 2], [f32; 2]); 4] = [
+    ([-0.5, -0.5], [0.0, 1.0]),
+    ([0.5, -0.5],  [1.0, 1.0]),
+    ([-0.5, 0.5],  [0.0, 0.0]),
+    ([0.5, 0.5],   [1.0, 0.0]),
+];
+
+pub(crate) const QUAD_INDICES: [u32; 6] = [
+    0, 1, 2,
+    2, 1, 3,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AtlasId(usize);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Sprite {
+    pub position: cgmath::Vector2<f32>,
+    // Counter clockwise, in radians, around the sprite center
+    pub rotation: f32,
+    // Size in world units
+    pub scale: cgmath::Vector2<f32>,
+    pub tint: [f32; 4],
+    pub uv: UvRect,
+    pub flip_x: bool,
+    pub flip_y: bool,
+    // Sprites on higher layers are drawn on top, equal layers keep submission order
+    pub layer: i32,
+}
+
+impl Sprite {
+    pub fn new(position: cgmath::Vector2<f32>, scale: cgmath::Vector2<f32>) -> Self {
+        Self {
+            position,
+            rotation: 0.0,
+            scale,
+            tint: [1.0, 1.0, 1.0, 1.0],
+            uv: UvRect::FULL,
+            flip_x: false,
+            flip_y: false,
+            layer: 0,
+        }
+    }
+
+    // A sprite showing an atlas region at its pixel size
+    pub fn from_region(position: cgmath::Vector2<f32>, region: &AtlasRegion) -> Self {
+        Self {
+            uv: region.uv,
+            ..Self::new(position, cgmath::Vector2::new(region.width as f32, region.height as f32))
+        }
+    }
+
+    pub fn with_rotation(mut self, rotation: f32) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    pub fn with_tint(mut self, tint: [f32; 4]) -> Self {
+        self.tint = tint;
+        self
+    }
+
+    pub fn with_uv(mut self, uv: UvRect) -> Self {
+        self.uv = uv;
+        self
+    }
+
+    pub fn with_flip(mut self, flip_x: bool, flip_y: bool) -> Self {
+        self.flip_x = flip_x;
+        self.flip_y = flip_y;
+        self
+    }
+
+    pub fn with_layer(mut self, layer: i32) -> Self {
+        self.layer = layer;
+        self
+    }
+
+    fn vertices(&self) -> [SpriteVertex; 4] {
+        let (sin, cos) = self.rotation.sin_cos();
+        QUAD.map(|(corner, tex_coords)| {
+            let x = corner[0] * self.scale.x;
+            let y = corner[1] * self.scale.y;
+            let u = if self.flip_x { 1.0 - tex_coords[0] } else { tex_coords[0] };
+            let v = if self.flip_y { 1.0 - tex_coords[1] } else { tex_coords[1] };
+            SpriteVertex {
+                position: [
+                    self.position.x + x * cos - y * sin,
+                    self.position.y + x * sin + y * cos,
+                    0.0,
+                ],
+                tex_coords: [
+                    self.uv.min[0] + (self.uv.max[0] - self.uv.min[0]) * u,
+                    self.uv.min[1] + (self.uv.max[1] - self.uv.min[1]) * v,
+                ],
+                color: self.tint,
+            }
+        })
+    }
+}
+
+// All the sprites of one atlas share a single dynamic vertex buffer
+struct SpriteBatch {
+    atlas: TextureAtlas,
+    bind_group: wgpu::BindGroup,
+    vertex_buffer: Tracked<wgpu::Buffer>,
+    // In sprites
+    capacity: usize,
+    vertices: Vec<SpriteVertex>,
+}
+
+// A run of consecutive sprites of the same atlas, in sprites
+struct SpriteDraw {
+    atlas: usize,
+    first: u32,
+    count: u32,
+}
+
+pub struct SpriteRenderer {
+    pipeline: wgpu::RenderPipeline,
+    pipeline_layout: wgpu::PipelineLayout,
+    format: wgpu::TextureFormat,
+    texture_bind_group_layout: wgpu::BindGroupLayout,
+    pub camera: OrthographicCamera,
+    camera_uniform: CameraUniform,
+    camera_buffer: Tracked<wgpu::Buffer>,
+    camera_bind_group: wgpu::BindGroup,
+    index_buffer: Tracked<wgpu::Buffer>,
+    // In sprites
+    index_capacity: usize,
+    batches: Vec<SpriteBatch>,
+    queued: Vec<(AtlasId, Sprite)>,
+    draws: Vec<SpriteDraw>,
+}
+
+impl SpriteRenderer {
+    pub const SHADER: &'static str = "sprite.wgsl";
+
+    pub fn new(device: &wgpu::Device, shader: &wgpu::ShaderModule, format: wgpu::TextureFormat, width: u32, height: u32) -> Self {
+        let texture_bind_group_layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ],
+                label: Some("sprite_texture_bind_group_layout"),
+            }
+        );
+
+        let camera = OrthographicCamera::new(width as f32, height as f32);
+        let mut camera_uniform = CameraUniform::new();
+        camera_uniform.update_view_proj_ortho(&camera);
+
+        let camera_buffer = stats::track_buffer(device, device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Sprite Camera Buffer"),
+            size: std::mem::size_of::<CameraUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        }));
+
+        let camera_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("sprite_camera_bind_group_layout"),
+        });
+
+        let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &camera_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: camera_buffer.as_entire_binding(),
+                }
+            ],
+            label: Some("sprite_camera_bind_group"),
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Sprite Pipeline Layout"),
+            bind_group_layouts: &[
+                &texture_bind_group_layout,
+                &camera_bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = Self::create_pipeline(device, &pipeline_layout, shader, format);
+
+        let index_capacity = 1024;
+        let index_buffer = create_quad_index_buffer(device, index_capacity, Some("Sprite Index Buffer"));
+
+        Self {
+            pipeline,
+            pipeline_layout,
+            format,
+            texture_bind_group_layout,
+            camera,
+            camera_uniform,
+            camera_buffer,
+            camera_bind_group,
+            index_buffer,
+            index_capacity,
+            batches: Vec::new(),
+            queued: Vec::new(),
+            draws: Vec::new(),
+        }
+    }
+
+    // Rebuilds the pipeline from a reloaded shader, the old one is kept on errors
+    pub fn reload_shader(&mut self, device: &wgpu::Device, shader: &wgpu::ShaderModule) -> Result<()> {
+        self.pipeline = shader::validated(device, || Self::create_pipeline(device, &self.pipeline_layout, shader, self.format))?;
+        Ok(())
+    }
+
+    fn create_pipeline(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        shader: &wgpu::ShaderModule,
+        format: wgpu::TextureFormat,
+    ) -> wgpu::RenderPipeline {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Sprite Pipeline"),
+            layout: Some(layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: "vs_main",
+                buffers: &[SpriteVertex::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                // Flipped and negatively scaled sprites turn their back to the camera
+                cull_mode: None,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                unclipped_depth: false,
+                conservative: false
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+        })
+    }
+
+    pub fn add_atlas(&mut self, device: &wgpu::Device, atlas: TextureAtlas) -> AtlasId {
+        let bind_group = device.create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                layout: &self.texture_bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&atlas.texture.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&atlas.texture.sampler),
+                    }
+                ],
+                label: Some("sprite_atlas_bind_group"),
+            }
+        );
+
+        let capacity = 256;
+        self.batches.push(SpriteBatch {
+            atlas,
+            bind_group,
+            vertex_buffer: Self::create_vertex_buffer(device, capacity),
+            capacity,
+            vertices: Vec::new(),
+        });
+
+        AtlasId(self.batches.len() - 1)
+    }
+
+    // Uploads the atlases of a renderer from a lost device in their original order,
+    // so the ids handed out by the old renderer stay valid
+    pub fn restore_from(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, old: &SpriteRenderer) {
+        for batch in &old.batches {
+            self.add_atlas(device, batch.atlas.recreate(device, queue));
+        }
+    }
+
+    // Fails for ids that came from another renderer
+    pub fn atlas(&self, id: AtlasId) -> Result<&TextureAtlas> {
+        self.batches.get(id.0)
+            .map(|batch| &batch.atlas)
+            .ok_or_else(|| anyhow!("Unknown sprite atlas {:?}", id))
+    }
+
+    // Queues a sprite for the next prepared frame
+    pub fn draw(&mut self, atlas: AtlasId, sprite: Sprite) -> Result<()> {
+        self.atlas(atlas)?;
+        self.queued.push((atlas, sprite));
+        Ok(())
+    }
+
+    // Sorts the queued sprites by layer and uploads them, the queue is emptied afterwards
+    pub fn prepare(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        self.camera_uniform.update_view_proj_ortho(&self.camera);
+        stats::write_buffer(queue, &self.camera_buffer, 0, bytemuck::cast_slice(&[self.camera_uniform]));
+
+        // Stable, so sprites on the same layer keep their submission order
+        self.queued.sort_by_key(|(_, sprite)| sprite.layer);
+
+        for batch in self.batches.iter_mut() {
+            batch.vertices.clear();
+        }
+        self.draws.clear();
+
+        // Only ids that `draw` checked get queued
+        for (atlas, sprite) in self.queued.drain(..) {
+            let batch = &mut self.batches[atlas.0];
+            let first = (batch.vertices.len() / QUAD.len()) as u32;
+            batch.vertices.extend_from_slice(&sprite.vertices());
+
+            match self.draws.last_mut() {
+                Some(draw) if draw.atlas == atlas.0 => draw.count += 1,
+                _ => self.draws.push(SpriteDraw { atlas: atlas.0, first, count: 1 }),
+            }
+        }
+
+        let mut most_sprites = 0;
+        for batch in self.batches.iter_mut() {
+            let sprites = batch.vertices.len() / QUAD.len();
+            most_sprites = most_sprites.max(sprites);
+            if sprites == 0 {
+                continue;
+            }
+            if sprites > batch.capacity {
+                batch.capacity = sprites.next_power_of_two();
+                batch.vertex_buffer = Self::create_vertex_buffer(device, batch.capacity);
+            }
+            stats::write_buffer(queue, &batch.vertex_buffer, 0, bytemuck::cast_slice(&batch.vertices));
+        }
+
+        if most_sprites > self.index_capacity {
+            self.index_capacity = most_sprites.next_power_of_two();
+            self.index_buffer = create_quad_index_buffer(device, self.index_capacity, Some("Sprite Index Buffer"));
+        }
+    }
+
+    pub fn render<'a>(&'a self, render_pass: &mut TrackedRenderPass<'a>) {
+        if self.draws.is_empty() {
+            return;
+        }
+
+        render_pass.set_pipeline(&self.pipeline, wgpu::PrimitiveTopology::TriangleList);
+        render_pass.set_bind_group(1, &self.camera_bind_group, &[]);
+        render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+
+        let mut bound = None;
+        for draw in self.draws.iter() {
+            if bound != Some(draw.atlas) {
+                let batch = &self.batches[draw.atlas];
+                render_pass.set_bind_group(0, &batch.bind_group, &[]);
+                render_pass.set_vertex_buffer(0, batch.vertex_buffer.slice(..));
+                bound = Some(draw.atlas);
+            }
+            let indices = QUAD_INDICES.len() as u32;
+            render_pass.draw_indexed(draw.first * indices..(draw.first + draw.count) * indices, 0, 0..1);
+        }
+    }
+
+    fn create_vertex_buffer(device: &wgpu::Device, sprites: usize) -> Tracked<wgpu::Buffer> {
+        stats::track_buffer(device, device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Sprite Vertex Buffer"),
+            size: (sprites * QUAD.len() * std::mem::size_of::<SpriteVertex>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        }))
+    }
+}
+
+// Index buffer for `quads` quads laid out like QUAD, the pattern never changes
+// so it's generated once per capacity
+pub(crate) fn create_quad_index_buffer(device: &wgpu::Device, quads: usize, label: Option<&str>) -> Tracked<wgpu::Buffer> {
+    use wgpu::util::DeviceExt;
+
+    let indices: Vec<u32> = (0..quads as u32)
+        .flat_map(|quad| QUAD_INDICES.map(|index| quad * QUAD.len() as u32 + index))
+        .collect();
+
+    stats::track_buffer(device, device.create_buffer_init(
+        &wgpu::util::BufferInitDescriptor {
+            label,
+            contents: bytemuck::cast_slice(&indices),
+            usage: wgpu::BufferUsages::INDEX,
+        }
+    ))
+}
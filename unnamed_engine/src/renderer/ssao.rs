@@ -0,0 +1,343 @@
+// Screen-space ambient occlusion
+//
+//     state.set_ssao_enabled(true)?;
+//     state.ssao().unwrap().settings = SsaoSettings::default().with_radius(1.0).with_sample_count(24);
+//
+// Every pixel samples the depth buffer on a spiral within `radius` of it, rotated per pixel,
+// and the more geometry rises above its surface there the less ambient light it gets. Normals
+// are reconstructed from depth as well, so the forward path only draws a depth prepass of the
+// opaque objects for it and the deferred path reuses the G-buffer's depth. Two depth-aware blur
+// passes then smooth out the noise without bleeding over edges, into the occlusion map of
+// `Lights` that both paths multiply the ambient light with. Point lights aren't occluded, and
+// transparent objects take the occlusion of what is behind them.
+use std::sync::Arc;
+
+use anyhow::*;
+use cgmath::{InnerSpace, SquareMatrix};
+
+use super::{
+    camera::Camera,
+    lighting,
+    shader,
+    stats::{self, Tracked, TrackedRenderPass},
+    texture::Texture,
+};
+
+// Samples per pixel past this are ignored
+pub const MAX_SAMPLES: u32 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SsaoSettings {
+    // How far occluders reach in world units
+    pub radius: f32,
+    // Scales the darkening, 0 leaves the ambient light alone
+    pub intensity: f32,
+    // More are smoother and slower
+    pub sample_count: u32,
+}
+
+impl Default for SsaoSettings {
+    fn default() -> Self {
+        Self { radius: 0.5, intensity: 1.0, sample_count: 16 }
+    }
+}
+
+impl SsaoSettings {
+    pub fn with_radius(mut self, radius: f32) -> Self {
+        self.radius = radius;
+        self
+    }
+
+    pub fn with_intensity(mut self, intensity: f32) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    pub fn with_sample_count(mut self, sample_count: u32) -> Self {
+        self.sample_count = sample_count;
+        self
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SsaoUniform {
+    inverse_view_proj: [[f32; 4]; 4],
+    eye: [f32; 4],
+    forward: [f32; 4],
+    radius: f32,
+    intensity: f32,
+    // Pixels per world unit at a view depth of 1
+    projection_scale: f32,
+    sample_count: u32,
+}
+
+struct Target {
+    _texture: Tracked<wgpu::Texture>,
+    view: wgpu::TextureView,
+}
+
+impl Target {
+    fn new(device: &wgpu::Device, width: u32, height: u32, label: &str) -> Self {
+        let texture = stats::track_texture(device, device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: lighting::OCCLUSION_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        }));
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        Self { _texture: texture, view }
+    }
+}
+
+// The raw and half blurred occlusion, recreated with the depth buffer on resize
+struct Targets {
+    raw: Target,
+    blurred_x: Target,
+    ao_bind_group: wgpu::BindGroup,
+    blur_x_bind_group: wgpu::BindGroup,
+    blur_y_bind_group: wgpu::BindGroup,
+    height: u32,
+}
+
+struct Pipelines {
+    ao: wgpu::RenderPipeline,
+    blur_x: wgpu::RenderPipeline,
+    blur_y: wgpu::RenderPipeline,
+}
+
+pub struct SsaoRenderer {
+    pub settings: SsaoSettings,
+    // The main shader's `fs_depth` for the forward path's depth prepass, kept up to date by
+    // the state with the other main pipelines
+    pub depth_pipeline: Arc<wgpu::RenderPipeline>,
+    pipelines: Pipelines,
+    ao_pipeline_layout: wgpu::PipelineLayout,
+    blur_pipeline_layout: wgpu::PipelineLayout,
+    ao_bind_group_layout: wgpu::BindGroupLayout,
+    blur_bind_group_layout: wgpu::BindGroupLayout,
+    uniform_buffer: Tracked<wgpu::Buffer>,
+    targets: Targets,
+}
+
+impl SsaoRenderer {
+    pub const SHADER: &'static str = "ssao.wgsl";
+
+    // The targets match the size of `depth`
+    pub fn new(device: &wgpu::Device, depth_pipeline: Arc<wgpu::RenderPipeline>, shader: &wgpu::ShaderModule, depth: &Texture) -> Result<Self> {
+        let uniform_buffer = stats::track_buffer(device, device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("SSAO Buffer"),
+            size: std::mem::size_of::<SsaoUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        }));
+
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+            },
+            count: None,
+        };
+        let uniform_entry = wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let ao_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[uniform_entry, texture_entry(1)],
+            label: Some("ssao_bind_group_layout"),
+        });
+        let blur_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[uniform_entry, texture_entry(1), texture_entry(2)],
+            label: Some("ssao_blur_bind_group_layout"),
+        });
+        let ao_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("SSAO Pipeline Layout"),
+            bind_group_layouts: &[&ao_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let blur_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("SSAO Blur Pipeline Layout"),
+            bind_group_layouts: &[&blur_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipelines = Self::create_pipelines(device, &ao_pipeline_layout, &blur_pipeline_layout, shader)?;
+        let targets = Self::create_targets(device, &ao_bind_group_layout, &blur_bind_group_layout, &uniform_buffer, depth);
+
+        Ok(Self {
+            settings: SsaoSettings::default(),
+            depth_pipeline,
+            pipelines,
+            ao_pipeline_layout,
+            blur_pipeline_layout,
+            ao_bind_group_layout,
+            blur_bind_group_layout,
+            uniform_buffer,
+            targets,
+        })
+    }
+
+    pub fn reload_shader(&mut self, device: &wgpu::Device, shader: &wgpu::ShaderModule) -> Result<()> {
+        self.pipelines = Self::create_pipelines(device, &self.ao_pipeline_layout, &self.blur_pipeline_layout, shader)?;
+        Ok(())
+    }
+
+    fn create_pipelines(
+        device: &wgpu::Device,
+        ao_layout: &wgpu::PipelineLayout,
+        blur_layout: &wgpu::PipelineLayout,
+        shader: &wgpu::ShaderModule,
+    ) -> Result<Pipelines> {
+        let create = |layout, entry_point, label| device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(label),
+            layout: Some(layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: shader,
+                entry_point,
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: lighting::OCCLUSION_FORMAT,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+        shader::validated(device, || Pipelines {
+            ao: create(ao_layout, "fs_ao", "SSAO Pipeline"),
+            blur_x: create(blur_layout, "fs_blur_x", "SSAO Blur X Pipeline"),
+            blur_y: create(blur_layout, "fs_blur_y", "SSAO Blur Y Pipeline"),
+        })
+    }
+
+    fn create_targets(
+        device: &wgpu::Device,
+        ao_layout: &wgpu::BindGroupLayout,
+        blur_layout: &wgpu::BindGroupLayout,
+        uniform_buffer: &wgpu::Buffer,
+        depth: &Texture,
+    ) -> Targets {
+        let (width, height) = (depth.texture.width(), depth.texture.height());
+        let raw = Target::new(device, width, height, "SSAO Texture");
+        let blurred_x = Target::new(device, width, height, "SSAO Blur Texture");
+
+        let bind_group = |layout, source: Option<&Target>, label| {
+            let mut entries = vec![
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&depth.view),
+                },
+            ];
+            if let Some(source) = source {
+                entries.push(wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&source.view),
+                });
+            }
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout,
+                entries: &entries,
+                label: Some(label),
+            })
+        };
+        let ao_bind_group = bind_group(ao_layout, None, "ssao_bind_group");
+        let blur_x_bind_group = bind_group(blur_layout, Some(&raw), "ssao_blur_x_bind_group");
+        let blur_y_bind_group = bind_group(blur_layout, Some(&blurred_x), "ssao_blur_y_bind_group");
+
+        Targets { raw, blurred_x, ao_bind_group, blur_x_bind_group, blur_y_bind_group, height }
+    }
+
+    // `depth` is the new depth buffer
+    pub fn resize(&mut self, device: &wgpu::Device, depth: &Texture) {
+        self.targets = Self::create_targets(device, &self.ao_bind_group_layout, &self.blur_bind_group_layout, &self.uniform_buffer, depth);
+    }
+
+    pub fn prepare(&self, queue: &wgpu::Queue, camera: &Camera) {
+        let view_proj = camera.build_view_projection_matrix();
+        let forward = (camera.target - camera.eye).normalize();
+        let half_fovy = cgmath::Rad::from(cgmath::Deg(camera.fovy * 0.5));
+        let uniform = SsaoUniform {
+            inverse_view_proj: view_proj.invert().unwrap_or(cgmath::Matrix4::identity()).into(),
+            eye: camera.eye.to_homogeneous().into(),
+            forward: forward.extend(0.0).into(),
+            radius: self.settings.radius.max(1e-4),
+            intensity: self.settings.intensity.max(0.0),
+            projection_scale: 0.5 * self.targets.height as f32 / cgmath::Angle::tan(half_fovy),
+            sample_count: self.settings.sample_count.clamp(1, MAX_SAMPLES),
+        };
+        stats::write_buffer(queue, &self.uniform_buffer, 0, bytemuck::bytes_of(&uniform));
+    }
+
+    // Renders the blurred occlusion of the depth buffer into `output`, the occlusion map of
+    // `Lights`. The timestamps span all three passes
+    pub fn render(&self, queue: &wgpu::Queue, encoder: &mut wgpu::CommandEncoder, output: &wgpu::TextureView, timestamp_writes: Option<wgpu::RenderPassTimestampWrites>) {
+        let (begin, end) = match timestamp_writes {
+            Some(writes) => (
+                Some(wgpu::RenderPassTimestampWrites {
+                    query_set: writes.query_set,
+                    beginning_of_pass_write_index: writes.beginning_of_pass_write_index,
+                    end_of_pass_write_index: None,
+                }),
+                Some(wgpu::RenderPassTimestampWrites {
+                    query_set: writes.query_set,
+                    beginning_of_pass_write_index: None,
+                    end_of_pass_write_index: writes.end_of_pass_write_index,
+                }),
+            ),
+            None => (None, None),
+        };
+        let passes = [
+            ("SSAO Pass", &self.targets.raw.view, &self.pipelines.ao, &self.targets.ao_bind_group, begin),
+            ("SSAO Blur X Pass", &self.targets.blurred_x.view, &self.pipelines.blur_x, &self.targets.blur_x_bind_group, None),
+            ("SSAO Blur Y Pass", output, &self.pipelines.blur_y, &self.targets.blur_y_bind_group, end),
+        ];
+        for (label, view, pipeline, bind_group, timestamp_writes) in passes {
+            let mut render_pass = TrackedRenderPass::new(queue, encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some(label),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes,
+                occlusion_query_set: None,
+            }));
+            render_pass.set_pipeline(pipeline, wgpu::PrimitiveTopology::TriangleList);
+            render_pass.set_bind_group(0, bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+    }
+}
@@ -0,0 +1,446 @@
+// Renderer statistics, per frame counters and live GPU memory
+//
+// Draw calls, triangles, instances and pipeline and bind group switches are counted by
+// `TrackedRenderPass`, which renderers record into instead of a bare `wgpu::RenderPass`.
+// Uploads are counted by `write_buffer` and `write_texture`. Frame counters are kept per
+// queue, so every thread recording for a device adds to the same ones and two devices
+// never mix. `FrameCounters::take` returns and resets them.
+//
+// Memory is counted per device by wrapping long lived buffers and textures in `Tracked`,
+// which adds their size when created and removes it when dropped. Short lived readback
+// and scratch resources aren't tracked.
+use std::{
+    collections::VecDeque,
+    ops::{Deref, Range},
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+// Frames kept for graphs, a few seconds at 60 fps
+pub const HISTORY_LEN: usize = 240;
+const MAX_BIND_GROUPS: usize = 8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    pub draw_calls: u32,
+    pub pipeline_switches: u32,
+    pub bind_group_switches: u32,
+    pub triangles: u64,
+    pub instances: u64,
+    // Bytes written with `write_buffer` and `write_texture`
+    pub upload_bytes: u64,
+}
+
+// What was recorded for one queue since the last `take`
+#[derive(Default)]
+pub struct FrameCounters {
+    draw_calls: AtomicU64,
+    pipeline_switches: AtomicU64,
+    bind_group_switches: AtomicU64,
+    triangles: AtomicU64,
+    instances: AtomicU64,
+    upload_bytes: AtomicU64,
+}
+
+static QUEUES: Mutex<Vec<(wgpu::Id<wgpu::Queue>, Weak<FrameCounters>)>> = Mutex::new(Vec::new());
+
+impl FrameCounters {
+    // Counts are only kept while someone holds the counters, the state keeps its queue's
+    pub fn of(queue: &wgpu::Queue) -> Arc<Self> {
+        let mut queues = QUEUES.lock().unwrap_or_else(|e| e.into_inner());
+        queues.retain(|(_, counters)| counters.strong_count() > 0);
+        let id = queue.global_id();
+        if let Some(counters) = queues.iter().find(|(queue, _)| *queue == id).and_then(|(_, counters)| counters.upgrade()) {
+            return counters;
+        }
+        let counters = Arc::new(Self::default());
+        queues.push((id, Arc::downgrade(&counters)));
+        counters
+    }
+
+    fn add(&self, stats: &FrameStats) {
+        self.draw_calls.fetch_add(stats.draw_calls as u64, Ordering::Relaxed);
+        self.pipeline_switches.fetch_add(stats.pipeline_switches as u64, Ordering::Relaxed);
+        self.bind_group_switches.fetch_add(stats.bind_group_switches as u64, Ordering::Relaxed);
+        self.triangles.fetch_add(stats.triangles, Ordering::Relaxed);
+        self.instances.fetch_add(stats.instances, Ordering::Relaxed);
+        self.upload_bytes.fetch_add(stats.upload_bytes, Ordering::Relaxed);
+    }
+
+    // Everything counted since the last call
+    pub fn take(&self) -> FrameStats {
+        FrameStats {
+            draw_calls: self.draw_calls.swap(0, Ordering::Relaxed) as u32,
+            pipeline_switches: self.pipeline_switches.swap(0, Ordering::Relaxed) as u32,
+            bind_group_switches: self.bind_group_switches.swap(0, Ordering::Relaxed) as u32,
+            triangles: self.triangles.swap(0, Ordering::Relaxed),
+            instances: self.instances.swap(0, Ordering::Relaxed),
+            upload_bytes: self.upload_bytes.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+fn record_upload(queue: &wgpu::Queue, bytes: usize) {
+    FrameCounters::of(queue).upload_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+pub fn write_buffer(queue: &wgpu::Queue, buffer: &wgpu::Buffer, offset: wgpu::BufferAddress, data: &[u8]) {
+    record_upload(queue, data.len());
+    queue.write_buffer(buffer, offset, data);
+}
+
+pub fn write_texture(
+    queue: &wgpu::Queue,
+    texture: wgpu::ImageCopyTexture,
+    data: &[u8],
+    layout: wgpu::ImageDataLayout,
+    size: wgpu::Extent3d,
+) {
+    record_upload(queue, data.len());
+    queue.write_texture(texture, data, layout, size);
+}
+
+// `wgpu::RenderPass` that counts what is recorded into it
+// Setting the pipeline or a bind group that is already bound isn't counted as a switch
+// The counts go to the queue the pass is submitted on once the pass is dropped
+pub struct TrackedRenderPass<'a> {
+    pass: wgpu::RenderPass<'a>,
+    pipeline: Option<wgpu::Id<wgpu::RenderPipeline>>,
+    topology: wgpu::PrimitiveTopology,
+    bind_groups: [Option<wgpu::Id<wgpu::BindGroup>>; MAX_BIND_GROUPS],
+    stats: FrameStats,
+    counters: Arc<FrameCounters>,
+}
+
+impl<'a> TrackedRenderPass<'a> {
+    pub fn new(queue: &wgpu::Queue, pass: wgpu::RenderPass<'a>) -> Self {
+        Self {
+            pass,
+            pipeline: None,
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            bind_groups: [None; MAX_BIND_GROUPS],
+            stats: FrameStats::default(),
+            counters: FrameCounters::of(queue),
+        }
+    }
+
+    // The topology must match the pipeline's, it is only used to count triangles
+    pub fn set_pipeline(&mut self, pipeline: &'a wgpu::RenderPipeline, topology: wgpu::PrimitiveTopology) {
+        self.topology = topology;
+        if self.pipeline != Some(pipeline.global_id()) {
+            self.pipeline = Some(pipeline.global_id());
+            self.stats.pipeline_switches += 1;
+        }
+        self.pass.set_pipeline(pipeline);
+    }
+
+    pub fn set_bind_group(&mut self, index: u32, bind_group: &'a wgpu::BindGroup, offsets: &[wgpu::DynamicOffset]) {
+        let bound = &mut self.bind_groups[index as usize];
+        // New dynamic offsets always rebind
+        if *bound != Some(bind_group.global_id()) || !offsets.is_empty() {
+            *bound = Some(bind_group.global_id());
+            self.stats.bind_group_switches += 1;
+        }
+        self.pass.set_bind_group(index, bind_group, offsets);
+    }
+
+    pub fn set_vertex_buffer(&mut self, slot: u32, buffer_slice: wgpu::BufferSlice<'a>) {
+        self.pass.set_vertex_buffer(slot, buffer_slice);
+    }
+
+    pub fn set_index_buffer(&mut self, buffer_slice: wgpu::BufferSlice<'a>, index_format: wgpu::IndexFormat) {
+        self.pass.set_index_buffer(buffer_slice, index_format);
+    }
+
+    pub fn begin_occlusion_query(&mut self, query_index: u32) {
+        self.pass.begin_occlusion_query(query_index);
+    }
+
+    pub fn end_occlusion_query(&mut self) {
+        self.pass.end_occlusion_query();
+    }
+
+    pub fn draw(&mut self, vertices: Range<u32>, instances: Range<u32>) {
+        self.record_draw(vertices.len() as u64, instances.len() as u64);
+        self.pass.draw(vertices, instances);
+    }
+
+    pub fn draw_indexed(&mut self, indices: Range<u32>, base_vertex: i32, instances: Range<u32>) {
+        self.record_draw(indices.len() as u64, instances.len() as u64);
+        self.pass.draw_indexed(indices, base_vertex, instances);
+    }
+
+    // Counted as one draw call, how many instances it draws is only known on the GPU
+    pub fn draw_indexed_indirect(&mut self, indirect_buffer: &'a wgpu::Buffer, indirect_offset: wgpu::BufferAddress) {
+        self.stats.draw_calls += 1;
+        self.pass.draw_indexed_indirect(indirect_buffer, indirect_offset);
+    }
+
+    // Needs Features::MULTI_DRAW_INDIRECT, `count` draws in one call
+    pub fn multi_draw_indexed_indirect(&mut self, indirect_buffer: &'a wgpu::Buffer, indirect_offset: wgpu::BufferAddress, count: u32) {
+        self.stats.draw_calls += 1;
+        self.pass.multi_draw_indexed_indirect(indirect_buffer, indirect_offset, count);
+    }
+
+    fn record_draw(&mut self, vertices: u64, instances: u64) {
+        let triangles = match self.topology {
+            wgpu::PrimitiveTopology::TriangleList => vertices / 3,
+            wgpu::PrimitiveTopology::TriangleStrip => vertices.saturating_sub(2),
+            _ => 0,
+        };
+        self.stats.draw_calls += 1;
+        self.stats.triangles += triangles * instances;
+        self.stats.instances += instances;
+    }
+}
+
+impl Drop for TrackedRenderPass<'_> {
+    fn drop(&mut self) {
+        self.counters.add(&self.stats);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MemoryKind {
+    VertexBuffer,
+    IndexBuffer,
+    UniformBuffer,
+    StorageBuffer,
+    // Buffers only copied to or from
+    StagingBuffer,
+    Texture,
+    // Textures that are rendered to, including depth buffers
+    RenderTarget,
+}
+
+impl MemoryKind {
+    pub const ALL: [MemoryKind; 7] = [
+        MemoryKind::VertexBuffer,
+        MemoryKind::IndexBuffer,
+        MemoryKind::UniformBuffer,
+        MemoryKind::StorageBuffer,
+        MemoryKind::StagingBuffer,
+        MemoryKind::Texture,
+        MemoryKind::RenderTarget,
+    ];
+
+    // Storage comes first, particles are storage buffers drawn as vertex buffers
+    fn of_buffer(usage: wgpu::BufferUsages) -> Self {
+        if usage.contains(wgpu::BufferUsages::STORAGE) {
+            MemoryKind::StorageBuffer
+        } else if usage.contains(wgpu::BufferUsages::VERTEX) {
+            MemoryKind::VertexBuffer
+        } else if usage.contains(wgpu::BufferUsages::INDEX) {
+            MemoryKind::IndexBuffer
+        } else if usage.contains(wgpu::BufferUsages::UNIFORM) {
+            MemoryKind::UniformBuffer
+        } else {
+            MemoryKind::StagingBuffer
+        }
+    }
+
+    fn of_texture(usage: wgpu::TextureUsages) -> Self {
+        if usage.contains(wgpu::TextureUsages::RENDER_ATTACHMENT) {
+            MemoryKind::RenderTarget
+        } else {
+            MemoryKind::Texture
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub bytes: u64,
+    pub count: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GpuMemory {
+    usage: [MemoryUsage; MemoryKind::ALL.len()],
+}
+
+impl GpuMemory {
+    pub fn get(&self, kind: MemoryKind) -> MemoryUsage {
+        self.usage[kind.index()]
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.usage.iter().map(|usage| usage.bytes).sum()
+    }
+
+    // Live memory of everything tracked on `device`
+    pub fn of_device(device: &wgpu::Device) -> Self {
+        let counters = DeviceMemory::of(device);
+        let mut memory = Self::default();
+        for kind in MemoryKind::ALL {
+            memory.usage[kind.index()] = MemoryUsage {
+                bytes: counters.bytes[kind.index()].load(Ordering::Relaxed).max(0) as u64,
+                count: counters.counts[kind.index()].load(Ordering::Relaxed).max(0) as u32,
+            };
+        }
+        memory
+    }
+}
+
+#[derive(Default)]
+struct DeviceMemory {
+    bytes: [AtomicI64; MemoryKind::ALL.len()],
+    counts: [AtomicI64; MemoryKind::ALL.len()],
+}
+
+type Registry = Vec<(wgpu::Id<wgpu::Device>, Weak<DeviceMemory>)>;
+static DEVICES: Mutex<Registry> = Mutex::new(Vec::new());
+
+impl DeviceMemory {
+    // Counters stay alive while any resource of the device is tracked
+    fn of(device: &wgpu::Device) -> Arc<Self> {
+        let mut devices = DEVICES.lock().unwrap_or_else(|e| e.into_inner());
+        devices.retain(|(_, counters)| counters.strong_count() > 0);
+        let id = device.global_id();
+        if let Some(counters) = devices.iter().find(|(device, _)| *device == id).and_then(|(_, counters)| counters.upgrade()) {
+            return counters;
+        }
+        let counters = Arc::new(Self::default());
+        devices.push((id, Arc::downgrade(&counters)));
+        counters
+    }
+}
+
+// Removes its resource from the device's memory when dropped
+struct Allocation {
+    counters: Arc<DeviceMemory>,
+    kind: MemoryKind,
+    bytes: u64,
+}
+
+impl Allocation {
+    fn new(device: &wgpu::Device, kind: MemoryKind, bytes: u64) -> Self {
+        let counters = DeviceMemory::of(device);
+        counters.bytes[kind.index()].fetch_add(bytes as i64, Ordering::Relaxed);
+        counters.counts[kind.index()].fetch_add(1, Ordering::Relaxed);
+        Self { counters, kind, bytes }
+    }
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        self.counters.bytes[self.kind.index()].fetch_sub(self.bytes as i64, Ordering::Relaxed);
+        self.counters.counts[self.kind.index()].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// A buffer or texture counted in the device's memory, derefs to the resource
+pub struct Tracked<T> {
+    resource: T,
+    _allocation: Allocation,
+}
+
+impl<T> Deref for Tracked<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.resource
+    }
+}
+
+pub fn track_buffer(device: &wgpu::Device, buffer: wgpu::Buffer) -> Tracked<wgpu::Buffer> {
+    let allocation = Allocation::new(device, MemoryKind::of_buffer(buffer.usage()), buffer.size());
+    Tracked { resource: buffer, _allocation: allocation }
+}
+
+pub fn track_texture(device: &wgpu::Device, texture: wgpu::Texture) -> Tracked<wgpu::Texture> {
+    let allocation = Allocation::new(device, MemoryKind::of_texture(texture.usage()), texture_bytes(&texture));
+    Tracked { resource: texture, _allocation: allocation }
+}
+
+// Size of every mip level, block compressed formats included
+pub fn texture_bytes(texture: &wgpu::Texture) -> u64 {
+    let format = texture.format();
+    let (block_width, block_height) = format.block_dimensions();
+    let block_bytes = format.block_size(None)
+        .or_else(|| format.block_size(Some(wgpu::TextureAspect::DepthOnly)))
+        .unwrap_or(4) as u64;
+    let size = texture.size();
+    let layers = match texture.dimension() {
+        wgpu::TextureDimension::D3 => 1,
+        _ => size.depth_or_array_layers as u64,
+    };
+
+    (0..texture.mip_level_count())
+        .map(|level| {
+            let extent = size.mip_level_size(level, texture.dimension());
+            let blocks_x = extent.width.div_ceil(block_width) as u64;
+            let blocks_y = extent.height.div_ceil(block_height) as u64;
+            let depth = match texture.dimension() {
+                wgpu::TextureDimension::D3 => extent.depth_or_array_layers as u64,
+                _ => 1,
+            };
+            blocks_x * blocks_y * depth * block_bytes
+        })
+        .sum::<u64>() * layers * texture.sample_count() as u64
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RenderStats {
+    pub frame: FrameStats,
+    pub memory: GpuMemory,
+}
+
+impl RenderStats {
+    // Lines for the on-screen overlay
+    pub fn summary(&self) -> Vec<String> {
+        let frame = &self.frame;
+        let mut lines = vec![
+            format!("{} draws, {} triangles, {} instances", frame.draw_calls, frame.triangles, frame.instances),
+            format!("{} pipeline and {} bind group switches", frame.pipeline_switches, frame.bind_group_switches),
+            format!("{:.1} KiB uploaded", frame.upload_bytes as f64 / 1024.0),
+        ];
+        lines.extend(MemoryKind::ALL.iter()
+            .map(|kind| (kind, self.memory.get(*kind)))
+            .filter(|(_, usage)| usage.count > 0)
+            .map(|(kind, usage)| format!("{:?}: {} ({:.2} MiB)", kind, usage.count, usage.bytes as f64 / (1024.0 * 1024.0))));
+        lines
+    }
+}
+
+// The last `HISTORY_LEN` frames, oldest first
+#[derive(Debug, Clone, Default)]
+pub struct StatsHistory {
+    frames: VecDeque<RenderStats>,
+}
+
+impl StatsHistory {
+    pub fn push(&mut self, stats: RenderStats) {
+        if self.frames.len() == HISTORY_LEN {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(stats);
+    }
+
+    pub fn latest(&self) -> Option<&RenderStats> {
+        self.frames.back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &RenderStats> {
+        self.frames.iter()
+    }
+
+    // One value per frame for graphs, e.g. `history.series(|stats| stats.frame.draw_calls as f32)`
+    pub fn series(&self, value: impl Fn(&RenderStats) -> f32) -> Vec<f32> {
+        self.frames.iter().map(value).collect()
+    }
+}
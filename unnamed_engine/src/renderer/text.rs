@@ -0,0 +1,806 @@
+use std::collections::HashMap;
+
+use anyhow::*;
+
+use super::{
+    camera::{Camera, OPENGL_TO_WGPU_MATRIX},
+    shader,
+    sprite::{create_quad_index_buffer, QUAD_INDICES},
+    stats::{self, Tracked, TrackedRenderPass},
+    texture::Texture,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FontId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TextAlign {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum TextSpace {
+    // Top left corner of the text block, in pixels from the top left of the screen
+    Screen([f32; 2]),
+    // Label facing the camera, centered above `position`
+    // `line_height` is the height of one line in world units
+    World { position: cgmath::Point3<f32>, line_height: f32 },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TextSection<'a> {
+    pub text: &'a str,
+    pub font: FontId,
+    // Font size in pixels
+    pub size: f32,
+    pub color: [f32; 4],
+    // Lines are wrapped at word boundaries when they get longer than this, in pixels
+    pub max_width: Option<f32>,
+    pub align: TextAlign,
+    pub space: TextSpace,
+}
+
+impl<'a> TextSection<'a> {
+    pub fn new(text: &'a str, font: FontId, size: f32, space: TextSpace) -> Self {
+        Self {
+            text,
+            font,
+            size,
+            color: [1.0, 1.0, 1.0, 1.0],
+            max_width: None,
+            align: TextAlign::Left,
+            space,
+        }
+    }
+
+    pub fn with_color(mut self, color: [f32; 4]) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn with_max_width(mut self, max_width: f32) -> Self {
+        self.max_width = Some(max_width);
+        self
+    }
+
+    pub fn with_align(mut self, align: TextAlign) -> Self {
+        self.align = align;
+        self
+    }
+}
+
+// A glyph placed by the layout, relative to the top left of the text block in pixels
+// `y` is the baseline of the glyph's line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LayoutGlyph {
+    pub font: FontId,
+    pub glyph: u16,
+    pub character: char,
+    pub x: f32,
+    pub y: f32,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TextLayout {
+    pub glyphs: Vec<LayoutGlyph>,
+    pub width: f32,
+    pub height: f32,
+    pub line_height: f32,
+    pub lines: usize,
+}
+
+// Loaded fonts and their fallback chains
+#[derive(Default)]
+pub struct FontCollection {
+    fonts: Vec<fontdue::Font>,
+    fallbacks: Vec<Vec<FontId>>,
+}
+
+impl FontCollection {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Loads a TTF or OTF font
+    pub fn load(&mut self, bytes: &[u8]) -> Result<FontId> {
+        let font = fontdue::Font::from_bytes(bytes, fontdue::FontSettings::default())
+            .map_err(|e| anyhow!("Failed to load font: {}", e))?;
+        self.fonts.push(font);
+        self.fallbacks.push(Vec::new());
+        Ok(FontId(self.fonts.len() - 1))
+    }
+
+    // Characters missing from `font` are looked up in its fallbacks, in the order they were added
+    pub fn add_fallback(&mut self, font: FontId, fallback: FontId) -> Result<()> {
+        self.font(fallback)?;
+        self.fallbacks.get_mut(font.0)
+            .ok_or_else(|| anyhow!("Unknown font {:?}", font))?
+            .push(fallback);
+        Ok(())
+    }
+
+    // Fails for ids that came from another collection
+    pub fn font(&self, font: FontId) -> Result<&fontdue::Font> {
+        self.fonts.get(font.0).ok_or_else(|| anyhow!("Unknown font {:?}", font))
+    }
+
+    // Picks the first font of the chain that has the character, or the
+    // primary font's missing glyph when none of them has it
+    fn resolve(&self, font: FontId, character: char) -> (FontId, u16) {
+        std::iter::once(font)
+            .chain(self.fallbacks[font.0].iter().copied())
+            .find_map(|candidate| {
+                match self.fonts[candidate.0].lookup_glyph_index(character) {
+                    0 => None,
+                    glyph => Some((candidate, glyph)),
+                }
+            })
+            .unwrap_or((font, 0))
+    }
+
+    fn line_metrics(&self, font: FontId, size: f32) -> fontdue::LineMetrics {
+        self.fonts[font.0].horizontal_line_metrics(size).unwrap_or(fontdue::LineMetrics {
+            ascent: size * 0.8,
+            descent: -size * 0.2,
+            line_gap: 0.0,
+            new_line_size: size,
+        })
+    }
+
+    // Places the glyphs of `text`, kerning pairs that come from the same font and
+    // breaking lines at whitespace when they don't fit `max_width`
+    pub fn layout(&self, text: &str, font: FontId, size: f32, max_width: Option<f32>, align: TextAlign) -> Result<TextLayout> {
+        // Fallbacks are checked when they are added, so only the primary font can be unknown
+        self.font(font)?;
+
+        struct Line {
+            glyphs: Vec<(LayoutGlyph, f32)>,
+        }
+
+        impl Line {
+            // Trailing whitespace doesn't count towards the width of a line
+            fn width(&self) -> f32 {
+                self.glyphs.iter()
+                    .rev()
+                    .find(|(glyph, _)| !glyph.character.is_whitespace())
+                    .map(|(glyph, advance)| glyph.x + advance)
+                    .unwrap_or(0.0)
+            }
+        }
+
+        let metrics = self.line_metrics(font, size);
+        let mut lines: Vec<Line> = Vec::new();
+
+        for paragraph in text.split('\n') {
+            let mut line = Line { glyphs: Vec::new() };
+            let mut x = 0.0;
+            let mut previous: Option<(FontId, u16)> = None;
+            // Index of the first glyph after the last whitespace of the line
+            let mut break_at: Option<usize> = None;
+
+            for character in paragraph.chars().filter(|c| *c != '\r') {
+                let (glyph_font, glyph) = self.resolve(font, character);
+                let face = &self.fonts[glyph_font.0];
+                let advance = face.metrics_indexed(glyph, size).advance_width;
+                let kern = match previous {
+                    Some((previous_font, previous_glyph)) if previous_font == glyph_font => {
+                        face.horizontal_kern_indexed(previous_glyph, glyph, size).unwrap_or(0.0)
+                    },
+                    _ => 0.0,
+                };
+
+                let mut glyph_x = x + kern;
+                let overflows = max_width.is_some_and(|max_width| glyph_x + advance > max_width);
+                if overflows && !character.is_whitespace() && !line.glyphs.is_empty() {
+                    // Move the current word to a new line, or break the word itself
+                    // when it's longer than a whole line
+                    let rest = match break_at {
+                        Some(index) if index < line.glyphs.len() => line.glyphs.split_off(index),
+                        _ => Vec::new(),
+                    };
+                    lines.push(line);
+
+                    let shift = rest.first().map(|(glyph, _)| glyph.x).unwrap_or(0.0);
+                    line = Line {
+                        glyphs: rest.into_iter().map(|(mut glyph, advance)| {
+                            glyph.x -= shift;
+                            (glyph, advance)
+                        }).collect(),
+                    };
+                    glyph_x = line.glyphs.last().map(|(glyph, advance)| glyph.x + advance).unwrap_or(0.0);
+                    break_at = None;
+                }
+
+                line.glyphs.push((LayoutGlyph {
+                    font: glyph_font,
+                    glyph,
+                    character,
+                    x: glyph_x,
+                    y: 0.0,
+                    line: 0,
+                }, advance));
+                x = glyph_x + advance;
+                previous = Some((glyph_font, glyph));
+
+                if character.is_whitespace() {
+                    break_at = Some(line.glyphs.len());
+                }
+            }
+
+            lines.push(line);
+        }
+
+        let line_height = metrics.new_line_size;
+        let widest = lines.iter().map(Line::width).fold(0.0, f32::max);
+        let width = max_width.unwrap_or(widest);
+
+        let mut layout = TextLayout {
+            glyphs: Vec::new(),
+            width,
+            height: line_height * lines.len() as f32,
+            line_height,
+            lines: lines.len(),
+        };
+
+        for (index, line) in lines.iter().enumerate() {
+            let offset = match align {
+                TextAlign::Left => 0.0,
+                TextAlign::Center => (width - line.width()) * 0.5,
+                TextAlign::Right => width - line.width(),
+            };
+            let baseline = metrics.ascent + line_height * index as f32;
+            layout.glyphs.extend(line.glyphs.iter().map(|(glyph, _)| LayoutGlyph {
+                x: glyph.x + offset,
+                y: baseline,
+                line: index,
+                ..*glyph
+            }));
+        }
+
+        Ok(layout)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct GlyphKey {
+    font: FontId,
+    glyph: u16,
+    size: u32,
+}
+
+// Where a rasterized glyph lives in the atlas, in pixels
+#[derive(Debug, Clone, Copy)]
+struct GlyphEntry {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    xmin: i32,
+    ymin: i32,
+}
+
+// Single channel coverage atlas filled on demand with every glyph that gets drawn
+// A CPU copy is kept so the texture can grow without losing its contents
+struct GlyphAtlas {
+    pixels: Vec<u8>,
+    size: u32,
+    max_size: u32,
+    entries: HashMap<GlyphKey, GlyphEntry>,
+    cursor: (u32, u32),
+    shelf_height: u32,
+    dirty: bool,
+    resized: bool,
+}
+
+impl GlyphAtlas {
+    const PADDING: u32 = 1;
+
+    fn new(size: u32, max_size: u32) -> Self {
+        Self {
+            pixels: vec![0; (size * size) as usize],
+            size,
+            max_size,
+            entries: HashMap::new(),
+            cursor: (0, 0),
+            shelf_height: 0,
+            dirty: true,
+            resized: true,
+        }
+    }
+
+    fn get(&mut self, fonts: &FontCollection, key: GlyphKey) -> Option<GlyphEntry> {
+        if let Some(entry) = self.entries.get(&key) {
+            return Some(*entry);
+        }
+
+        let (metrics, coverage) = fonts.font(key.font).ok()?.rasterize_indexed(key.glyph, f32::from_bits(key.size));
+        let (width, height) = (metrics.width as u32, metrics.height as u32);
+        let (x, y) = if width == 0 || height == 0 {
+            (0, 0)
+        } else {
+            self.allocate(width, height)?
+        };
+
+        for row in 0..height {
+            let src = (row * width) as usize;
+            let dst = ((y + row) * self.size + x) as usize;
+            self.pixels[dst..dst + width as usize].copy_from_slice(&coverage[src..src + width as usize]);
+        }
+        self.dirty = true;
+
+        let entry = GlyphEntry { x, y, width, height, xmin: metrics.xmin, ymin: metrics.ymin };
+        self.entries.insert(key, entry);
+        Some(entry)
+    }
+
+    fn allocate(&mut self, width: u32, height: u32) -> Option<(u32, u32)> {
+        let (w, h) = (width + Self::PADDING, height + Self::PADDING);
+        loop {
+            if self.cursor.0 + w > self.size {
+                self.cursor = (0, self.cursor.1 + self.shelf_height);
+                self.shelf_height = 0;
+            }
+            if w <= self.size && self.cursor.1 + h <= self.size {
+                let position = self.cursor;
+                self.cursor.0 += w;
+                self.shelf_height = self.shelf_height.max(h);
+                return Some(position);
+            }
+            if !self.grow() {
+                log::warn!("Glyph atlas is full at {}x{}", self.size, self.size);
+                return None;
+            }
+        }
+    }
+
+    // Doubles the atlas, existing glyphs keep their pixel positions
+    fn grow(&mut self) -> bool {
+        if self.size * 2 > self.max_size {
+            return false;
+        }
+
+        let size = self.size * 2;
+        let mut pixels = vec![0; (size * size) as usize];
+        for row in 0..self.size {
+            let src = (row * self.size) as usize;
+            let dst = (row * size) as usize;
+            pixels[dst..dst + self.size as usize].copy_from_slice(&self.pixels[src..src + self.size as usize]);
+        }
+
+        // The shelf in progress may continue into the new space to its right
+        self.pixels = pixels;
+        self.size = size;
+        self.resized = true;
+        self.dirty = true;
+        true
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct TextVertex {
+    // Screen position in pixels, or world position of the label
+    anchor: [f32; 3],
+    // Corner offset from the anchor, in pixels or world units
+    offset: [f32; 2],
+    tex_coords: [f32; 2],
+    color: [f32; 4],
+    // 1 for billboarded world space text
+    world: f32,
+}
+
+impl TextVertex {
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<TextVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 7]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 11]>() as wgpu::BufferAddress,
+                    shader_location: 4,
+                    format: wgpu::VertexFormat::Float32,
+                }
+            ]
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct TextUniform {
+    screen_proj: [[f32; 4]; 4],
+    view_proj: [[f32; 4]; 4],
+    camera_right: [f32; 4],
+    camera_up: [f32; 4],
+}
+
+// A glyph waiting for the atlas to settle before its vertices are built
+struct QueuedGlyph {
+    entry: GlyphEntry,
+    anchor: [f32; 3],
+    min: [f32; 2],
+    max: [f32; 2],
+    color: [f32; 4],
+    world: bool,
+}
+
+pub struct TextRenderer {
+    pub fonts: FontCollection,
+    atlas: GlyphAtlas,
+    atlas_texture: Option<Texture>,
+    atlas_bind_group: Option<wgpu::BindGroup>,
+    texture_bind_group_layout: wgpu::BindGroupLayout,
+    pipeline: wgpu::RenderPipeline,
+    pipeline_layout: wgpu::PipelineLayout,
+    format: wgpu::TextureFormat,
+    uniform_buffer: Tracked<wgpu::Buffer>,
+    uniform_bind_group: wgpu::BindGroup,
+    vertex_buffer: Tracked<wgpu::Buffer>,
+    index_buffer: Tracked<wgpu::Buffer>,
+    // In glyphs
+    capacity: usize,
+    queued: Vec<QueuedGlyph>,
+    num_glyphs: u32,
+}
+
+impl TextRenderer {
+    pub const SHADER: &'static str = "text.wgsl";
+
+    pub fn new(device: &wgpu::Device, shader: &wgpu::ShaderModule, format: wgpu::TextureFormat) -> Self {
+        let texture_bind_group_layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ],
+                label: Some("glyph_atlas_bind_group_layout"),
+            }
+        );
+
+        let uniform_buffer = stats::track_buffer(device, device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Text Uniform Buffer"),
+            size: std::mem::size_of::<TextUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        }));
+
+        let uniform_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("text_uniform_bind_group_layout"),
+        });
+
+        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &uniform_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                }
+            ],
+            label: Some("text_uniform_bind_group"),
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Text Pipeline Layout"),
+            bind_group_layouts: &[
+                &texture_bind_group_layout,
+                &uniform_bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = Self::create_pipeline(device, &pipeline_layout, shader, format);
+
+        let capacity = 1024;
+
+        Self {
+            fonts: FontCollection::new(),
+            atlas: GlyphAtlas::new(512, device.limits().max_texture_dimension_2d),
+            atlas_texture: None,
+            atlas_bind_group: None,
+            texture_bind_group_layout,
+            pipeline,
+            pipeline_layout,
+            format,
+            uniform_buffer,
+            uniform_bind_group,
+            vertex_buffer: Self::create_vertex_buffer(device, capacity),
+            index_buffer: create_quad_index_buffer(device, capacity, Some("Text Index Buffer")),
+            capacity,
+            queued: Vec::new(),
+            num_glyphs: 0,
+        }
+    }
+
+    // Rebuilds the pipeline from a reloaded shader, the old one is kept on errors
+    pub fn reload_shader(&mut self, device: &wgpu::Device, shader: &wgpu::ShaderModule) -> Result<()> {
+        self.pipeline = shader::validated(device, || Self::create_pipeline(device, &self.pipeline_layout, shader, self.format))?;
+        Ok(())
+    }
+
+    fn create_pipeline(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        shader: &wgpu::ShaderModule,
+        format: wgpu::TextureFormat,
+    ) -> wgpu::RenderPipeline {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Text Pipeline"),
+            layout: Some(layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: "vs_main",
+                buffers: &[TextVertex::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: None,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                unclipped_depth: false,
+                conservative: false
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+        })
+    }
+
+    pub fn load_font(&mut self, bytes: &[u8]) -> Result<FontId> {
+        self.fonts.load(bytes)
+    }
+
+    // Lays the section out and queues its glyphs for the next prepared frame
+    pub fn queue(&mut self, section: &TextSection) -> Result<()> {
+        let layout = self.fonts.layout(section.text, section.font, section.size, section.max_width, section.align)?;
+
+        let (anchor, origin, scale, world) = match section.space {
+            TextSpace::Screen(position) => ([position[0], position[1], 0.0], [0.0, 0.0], 1.0, false),
+            TextSpace::World { position, line_height } => (
+                position.into(),
+                // Centered horizontally, standing on the anchor
+                [layout.width * 0.5, layout.height],
+                line_height / layout.line_height,
+                true,
+            ),
+        };
+
+        for glyph in layout.glyphs.iter() {
+            let key = GlyphKey { font: glyph.font, glyph: glyph.glyph, size: section.size.to_bits() };
+            let Some(entry) = self.atlas.get(&self.fonts, key) else { continue };
+            if entry.width == 0 || entry.height == 0 {
+                continue;
+            }
+
+            // Bitmaps are placed from their bottom left corner relative to the baseline
+            let left = glyph.x.round() + entry.xmin as f32;
+            let top = glyph.y.round() - entry.ymin as f32 - entry.height as f32;
+            self.queued.push(QueuedGlyph {
+                entry,
+                anchor,
+                min: [(left - origin[0]) * scale, (top - origin[1]) * scale],
+                max: [
+                    (left + entry.width as f32 - origin[0]) * scale,
+                    (top + entry.height as f32 - origin[1]) * scale,
+                ],
+                color: section.color,
+                world,
+            });
+        }
+
+        Ok(())
+    }
+
+    // Uploads new glyphs and the queued text, the queue is emptied afterwards
+    pub fn prepare(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, camera: &Camera, width: u32, height: u32) {
+        use cgmath::InnerSpace;
+
+        let forward = (camera.target - camera.eye).normalize();
+        let right = forward.cross(camera.up).normalize();
+        let up = right.cross(forward);
+        let screen_proj = OPENGL_TO_WGPU_MATRIX * cgmath::ortho(0.0, width as f32, height as f32, 0.0, -1.0, 1.0);
+        let uniform = TextUniform {
+            screen_proj: screen_proj.into(),
+            view_proj: camera.build_view_projection_matrix().into(),
+            camera_right: right.extend(0.0).into(),
+            camera_up: up.extend(0.0).into(),
+        };
+        stats::write_buffer(queue, &self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+
+        self.upload_atlas(device, queue);
+
+        let atlas_size = self.atlas.size as f32;
+        let vertices: Vec<TextVertex> = self.queued.drain(..).flat_map(|glyph| {
+            let uv_min = [glyph.entry.x as f32 / atlas_size, glyph.entry.y as f32 / atlas_size];
+            let uv_max = [
+                (glyph.entry.x + glyph.entry.width) as f32 / atlas_size,
+                (glyph.entry.y + glyph.entry.height) as f32 / atlas_size,
+            ];
+            let world = if glyph.world { 1.0 } else { 0.0 };
+            // Same corner order as the sprite quad
+            [
+                ([glyph.min[0], glyph.max[1]], [uv_min[0], uv_max[1]]),
+                ([glyph.max[0], glyph.max[1]], [uv_max[0], uv_max[1]]),
+                ([glyph.min[0], glyph.min[1]], [uv_min[0], uv_min[1]]),
+                ([glyph.max[0], glyph.min[1]], [uv_max[0], uv_min[1]]),
+            ].map(|(offset, tex_coords)| TextVertex {
+                anchor: glyph.anchor,
+                offset,
+                tex_coords,
+                color: glyph.color,
+                world,
+            })
+        }).collect();
+
+        self.num_glyphs = (vertices.len() / 4) as u32;
+        if self.num_glyphs == 0 {
+            return;
+        }
+
+        let glyphs = self.num_glyphs as usize;
+        if glyphs > self.capacity {
+            self.capacity = glyphs.next_power_of_two();
+            self.vertex_buffer = Self::create_vertex_buffer(device, self.capacity);
+            self.index_buffer = create_quad_index_buffer(device, self.capacity, Some("Text Index Buffer"));
+        }
+        stats::write_buffer(queue, &self.vertex_buffer, 0, bytemuck::cast_slice(&vertices));
+    }
+
+    pub fn render<'a>(&'a self, render_pass: &mut TrackedRenderPass<'a>) {
+        let Some(atlas_bind_group) = &self.atlas_bind_group else { return };
+        if self.num_glyphs == 0 {
+            return;
+        }
+
+        render_pass.set_pipeline(&self.pipeline, wgpu::PrimitiveTopology::TriangleList);
+        render_pass.set_bind_group(0, atlas_bind_group, &[]);
+        render_pass.set_bind_group(1, &self.uniform_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        render_pass.draw_indexed(0..self.num_glyphs * QUAD_INDICES.len() as u32, 0, 0..1);
+    }
+
+    fn upload_atlas(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        if self.atlas.resized || self.atlas_texture.is_none() {
+            let size = wgpu::Extent3d {
+                width: self.atlas.size,
+                height: self.atlas.size,
+                depth_or_array_layers: 1,
+            };
+            let texture = device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("Glyph Atlas"),
+                size,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::R8Unorm,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+                view_formats: &[],
+            });
+            let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+            let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                ..Default::default()
+            });
+
+            self.atlas_bind_group = Some(device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &self.texture_bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&sampler),
+                    }
+                ],
+                label: Some("glyph_atlas_bind_group"),
+            }));
+            self.atlas_texture = Some(Texture { texture: stats::track_texture(device, texture), view, sampler });
+            self.atlas.resized = false;
+            self.atlas.dirty = true;
+        }
+
+        if self.atlas.dirty {
+            let texture = self.atlas_texture.as_ref().unwrap();
+            stats::write_texture(
+                queue,
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture.texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                &self.atlas.pixels,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(self.atlas.size),
+                    rows_per_image: Some(self.atlas.size),
+                },
+                wgpu::Extent3d {
+                    width: self.atlas.size,
+                    height: self.atlas.size,
+                    depth_or_array_layers: 1,
+                },
+            );
+            self.atlas.dirty = false;
+        }
+    }
+
+    fn create_vertex_buffer(device: &wgpu::Device, glyphs: usize) -> Tracked<wgpu::Buffer> {
+        stats::track_buffer(device, device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Text Vertex Buffer"),
+            size: (glyphs * 4 * std::mem::size_of::<TextVertex>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        }))
+    }
+}
@@ -1,79 +1,508 @@
-use image::GenericImageView;
 use anyhow::*;
+use cgmath::InnerSpace;
+
+use super::{
+    compressed::CompressedImage,
+    mipmap,
+    stats::{self, Tracked},
+};
 
 pub struct Texture {
-    pub texture: wgpu::Texture,
+    pub texture: Tracked<wgpu::Texture>,
     pub view: wgpu::TextureView,
     pub sampler: wgpu::Sampler,
 }
 
 impl Texture {
+    pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+    pub fn create_depth_texture(device: &wgpu::Device, width: u32, height: u32, label: &str) -> Self {
+        let size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
+
+        let texture = device.create_texture(
+            &wgpu::TextureDescriptor {
+                label: Some(label),
+                size,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: Self::DEPTH_FORMAT,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            }
+        );
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(
+            &wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                mipmap_filter: wgpu::FilterMode::Nearest,
+                compare: Some(wgpu::CompareFunction::LessEqual),
+                lod_min_clamp: 0.0,
+                lod_max_clamp: 100.0,
+                ..Default::default()
+            }
+        );
+
+        Self { texture: stats::track_texture(device, texture), view, sampler }
+    }
+
     pub fn from_bytes(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         bytes: &[u8],
-        label: &str
+        label: &str,
+        options: &TextureOptions,
     ) -> Result<Self> {
         let img = image::load_from_memory(bytes)?;
-        Self::from_image(device, queue, &img, Some(label))
+        Self::from_image(device, queue, &img, Some(label), options)
     }
 
     pub fn from_image(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         img: &image::DynamicImage,
-        label: Option<&str>
+        label: Option<&str>,
+        options: &TextureOptions,
     ) -> Result<Self> {
-        let rgba = img.to_rgba8();
-        let dimensions = img.dimensions();
+        Self::from_layers(device, queue, &[img.to_rgba8()], wgpu::TextureViewDimension::D2, label, options)
+    }
 
-        let size = wgpu::Extent3d {
-            width: dimensions.0,
-            height: dimensions.1,
-            depth_or_array_layers: 1,
+    // All layers need the same size, shaders sample them as `texture_2d_array`
+    pub fn array_from_images(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        images: &[image::DynamicImage],
+        label: Option<&str>,
+        options: &TextureOptions,
+    ) -> Result<Self> {
+        let layers: Vec<image::RgbaImage> = images.iter().map(|img| img.to_rgba8()).collect();
+        Self::from_layers(device, queue, &layers, wgpu::TextureViewDimension::D2Array, label, options)
+    }
+
+    // Faces in the order +X, -X, +Y, -Y, +Z, -Z, all square and the same size
+    pub fn cubemap_from_images(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        faces: &[image::DynamicImage; 6],
+        label: Option<&str>,
+        options: &TextureOptions,
+    ) -> Result<Self> {
+        let layers: Vec<image::RgbaImage> = faces.iter().map(|img| img.to_rgba8()).collect();
+        ensure!(layers[0].width() == layers[0].height(), "Cubemap faces must be square, got {:?}", layers[0].dimensions());
+        Self::from_layers(device, queue, &layers, wgpu::TextureViewDimension::Cube, label, options)
+    }
+
+    // HDR cubemap from six faces, stored as Rgba16Float so values above 1 survive
+    // 8 bit faces are treated as sRGB and converted to linear
+    pub fn hdr_cubemap_from_images(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        faces: &[image::DynamicImage; 6],
+        label: Option<&str>,
+    ) -> Result<Self> {
+        let size = faces[0].width();
+        ensure!(
+            faces.iter().all(|face| face.width() == size && face.height() == size),
+            "Cubemap faces must be square and the same size, got {:?}",
+            faces.iter().map(|face| (face.width(), face.height())).collect::<Vec<_>>()
+        );
+
+        let layers: Vec<Vec<[f32; 4]>> = faces.iter().map(linear_texels).collect();
+        Self::from_hdr_faces(device, queue, &layers, size, label)
+    }
+
+    // Projects a latitude/longitude panorama onto a cubemap with `face_size` texels per side
+    // The panorama has +Y at the top and its horizontal center looks down -Z
+    pub fn cubemap_from_equirectangular(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        img: &image::DynamicImage,
+        face_size: u32,
+        label: Option<&str>,
+    ) -> Result<Self> {
+        ensure!(face_size > 0, "Cubemap faces can't be empty");
+        let (width, height) = (img.width(), img.height());
+        ensure!(width > 0 && height > 0, "Panorama can't be empty");
+        let texels = linear_texels(img);
+
+        // Bilinear, wrapping around horizontally
+        let sample = |u: f32, v: f32| {
+            let x = u * width as f32 - 0.5;
+            let y = (v * height as f32 - 0.5).clamp(0.0, height as f32 - 1.0);
+            let (x0, y0) = (x.floor(), y.floor());
+            let (fx, fy) = (x - x0, y - y0);
+            let texel = |x: f32, y: f32| {
+                let x = (x as i64).rem_euclid(width as i64) as u32;
+                let y = (y as u32).min(height - 1);
+                texels[(y * width + x) as usize]
+            };
+            let (a, b, c, d) = (texel(x0, y0), texel(x0 + 1.0, y0), texel(x0, y0 + 1.0), texel(x0 + 1.0, y0 + 1.0));
+            std::array::from_fn(|i| {
+                let top = a[i] + (b[i] - a[i]) * fx;
+                let bottom = c[i] + (d[i] - c[i]) * fx;
+                top + (bottom - top) * fy
+            })
+        };
+
+        let layers: Vec<Vec<[f32; 4]>> = (0..6)
+            .map(|face| {
+                (0..face_size * face_size)
+                    .map(|i| {
+                        let s = ((i % face_size) as f32 + 0.5) / face_size as f32 * 2.0 - 1.0;
+                        let t = ((i / face_size) as f32 + 0.5) / face_size as f32 * 2.0 - 1.0;
+                        let direction = cube_face_direction(face, s, t).normalize();
+                        let u = 0.5 + direction.x.atan2(-direction.z) / std::f32::consts::TAU;
+                        let v = 0.5 - direction.y.asin() / std::f32::consts::PI;
+                        sample(u, v)
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Self::from_hdr_faces(device, queue, &layers, face_size, label)
+    }
+
+    // KTX2 or DDS files with block compressed (or RGBA8) data, uploaded as is when the adapter
+    // supports the format and decoded on the CPU otherwise. The mip chain comes from the file,
+    // `options.mipmaps` is ignored and `options.srgb` only matters for legacy DDS headers
+    pub fn from_compressed(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        bytes: &[u8],
+        label: &str,
+        options: &TextureOptions,
+    ) -> Result<Self> {
+        let mut image = CompressedImage::parse(bytes, options.srgb)
+            .with_context(|| format!("Failed to load {}", label))?;
+        if !image.is_supported(device.features()) {
+            log::info!("{:?} isn't supported by the adapter, decoding {} on the CPU", image.format, label);
+            image = image.decode().with_context(|| format!("Failed to load {}", label))?;
+        }
+
+        let texture = device.create_texture(
+            &wgpu::TextureDescriptor {
+                label: Some(label),
+                size: image.size(),
+                mip_level_count: image.levels.len() as u32,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: image.format,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::COPY_SRC,
+                view_formats: &[],
+            }
+        );
+
+        let (_, block_height) = image.format.block_dimensions();
+        for (level, data) in image.levels.iter().enumerate() {
+            let physical = image.level_extent(level as u32).physical_size(image.format);
+            let rows = physical.height / block_height;
+            stats::write_texture(
+                queue,
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture,
+                    mip_level: level as u32,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                data,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(image.level_size(level as u32) as u32 / rows),
+                    rows_per_image: Some(rows),
+                },
+                wgpu::Extent3d { depth_or_array_layers: image.layers, ..physical },
+            );
+        }
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(image.view_dimension()),
+            ..Default::default()
+        });
+        let sampler = options.create_sampler(device)?;
+
+        Ok(Self { texture: stats::track_texture(device, texture), view, sampler })
+    }
+
+    fn from_hdr_faces(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        faces: &[Vec<[f32; 4]>],
+        size: u32,
+        label: Option<&str>,
+    ) -> Result<Self> {
+        let extent = wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 6,
         };
 
         let texture = device.create_texture(
             &wgpu::TextureDescriptor {
                 label,
-                size,
+                size: extent,
                 mip_level_count: 1,
                 sample_count: 1,
                 dimension: wgpu::TextureDimension::D2,
-                format: wgpu::TextureFormat::Rgba8UnormSrgb,
-                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+                format: wgpu::TextureFormat::Rgba16Float,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::COPY_SRC,
                 view_formats: &[],
             }
         );
 
-        queue.write_texture(
+        let halfs: Vec<u16> = faces.iter().flatten().flatten().map(|&c| f16_bits(c)).collect();
+        stats::write_texture(
+            queue,
             wgpu::ImageCopyTexture {
                 aspect: wgpu::TextureAspect::All,
                 texture: &texture,
                 mip_level: 0,
                 origin: wgpu::Origin3d::ZERO,
             },
-            &rgba,
+            bytemuck::cast_slice(&halfs),
             wgpu::ImageDataLayout {
                 offset: 0,
-                bytes_per_row: Some(4 * dimensions.0),
-                rows_per_image: Some(dimensions.1),
+                bytes_per_row: Some(8 * size),
+                rows_per_image: Some(size),
             },
-            size,
+            extent,
         );
 
-        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(
-            &wgpu::SamplerDescriptor {
-                address_mode_u: wgpu::AddressMode::ClampToEdge,
-                address_mode_v: wgpu::AddressMode::ClampToEdge,
-                address_mode_w: wgpu::AddressMode::ClampToEdge,
-                mag_filter: wgpu::FilterMode::Nearest,
-                min_filter: wgpu::FilterMode::Nearest,
-                ..Default::default()
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            ..Default::default()
+        });
+        let sampler = TextureOptions::default().with_filter(wgpu::FilterMode::Linear).create_sampler(device)?;
+
+        Ok(Self { texture: stats::track_texture(device, texture), view, sampler })
+    }
+
+    fn from_layers(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layers: &[image::RgbaImage],
+        view_dimension: wgpu::TextureViewDimension,
+        label: Option<&str>,
+        options: &TextureOptions,
+    ) -> Result<Self> {
+        ensure!(!layers.is_empty(), "A texture needs at least one image");
+        let dimensions = layers[0].dimensions();
+        ensure!(
+            layers.iter().all(|layer| layer.dimensions() == dimensions),
+            "All layers of {} must be {}x{}",
+            label.unwrap_or("a texture"),
+            dimensions.0,
+            dimensions.1
+        );
+
+        let size = wgpu::Extent3d {
+            width: dimensions.0,
+            height: dimensions.1,
+            depth_or_array_layers: layers.len() as u32,
+        };
+
+        let mip_level_count = if options.mipmaps {
+            mipmap::mip_level_count(dimensions.0, dimensions.1)
+        } else {
+            1
+        };
+
+        let texture = device.create_texture(
+            &wgpu::TextureDescriptor {
+                label,
+                size,
+                mip_level_count,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: options.format(),
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::COPY_SRC,
+                view_formats: &[],
             }
         );
 
-        Ok(Self { texture, view, sampler})
+        for (layer, rgba) in layers.iter().enumerate() {
+            stats::write_texture(
+                queue,
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d { x: 0, y: 0, z: layer as u32 },
+                },
+                rgba,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(4 * dimensions.0),
+                    rows_per_image: Some(dimensions.1),
+                },
+                wgpu::Extent3d { depth_or_array_layers: 1, ..size },
+            );
+        }
+
+        mipmap::generate_mipmaps(device, queue, &texture);
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(view_dimension),
+            ..Default::default()
+        });
+        let sampler = options.create_sampler(device)?;
+
+        Ok(Self { texture: stats::track_texture(device, texture), view, sampler })
+    }
+}
+
+// Direction through a texel of a cube face, `s` and `t` go from -1 to 1 right and down
+// Faces are in the order +X, -X, +Y, -Y, +Z, -Z like the layers of a cubemap
+pub fn cube_face_direction(face: u32, s: f32, t: f32) -> cgmath::Vector3<f32> {
+    match face {
+        0 => (1.0, -t, -s),
+        1 => (-1.0, -t, s),
+        2 => (s, 1.0, t),
+        3 => (s, -1.0, -t),
+        4 => (s, -t, 1.0),
+        _ => (-s, -t, -1.0),
+    }.into()
+}
+
+// Float RGBA texels, 8 and 16 bit images are sRGB and get linearized
+fn linear_texels(img: &image::DynamicImage) -> Vec<[f32; 4]> {
+    let hdr = matches!(img, image::DynamicImage::ImageRgb32F(_) | image::DynamicImage::ImageRgba32F(_));
+    let to_linear = |c: f32| if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) };
+
+    img.to_rgba32f().pixels()
+        .map(|pixel| {
+            let [r, g, b, a] = pixel.0;
+            if hdr { [r, g, b, a] } else { [to_linear(r), to_linear(g), to_linear(b), a] }
+        })
+        .collect()
+}
+
+// Rounds to the nearest half float, out of range values become infinity
+fn f16_bits(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let magnitude = value.abs();
+
+    if magnitude.is_nan() {
+        sign | 0x7E00
+    } else if magnitude >= 65520.0 {
+        sign | 0x7C00
+    } else if magnitude < 6.103_515_6e-5 {
+        // Subnormal, in steps of 2^-24
+        sign | (magnitude * 16_777_216.0).round() as u16
+    } else {
+        let exponent = ((bits >> 23) & 0xFF) + 15 - 127;
+        let mantissa = bits & 0x7F_FFFF;
+        // A carry out of the mantissa correctly bumps the exponent
+        sign | ((exponent << 10 | mantissa >> 13) + ((mantissa >> 12) & 1)) as u16
+    }
+}
+
+// How a texture is stored and sampled, the default matches pixel art and UI textures
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextureOptions {
+    // Generates the full mip chain on the GPU
+    pub mipmaps: bool,
+    // Color textures are sRGB, data like normal maps must be linear
+    pub srgb: bool,
+    pub address_mode: wgpu::AddressMode,
+    pub mag_filter: wgpu::FilterMode,
+    pub min_filter: wgpu::FilterMode,
+    pub mipmap_filter: wgpu::FilterMode,
+    // Maximum anisotropy from 1 to 16, above 1 needs every filter to be linear
+    pub anisotropy: u16,
+}
+
+impl Default for TextureOptions {
+    fn default() -> Self {
+        Self {
+            mipmaps: false,
+            srgb: true,
+            address_mode: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            anisotropy: 1,
+        }
+    }
+}
+
+impl TextureOptions {
+    // Repeating, mipmapped and filtered, for textures tiled over large surfaces
+    pub fn tiled() -> Self {
+        Self::default()
+            .with_mipmaps(true)
+            .with_address_mode(wgpu::AddressMode::Repeat)
+            .with_filter(wgpu::FilterMode::Linear)
+            .with_anisotropy(16)
+    }
+
+    pub fn normal_map() -> Self {
+        Self::tiled().linear()
+    }
+
+    pub fn with_mipmaps(mut self, mipmaps: bool) -> Self {
+        self.mipmaps = mipmaps;
+        self
+    }
+
+    pub fn linear(mut self) -> Self {
+        self.srgb = false;
+        self
+    }
+
+    pub fn with_address_mode(mut self, address_mode: wgpu::AddressMode) -> Self {
+        self.address_mode = address_mode;
+        self
+    }
+
+    // Sets the mag, min and mipmap filters at once
+    pub fn with_filter(mut self, filter: wgpu::FilterMode) -> Self {
+        self.mag_filter = filter;
+        self.min_filter = filter;
+        self.mipmap_filter = filter;
+        self
+    }
+
+    pub fn with_anisotropy(mut self, anisotropy: u16) -> Self {
+        self.anisotropy = anisotropy;
+        self
+    }
+
+    pub fn format(&self) -> wgpu::TextureFormat {
+        if self.srgb {
+            wgpu::TextureFormat::Rgba8UnormSrgb
+        } else {
+            wgpu::TextureFormat::Rgba8Unorm
+        }
+    }
+
+    pub fn create_sampler(&self, device: &wgpu::Device) -> Result<wgpu::Sampler> {
+        ensure!((1..=16).contains(&self.anisotropy), "Anisotropy must be between 1 and 16, got {}", self.anisotropy);
+        ensure!(
+            self.anisotropy == 1 || [self.mag_filter, self.min_filter, self.mipmap_filter].iter().all(|f| *f == wgpu::FilterMode::Linear),
+            "Anisotropic filtering needs linear mag, min and mipmap filters"
+        );
+
+        Ok(device.create_sampler(
+            &wgpu::SamplerDescriptor {
+                address_mode_u: self.address_mode,
+                address_mode_v: self.address_mode,
+                address_mode_w: self.address_mode,
+                mag_filter: self.mag_filter,
+                min_filter: self.min_filter,
+                mipmap_filter: self.mipmap_filter,
+                anisotropy_clamp: self.anisotropy,
+                ..Default::default()
+            }
+        ))
     }
 }
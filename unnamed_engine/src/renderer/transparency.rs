@@ -0,0 +1,354 @@
+// Blend modes of scene objects and weighted blended order independent transparency
+//
+// Opaque and cutout objects are drawn first, front to back, so the depth test rejects as
+// much as possible. Transparent ones follow back to front without writing depth. With OIT
+// enabled, alpha and premultiplied objects instead add up into an accumulation and a
+// revealage target in any order, which a fullscreen pass resolves over the opaque scene
+// (McGuire and Bavoil 2013). Additive blending doesn't depend on order, it stays sorted.
+use anyhow::*;
+
+use super::{
+    mesh::{InstanceRaw, Vertex},
+    shader,
+    stats::{self, Tracked, TrackedRenderPass},
+    texture::Texture,
+};
+
+pub const ACCUM_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+pub const REVEALAGE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
+
+// How an object combines with what is already on screen
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum BlendMode {
+    #[default]
+    Opaque,
+    // Opaque, but pixels under the scene's alpha cutoff are discarded, e.g. foliage and fences
+    Cutout,
+    // Straight alpha, for glass, water and ghost previews
+    Alpha,
+    // Adds its color weighted by alpha, for glows
+    Additive,
+    // Color already multiplied by alpha, for UI panels and textures authored that way
+    Premultiplied,
+}
+
+impl BlendMode {
+    // Drawn after the opaque queues, sorted back to front and without writing depth
+    pub fn is_transparent(self) -> bool {
+        matches!(self, BlendMode::Alpha | BlendMode::Additive | BlendMode::Premultiplied)
+    }
+
+    // Resolved by the OIT pass when it is enabled
+    pub fn is_order_independent(self) -> bool {
+        matches!(self, BlendMode::Alpha | BlendMode::Premultiplied)
+    }
+
+    pub fn blend_state(self) -> wgpu::BlendState {
+        match self {
+            BlendMode::Opaque | BlendMode::Cutout => wgpu::BlendState::REPLACE,
+            BlendMode::Alpha => wgpu::BlendState::ALPHA_BLENDING,
+            BlendMode::Additive => wgpu::BlendState {
+                color: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::SrcAlpha,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::Zero,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                },
+            },
+            BlendMode::Premultiplied => wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING,
+        }
+    }
+}
+
+// The OIT targets with the pipelines that fill and resolve them
+pub struct OitRenderer {
+    // Straight and premultiplied alpha write into the same targets
+    alpha_pipeline: wgpu::RenderPipeline,
+    premultiplied_pipeline: wgpu::RenderPipeline,
+    resolve_pipeline: wgpu::RenderPipeline,
+    resolve_pipeline_layout: wgpu::PipelineLayout,
+    resolve_bind_group_layout: wgpu::BindGroupLayout,
+    resolve_bind_group: wgpu::BindGroup,
+    accum: Tracked<wgpu::Texture>,
+    accum_view: wgpu::TextureView,
+    revealage: Tracked<wgpu::Texture>,
+    revealage_view: wgpu::TextureView,
+    format: wgpu::TextureFormat,
+}
+
+impl OitRenderer {
+    pub const SHADER: &'static str = "oit_resolve.wgsl";
+
+    // `scene_shader` and `scene_layout` are the main shader and its pipeline layout, the
+    // shader provides `fs_oit` and `fs_oit_premultiplied`
+    pub fn new(
+        device: &wgpu::Device,
+        scene_shader: &wgpu::ShaderModule,
+        scene_layout: &wgpu::PipelineLayout,
+        resolve_shader: &wgpu::ShaderModule,
+        format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+    ) -> Result<Self> {
+        let (alpha_pipeline, premultiplied_pipeline) = Self::create_accumulate_pipelines(device, scene_layout, scene_shader)?;
+
+        let resolve_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                Self::texture_entry(0),
+                Self::texture_entry(1),
+            ],
+            label: Some("oit_resolve_bind_group_layout"),
+        });
+        let resolve_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("OIT Resolve Pipeline Layout"),
+            bind_group_layouts: &[&resolve_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let resolve_pipeline = shader::validated(device, || {
+            Self::create_resolve_pipeline(device, &resolve_pipeline_layout, resolve_shader, format)
+        })?;
+
+        let (accum, accum_view) = Self::create_target(device, ACCUM_FORMAT, width, height, "OIT Accumulation Texture");
+        let (revealage, revealage_view) = Self::create_target(device, REVEALAGE_FORMAT, width, height, "OIT Revealage Texture");
+        let resolve_bind_group = Self::create_resolve_bind_group(device, &resolve_bind_group_layout, &accum_view, &revealage_view);
+
+        Ok(Self {
+            alpha_pipeline,
+            premultiplied_pipeline,
+            resolve_pipeline,
+            resolve_pipeline_layout,
+            resolve_bind_group_layout,
+            resolve_bind_group,
+            accum,
+            accum_view,
+            revealage,
+            revealage_view,
+            format,
+        })
+    }
+
+    fn texture_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+        wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+            },
+            count: None,
+        }
+    }
+
+    // Rebuilds the accumulation pipelines after the main shader changed
+    pub fn reload_scene_shader(&mut self, device: &wgpu::Device, scene_layout: &wgpu::PipelineLayout, shader: &wgpu::ShaderModule) -> Result<()> {
+        (self.alpha_pipeline, self.premultiplied_pipeline) = Self::create_accumulate_pipelines(device, scene_layout, shader)?;
+        Ok(())
+    }
+
+    pub fn reload_shader(&mut self, device: &wgpu::Device, shader: &wgpu::ShaderModule) -> Result<()> {
+        self.resolve_pipeline = shader::validated(device, || {
+            Self::create_resolve_pipeline(device, &self.resolve_pipeline_layout, shader, self.format)
+        })?;
+        Ok(())
+    }
+
+    fn create_accumulate_pipelines(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        shader: &wgpu::ShaderModule,
+    ) -> Result<(wgpu::RenderPipeline, wgpu::RenderPipeline)> {
+        shader::validated(device, || {
+            (
+                Self::create_accumulate_pipeline(device, layout, shader, "fs_oit"),
+                Self::create_accumulate_pipeline(device, layout, shader, "fs_oit_premultiplied"),
+            )
+        })
+    }
+
+    fn create_accumulate_pipeline(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        shader: &wgpu::ShaderModule,
+        entry_point: &str,
+    ) -> wgpu::RenderPipeline {
+        let add = wgpu::BlendComponent {
+            src_factor: wgpu::BlendFactor::One,
+            dst_factor: wgpu::BlendFactor::One,
+            operation: wgpu::BlendOperation::Add,
+        };
+        // Every layer scales the revealage by its transparency
+        let reveal = wgpu::BlendComponent {
+            src_factor: wgpu::BlendFactor::Zero,
+            dst_factor: wgpu::BlendFactor::OneMinusSrc,
+            operation: wgpu::BlendOperation::Add,
+        };
+
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("OIT Accumulate Pipeline"),
+            layout: Some(layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: "vs_main",
+                buffers: &[Vertex::desc(), InstanceRaw::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: shader,
+                entry_point,
+                targets: &[
+                    Some(wgpu::ColorTargetState {
+                        format: ACCUM_FORMAT,
+                        blend: Some(wgpu::BlendState { color: add, alpha: add }),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    }),
+                    Some(wgpu::ColorTargetState {
+                        format: REVEALAGE_FORMAT,
+                        blend: Some(wgpu::BlendState { color: reveal, alpha: reveal }),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    }),
+                ],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: Some(wgpu::Face::Back),
+                ..Default::default()
+            },
+            // Tested against the opaque scene, but layers never hide each other
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        })
+    }
+
+    fn create_resolve_pipeline(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        shader: &wgpu::ShaderModule,
+        format: wgpu::TextureFormat,
+    ) -> wgpu::RenderPipeline {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("OIT Resolve Pipeline"),
+            layout: Some(layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        })
+    }
+
+    fn create_target(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+        label: &str,
+    ) -> (Tracked<wgpu::Texture>, wgpu::TextureView) {
+        let texture = stats::track_texture(device, device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        }));
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        (texture, view)
+    }
+
+    fn create_resolve_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        accum_view: &wgpu::TextureView,
+        revealage_view: &wgpu::TextureView,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(accum_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(revealage_view),
+                },
+            ],
+            label: Some("oit_resolve_bind_group"),
+        })
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        (self.accum, self.accum_view) = Self::create_target(device, ACCUM_FORMAT, width, height, "OIT Accumulation Texture");
+        (self.revealage, self.revealage_view) = Self::create_target(device, REVEALAGE_FORMAT, width, height, "OIT Revealage Texture");
+        self.resolve_bind_group = Self::create_resolve_bind_group(device, &self.resolve_bind_group_layout, &self.accum_view, &self.revealage_view);
+    }
+
+    // Color attachments of the accumulation pass, cleared to no coverage
+    pub fn color_attachments(&self) -> [Option<wgpu::RenderPassColorAttachment<'_>>; 2] {
+        [
+            Some(wgpu::RenderPassColorAttachment {
+                view: &self.accum_view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                    store: wgpu::StoreOp::Store,
+                },
+            }),
+            Some(wgpu::RenderPassColorAttachment {
+                view: &self.revealage_view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                    store: wgpu::StoreOp::Store,
+                },
+            }),
+        ]
+    }
+
+    // The accumulation pipeline for an order independent blend mode
+    pub fn pipeline(&self, blend: BlendMode) -> Option<&wgpu::RenderPipeline> {
+        match blend {
+            BlendMode::Alpha => Some(&self.alpha_pipeline),
+            BlendMode::Premultiplied => Some(&self.premultiplied_pipeline),
+            _ => None,
+        }
+    }
+
+    // Blends the averaged layers over the target
+    pub fn resolve<'a>(&'a self, render_pass: &mut TrackedRenderPass<'a>) {
+        render_pass.set_pipeline(&self.resolve_pipeline, wgpu::PrimitiveTopology::TriangleList);
+        render_pass.set_bind_group(0, &self.resolve_bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}